@@ -217,6 +217,31 @@ impl Timestamps {
         Timestamps(result)
     }
 
+    /// Read the timestamps stored in the oplog itself. Returns None if the oplog has no
+    /// timestamps.
+    fn from_oplog(oplog: &ListOpLog) -> Option<Self> {
+        let spans = oplog.timestamps_in_range((0..oplog.len()).into());
+        if spans.is_empty() { return None; }
+
+        let mut result: HashMap<SmartString, Vec<DateTime<FixedOffset>>> = HashMap::new();
+        for (RemoteVersionSpan(agent, seq_range), ts) in spans {
+            let ts = DateTime::from_timestamp_millis(ts as i64).unwrap_or_default().fixed_offset();
+            let entry = result.entry(agent.into()).or_default();
+            if entry.len() < seq_range.end {
+                // Gaps are filled with the previous timestamp, like from_file does.
+                let last = entry.last().copied().unwrap_or_default();
+                entry.resize_with(seq_range.end, || last);
+            }
+            entry[seq_range.start..seq_range.end].fill(ts);
+        }
+
+        Some(Timestamps(result))
+    }
+
+    fn load(oplog: &ListOpLog, filename: Option<OsString>) -> Option<Self> {
+        filename.map(Self::from_file).or_else(|| Self::from_oplog(oplog))
+    }
+
     fn get_raw(&self, agent: &str, seq: usize) -> DateTime<FixedOffset> {
         self.0.get(agent).and_then(|t| {
             t.get(seq).or(t.last()).copied()
@@ -341,7 +366,7 @@ fn check_history(num_agents: usize, txns: &[TraceExportTxn]) {
 }
 
 pub fn export_trace_to_json(oplog: &ListOpLog, timestamp_filename: Option<OsString>, shatter: bool) -> TraceExportData {
-    let timestamps = timestamp_filename.map(Timestamps::from_file);
+    let timestamps = Timestamps::load(oplog, timestamp_filename);
 
     // TODO: A hashmap is overkill here. A vec + binary search would be fine. Eh.
     // Each chunk of operations has an ID so other ops can refer to it.
@@ -566,7 +591,7 @@ pub fn export_transformed(oplog: &ListOpLog, timestamp_filename: Option<OsString
     //
     // Note that the order that we traverse the operations here may be different from the order
     // that we export things in the export function above.
-    let timestamps = timestamp_filename.map(Timestamps::from_file);
+    let timestamps = Timestamps::load(oplog, timestamp_filename);
 
     let mut txns = vec![];
     // let timestamp: SmartString = timestamp.into();
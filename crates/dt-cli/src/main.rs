@@ -234,7 +234,8 @@ enum Commands {
         #[arg(short, long)]
         pretty: bool,
 
-        /// The file containing timestamps to merge
+        /// The file containing timestamps to merge. If missing, timestamps stored in the oplog are
+        /// used instead.
         #[arg(short)]
         timestamp_filename: Option<OsString>,
 
@@ -261,7 +262,8 @@ enum Commands {
         #[arg(short, long)]
         pretty: bool,
 
-        /// The file containing timestamps to merge
+        /// The file containing timestamps to merge. If missing, timestamps stored in the oplog are
+        /// used instead.
         #[arg(short)]
         timestamp_filename: Option<OsString>,
 
@@ -1,6 +1,7 @@
 use diamond_types::AgentId;
 use diamond_types::list::{ListCRDT as InnerListCRDT};
-use diamond_types::list::encoding::ENCODE_FULL;
+use diamond_types::causalgraph::summary::VersionSummary;
+use diamond_types::list::encoding::{ENCODE_FULL, ENCODE_PATCH};
 use rand::{distributions::Alphanumeric, Rng};
 
 #[swift_bridge::bridge]
@@ -16,6 +17,8 @@ mod ffi {
         pub fn replace_wchar(&mut self, wchar_pos: usize, remove: usize, ins: &str);
 
         pub fn encode(&self) -> Vec<u8>;
+        pub fn encode_version_summary(&self) -> Vec<u8>;
+        pub fn encode_patch_since_summary(&self, summary: &[u8]) -> Option<Vec<u8>>;
         pub fn save(&self, path: &str);
 
         pub fn to_string(&self) -> String;
@@ -96,6 +99,19 @@ impl ListCRDT {
         self.inner.oplog.encode(&ENCODE_FULL)
     }
 
+    pub fn encode_version_summary(&self) -> Vec<u8> {
+        self.inner.oplog.cg.agent_assignment.summarize_versions().encode()
+    }
+
+    /// Encode everything the remote peer is missing, given its version summary. Returns nil if
+    /// the summary can't be decoded.
+    pub fn encode_patch_since_summary(&self, summary: &[u8]) -> Option<Vec<u8>> {
+        let oplog = &self.inner.oplog;
+        let summary = VersionSummary::decode(summary).ok()?;
+        let (common, _) = oplog.cg.intersect_with_summary(&summary, &[]);
+        Some(oplog.encode_from(&ENCODE_PATCH, common.as_ref()))
+    }
+
     pub fn save(&self, path: &str) {
         let data = self.encode();
         std::fs::write(path, data).unwrap()
@@ -3,10 +3,12 @@ mod utils;
 use wasm_bindgen::prelude::*;
 // use serde_wasm_bindgen::Serializer;
 // use serde::{Serialize};
-use diamond_types::{AgentId, LV};
+use diamond_types::{AgentId, CreateValue, LV, Primitive, ROOT_CRDT_ID};
+use diamond_types::OpLog as DTMapOpLog;
 use diamond_types::list::{ListBranch as DTBranch, ListCRDT, ListOpLog as DTOpLog};
 use diamond_types::list::encoding::{ENCODE_FULL, ENCODE_PATCH};
 use diamond_types::list::operation::TextOperation;
+use diamond_types::causalgraph::summary::VersionSummary;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -23,6 +25,13 @@ type WasmResult<T = JsValue> = Result<T, serde_wasm_bindgen::Error>;
 #[wasm_bindgen]
 pub struct Branch(DTBranch);
 
+/// A multi-type document, with a map at the root.
+#[wasm_bindgen]
+pub struct MapDoc {
+    inner: DTMapOpLog,
+    agent_id: Option<AgentId>,
+}
+
 #[wasm_bindgen]
 pub struct OpLog {
     inner: DTOpLog,
@@ -86,6 +95,19 @@ pub fn get_patch_since(oplog: &DTOpLog, from_version: &[LV]) -> Vec<u8> {
     bytes
 }
 
+pub fn get_version_summary_bytes(oplog: &DTOpLog) -> Vec<u8> {
+    oplog.cg.agent_assignment.summarize_versions().encode()
+}
+
+pub fn get_patch_since_summary_bytes(oplog: &DTOpLog, summary_bytes: &[u8]) -> WasmResult<Vec<u8>> {
+    let summary = VersionSummary::decode(summary_bytes).map_err(|e| {
+        let js: JsValue = format!("Error decoding summary {:?}", e).into();
+        serde_wasm_bindgen::Error::from(js)
+    })?;
+    let (common, _) = oplog.cg.intersect_with_summary(&summary, &[]);
+    Ok(oplog.encode_from(&ENCODE_PATCH, common.as_ref()))
+}
+
 pub fn decode_and_add(oplog: &mut DTOpLog, bytes: &[u8]) -> WasmResult {
     match oplog.decode_and_add(bytes) {
         Ok(version) => {
@@ -112,11 +134,67 @@ pub fn merge_versions(oplog: &DTOpLog, a: &[LV], b: &[LV]) -> Box<[LV]> {
     result.as_ref().into()
 }
 
+/// Convert a javascript value into a primitive which can be stored in a document. Integers are
+/// stored as I64 values and all other numbers are stored as floats.
+fn js_to_primitive(value: JsValue) -> WasmResult<Primitive> {
+    if value.is_null() || value.is_undefined() {
+        Ok(Primitive::Nil)
+    } else if let Some(b) = value.as_bool() {
+        Ok(Primitive::Bool(b))
+    } else if let Some(n) = value.as_f64() {
+        if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+            Ok(Primitive::I64(n as i64))
+        } else {
+            Ok(Primitive::F64(n))
+        }
+    } else if let Some(s) = value.as_string() {
+        Ok(Primitive::Str(s.into()))
+    } else if value.is_instance_of::<js_sys::Uint8Array>() {
+        Ok(Primitive::Bytes(js_sys::Uint8Array::from(value).to_vec()))
+    } else {
+        let js: JsValue = "Unsupported value type".into();
+        Err(js.into())
+    }
+}
+
 fn unwrap_agentid(agent_id: Option<AgentId>) -> AgentId {
     agent_id.expect_throw("Agent missing. Set agent before modifying oplog.")
 }
 
 
+#[wasm_bindgen]
+impl MapDoc {
+    #[wasm_bindgen(constructor)]
+    pub fn new(agent_name: Option<String>) -> Self {
+        utils::set_panic_hook();
+
+        let mut inner = DTMapOpLog::new();
+        let agent_id = agent_name.map(|name| {
+            inner.cg.get_or_create_agent_id(name.as_str())
+        });
+
+        Self { inner, agent_id }
+    }
+
+    #[wasm_bindgen(js_name = setAgent)]
+    pub fn set_agent(&mut self, agent: &str) {
+        self.agent_id = Some(self.inner.cg.get_or_create_agent_id(agent));
+    }
+
+    /// Set a key in the root map to a primitive value. Numbers, strings, booleans, null and
+    /// Uint8Arrays are supported.
+    #[wasm_bindgen]
+    pub fn set(&mut self, key: &str, value: JsValue) -> WasmResult<usize> {
+        let value = js_to_primitive(value)?;
+        Ok(self.inner.local_map_set(unwrap_agentid(self.agent_id), ROOT_CRDT_ID, key, CreateValue::Primitive(value)))
+    }
+
+    #[wasm_bindgen]
+    pub fn checkout(&self) -> WasmResult {
+        serde_wasm_bindgen::to_value(&self.inner.checkout())
+    }
+}
+
 #[wasm_bindgen]
 impl Branch {
     #[wasm_bindgen(constructor)]
@@ -290,6 +368,18 @@ impl OpLog {
         get_patch_since(&self.inner, from_version)
     }
 
+    /// Get a compact binary summary of every version in the oplog. A remote peer can pass this to
+    /// getPatchSinceSummaryBytes to get all the changes we're missing.
+    #[wasm_bindgen(js_name = getVersionSummaryBytes)]
+    pub fn get_version_summary_bytes(&self) -> Vec<u8> {
+        get_version_summary_bytes(&self.inner)
+    }
+
+    #[wasm_bindgen(js_name = getPatchSinceSummaryBytes)]
+    pub fn get_patch_since_summary_bytes(&self, summary_bytes: &[u8]) -> WasmResult<Vec<u8>> {
+        get_patch_since_summary_bytes(&self.inner, summary_bytes)
+    }
+
     // This method adds 17kb to the wasm bundle, or 5kb after brotli.
     #[wasm_bindgen(js_name = fromBytes)]
     pub fn from_bytes(bytes: &[u8], agent_name: Option<String>) -> Self {
@@ -398,6 +488,16 @@ impl Doc {
         get_patch_since(&self.inner.oplog, from_version)
     }
 
+    #[wasm_bindgen(js_name = getVersionSummaryBytes)]
+    pub fn get_version_summary_bytes(&self) -> Vec<u8> {
+        get_version_summary_bytes(&self.inner.oplog)
+    }
+
+    #[wasm_bindgen(js_name = getPatchSinceSummaryBytes)]
+    pub fn get_patch_since_summary_bytes(&self, summary_bytes: &[u8]) -> WasmResult<Vec<u8>> {
+        get_patch_since_summary_bytes(&self.inner.oplog, summary_bytes)
+    }
+
     // TODO: Do better error handling here.
     // pub fn from_bytes(bytes: &[u8], agent_name: Option<String>) -> WasmResult<Doc> {
     #[wasm_bindgen(js_name = fromBytes)]
@@ -1,7 +1,9 @@
 use std::collections::{btree_map, BTreeMap, BTreeSet};
-use smallvec::SmallVec;
-use crate::{CRDTKind, DTRange, Branch, OpLog, LV, LVKey, RegisterInfo, RegisterState, RegisterValue, ROOT_CRDT_ID, Primitive};
+use smallvec::{smallvec, SmallVec};
+use crate::{CRDTKind, DTRange, Branch, Frontier, OpLog, LV, LVKey, RegisterInfo, RegisterState, RegisterValue, ROOT_CRDT_ID, Primitive};
 use smartstring::alias::String as SmartString;
+use crate::oplog::create_to_snapshot;
+use crate::path::{expect_kind, PathError, PathSegment};
 
 pub(crate) fn btree_range_for_crdt<V>(map: &BTreeMap<(LVKey, SmartString), V>, crdt: LVKey) -> btree_map::Range<'_, (LVKey, SmartString), V> {
     let empty_str: SmartString = "".into();
@@ -30,40 +32,91 @@ impl RegisterState {
             f(rv);
         }
     }
+
+    fn contains_value(&self, val: &RegisterValue) -> bool {
+        self.value == *val || self.conflicts_with.contains(val)
+    }
 }
 
 impl OpLog {
-    pub fn checkout_at_version(_frontier: &[LV]) -> Branch {
-        todo!()
+    /// Get a branch containing the state of the document at the specified version.
+    ///
+    /// The frontier must be a valid version within this oplog. Map registers are resolved using
+    /// only the operations known at that version, and text CRDTs are replayed up to it.
+    pub fn checkout_at_version(&self, frontier: &[LV]) -> Branch {
+        let frontier = Frontier::from_unsorted(frontier);
+        if frontier == self.cg.version {
+            return self.checkout_tip();
+        }
+
+        // Everything in these (descending) ranges happened after the requested version.
+        let excluded = self.cg.diff_since_rev(frontier.as_ref());
+        self.checkout_filtered(frontier, &excluded)
     }
 
     /// Get the current value for this register, ignoring any other conflicting values.
     ///
     /// TODO: Is it worth keeping this method? Users could just call get_state below and throw out
     /// the conflicting values...
-    fn value_for_register_nc(&self, info: &RegisterInfo) -> RegisterValue {
+    fn value_for_register_nc(&self, info: &RegisterInfo) -> Option<RegisterValue> {
         // We're calculating but not using the conflicting ops. But eh - conflicts are rare.
-        let (active_idx, _) = self.tie_break_mv(info);
-        (&info.ops[active_idx]).into()
+        let (active_idx, _) = self.tie_break_mv(info)?;
+        Some((&info.ops[active_idx]).into())
     }
 
     /// Get this register's state. This includes the current value and any other conflicting values.
-    fn get_state_for_register(&self, info: &RegisterInfo) -> RegisterState {
-        let (active_idx, other_idxes) = self.tie_break_mv(info);
+    /// Returns None if the register has been deleted.
+    fn get_state_for_register(&self, info: &RegisterInfo) -> Option<RegisterState> {
+        let (active_idx, other_idxes) = self.tie_break_mv(info)?;
 
-        RegisterState {
+        Some(RegisterState {
             value: (&info.ops[active_idx]).into(),
             conflicts_with: other_idxes.map(|iter| {
                 iter.map(|idx| (&info.ops[idx]).into()).collect()
             }).unwrap_or_default(),
-        }
+        })
+    }
+
+
+    /// Get this register's state, ignoring any operations in the excluded ranges. Returns None if
+    /// every operation on the register has been excluded, or if the register has been deleted.
+    fn get_state_for_register_excluding(&self, info: &RegisterInfo, excluded: &[DTRange]) -> Option<RegisterState> {
+        if excluded.is_empty() { return self.get_state_for_register(info); }
+
+        let visible_versions: SmallVec<LV, 4> = info.ops.iter()
+            .map(|(v, _)| *v)
+            .filter(|v| !excluded.iter().any(|r| r.contains(*v)))
+            .collect();
+        if visible_versions.is_empty() { return None; }
+
+        let dominators = self.cg.graph.find_dominators(&visible_versions);
+        let supremum: SmallVec<usize, 2> = dominators.iter().map(|v| {
+            info.ops.binary_search_by_key(v, |e| e.0).unwrap()
+        }).collect();
+
+        let (active_idx, other_idxes) = self.tie_break_idxes(&info.ops, &supremum)?;
+        Some(RegisterState {
+            value: (&info.ops[active_idx]).into(),
+            conflicts_with: other_idxes.map(|iter| {
+                iter.map(|idx| (&info.ops[idx]).into()).collect()
+            }).unwrap_or_default(),
+        })
     }
 
+    /// Get the state of a standalone register CRDT. Registers which haven't been set (at this
+    /// version) contain nil.
+    fn get_state_for_register_crdt(&self, crdt: LVKey, excluded: &[DTRange]) -> RegisterState {
+        let info = self.registers.get(&crdt).unwrap();
+        self.get_state_for_register_excluding(info, excluded).unwrap_or_else(|| RegisterState {
+            value: RegisterValue::Primitive(Primitive::Nil),
+            conflicts_with: vec![],
+        })
+    }
 
     fn checkout_map_key_nc(&self, crdt: LVKey, key: &str) -> Option<RegisterValue> {
         // Just checkout this path item.
         let info = self.map_keys.get(&(crdt, key.into()))?;
-        Some(self.value_for_register_nc(info))
+        self.value_for_register_nc(info)
     }
 
     pub fn checkout_at_path_nc(&self, path: &[&str]) -> Option<RegisterValue> {
@@ -90,50 +143,108 @@ impl OpLog {
     }
 
     pub fn checkout_tip(&self) -> Branch {
+        self.checkout_filtered(self.cg.version.clone(), &[])
+    }
+
+    /// Checkout the document at the specified frontier. The excluded ranges must name every
+    /// operation in the oplog which isn't contained in the frontier.
+    fn checkout_filtered(&self, frontier: Frontier, excluded: &[DTRange]) -> Branch {
+        let mut result = Branch {
+            frontier: frontier.clone(),
+            maps: Default::default(),
+            texts: Default::default(),
+            collections: Default::default(),
+            registers: Default::default(),
+            lists: Default::default(),
+            counters: Default::default(),
+        };
+
+        self.copy_crdt_into(&mut result, CRDTKind::Map, ROOT_CRDT_ID, frontier.as_ref(), excluded, None);
+        result
+    }
+
+    /// Get the items in a collection, ignoring any operations in the excluded ranges.
+    fn collection_items_excluding(&self, crdt: LVKey, excluded: &[DTRange]) -> BTreeMap<LV, RegisterValue> {
+        let info = self.collections.get(&crdt).unwrap();
+        if excluded.is_empty() {
+            return info.iter_live().map(|(v, val)| (v, create_to_snapshot(v, val))).collect();
+        }
+
+        let is_visible = |v: &LV| !excluded.iter().any(|r| r.contains(*v));
+        let removed: BTreeSet<LV> = info.removes.iter()
+            .filter(|(v, _)| is_visible(v))
+            .map(|(_, target)| *target)
+            .collect();
+
+        info.inserts.iter()
+            .filter(|(v, _)| is_visible(v) && !removed.contains(v))
+            .map(|(v, val)| (*v, create_to_snapshot(*v, val)))
+            .collect()
+    }
+
+    /// Copy the state of the named CRDT (and everything it contains) at the specified version into
+    /// the branch. If copied is passed, the IDs of all copied CRDTs are added to the set.
+    fn copy_crdt_into(&self, result: &mut Branch, kind: CRDTKind, crdt: LVKey, frontier: &[LV], excluded: &[DTRange], mut copied: Option<&mut BTreeSet<LVKey>>) {
         // There's 2 strategies I could employ here:
         // 1. Walk recursively through the tree and copy items
         // 2. Walk through all the living items (registers, maps, texts) and copy them
 
         // I'm going with option 2, but that might not be the best option.
 
-        let mut maps_to_copy = vec![ROOT_CRDT_ID];
-        let mut result = Branch {
-            frontier: self.cg.version.clone(),
-            maps: Default::default(),
-            texts: Default::default(),
-        };
+        // I could use recursion here but this avoids stack-smashing attacks.
+        let mut to_copy = vec![(kind, crdt)];
 
-        while let Some(crdt) = maps_to_copy.pop() {
-            let mut this_map = BTreeMap::new();
-            for ((this_id, key), info) in btree_range_for_crdt(&self.map_keys, crdt) {
-                debug_assert_eq!(*this_id, crdt);
-                let state = self.get_state_for_register(info);
-
-                state.each_value(|rv| {
-                    // Recursively copy value and conflicting values.
-                    match rv {
-                        RegisterValue::Primitive(_) => {}
-                        RegisterValue::OwnedCRDT(CRDTKind::Map, child_map) => {
-                            // I could use recursion here but this avoids stack-smashing attacks.
-                            maps_to_copy.push(*child_map);
-                        }
-                        RegisterValue::OwnedCRDT(CRDTKind::Register, _) => { todo!() }
-                        RegisterValue::OwnedCRDT(CRDTKind::Collection, _) => { todo!() }
-                        RegisterValue::OwnedCRDT(CRDTKind::Text, text_crdt) => {
-                            // Eventually (rich) text items might contain more embedded CRDTs. But for
-                            // now this is fine.
-                            let rope = self.checkout_text(*text_crdt);
-                            result.texts.insert(*text_crdt, rope);
-                        }
-                    }
-                });
+        while let Some((kind, crdt)) = to_copy.pop() {
+            let mut visit_child = |rv: &RegisterValue| {
+                if let RegisterValue::OwnedCRDT(kind, child) = rv {
+                    to_copy.push((*kind, *child));
+                }
+            };
 
-                this_map.insert(key.clone(), state);
+            match kind {
+                CRDTKind::Map => {
+                    let mut this_map = BTreeMap::new();
+                    for ((this_id, key), info) in btree_range_for_crdt(&self.map_keys, crdt) {
+                        debug_assert_eq!(*this_id, crdt);
+                        let Some(state) = self.get_state_for_register_excluding(info, excluded) else {
+                            // The key hadn't been set yet at this version, or it was deleted.
+                            continue;
+                        };
+
+                        // Recursively copy value and conflicting values.
+                        state.each_value(&mut visit_child);
+                        this_map.insert(key.clone(), state);
+                    }
+                    result.maps.insert(crdt, this_map);
+                }
+                CRDTKind::Collection => {
+                    let items = self.collection_items_excluding(crdt, excluded);
+                    items.values().for_each(&mut visit_child);
+                    result.collections.insert(crdt, items);
+                }
+                CRDTKind::List => {
+                    let items = self.checkout_list_at(crdt, frontier);
+                    items.iter().for_each(&mut visit_child);
+                    result.lists.insert(crdt, items);
+                }
+                CRDTKind::Text => {
+                    // Eventually (rich) text items might contain more embedded CRDTs. But for
+                    // now this is fine.
+                    result.texts.insert(crdt, self.checkout_text_at(crdt, frontier));
+                }
+                CRDTKind::Register => {
+                    let state = self.get_state_for_register_crdt(crdt, excluded);
+                    state.each_value(&mut visit_child);
+                    result.registers.insert(crdt, state);
+                }
+                CRDTKind::Counter => {
+                    let value = self.counters.get(&crdt).unwrap().value_excluding(excluded);
+                    result.counters.insert(crdt, value);
+                }
             }
-            result.maps.insert(crdt, this_map);
-        }
 
-        result
+            if let Some(copied) = copied.as_deref_mut() { copied.insert(crdt); }
+        }
     }
 }
 
@@ -149,6 +260,10 @@ impl Branch {
             frontier: Default::default(),
             maps: BTreeMap::from([(ROOT_CRDT_ID, Default::default())]),
             texts: Default::default(),
+            collections: Default::default(),
+            registers: Default::default(),
+            lists: Default::default(),
+            counters: Default::default(),
         }
     }
 
@@ -174,108 +289,319 @@ impl Branch {
                     self.recursive_delete_reg_state(state);
                 }
             }
+            CRDTKind::Collection => {
+                let Some(items) = self.collections.remove(&crdt) else { return; };
+                for (_, val) in items {
+                    if let RegisterValue::OwnedCRDT(kind, key) = val {
+                        self.recursive_delete(kind, key);
+                    }
+                }
+            }
+            CRDTKind::List => {
+                let Some(items) = self.lists.remove(&crdt) else { return; };
+                for val in items {
+                    if let RegisterValue::OwnedCRDT(kind, key) = val {
+                        self.recursive_delete(kind, key);
+                    }
+                }
+            }
+            CRDTKind::Register => {
+                let Some(state) = self.registers.remove(&crdt) else { return; };
+                self.recursive_delete_reg_state(state);
+            }
+            CRDTKind::Counter => {
+                self.counters.remove(&crdt);
+            }
             CRDTKind::Text => {
                 self.texts.remove(&crdt); // Easy peasy!
             }
-            _ => { todo!() }
         }
     }
 
     /// Returns the list of version ranges which were merged, in reverse order (!!!)
     pub fn merge_changes_to_tip(&mut self, oplog: &OpLog) -> SmallVec<DTRange, 4> {
-        // Well, for now nothing can be deleted yet. So that makes things easier.
-        let diff_rev = oplog.cg.diff_since_rev(self.frontier.as_ref());
-
-        for range in diff_rev.iter().rev() {
-            // for (_, text_crdt) in self.text_index.range(*range) {
-            //     text_crdts_to_send.insert(*text_crdt);
-            // }
-
-            for (_v, (map_crdt, key)) in oplog.map_index.range(*range) {
-                if oplog.deleted_crdts.contains(map_crdt) { continue; } // Container was deleted. Ignore!
-
-                // I could be more clever here, but the easier answer is to just fully replace this
-                // object key with the new (current) value.
-                let obj = self.maps.entry(*map_crdt).or_default();
-                let info = oplog.map_keys.get(&(*map_crdt, key.clone())).unwrap();
-                let state = oplog.get_state_for_register(info);
-
-                // I could iterate through the state looking for new CRDT items to insert, but I
-                // don't think I need to since they'll also show up in the map_index set.
-                let old_state = obj.insert(key.clone(), state);
-
-                let Some(old_state) = old_state else { continue; };
-                old_state.each_value(|v| {
-                    if let RegisterValue::OwnedCRDT(kind, key) = v {
-                        // A register was superceded which used to store a CRDT value. Recursively
-                        // delete the old value.
-                        self.recursive_delete(*kind, *key);
+        self.merge_inner(oplog, oplog.cg.version.as_ref())
+    }
+
+    /// Merge the changes in the oplog up to the specified frontier into this branch. The frontier
+    /// can be any version in the oplog - including versions which are concurrent with the
+    /// branch's current version. Afterwards the branch will contain the union of both versions.
+    ///
+    /// Only CRDTs which have changed are updated.
+    pub fn merge(&mut self, oplog: &OpLog, frontier: &[LV]) {
+        self.merge_inner(oplog, frontier);
+    }
+
+    fn merge_inner(&mut self, oplog: &OpLog, frontier: &[LV]) -> SmallVec<DTRange, 4> {
+        let (_, new_ranges_rev) = oplog.cg.graph.diff_rev(self.frontier.as_ref(), frontier);
+        if new_ranges_rev.is_empty() { return new_ranges_rev; }
+
+        let new_frontier = oplog.cg.graph.find_dominators_2(self.frontier.as_ref(), frontier);
+        let at_tip = new_frontier == oplog.cg.version;
+        // Operations which happened after the version we're moving to. These are ignored.
+        let excluded = if at_tip { smallvec![] } else { oplog.cg.diff_since_rev(new_frontier.as_ref()) };
+
+        // Find the map keys and text CRDTs which have been modified by the merged operations.
+        //
+        // Keys are ordered with the root CRDT first (since its ID wraps to 0) so container maps
+        // are (usually) processed before their children.
+        let mut changed_keys: BTreeSet<(LVKey, &SmartString)> = BTreeSet::new();
+        let mut changed_registers = BTreeSet::new();
+        let mut changed_texts = BTreeSet::new();
+        let mut changed_lists = BTreeSet::new();
+        // The collection and counter indexes contain every operation, so we can always use them.
+        let mut changed_collections = BTreeSet::new();
+        let mut changed_counters = BTreeSet::new();
+        for range in new_ranges_rev.iter() {
+            for (_, collection_crdt) in oplog.collection_index.range(*range) {
+                changed_collections.insert(*collection_crdt);
+            }
+            for (_, counter_crdt) in oplog.counter_index.range(*range) {
+                changed_counters.insert(*counter_crdt);
+            }
+        }
+        if at_tip {
+            // When we're merging to the tip of the oplog, the indexes name the operations we care
+            // about. Any operation which has since been superseded will be superseded by another
+            // operation within the merged range.
+            for range in new_ranges_rev.iter() {
+                for (_, (crdt, key)) in oplog.map_index.range(*range) {
+                    changed_keys.insert((crdt.wrapping_add(1), key));
+                }
+                for (_, register_crdt) in oplog.register_index.range(*range) {
+                    changed_registers.insert(*register_crdt);
+                }
+                for (_, text_crdt) in oplog.text_index.range(*range) {
+                    changed_texts.insert(*text_crdt);
+                }
+                for (_, list_crdt) in oplog.list_index.range(*range) {
+                    changed_lists.insert(*list_crdt);
+                }
+            }
+        } else {
+            for ((crdt, key), info) in oplog.map_keys.iter() {
+                if info.iter_ops_in_ranges(&new_ranges_rev).next().is_some() {
+                    changed_keys.insert((crdt.wrapping_add(1), key));
+                }
+            }
+            for (register_crdt, info) in oplog.registers.iter() {
+                if info.iter_ops_in_ranges(&new_ranges_rev).next().is_some() {
+                    changed_registers.insert(*register_crdt);
+                }
+            }
+            for (text_crdt, info) in oplog.texts.iter() {
+                let modified = new_ranges_rev.iter().any(|r| {
+                    let idx = info.ops.find_next_index(r.start);
+                    idx < info.ops.num_entries() && info.ops.0[idx].0 < r.end
+                });
+                if modified { changed_texts.insert(*text_crdt); }
+            }
+            for (list_crdt, info) in oplog.lists.iter() {
+                let modified = new_ranges_rev.iter().any(|r| {
+                    let idx = info.ops.ops.find_next_index(r.start);
+                    idx < info.ops.ops.num_entries() && info.ops.ops.0[idx].0 < r.end
+                });
+                if modified { changed_lists.insert(*list_crdt); }
+            }
+        }
+
+        // CRDTs which were created by this merge and copied in at the new version. They don't
+        // need to be updated again.
+        let mut copied = BTreeSet::new();
+
+        for (crdt, key) in changed_keys {
+            let crdt = crdt.wrapping_sub(1);
+            if copied.contains(&crdt) { continue; }
+            // If the container is missing, its either been deleted or it'll be copied in when we
+            // process its parent.
+            let Some(obj) = self.maps.get_mut(&crdt) else { continue; };
+
+            let info = oplog.map_keys.get(&(crdt, key.clone())).unwrap();
+            let Some(state) = oplog.get_state_for_register_excluding(info, &excluded) else {
+                // The key has been deleted.
+                if let Some(old_state) = obj.remove(key) {
+                    self.recursive_delete_reg_state(old_state);
+                }
+                continue;
+            };
+
+            let old_state = obj.insert(key.clone(), state.clone());
+            self.update_register_children(oplog, old_state, &state, new_frontier.as_ref(), &excluded, &mut copied);
+        }
+
+        for register_crdt in changed_registers {
+            if copied.contains(&register_crdt) { continue; }
+            if !self.registers.contains_key(&register_crdt) { continue; }
+
+            let state = oplog.get_state_for_register_crdt(register_crdt, &excluded);
+            let old_state = self.registers.insert(register_crdt, state.clone());
+            self.update_register_children(oplog, old_state, &state, new_frontier.as_ref(), &excluded, &mut copied);
+        }
+
+        for collection_crdt in changed_collections {
+            if copied.contains(&collection_crdt) { continue; }
+            if !self.collections.contains_key(&collection_crdt) { continue; }
+
+            let items = oplog.collection_items_excluding(collection_crdt, &excluded);
+            let old_items = self.collections.insert(collection_crdt, items.clone()).unwrap();
+
+            for (item, v) in old_items.iter() {
+                if let RegisterValue::OwnedCRDT(kind, child) = v {
+                    if !items.contains_key(item) {
+                        self.recursive_delete(*kind, *child);
                     }
-                })
+                }
             }
 
-            for (_v, text_crdt) in oplog.text_index.range(*range) {
-                if oplog.deleted_crdts.contains(text_crdt) { continue; }
+            for v in items.values() {
+                if let RegisterValue::OwnedCRDT(kind, child) = v {
+                    if !self.contains_crdt(*kind, *child) {
+                        oplog.copy_crdt_into(self, *kind, *child, new_frontier.as_ref(), &excluded, Some(&mut copied));
+                    }
+                }
+            }
+        }
 
-                let textinfo = oplog.texts.get(text_crdt).unwrap();
-                let text_content = self.texts.entry(*text_crdt).or_default();
+        for list_crdt in changed_lists {
+            if copied.contains(&list_crdt) { continue; }
+            // Lists which aren't in the branch have either been deleted or haven't been created yet.
+            let Some(items) = self.lists.get_mut(&list_crdt) else { continue; };
+
+            let owned_crdts = |items: &[RegisterValue]| -> Vec<(CRDTKind, LVKey)> {
+                items.iter().filter_map(|v| match v {
+                    RegisterValue::OwnedCRDT(kind, child) => Some((*kind, *child)),
+                    RegisterValue::Primitive(_) => None,
+                }).collect()
+            };
+            let old_crdts = owned_crdts(items);
+
+            let info = oplog.lists.get(&list_crdt).unwrap();
+            info.merge_into(items, &oplog.cg, self.frontier.as_ref(), new_frontier.as_ref(), |lv| {
+                create_to_snapshot(lv, &info.values[&lv])
+            });
+            let new_crdts = owned_crdts(items);
+
+            let new_ids: BTreeSet<LVKey> = new_crdts.iter().map(|(_, child)| *child).collect();
+            for (kind, child) in old_crdts {
+                if !new_ids.contains(&child) {
+                    self.recursive_delete(kind, child);
+                }
+            }
 
-                textinfo.merge_into(text_content, &oplog.cg, self.frontier.as_ref(), oplog.cg.version.as_ref());
+            for (kind, child) in new_crdts {
+                if !self.contains_crdt(kind, child) {
+                    oplog.copy_crdt_into(self, kind, child, new_frontier.as_ref(), &excluded, Some(&mut copied));
+                }
             }
         }
 
-        self.frontier = oplog.cg.version.clone();
-        diff_rev
+        for counter_crdt in changed_counters {
+            if copied.contains(&counter_crdt) { continue; }
+            let Some(value) = self.counters.get_mut(&counter_crdt) else { continue; };
+            *value = oplog.counters.get(&counter_crdt).unwrap().value_excluding(&excluded);
+        }
+
+        for text_crdt in changed_texts {
+            if copied.contains(&text_crdt) { continue; }
+            // Texts which aren't in the branch have either been deleted or haven't been created yet.
+            let Some(text_content) = self.texts.get_mut(&text_crdt) else { continue; };
+
+            let textinfo = oplog.texts.get(&text_crdt).unwrap();
+            textinfo.merge_into(text_content, &oplog.cg, self.frontier.as_ref(), new_frontier.as_ref());
+        }
+
+        self.frontier = new_frontier;
+        new_ranges_rev
     }
 
-    pub fn crdt_at_path(&self, path: &[&str]) -> (CRDTKind, LVKey) {
+    /// After a register's state has changed, recursively delete any CRDT values which have been
+    /// superseded and copy in any newly created CRDTs.
+    fn update_register_children(&mut self, oplog: &OpLog, old_state: Option<RegisterState>, state: &RegisterState, frontier: &[LV], excluded: &[DTRange], copied: &mut BTreeSet<LVKey>) {
+        if let Some(old_state) = old_state {
+            old_state.each_value(|v| {
+                if let RegisterValue::OwnedCRDT(kind, child) = v {
+                    if !state.contains_value(v) {
+                        self.recursive_delete(*kind, *child);
+                    }
+                }
+            });
+        }
+
+        state.each_value(|v| {
+            if let RegisterValue::OwnedCRDT(kind, child) = v {
+                if !self.contains_crdt(*kind, *child) {
+                    oplog.copy_crdt_into(self, *kind, *child, frontier, excluded, Some(copied));
+                }
+            }
+        });
+    }
+
+    fn contains_crdt(&self, kind: CRDTKind, crdt: LVKey) -> bool {
+        match kind {
+            CRDTKind::Map => self.maps.contains_key(&crdt),
+            CRDTKind::Register => self.registers.contains_key(&crdt),
+            CRDTKind::Collection => self.collections.contains_key(&crdt),
+            CRDTKind::List => self.lists.contains_key(&crdt),
+            CRDTKind::Counter => self.counters.contains_key(&crdt),
+            CRDTKind::Text => self.texts.contains_key(&crdt),
+        }
+    }
+
+    /// Get the value of the named counter CRDT, if it exists in this branch.
+    pub fn counter(&self, crdt: LVKey) -> Option<i64> {
+        self.counters.get(&crdt).copied()
+    }
+
+    /// Get the state of the named register CRDT, if it exists in this branch.
+    pub fn register(&self, crdt: LVKey) -> Option<&RegisterState> {
+        self.registers.get(&crdt)
+    }
+
+    /// Find the CRDT at the specified path in the branch. Map keys are looked up by name, list
+    /// items by position and collection items by ID.
+    pub fn crdt_at_path(&self, path: &[PathSegment]) -> Result<(CRDTKind, LVKey), PathError> {
         let mut kind = CRDTKind::Map;
         let mut key = ROOT_CRDT_ID;
 
         for p in path {
-            match kind {
-                CRDTKind::Map => {
-                    let obj = self.maps.get(&key).unwrap();
-                    let state = obj.get(*p).unwrap();
-
-                    match state.value {
-                        RegisterValue::Primitive(_) => {
-                            panic!("Found primitive, not CRDT");
-                        }
-                        RegisterValue::OwnedCRDT(new_kind, new_key) => {
-                            kind = new_kind;
-                            key = new_key;
-                        }
-                    }
+            let value = match (kind, *p) {
+                (CRDTKind::Map, PathSegment::Key(k)) => {
+                    &self.maps[&key].get(k).ok_or(PathError::MissingKey)?.value
+                }
+                (CRDTKind::List, PathSegment::Index(idx)) => {
+                    self.lists[&key].get(idx).ok_or(PathError::MissingKey)?
                 }
-                _ => {
-                    panic!("Invalid path in document");
+                (CRDTKind::Collection, PathSegment::Index(item)) => {
+                    self.collections[&key].get(&item).ok_or(PathError::MissingKey)?
+                }
+                _ => { return Err(PathError::InvalidSegment); }
+            };
+
+            match value {
+                RegisterValue::Primitive(_) => { return Err(PathError::NotCRDT); }
+                RegisterValue::OwnedCRDT(new_kind, new_key) => {
+                    kind = *new_kind;
+                    key = *new_key;
                 }
             }
         }
 
-        (kind, key)
+        Ok((kind, key))
     }
 
-    pub fn text_at_path(&self, path: &[&str]) -> LVKey {
-        let (kind, key) = self.crdt_at_path(path);
-        if kind != CRDTKind::Text {
-            panic!("Unexpected CRDT kind {:?}", kind);
-        } else { key }
+    pub fn text_at_path(&self, path: &[PathSegment]) -> Result<LVKey, PathError> {
+        expect_kind(self.crdt_at_path(path)?, CRDTKind::Text)
     }
 
-    pub fn register_in_map(&self, path: &[&str], key: &str) -> Option<&RegisterValue> {
-        let (kind, crdt) = self.crdt_at_path(path);
-        if kind != CRDTKind::Map {
-            panic!("Expected a map, found a {:?}", kind);
-        }
-
-        Some(&self.maps.get(&crdt)?.get(key)?.value)
+    pub fn register_in_map(&self, path: &[PathSegment], key: &str) -> Result<&RegisterValue, PathError> {
+        let crdt = expect_kind(self.crdt_at_path(path)?, CRDTKind::Map)?;
+        Ok(&self.maps[&crdt].get(key).ok_or(PathError::MissingKey)?.value)
     }
 
-    // TODO: Probably better to return a Result here.
-    pub fn str_in_map(&self, path: &[&str], key: &str) -> Option<&str> {
-        if let RegisterValue::Primitive(Primitive::Str(s)) = self.register_in_map(path, key)? {
+    /// Get the string stored at the named key in a map. Returns None if the key is missing or if
+    /// it contains some other kind of value.
+    pub fn str_in_map(&self, path: &[PathSegment], key: &str) -> Option<&str> {
+        if let RegisterValue::Primitive(Primitive::Str(s)) = self.register_in_map(path, key).ok()? {
             Some(s.as_str())
         } else {
             None
@@ -292,31 +618,73 @@ impl Branch {
             .copied()
             .collect();
 
+        let mut owned_collection_crdts = BTreeSet::new();
+        let root_collection_crdts: BTreeSet<_> = self.collections.keys()
+            .copied()
+            .collect();
+
+        let mut owned_register_crdts = BTreeSet::new();
+        let root_register_crdts: BTreeSet<_> = self.registers.keys()
+            .copied()
+            .collect();
+
+        let mut owned_list_crdts = BTreeSet::new();
+        let root_list_crdts: BTreeSet<_> = self.lists.keys()
+            .copied()
+            .collect();
+
+        let mut owned_counter_crdts = BTreeSet::new();
+        let root_counter_crdts: BTreeSet<_> = self.counters.keys()
+            .copied()
+            .collect();
+
+        let mut visit = |v: &RegisterValue| {
+            if let RegisterValue::OwnedCRDT(kind, key) = v {
+                // Each CRDT should only be referenced once.
+                assert!(match kind {
+                    CRDTKind::Map => &mut owned_map_crdts,
+                    CRDTKind::Text => &mut owned_text_crdts,
+                    CRDTKind::Collection => &mut owned_collection_crdts,
+                    CRDTKind::Register => &mut owned_register_crdts,
+                    CRDTKind::List => &mut owned_list_crdts,
+                    CRDTKind::Counter => &mut owned_counter_crdts,
+                }.insert(*key));
+            }
+        };
+
         for (map_crdt, state) in &self.maps {
             root_map_crdts.insert(*map_crdt);
 
             for reg_state in state.values() {
-                reg_state.each_value(|v| {
-                    if let RegisterValue::OwnedCRDT(kind, key) = v {
-                        match kind {
-                            CRDTKind::Map => &mut owned_map_crdts,
-                            CRDTKind::Text => &mut owned_text_crdts,
-                            _ => { unimplemented!() }
-                        }.insert(*key);
-                    }
-                });
+                reg_state.each_value(&mut visit);
             }
         }
 
+        for items in self.collections.values() {
+            items.values().for_each(&mut visit);
+        }
+
+        for state in self.registers.values() {
+            state.each_value(&mut visit);
+        }
+
+        for items in self.lists.values() {
+            items.iter().for_each(&mut visit);
+        }
+
         assert_eq!(owned_map_crdts, root_map_crdts);
         assert_eq!(owned_text_crdts, root_text_crdts);
+        assert_eq!(owned_collection_crdts, root_collection_crdts);
+        assert_eq!(owned_register_crdts, root_register_crdts);
+        assert_eq!(owned_list_crdts, root_list_crdts);
+        assert_eq!(owned_counter_crdts, root_counter_crdts);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{CRDTKind, CreateValue, Branch, OpLog, Primitive, ROOT_CRDT_ID};
-    use crate::list::operation::TextOperation;
+    use crate::{CRDTKind, CreateValue, Branch, Frontier, OpLog, PathError, PathSegment, Primitive, RegisterValue, ROOT_CRDT_ID};
+    use crate::list::operation::{ListOpKind, TextOperation};
 
     fn check_oplog_checkouts_match(oplog: &OpLog) -> Branch {
         // There's two ways we can get a checkout for an oplog: Either call checkout_tip() or
@@ -336,6 +704,21 @@ mod tests {
         branch1
     }
 
+    /// Check out every pair of versions and merge one into the other. The result should match
+    /// checking out the merged version directly.
+    fn check_all_checkouts(oplog: &OpLog, versions: &[Frontier]) {
+        for from in versions.iter() {
+            for to in versions.iter() {
+                let mut branch = oplog.checkout_at_version(from.as_ref());
+                branch.merge(oplog, to.as_ref());
+                branch.dbg_check(true);
+
+                let merged = oplog.cg.graph.find_dominators_2(from.as_ref(), to.as_ref());
+                assert_eq!(branch, oplog.checkout_at_version(merged.as_ref()));
+            }
+        }
+    }
+
     #[test]
     fn simple_branch_checkout() {
         let mut oplog = OpLog::new();
@@ -356,8 +739,8 @@ mod tests {
         let parents = oplog.cg.version.clone();
         let a = oplog.cg.assign_local_op_with_parents(parents.as_ref(), seph, 1).start;
         let b = oplog.cg.assign_local_op_with_parents(parents.as_ref(), kaarina, 1).start;
-        oplog.remote_map_set(child_obj, a, "yo", CreateValue::Primitive(Primitive::I64(123)));
-        oplog.remote_map_set(child_obj, b, "yo", CreateValue::Primitive(Primitive::I64(321)));
+        oplog.remote_map_set(child_obj, a, "yo", CreateValue::Primitive(Primitive::I64(123))).unwrap();
+        oplog.remote_map_set(child_obj, b, "yo", CreateValue::Primitive(Primitive::I64(321))).unwrap();
 
         // let b = oplog.checkout_tip();
         // dbg!(b);
@@ -404,4 +787,352 @@ mod tests {
 
         assert_eq!(branch_expected, branch_incremental);
     }
+
+    #[test]
+    fn checkout_at_version_matches_history() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let mut snapshots = vec![oplog.clone()];
+
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "content", CreateValue::NewCRDT(CRDTKind::Text));
+        snapshots.push(oplog.clone());
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "Oh hai!"));
+        snapshots.push(oplog.clone());
+        let child_obj = oplog.local_map_set(seph, ROOT_CRDT_ID, "child", CreateValue::NewCRDT(CRDTKind::Map));
+        oplog.local_map_set(seph, child_obj, "a", CreateValue::Primitive(Primitive::I64(1)));
+        snapshots.push(oplog.clone());
+        oplog.local_text_op(seph, text, TextOperation::new_delete(0..3));
+        oplog.local_map_set(seph, child_obj, "a", CreateValue::Primitive(Primitive::I64(2)));
+        snapshots.push(oplog.clone());
+        // Overwriting the child map deletes it, but it should still show up in old checkouts.
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "child", CreateValue::Primitive(Primitive::Bool(true)));
+        snapshots.push(oplog.clone());
+
+        for s in snapshots {
+            let branch = oplog.checkout_at_version(s.cg.version.as_ref());
+            branch.dbg_check(true);
+            assert_eq!(branch, s.checkout_tip());
+        }
+    }
+
+    #[test]
+    fn checkout_at_concurrent_version() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "content", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "abc"));
+
+        let parents = oplog.cg.version.clone();
+        let a = oplog.cg.assign_local_op_with_parents(parents.as_ref(), seph, 1).start;
+        oplog.remote_map_set(ROOT_CRDT_ID, a, "yo", CreateValue::Primitive(Primitive::I64(123))).unwrap();
+        let b = oplog.cg.assign_local_op_with_parents(parents.as_ref(), kaarina, 2);
+        oplog.remote_map_set(ROOT_CRDT_ID, b.start, "yo", CreateValue::Primitive(Primitive::I64(321))).unwrap();
+        oplog.remote_text_op(text, (b.start + 1..b.end).into(), TextOperation::new_insert(0, "X"));
+        oplog.dbg_check(true);
+
+        let branch_a = oplog.checkout_at_version(&[a]);
+        branch_a.dbg_check(true);
+        assert_eq!(branch_a.texts[&text].to_string(), "abc");
+        assert_eq!(branch_a.register_in_map(&[], "yo"), Ok(&RegisterValue::Primitive(Primitive::I64(123))));
+
+        let branch_b = oplog.checkout_at_version(&[b.last()]);
+        branch_b.dbg_check(true);
+        assert_eq!(branch_b.texts[&text].to_string(), "Xabc");
+        assert_eq!(branch_b.register_in_map(&[], "yo"), Ok(&RegisterValue::Primitive(Primitive::I64(321))));
+
+        // Checking out at the merged version should give us the same result as the tip.
+        let merged = oplog.checkout_at_version(&[a, b.last()]);
+        assert_eq!(merged, oplog.checkout_tip());
+        // The frontier doesn't need to be sorted.
+        assert_eq!(oplog.checkout_at_version(&[b.last(), a]), merged);
+
+        let empty = oplog.checkout_at_version(&[]);
+        assert_eq!(empty, Branch::new());
+    }
+
+    #[test]
+    fn merge_to_arbitrary_versions() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+        let mut versions = vec![oplog.cg.version.clone()];
+
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "content", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "abc"));
+        versions.push(oplog.cg.version.clone());
+
+        // Two concurrent branches of history.
+        let parents = oplog.cg.version.clone();
+        let a = oplog.cg.assign_local_op_with_parents(parents.as_ref(), seph, 3);
+        oplog.remote_map_set(ROOT_CRDT_ID, a.start, "child", CreateValue::NewCRDT(CRDTKind::Map)).unwrap();
+        oplog.remote_map_set(a.start, a.start + 1, "x", CreateValue::Primitive(Primitive::I64(1))).unwrap();
+        oplog.remote_text_op(text, (a.start + 2..a.end).into(), TextOperation::new_insert(3, "d"));
+        versions.push(Frontier::new_1(a.last()));
+
+        let b = oplog.cg.assign_local_op_with_parents(parents.as_ref(), kaarina, 3);
+        oplog.remote_map_set(ROOT_CRDT_ID, b.start, "child", CreateValue::NewCRDT(CRDTKind::Text)).unwrap();
+        oplog.remote_text_op(b.start, (b.start + 1..b.end).into(), TextOperation::new_insert(0, "hi"));
+        versions.push(Frontier::new_1(b.last()));
+        versions.push(oplog.cg.version.clone());
+
+        // Then overwrite the conflicting values.
+        let text_2 = oplog.local_map_set(kaarina, ROOT_CRDT_ID, "child", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(kaarina, text_2, TextOperation::new_insert(0, "yo"));
+        oplog.local_text_op(seph, text, TextOperation::new_delete(0..2));
+        versions.push(oplog.cg.version.clone());
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "content", CreateValue::Primitive(Primitive::Nil));
+        versions.push(oplog.cg.version.clone());
+        oplog.dbg_check(true);
+
+        check_all_checkouts(&oplog, &versions);
+    }
+
+    #[test]
+    fn collection_checkouts() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let mut versions = vec![oplog.cg.version.clone()];
+
+        let list = oplog.local_map_set(seph, ROOT_CRDT_ID, "list", CreateValue::NewCRDT(CRDTKind::Collection));
+        let a = oplog.local_collection_insert(seph, list, CreateValue::Primitive(Primitive::I64(1)));
+        versions.push(oplog.cg.version.clone());
+        let text = oplog.local_collection_insert(seph, list, CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+        let map = oplog.local_collection_insert(seph, list, CreateValue::NewCRDT(CRDTKind::Map));
+        oplog.local_map_set(seph, map, "x", CreateValue::NewCRDT(CRDTKind::Text));
+        versions.push(oplog.cg.version.clone());
+
+        let parents = oplog.cg.version.clone();
+        let v = oplog.cg.assign_local_op_with_parents(parents.as_ref(), seph, 1).start;
+        oplog.remote_collection_remove(list, v, text).unwrap();
+        versions.push(Frontier::new_1(v));
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+        let v2 = oplog.cg.assign_local_op_with_parents(parents.as_ref(), kaarina, 2).start;
+        oplog.remote_collection_remove(list, v2, text).unwrap();
+        oplog.remote_collection_remove(list, v2 + 1, map).unwrap();
+        versions.push(Frontier::new_1(v2 + 1));
+        oplog.local_collection_remove(seph, list, a);
+        versions.push(oplog.cg.version.clone());
+        oplog.dbg_check(true);
+
+        let branch = check_oplog_checkouts_match(&oplog);
+        assert_eq!(branch.collections[&list].len(), 0);
+
+        check_all_checkouts(&oplog, &versions);
+    }
+
+    #[test]
+    fn map_delete_checkouts() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let mut versions = vec![oplog.cg.version.clone()];
+
+        let map = oplog.local_map_set(seph, ROOT_CRDT_ID, "map", CreateValue::NewCRDT(CRDTKind::Map));
+        oplog.local_map_set(seph, map, "x", CreateValue::Primitive(Primitive::I64(1)));
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "text", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+        versions.push(oplog.cg.version.clone());
+
+        let parents = oplog.cg.version.clone();
+        let v = oplog.cg.assign_local_op_with_parents(parents.as_ref(), seph, 2).start;
+        oplog.remote_map_set(ROOT_CRDT_ID, v, "map", CreateValue::Deleted).unwrap();
+        oplog.remote_map_set(ROOT_CRDT_ID, v + 1, "text", CreateValue::Deleted).unwrap();
+        versions.push(Frontier::new_1(v + 1));
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+        let v2 = oplog.cg.assign_local_op_with_parents(parents.as_ref(), kaarina, 1).start;
+        oplog.remote_map_set(map, v2, "y", CreateValue::Primitive(Primitive::I64(2))).unwrap();
+        versions.push(Frontier::new_1(v2));
+        versions.push(oplog.cg.version.clone());
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "text", CreateValue::Primitive(Primitive::Bool(true)));
+        oplog.local_map_delete(seph, map, "x");
+        versions.push(oplog.cg.version.clone());
+        oplog.dbg_check(true);
+
+        let branch = check_oplog_checkouts_match(&oplog);
+        assert!(!branch.maps[&ROOT_CRDT_ID].contains_key("map"));
+        assert!(!branch.maps.contains_key(&map));
+        assert!(branch.texts.is_empty());
+
+        check_all_checkouts(&oplog, &versions);
+    }
+
+    #[test]
+    fn register_checkouts() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let mut versions = vec![oplog.cg.version.clone()];
+
+        let reg = oplog.local_map_set(seph, ROOT_CRDT_ID, "reg", CreateValue::NewCRDT(CRDTKind::Register));
+        versions.push(oplog.cg.version.clone());
+        let text = oplog.local_register_set(seph, reg, CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+        versions.push(oplog.cg.version.clone());
+
+        // Concurrent sets leave the register with a conflict.
+        let parents = oplog.cg.version.clone();
+        let v = oplog.cg.assign_local_op_with_parents(parents.as_ref(), seph, 1).start;
+        oplog.remote_register_set(reg, v, CreateValue::NewCRDT(CRDTKind::Map)).unwrap();
+        versions.push(Frontier::new_1(v));
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+        let v2 = oplog.cg.assign_local_op_with_parents(parents.as_ref(), kaarina, 1).start;
+        oplog.remote_register_set(reg, v2, CreateValue::Primitive(Primitive::I64(10))).unwrap();
+        versions.push(Frontier::new_1(v2));
+        versions.push(oplog.cg.version.clone());
+        oplog.local_map_set(seph, v, "x", CreateValue::NewCRDT(CRDTKind::Collection));
+        versions.push(oplog.cg.version.clone());
+        oplog.local_register_set(seph, reg, CreateValue::Primitive(Primitive::Nil));
+        versions.push(oplog.cg.version.clone());
+        oplog.dbg_check(true);
+
+        let branch = check_oplog_checkouts_match(&oplog);
+        assert_eq!(branch.register(reg).unwrap().value, RegisterValue::Primitive(Primitive::Nil));
+        assert!(!branch.maps.contains_key(&v));
+
+        let conflicted = oplog.checkout_at_version(&[v, v2]);
+        assert_eq!(conflicted.register(reg).unwrap().conflicts_with.len(), 1);
+        assert!(conflicted.maps.contains_key(&v));
+
+        check_all_checkouts(&oplog, &versions);
+    }
+
+    #[test]
+    fn counter_checkouts() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+        let mut versions = vec![oplog.cg.version.clone()];
+
+        let counter = oplog.local_map_set(seph, ROOT_CRDT_ID, "count", CreateValue::NewCRDT(CRDTKind::Counter));
+        versions.push(oplog.cg.version.clone());
+        oplog.local_counter_increment(seph, counter, 3);
+        versions.push(oplog.cg.version.clone());
+
+        let parents = oplog.cg.version.clone();
+        let v = oplog.cg.assign_local_op_with_parents(parents.as_ref(), seph, 1).start;
+        oplog.remote_counter_increment(counter, v, 2).unwrap();
+        versions.push(Frontier::new_1(v));
+        let v2 = oplog.cg.assign_local_op_with_parents(parents.as_ref(), kaarina, 1).start;
+        oplog.remote_counter_increment(counter, v2, -10).unwrap();
+        versions.push(Frontier::new_1(v2));
+        versions.push(oplog.cg.version.clone());
+        oplog.local_counter_decrement(seph, counter, 1);
+        versions.push(oplog.cg.version.clone());
+        oplog.dbg_check(true);
+
+        let branch = check_oplog_checkouts_match(&oplog);
+        assert_eq!(branch.counter(counter), Some(-6));
+        assert_eq!(oplog.checkout_at_version(&[v]).counter(counter), Some(5));
+        assert_eq!(oplog.checkout_at_version(&[v2]).counter(counter), Some(-7));
+
+        check_all_checkouts(&oplog, &versions);
+
+        // Overwriting the counter removes it from the branch.
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "count", CreateValue::Primitive(Primitive::I64(0)));
+        oplog.dbg_check(true);
+        let branch = check_oplog_checkouts_match(&oplog);
+        assert_eq!(branch.counter(counter), None);
+    }
+
+    #[test]
+    fn branch_path_lookups() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+
+        let list = oplog.local_map_set(seph, ROOT_CRDT_ID, "list", CreateValue::NewCRDT(CRDTKind::List));
+        let inner = oplog.local_list_insert(seph, list, 0, CreateValue::NewCRDT(CRDTKind::Collection));
+        let text = oplog.local_collection_insert(seph, inner, CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "s", CreateValue::Primitive(Primitive::Str("hi".into())));
+        let branch = check_oplog_checkouts_match(&oplog);
+
+        let paths: [&[PathSegment]; 7] = [
+            &[],
+            &["list".into()],
+            &["list".into(), 0.into()],
+            &["list".into(), 0.into(), text.into()],
+            &["list".into(), 1.into()],
+            &["list".into(), "nope".into()],
+            &["s".into()],
+        ];
+        for path in paths {
+            assert_eq!(branch.crdt_at_path(path), oplog.crdt_at_path(path));
+        }
+
+        assert_eq!(branch.text_at_path(&["list".into(), 0.into(), text.into()]), Ok(text));
+        assert_eq!(branch.register_in_map(&["list".into()], "s"), Err(PathError::WrongKind {
+            expected: CRDTKind::Map,
+            actual: CRDTKind::List,
+        }));
+        assert_eq!(branch.str_in_map(&[], "s"), Some("hi"));
+        assert_eq!(branch.str_in_map(&[], "missing"), None);
+    }
+
+    #[test]
+    fn list_checkouts() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let mut versions = vec![oplog.cg.version.clone()];
+
+        let list = oplog.local_map_set(seph, ROOT_CRDT_ID, "list", CreateValue::NewCRDT(CRDTKind::List));
+        oplog.local_list_insert(seph, list, 0, CreateValue::Primitive(Primitive::I64(1)));
+        versions.push(oplog.cg.version.clone());
+        let text = oplog.local_list_insert(seph, list, 1, CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+        let map = oplog.local_list_insert(seph, list, 0, CreateValue::NewCRDT(CRDTKind::Map));
+        oplog.local_map_set(seph, map, "x", CreateValue::NewCRDT(CRDTKind::Text));
+        versions.push(oplog.cg.version.clone());
+
+        // Concurrently delete the text and insert another item.
+        let parents = oplog.cg.version.clone();
+        let v = oplog.cg.assign_local_op_with_parents(parents.as_ref(), seph, 1).start;
+        oplog.remote_list_op(list, (v..v + 1).into(), TextOperation::new_delete(2..3), vec![]).unwrap();
+        versions.push(Frontier::new_1(v));
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+        let v2 = oplog.cg.assign_local_op_with_parents(parents.as_ref(), kaarina, 2).start;
+        let ins = TextOperation { loc: (1..3).into(), kind: ListOpKind::Ins, content: None };
+        oplog.remote_list_op(list, (v2..v2 + 2).into(), ins, vec![
+            CreateValue::NewCRDT(CRDTKind::Collection),
+            CreateValue::Primitive(Primitive::I64(2)),
+        ]).unwrap();
+        versions.push(Frontier::new_1(v2 + 1));
+        versions.push(oplog.cg.version.clone());
+        oplog.local_list_delete(seph, list, 0..2);
+        versions.push(oplog.cg.version.clone());
+        oplog.dbg_check(true);
+
+        let branch = check_oplog_checkouts_match(&oplog);
+        assert_eq!(branch.lists[&list], vec![
+            RegisterValue::Primitive(Primitive::I64(2)),
+            RegisterValue::Primitive(Primitive::I64(1)),
+        ]);
+        assert!(branch.texts.is_empty());
+
+        check_all_checkouts(&oplog, &versions);
+    }
+
+    #[test]
+    fn merge_incrementally() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let mut branch = Branch::new();
+
+        let child_obj = oplog.local_map_set(seph, ROOT_CRDT_ID, "child", CreateValue::NewCRDT(CRDTKind::Map));
+        let v1 = oplog.cg.version.clone();
+        let text = oplog.local_map_set(seph, child_obj, "text", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi there"));
+        let v2 = oplog.cg.version.clone();
+        oplog.local_text_op(seph, text, TextOperation::new_delete(0..3));
+
+        branch.merge(&oplog, v1.as_ref());
+        assert_eq!(branch, oplog.checkout_at_version(v1.as_ref()));
+        branch.merge(&oplog, v2.as_ref());
+        assert_eq!(branch.texts[&text].to_string(), "hi there");
+        // Merging an old version shouldn't change anything.
+        branch.merge(&oplog, v1.as_ref());
+        assert_eq!(branch, oplog.checkout_at_version(v2.as_ref()));
+        branch.merge(&oplog, oplog.cg.version.as_ref());
+        assert_eq!(branch.texts[&text].to_string(), "there");
+        assert_eq!(branch, oplog.checkout_tip());
+    }
 }
\ No newline at end of file
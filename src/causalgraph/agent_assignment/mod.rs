@@ -1,6 +1,7 @@
 use std::cmp::Ordering;
 use smartstring::alias::String as SmartString;
 use rle::HasLength;
+use crate::causalgraph::agent_metadata::{AgentMetadata, Timestamp};
 use crate::causalgraph::agent_span::{AgentSpan, AgentVersion};
 use crate::{AgentId, DTRange, LV};
 use crate::rle::{KVPair, RleVec};
@@ -24,6 +25,12 @@ pub(crate) struct ClientData {
     /// of time spans must always obey the partial order of changes. But it will not necessarily
     /// agree with the order amongst time spans.
     pub(crate) lv_for_seq: RleVec<KVPair<DTRange>>,
+
+    /// Application supplied metadata about this agent. See [`agent_metadata`](crate::causalgraph::agent_metadata).
+    pub(crate) metadata: Option<Box<AgentMetadata>>,
+
+    /// Wall-clock timestamps for spans of this agent's operations, sorted by seq.
+    pub(crate) timestamps: Vec<(DTRange, Timestamp)>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -84,6 +91,31 @@ pub const MAX_AGENT_NAME_LENGTH: usize = 50;
 impl AgentAssignment {
     pub fn new() -> Self { Self::default() }
 
+    /// Remove the assignment of all local versions at or after len, and any agents added after
+    /// the first num_agents agents.
+    pub(crate) fn truncate(&mut self, len: usize, num_agents: AgentId) {
+        let num_entries = match self.client_with_lv.find_index(len) {
+            Ok(idx) => {
+                let KVPair(start, span) = &mut self.client_with_lv.0[idx];
+                if *start < len {
+                    span.seq_range.end = span.seq_range.start + (len - *start);
+                    idx + 1
+                } else { idx }
+            }
+            Err(idx) => idx,
+        };
+        self.client_with_lv.0.truncate(num_entries);
+
+        self.client_data.truncate(num_agents as usize);
+        for client in self.client_data.iter_mut() {
+            // Entries are usually (but not always) in LV order.
+            client.lv_for_seq.0.retain_mut(|KVPair(_, lv_range)| {
+                lv_range.end = lv_range.end.min(len);
+                lv_range.start < len
+            });
+        }
+    }
+
     pub fn get_agent_id(&self, name: &str) -> Option<AgentId> {
         self.client_data.iter()
             .position(|client_data| client_data.name == name)
@@ -102,7 +134,9 @@ impl AgentAssignment {
             // Create a new id.
             self.client_data.push(ClientData {
                 name: SmartString::from(name),
-                lv_for_seq: RleVec::new()
+                lv_for_seq: RleVec::new(),
+                metadata: None,
+                timestamps: Vec::new(),
             });
             (self.client_data.len() - 1) as AgentId
         }
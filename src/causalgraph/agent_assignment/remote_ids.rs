@@ -100,6 +100,8 @@ pub type RemoteFrontier<'a> = SmallVec<RemoteVersion<'a>, 2>;
 
 pub type RemoteFrontierOwned = SmallVec<RemoteVersionOwned, 2>;
 
+pub use crate::encoding::summary::{encode_remote_frontier, decode_remote_frontier};
+
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 #[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum VersionConversionError {
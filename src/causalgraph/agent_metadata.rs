@@ -0,0 +1,223 @@
+//! Metadata about agents, and wall-clock timestamps for their operations.
+//!
+//! Agents are only identified by name in the causal graph. Applications can attach an
+//! [`AgentMetadata`] record to each agent (display name, user ID and arbitrary key / value data)
+//! and a wall-clock [`Timestamp`] to spans of each agent's operations. This data is sent to remote
+//! peers along with operations, but it isn't a CRDT: metadata received from a peer replaces any
+//! metadata we have for that agent, and the first timestamp we see for any operation is kept.
+
+use std::collections::BTreeMap;
+use rle::HasLength;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use smartstring::alias::String as SmartString;
+use crate::{AgentId, CausalGraph, DTRange, LV};
+use crate::causalgraph::agent_assignment::ClientData;
+use crate::rle::KVPair;
+
+/// Wall-clock time, in milliseconds since the unix epoch.
+pub type Timestamp = u64;
+
+/// Metadata describing an agent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AgentMetadata {
+    pub display_name: Option<SmartString>,
+    pub user_id: Option<SmartString>,
+    /// Any other application specific data.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub extra: BTreeMap<SmartString, Vec<u8>>,
+}
+
+impl ClientData {
+    /// Add a timestamp for a span of seq numbers. Any part of the span which already has a
+    /// timestamp is left alone.
+    fn insert_timestamp(&mut self, mut seq_range: DTRange, timestamp: Timestamp) {
+        let mut idx = self.timestamps.partition_point(|(r, _)| r.end <= seq_range.start);
+        while !seq_range.is_empty() {
+            match self.timestamps.get(idx) {
+                Some((r, _)) if r.start <= seq_range.start => {
+                    // The start of the span is already covered.
+                    seq_range.start = r.end.min(seq_range.end);
+                    idx += 1;
+                }
+                next => {
+                    let end = next.map_or(seq_range.end, |(r, _)| r.start.min(seq_range.end));
+                    let new_range: DTRange = (seq_range.start..end).into();
+
+                    // Extend the previous entry if we can.
+                    match idx.checked_sub(1).map(|i| &mut self.timestamps[i]) {
+                        Some((r, ts)) if r.end == new_range.start && *ts == timestamp => {
+                            r.end = new_range.end;
+                        }
+                        _ => {
+                            self.timestamps.insert(idx, (new_range, timestamp));
+                            idx += 1;
+                        }
+                    }
+                    seq_range.start = end;
+                }
+            }
+        }
+    }
+
+    fn timestamp_at_seq(&self, seq: usize) -> Option<Timestamp> {
+        let idx = self.timestamps.partition_point(|(r, _)| r.end <= seq);
+        self.timestamps.get(idx)
+            .filter(|(r, _)| r.start <= seq)
+            .map(|(_, ts)| *ts)
+    }
+}
+
+impl CausalGraph {
+    /// Get the metadata for an agent, if any has been set.
+    pub fn agent_metadata(&self, agent: AgentId) -> Option<&AgentMetadata> {
+        self.agent_assignment.client_data[agent as usize].metadata.as_deref()
+    }
+
+    /// Set (or replace) the metadata for an agent.
+    pub fn set_agent_metadata(&mut self, agent: AgentId, metadata: AgentMetadata) {
+        self.agent_assignment.client_data[agent as usize].metadata = Some(Box::new(metadata));
+    }
+
+    /// Set the wall-clock timestamp of a range of local versions. Versions which already have a
+    /// timestamp aren't changed.
+    pub fn set_timestamp(&mut self, range: DTRange, timestamp: Timestamp) {
+        let spans: Vec<_> = self.agent_assignment.client_with_lv.iter_range(range)
+            .map(|KVPair(_, span)| span)
+            .collect();
+        for span in spans {
+            self.agent_assignment.client_data[span.agent as usize].insert_timestamp(span.seq_range, timestamp);
+        }
+    }
+
+    /// Get the wall-clock timestamp of a version, if it has one.
+    pub fn timestamp_at(&self, v: LV) -> Option<Timestamp> {
+        let (agent, seq) = self.agent_assignment.local_to_agent_version(v);
+        self.agent_assignment.client_data[agent as usize].timestamp_at_seq(seq)
+    }
+
+    /// Metadata for all the agents which made any of the versions in the passed ranges.
+    pub(crate) fn agent_metadata_in_ranges(&self, ranges: &[DTRange]) -> Vec<(&str, &AgentMetadata)> {
+        let mut agents: Vec<AgentId> = ranges.iter()
+            .flat_map(|r| self.agent_assignment.client_with_lv.iter_range(*r))
+            .map(|KVPair(_, span)| span.agent)
+            .collect();
+        agents.sort_unstable();
+        agents.dedup();
+
+        agents.into_iter().filter_map(|agent| {
+            let client = &self.agent_assignment.client_data[agent as usize];
+            client.metadata.as_deref().map(|m| (client.name.as_str(), m))
+        }).collect()
+    }
+
+    /// The timestamps of the versions in the passed ranges, as (agent name, seq range, timestamp).
+    pub(crate) fn timestamps_in_ranges(&self, ranges: &[DTRange]) -> Vec<(&str, DTRange, Timestamp)> {
+        let mut result = vec![];
+        for range in ranges {
+            for KVPair(_, span) in self.agent_assignment.client_with_lv.iter_range(*range) {
+                let client = &self.agent_assignment.client_data[span.agent as usize];
+                let start = client.timestamps.partition_point(|(r, _)| r.end <= span.seq_range.start);
+                for (r, ts) in client.timestamps[start..].iter() {
+                    if r.start >= span.seq_range.end { break; }
+                    let seq_range = (r.start.max(span.seq_range.start)..r.end.min(span.seq_range.end)).into();
+                    result.push((client.name.as_str(), seq_range, *ts));
+                }
+            }
+        }
+        result
+    }
+
+    /// Store metadata and timestamps received from a remote peer. Entries naming agents we don't
+    /// know about are ignored.
+    pub(crate) fn merge_agent_metadata<'a, M, T>(&mut self, metadata: M, timestamps: T)
+        where M: IntoIterator<Item = (&'a str, AgentMetadata)>, T: IntoIterator<Item = (&'a str, DTRange, Timestamp)>
+    {
+        for (name, m) in metadata {
+            if let Some(agent) = self.agent_assignment.get_agent_id(name) {
+                self.set_agent_metadata(agent, m);
+            }
+        }
+        for (name, seq_range, ts) in timestamps {
+            if let Some(agent) = self.agent_assignment.get_agent_id(name) {
+                self.agent_assignment.client_data[agent as usize].insert_timestamp(seq_range, ts);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CausalGraph, CreateValue, OpLog, Primitive, ROOT_CRDT_ID};
+    use crate::list::encoding::ENCODE_PATCH;
+    use crate::list::ListOpLog;
+    use super::*;
+
+    #[test]
+    fn timestamps_keep_first_value() {
+        let mut cg = CausalGraph::new();
+        let seph = cg.get_or_create_agent_id("seph");
+        let mike = cg.get_or_create_agent_id("mike");
+        cg.assign_local_op(seph, 10);
+        cg.assign_local_op(mike, 5);
+        cg.assign_local_op(seph, 5);
+
+        cg.set_timestamp((2..4).into(), 100);
+        cg.set_timestamp((0..12).into(), 200);
+        cg.set_timestamp((6..20).into(), 300);
+        assert_eq!(cg.timestamp_at(0), Some(200));
+        assert_eq!(cg.timestamp_at(3), Some(100));
+        assert_eq!(cg.timestamp_at(11), Some(200));
+        assert_eq!(cg.timestamp_at(14), Some(300));
+        assert_eq!(cg.timestamp_at(19), Some(300));
+        assert_eq!(cg.agent_assignment.client_data[seph as usize].timestamps, vec![
+            ((0..2).into(), 200), ((2..4).into(), 100), ((4..10).into(), 200), ((10..15).into(), 300),
+        ]);
+
+        assert_eq!(cg.timestamps_in_ranges(&[(8..12).into()]), vec![
+            ("seph", (8..10).into(), 200), ("mike", (0..2).into(), 200),
+        ]);
+
+        assert_eq!(cg.agent_metadata(seph), None);
+        let metadata = AgentMetadata { display_name: Some("Seph".into()), ..Default::default() };
+        cg.set_agent_metadata(seph, metadata.clone());
+        assert_eq!(cg.agent_metadata(seph), Some(&metadata));
+        assert_eq!(cg.agent_metadata_in_ranges(&[(0..20).into()]), vec![("seph", &metadata)]);
+    }
+
+    #[test]
+    fn metadata_and_timestamps_are_sent_to_peers() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        let mike = a.get_or_create_agent_id("mike");
+        a.add_insert(seph, 0, "hi");
+        a.add_insert(mike, 2, " there");
+        a.set_timestamp((0..8).into(), 1_700_000_000_000);
+        a.add_insert(seph, 0, "x");
+        let metadata = AgentMetadata {
+            display_name: Some("Seph".into()),
+            user_id: Some("u123".into()),
+            extra: [("color".into(), vec![0xff, 0, 0])].into_iter().collect(),
+        };
+        a.set_agent_metadata(seph, metadata.clone());
+
+        let b = ListOpLog::load_from(&a.encode(&ENCODE_PATCH)).unwrap();
+        let b_seph = b.get_agent_id("seph").unwrap();
+        assert_eq!(b.agent_metadata(b_seph), Some(&metadata));
+        assert_eq!(b.agent_metadata(b.get_agent_id("mike").unwrap()), None);
+        assert_eq!(b.timestamp_at(7), Some(1_700_000_000_000));
+        assert_eq!(b.timestamp_at(8), None);
+        assert_eq!(b.timestamps_in_range((0..9).into()), a.timestamps_in_range((0..9).into()));
+
+        let mut a = OpLog::new();
+        let seph = a.cg.get_or_create_agent_id("seph");
+        a.local_map_set(seph, ROOT_CRDT_ID, "x", CreateValue::Primitive(Primitive::I64(1)));
+        a.cg.set_timestamp((0..1).into(), 1234);
+        a.cg.set_agent_metadata(seph, metadata.clone());
+        let mut b = OpLog::new();
+        b.merge_ops(a.ops_since(&[])).unwrap();
+        assert_eq!(b.cg.agent_metadata(0), Some(&metadata));
+        assert_eq!(b.cg.timestamp_at(0), Some(1234));
+    }
+}
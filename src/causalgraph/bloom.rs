@@ -0,0 +1,398 @@
+//! Bloom filter based set reconciliation for causal graphs.
+//!
+//! When two peers' histories have diverged a lot (many agents, long offline periods), a full
+//! [`VersionSummary`](crate::causalgraph::summary::VersionSummary) can get large because it names
+//! every run of known sequence numbers for every agent. A [`BloomSummary`] is a fixed size
+//! (probabilistic) alternative.
+//!
+//! Each agent's sequence numbers are split into fixed sized blocks, and we add a hash of
+//! (agent name, block number, number of seqs known in that block) for every block we know about
+//! to a bloom filter. The receiving peer checks each of its own blocks against the filter. Any
+//! block which isn't in the filter (and everything which descends from it) is missing from the
+//! remote peer.
+//!
+//! Bloom filters have false positives. If a block is wrongly reported as known by the remote peer,
+//! the remote peer will end up missing some changes. This is detected when the remote peer can't
+//! merge the patch (because its missing the parents of some changes) or because it doesn't know
+//! about some version in our frontier after merging. In either case, the peers fall back to
+//! exchanging full version summaries. The receiving peer checks for this with
+//! [`CausalGraph::bloom_fallback_summary`].
+
+use smallvec::SmallVec;
+use rle::{HasLength, MergableSpan};
+use crate::{CausalGraph, DTRange, Frontier, LV};
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
+use crate::causalgraph::summary::VersionSummary;
+use crate::encoding::ChunkType;
+use crate::encoding::parseerror::ParseError;
+use crate::encoding::summary::{finish, read_chunk};
+use crate::encoding::tools::{calc_checksum, push_str};
+use crate::encoding::varint::{push_u32, push_usize};
+use crate::rle::KVPair;
+
+/// The default number of sequence numbers in each block.
+pub const DEFAULT_BLOCK_SIZE: usize = 16;
+
+/// The default number of bits in the filter per block. 10 bits gives a false positive rate of
+/// about 1%.
+pub const DEFAULT_BITS_PER_BLOCK: usize = 10;
+
+/// A bloom filter summarizing the versions known by a peer. See the [module
+/// documentation](crate::causalgraph::bloom) for details.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BloomSummary {
+    block_size: usize,
+    num_hashes: u32,
+    bits: Vec<u64>,
+}
+
+/// Visit each block of sequence numbers we know about. The visitor is passed the agent name, the
+/// block number and the number of known seqs in the block, along with the local versions of those
+/// seqs.
+fn for_each_block<F>(cg: &CausalGraph, block_size: usize, mut visitor: F)
+    where F: FnMut(&str, usize, usize, &[DTRange])
+{
+    for client in cg.agent_assignment.client_data.iter() {
+        let mut current: Option<(usize, usize, SmallVec<DTRange, 2>)> = None;
+
+        for KVPair(seq_start, lv_range) in client.lv_for_seq.iter() {
+            let mut seq = *seq_start;
+            let mut lv = lv_range.start;
+            let seq_end = *seq_start + lv_range.len();
+
+            while seq < seq_end {
+                let block = seq / block_size;
+                let len = ((block + 1) * block_size).min(seq_end) - seq;
+
+                match &mut current {
+                    Some((b, _, _)) if *b == block => {},
+                    _ => {
+                        if let Some((b, count, lvs)) = current.take() {
+                            visitor(&client.name, b, count, &lvs);
+                        }
+                        current = Some((block, 0, SmallVec::new()));
+                    }
+                }
+
+                let (_, count, lvs) = current.as_mut().unwrap();
+                *count += len;
+                lvs.push((lv..lv + len).into());
+
+                seq += len;
+                lv += len;
+            }
+        }
+
+        if let Some((b, count, lvs)) = current {
+            visitor(&client.name, b, count, &lvs);
+        }
+    }
+}
+
+impl BloomSummary {
+    fn num_bits(&self) -> usize {
+        self.bits.len() * 64
+    }
+
+    /// Get the bit indexes for the named block, using double hashing.
+    fn bit_indexes(&self, name: &str, block: usize, count: usize) -> impl Iterator<Item = usize> {
+        let mut key = vec![];
+        push_str(&mut key, name);
+        push_usize(&mut key, block);
+        push_usize(&mut key, count);
+        let h1 = calc_checksum(&key) as usize;
+        key.push(0xff);
+        let h2 = calc_checksum(&key) as usize | 1;
+
+        let num_bits = self.num_bits();
+        (0..self.num_hashes as usize).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    fn insert(&mut self, name: &str, block: usize, count: usize) {
+        for idx in self.bit_indexes(name, block, count).collect::<SmallVec<usize, 16>>() {
+            self.bits[idx / 64] |= 1 << (idx % 64);
+        }
+    }
+
+    fn contains(&self, name: &str, block: usize, count: usize) -> bool {
+        self.bit_indexes(name, block, count)
+            .all(|idx| self.bits[idx / 64] & (1 << (idx % 64)) != 0)
+    }
+
+    /// Encode the summary in a compact binary format. Decode with [`BloomSummary::decode`].
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.bits.len() * 8 + 10);
+        push_usize(&mut data, self.block_size);
+        push_u32(&mut data, self.num_hashes);
+        push_usize(&mut data, self.bits.len());
+        for word in self.bits.iter() {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        finish(ChunkType::BloomSummary, &data)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = read_chunk(bytes, ChunkType::BloomSummary)?;
+        let block_size = reader.next_usize()?;
+        let num_hashes = reader.next_u32()?;
+        let num_words = reader.next_usize()?;
+
+        if block_size == 0 || num_hashes == 0 || num_hashes > 32 || num_words == 0 {
+            return Err(ParseError::GenericInvalidData);
+        }
+        if num_words.checked_mul(8) != Some(reader.len()) {
+            return Err(ParseError::InvalidLength);
+        }
+
+        let bits = reader.0.chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+
+        Ok(Self { block_size, num_hashes, bits })
+    }
+}
+
+impl CausalGraph {
+    /// Summarize every version we know about in a bloom filter, using the default settings.
+    pub fn bloom_summary(&self) -> BloomSummary {
+        self.bloom_summary_with(DEFAULT_BLOCK_SIZE, DEFAULT_BITS_PER_BLOCK)
+    }
+
+    /// Summarize every version we know about in a bloom filter.
+    ///
+    /// Larger blocks make the filter smaller, but more changes the remote peer already has will be
+    /// resent when blocks don't match. More bits per block lowers the false positive rate.
+    pub fn bloom_summary_with(&self, block_size: usize, bits_per_block: usize) -> BloomSummary {
+        assert!(block_size > 0);
+
+        let mut num_blocks = 0;
+        for_each_block(self, block_size, |_, _, _, _| num_blocks += 1);
+
+        let num_words = (num_blocks * bits_per_block).div_ceil(64).max(1);
+        // The optimal number of hashes is bits_per_block * ln(2).
+        let num_hashes = ((bits_per_block as f64 * std::f64::consts::LN_2).round() as u32).clamp(1, 16);
+
+        let mut summary = BloomSummary {
+            block_size,
+            num_hashes,
+            bits: vec![0; num_words],
+        };
+
+        for_each_block(self, block_size, |name, block, count, _| {
+            summary.insert(name, block, count);
+        });
+
+        summary
+    }
+
+    /// Compare a remote peer's bloom summary with our local versions. This returns the version
+    /// we (probably) have in common with the remote peer, and the (ordered) local version ranges
+    /// the remote peer is probably missing. Those ranges are the same as `diff_since(frontier)`.
+    ///
+    /// Because of false positives, the remote peer might also be missing some other versions.
+    pub fn intersect_with_bloom_summary(&self, summary: &BloomSummary) -> (Frontier, SmallVec<DTRange, 4>) {
+        // First find all the blocks the remote peer is missing.
+        let mut missing_blocks: Vec<DTRange> = vec![];
+        for_each_block(self, summary.block_size, |name, block, count, lvs| {
+            if !summary.contains(name, block, count) {
+                missing_blocks.extend_from_slice(lvs);
+            }
+        });
+        missing_blocks.sort_unstable_by_key(|r| r.start);
+
+        // Then extend that to include everything which descends from a missing version. Versions
+        // are always after their parents, so we can do this in a single pass.
+        let mut missing: SmallVec<DTRange, 4> = SmallVec::new();
+        let mut common: Vec<LV> = vec![];
+        let mut next_block = missing_blocks.iter().peekable();
+
+        let is_missing = |missing: &SmallVec<DTRange, 4>, v: LV| {
+            missing.binary_search_by(|r| {
+                if r.end <= v { std::cmp::Ordering::Less }
+                else if r.start > v { std::cmp::Ordering::Greater }
+                else { std::cmp::Ordering::Equal }
+            }).is_ok()
+        };
+
+        for entry in self.graph.iter() {
+            let span = entry.span;
+            while next_block.next_if(|r| r.end <= span.start).is_some() {}
+
+            let missing_from = if entry.parents.iter().any(|p| is_missing(&missing, *p)) {
+                span.start
+            } else {
+                next_block.peek()
+                    .filter(|r| r.start < span.end)
+                    .map(|r| r.start.max(span.start))
+                    .unwrap_or(span.end)
+            };
+
+            if missing_from > span.start {
+                common.push(missing_from - 1);
+            }
+            if missing_from < span.end {
+                let r: DTRange = (missing_from..span.end).into();
+                match missing.last_mut() {
+                    Some(last) if last.can_append(&r) => last.append(r),
+                    _ => missing.push(r),
+                }
+            }
+        }
+
+        (self.graph.find_dominators(&common), missing)
+    }
+
+    /// Check whether we have every change from a peer which sent us a patch in response to our
+    /// bloom summary. The remote frontier is the sender's version when it made the patch. Call
+    /// this after trying to merge the patch, even if merging it failed.
+    ///
+    /// If a false positive in our filter made the sender leave out changes we don't have, this
+    /// returns a full version summary to send to the peer instead. The peer replies with a patch
+    /// from [`intersect_with_summary`](CausalGraph::intersect_with_summary). Returns None if we
+    /// have every change.
+    pub fn bloom_fallback_summary(&self, remote_frontier: &[RemoteVersion]) -> Option<VersionSummary> {
+        let has_all = remote_frontier.iter().all(|rv| {
+            self.agent_assignment.try_remote_to_local_version(*rv).is_ok()
+        });
+        if has_all { None } else { Some(self.agent_assignment.summarize_versions()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+    use crate::list::encoding::ENCODE_PATCH;
+    use crate::list::{ListCRDT, ListOpLog};
+    use crate::list::old_fuzzer_tools::old_make_random_change;
+    use crate::list_fuzzer_tools::choose_2;
+    use super::*;
+
+    fn check_bloom(a: &ListOpLog, b: &mut ListOpLog, summary: &BloomSummary) {
+        let (common, missing) = a.cg.intersect_with_bloom_summary(summary);
+        assert_eq!(a.cg.diff_since(common.as_ref()), missing);
+
+        // If a false positive left out the parents of some changes, the patch won't merge.
+        let patch = a.encode_from(&ENCODE_PATCH, common.as_ref());
+        let _ = b.decode_and_add(&patch);
+
+        if let Some(summary) = b.cg.bloom_fallback_summary(&a.remote_frontier()) {
+            let (common, _) = a.cg.intersect_with_summary(&summary, &[]);
+            b.decode_and_add(&a.encode_from(&ENCODE_PATCH, common.as_ref())).unwrap();
+        }
+        assert_eq!(b.cg.bloom_fallback_summary(&a.remote_frontier()), None);
+    }
+
+    #[test]
+    fn empty_graphs() {
+        let cg = CausalGraph::new();
+        let summary = cg.bloom_summary();
+        assert_eq!(cg.intersect_with_bloom_summary(&summary), (Frontier::root(), SmallVec::new()));
+        assert_eq!(BloomSummary::decode(&summary.encode()).unwrap(), summary);
+    }
+
+    #[test]
+    fn finds_missing_versions() {
+        let mut a = ListOpLog::new();
+        a.get_or_create_agent_id("seph");
+        for i in 0..100 {
+            a.add_insert(0, i, "a");
+        }
+        let mut b = a.clone();
+        b.get_or_create_agent_id("mike");
+        for i in 0..30 {
+            a.add_insert(0, i, "x");
+        }
+        for i in 0..10 {
+            b.add_insert(1, i, "y");
+        }
+
+        let summary = BloomSummary::decode(&b.cg.bloom_summary().encode()).unwrap();
+        let (common, missing) = a.cg.intersect_with_bloom_summary(&summary);
+        // The last block b knows from seph is 96..100, which doesn't match our block 96..112.
+        assert_eq!(common.as_ref(), &[95]);
+        assert_eq!(missing.as_slice(), &[(96..130).into()]);
+
+        check_bloom(&a, &mut b, &summary);
+        let summary = a.cg.bloom_summary();
+        check_bloom(&b, &mut a, &summary);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn false_positives_fall_back() {
+        let mut a = ListOpLog::new();
+        let mut b = ListOpLog::new();
+        a.get_or_create_agent_id("seph");
+        b.get_or_create_agent_id("mike");
+        a.add_insert(0, 0, "hi");
+        b.add_insert(0, 0, "yo");
+
+        // A filter with every bit set claims to contain everything.
+        let mut summary = b.cg.bloom_summary();
+        summary.bits.fill(u64::MAX);
+        let (common, missing) = a.cg.intersect_with_bloom_summary(&summary);
+        assert_eq!(common, a.cg.version);
+        assert!(missing.is_empty());
+
+        // So b can't merge the patch, and it asks for everything it's missing with a full summary.
+        assert!(b.decode_and_add(&a.encode_from(&ENCODE_PATCH, common.as_ref())).is_err());
+        let fallback = b.cg.bloom_fallback_summary(&a.remote_frontier()).unwrap();
+        assert_eq!(fallback, b.cg.agent_assignment.summarize_versions());
+
+        let (common, _) = a.cg.intersect_with_summary(&fallback, &[]);
+        b.decode_and_add(&a.encode_from(&ENCODE_PATCH, common.as_ref())).unwrap();
+        assert_eq!(b.cg.bloom_fallback_summary(&a.remote_frontier()), None);
+        assert!(b.cg.graph.frontier_contains_frontier(b.local_frontier_ref(), &[3]));
+    }
+
+    #[test]
+    fn decode_rejects_bad_data() {
+        let bytes = CausalGraph::new().bloom_summary().encode();
+        for i in 0..bytes.len() {
+            assert!(BloomSummary::decode(&bytes[..i]).is_err());
+        }
+    }
+
+    fn bloom_fuzz(seed: u64) {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut docs = [ListCRDT::new(), ListCRDT::new(), ListCRDT::new()];
+        for doc in docs.iter_mut() {
+            for a in 0..3 {
+                doc.get_or_create_agent_id(format!("agent {}", a).as_str());
+            }
+        }
+
+        for _i in 0..30 {
+            for _ in 0..2 {
+                let idx = rng.gen_range(0..docs.len());
+                old_make_random_change(&mut docs[idx], None, idx as _, &mut rng, false);
+            }
+
+            let (a_idx, a, _b_idx, b) = choose_2(&mut docs, &mut rng);
+            // Use some tiny filters to test the fallback.
+            let bits = [1, 4, 10][a_idx];
+            let block_size = rng.gen_range(1..20);
+
+            let summary = b.oplog.cg.bloom_summary_with(block_size, bits);
+            check_bloom(&a.oplog, &mut b.oplog, &summary);
+            let summary = a.oplog.cg.bloom_summary_with(block_size, bits);
+            check_bloom(&b.oplog, &mut a.oplog, &summary);
+
+            // Patches don't include deleted content, so the oplogs won't be identical.
+            assert_eq!(a.oplog.cg.agent_assignment.summarize_versions(), b.oplog.cg.agent_assignment.summarize_versions());
+            assert_eq!(a.oplog.checkout_tip().content(), b.oplog.checkout_tip().content());
+        }
+    }
+
+    #[test]
+    fn bloom_fuzz_once() {
+        bloom_fuzz(123);
+    }
+
+    #[test]
+    fn bloom_fuzz_many() {
+        for seed in 0..20 {
+            bloom_fuzz(seed);
+        }
+    }
+}
@@ -57,6 +57,19 @@ impl CausalGraph {
     //     )
     // }
 
+    /// Roll back the causal graph to an earlier state, by removing all operations at or after the
+    /// specified local version. The caller passes in the version and number of agents from the
+    /// earlier state.
+    pub(crate) fn truncate(&mut self, len: usize, version: Frontier, num_agents: AgentId) {
+        self.agent_assignment.truncate(len, num_agents);
+        self.graph.truncate(len);
+        #[cfg(feature = "version_hashes")]
+        self.hashes.truncate(len);
+        #[cfg(feature = "version_hashes")]
+        self.signatures.truncate_agents(num_agents);
+        self.version = version;
+    }
+
     pub(crate) fn check_flat(&self) {
         assert_eq!(self.len_assignment(), self.len_history());
     }
@@ -291,4 +304,27 @@ mod tests {
         cg.merge_and_assign(&[4], (agent, 5..15).into());
         cg.dbg_check(true);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn truncate_restores_earlier_state() {
+        let mut cg = CausalGraph::new();
+        let seph = cg.get_or_create_agent_id("seph");
+        cg.merge_and_assign(&[], (seph, 0..10).into());
+        cg.merge_and_assign(&[4], (seph, 10..12).into());
+        cg.dbg_check(true);
+
+        let before = cg.clone();
+        let len = cg.len();
+
+        // Extend the last entry, add some concurrent entries and a new agent.
+        cg.merge_and_assign(&[11], (seph, 12..20).into());
+        let kaarina = cg.get_or_create_agent_id("kaarina");
+        cg.merge_and_assign(&[3], (kaarina, 0..5).into());
+        cg.merge_and_assign(&[19, 24], (kaarina, 5..6).into());
+        cg.dbg_check(true);
+
+        cg.truncate(len, before.version.clone(), before.num_agents());
+        cg.dbg_check(true);
+        assert_eq!(format!("{:?}", cg), format!("{:?}", before));
+    }
+}
@@ -1,4 +1,4 @@
-use rle::{HasLength, MergableSpan, SplitableSpan, SplitableSpanHelpers};
+use rle::{HasLength, HasRleKey, MergableSpan, SplitableSpan, SplitableSpanHelpers};
 use crate::{DTRange, Frontier, LV};
 use crate::causalgraph::agent_span::AgentSpan;
 
@@ -28,6 +28,12 @@ impl HasLength for CGEntry {
     }
 }
 
+impl HasRleKey for CGEntry {
+    fn rle_key(&self) -> usize {
+        self.start
+    }
+}
+
 impl MergableSpan for CGEntry {
     fn can_append(&self, other: &Self) -> bool {
         let end = self.start + self.len();
@@ -80,6 +80,27 @@ impl Graph {
         self.entries.end()
     }
 
+    /// Remove all entries at or after the specified version. This is used to roll back entries
+    /// which were added while merging invalid data.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        let num_entries = match self.entries.find_index(len) {
+            Ok(idx) => {
+                let entry = &mut self.entries.0[idx];
+                if entry.span.start < len {
+                    entry.span.end = len;
+                    idx + 1
+                } else { idx }
+            }
+            Err(idx) => idx,
+        };
+        self.entries.0.truncate(num_entries);
+
+        for entry in self.entries.0.iter_mut() {
+            entry.child_indexes.retain(|idx| *idx < num_entries);
+        }
+        self.root_child_indexes.retain(|idx| *idx < num_entries);
+    }
+
     /// Insert a new history entry for the specified range of versions, and the named parents.
     ///
     /// This method will try to extend the last entry if it can.
@@ -0,0 +1,182 @@
+//! Content-addressed (Merkle) hashes for the versions in a causal graph.
+//!
+//! Every version gets a SHA-256 hash covering the hashes of its parents, its agent and seq and
+//! the content of the operation at that version. Two peers which agree on the hash of a version
+//! agree on the entire history leading up to it, including the content of every operation.
+//!
+//! Versions are hashed one at a time rather than per graph entry, since peers don't split runs
+//! at the same places. The hashes are cached in the causal graph (indexed by LV), but the cache is
+//! only filled in on demand - usually when we're asked to verify hashes sent by a remote peer.
+//!
+//! The content bytes of each operation are supplied by the oplog which owns the causal graph.
+
+use rle::HasLength;
+use sha2::{Digest, Sha256};
+use crate::{CausalGraph, DTRange, LV};
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
+use crate::encoding::parseerror::ParseError;
+use crate::encoding::tools::push_str;
+use crate::encoding::varint::push_usize;
+
+pub type VersionHash = [u8; 32];
+
+/// Hash the version at (agent, seq) with the specified parents and operation content.
+fn hash_version(parents: &mut [&VersionHash], agent: &str, seq: usize, content: &[u8]) -> VersionHash {
+    // Parents are sorted by hash, since peers don't agree on the local order of versions.
+    parents.sort_unstable();
+
+    let mut buf = Vec::with_capacity(parents.len() * 32 + agent.len() + content.len() + 10);
+    push_usize(&mut buf, parents.len());
+    for p in parents.iter() {
+        buf.extend_from_slice(*p);
+    }
+    push_str(&mut buf, agent);
+    push_usize(&mut buf, seq);
+    push_usize(&mut buf, content.len());
+    buf.extend_from_slice(content);
+
+    Sha256::digest(&buf).into()
+}
+
+/// Hash a set of version hashes. This is used to name a frontier.
+pub fn hash_frontier<'a, I: IntoIterator<Item = &'a VersionHash>>(hashes: I) -> VersionHash {
+    let mut hashes: Vec<&VersionHash> = hashes.into_iter().collect();
+    hashes.sort_unstable();
+
+    let mut hasher = Sha256::new();
+    hasher.update((hashes.len() as u64).to_le_bytes());
+    for h in hashes {
+        hasher.update(h);
+    }
+    hasher.finalize().into()
+}
+
+/// The hashes of all versions in a causal graph. Hashes which aren't in the causal graph's cache
+/// are computed when this is created.
+#[derive(Debug)]
+pub(crate) struct VersionHashes<'a> {
+    cached: &'a [VersionHash],
+    tail: Vec<VersionHash>,
+}
+
+impl<'a> VersionHashes<'a> {
+    pub(crate) fn get(&self, v: LV) -> &VersionHash {
+        if v < self.cached.len() { &self.cached[v] }
+        else { &self.tail[v - self.cached.len()] }
+    }
+
+    pub(crate) fn frontier_hash(&self, frontier: &[LV]) -> VersionHash {
+        hash_frontier(frontier.iter().map(|v| self.get(*v)))
+    }
+
+    /// Check a list of (version, hash) pairs sent from a remote peer against our local hashes.
+    pub(crate) fn verify(&self, cg: &CausalGraph, expected: &[(RemoteVersion, VersionHash)]) -> Result<(), ParseError> {
+        for (rv, hash) in expected {
+            let v = cg.agent_assignment.try_remote_to_local_version(*rv)
+                .map_err(ParseError::InvalidRemoteID)?;
+            if self.get(v) != hash {
+                return Err(ParseError::HashMismatch);
+            }
+        }
+        Ok(())
+    }
+
+    /// The newly computed hashes, for storing back in the causal graph's cache.
+    pub(crate) fn into_tail(self) -> Vec<VersionHash> {
+        self.tail
+    }
+}
+
+impl CausalGraph {
+    /// Get the hashes of all versions in the causal graph.
+    ///
+    /// `content` is called (in order) for every version which isn't cached, and should append the
+    /// content bytes of the operation at that version.
+    pub(crate) fn version_hashes_with<F>(&self, mut content: F) -> VersionHashes<'_>
+        where F: FnMut(LV, &mut Vec<u8>)
+    {
+        let cached = &self.hashes[..];
+        let range: DTRange = (cached.len()..self.len()).into();
+        let mut tail: Vec<VersionHash> = Vec::with_capacity(range.len());
+        let mut buf = Vec::new();
+
+        let get = |tail: &[VersionHash], v: LV| -> VersionHash {
+            if v < cached.len() { cached[v] } else { tail[v - cached.len()] }
+        };
+
+        for entry in self.graph.iter_range(range) {
+            for v in entry.span.iter() {
+                let parents: Vec<VersionHash> = if v == entry.span.start {
+                    entry.parents.iter().map(|p| get(&tail, *p)).collect()
+                } else {
+                    vec![get(&tail, v - 1)]
+                };
+                let mut parents: Vec<&VersionHash> = parents.iter().collect();
+
+                buf.clear();
+                content(v, &mut buf);
+
+                let RemoteVersion(agent, seq) = self.agent_assignment.local_to_remote_version(v);
+                tail.push(hash_version(&mut parents, agent, seq, &buf));
+            }
+        }
+
+        VersionHashes { cached, tail }
+    }
+
+    /// Store newly computed hashes in the cache. The hashes must start at the end of the cache.
+    pub(crate) fn cache_version_hashes(&mut self, tail: Vec<VersionHash>) {
+        self.hashes.extend(tail);
+        debug_assert!(self.hashes.len() <= self.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::causalgraph::agent_span::AgentSpan;
+    use crate::CausalGraph;
+    use super::*;
+
+    #[test]
+    fn hashes_depend_on_history_and_content() {
+        let mut a = CausalGraph::new();
+        let seph = a.get_or_create_agent_id("seph");
+        let mike = a.get_or_create_agent_id("mike");
+        a.merge_and_assign(&[], AgentSpan { agent: seph, seq_range: (0..3).into() });
+        a.merge_and_assign(&[], AgentSpan { agent: mike, seq_range: (0..2).into() });
+        a.merge_and_assign(&[2, 4], AgentSpan { agent: seph, seq_range: (3..4).into() });
+
+        // The same history, with concurrent versions assigned in the other order.
+        let mut b = CausalGraph::new();
+        let mike = b.get_or_create_agent_id("mike");
+        let seph = b.get_or_create_agent_id("seph");
+        b.merge_and_assign(&[], AgentSpan { agent: mike, seq_range: (0..2).into() });
+        b.merge_and_assign(&[], AgentSpan { agent: seph, seq_range: (0..3).into() });
+        b.merge_and_assign(&[1, 4], AgentSpan { agent: seph, seq_range: (3..4).into() });
+
+        let content = |cg: &CausalGraph, v: LV, buf: &mut Vec<u8>| {
+            buf.push(cg.agent_assignment.local_to_remote_version(v).1 as u8);
+        };
+        let ha = a.version_hashes_with(|v, buf| content(&a, v, buf));
+        let hb = b.version_hashes_with(|v, buf| content(&b, v, buf));
+        assert_eq!(ha.get(5), hb.get(5));
+        assert_eq!(ha.get(2), hb.get(4));
+        assert_ne!(ha.get(2), ha.get(4));
+        assert_eq!(ha.frontier_hash(&[2, 4]), hb.frontier_hash(&[4, 1]));
+        assert_ne!(ha.frontier_hash(&[2, 4]), ha.frontier_hash(&[5]));
+
+        // Changing the content of any ancestor changes the hash.
+        let hc = a.version_hashes_with(|v, buf| {
+            content(&a, v, buf);
+            if v == 0 { buf.push(1); }
+        });
+        assert_eq!(ha.get(3), hc.get(3));
+        assert_ne!(ha.get(5), hc.get(5));
+
+        // Cached hashes are reused.
+        let tail = ha.into_tail();
+        a.cache_version_hashes(tail[..3].to_vec());
+        let hashes = a.version_hashes_with(|v, buf| content(&a, v, buf));
+        assert_eq!(hashes.into_tail(), &tail[3..]);
+    }
+}
@@ -10,8 +10,13 @@ pub mod graph;
 mod eq;
 pub mod entry;
 pub mod summary;
+pub mod bloom;
+#[cfg(feature = "version_hashes")]
+pub mod hashes;
+pub mod signatures;
 pub mod agent_span;
 pub mod agent_assignment;
+pub mod agent_metadata;
 
 #[cfg(test)]
 mod enc_fuzzer;
@@ -29,6 +34,15 @@ pub struct CausalGraph {
     /// At its core, this data set compactly stores the list of parents for every operation.
     pub graph: Graph,
 
+    /// Cached content hashes for versions in the graph, indexed by LV. This only covers a prefix
+    /// of the graph, and is filled in on demand. See [`hashes`] for details.
+    #[cfg(feature = "version_hashes")]
+    pub(crate) hashes: Vec<hashes::VersionHash>,
+
+    /// Signatures over spans of versions. See [`signatures`] for details.
+    #[cfg(feature = "version_hashes")]
+    pub(crate) signatures: signatures::SignatureStore,
+
     /// This is the version you get if you load the entire causal graph
     pub version: Frontier,
 }
@@ -0,0 +1,382 @@
+//! Signed runs of operations.
+//!
+//! When peers don't trust each other, each agent can be given a public key. The operations made by
+//! that agent are then signed in runs - each signature covers a span of seq numbers from a single
+//! agent. The signed message is a SHA-256 hash over the agent's name, the seq range and the
+//! [version hash](crate::causalgraph::hashes) of every version in the span. Because version hashes
+//! cover all history, a signature also pins down everything the signed operations depend on.
+//!
+//! Diamond types doesn't implement any signature scheme itself. Signing and verification are done
+//! by callbacks, and public keys are opaque byte strings.
+//!
+//! Signatures are stored in the causal graph so they can be forwarded to other peers along with
+//! the operations they cover.
+
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use smartstring::alias::String as SmartString;
+#[cfg(feature = "version_hashes")]
+use {
+    rle::HasLength,
+    sha2::{Digest, Sha256},
+    crate::{AgentId, CausalGraph, DTRange},
+    crate::causalgraph::hashes::{VersionHash, VersionHashes},
+    crate::encoding::parseerror::ParseError,
+    crate::encoding::tools::push_str,
+    crate::encoding::varint::push_usize,
+    crate::rle::KVPair,
+};
+
+/// A signed span of versions, as sent over the wire: (agent name, seq range, signature).
+#[cfg(feature = "version_hashes")]
+pub(crate) type SignedSpan<'a> = (&'a str, DTRange, &'a [u8]);
+
+/// The signatures we know about. This is indexed by agent ID, and each agent's list is sorted by
+/// seq.
+#[cfg(feature = "version_hashes")]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SignatureStore(Vec<Vec<(DTRange, Vec<u8>)>>);
+
+#[cfg(feature = "version_hashes")]
+impl SignatureStore {
+    fn for_agent(&self, agent: AgentId) -> &[(DTRange, Vec<u8>)] {
+        self.0.get(agent as usize).map_or(&[], |sigs| sigs.as_slice())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.iter().all(|sigs| sigs.is_empty())
+    }
+
+    /// Add a signature. Signatures for exactly the same span are ignored.
+    pub(crate) fn insert(&mut self, agent: AgentId, seq_range: DTRange, signature: Vec<u8>) {
+        let agent = agent as usize;
+        if self.0.len() <= agent {
+            self.0.resize_with(agent + 1, Vec::new);
+        }
+
+        let sigs = &mut self.0[agent];
+        if sigs.iter().any(|(r, _)| *r == seq_range) { return; }
+        let idx = sigs.partition_point(|(r, _)| r.start <= seq_range.start);
+        sigs.insert(idx, (seq_range, signature));
+    }
+
+    pub(crate) fn truncate_agents(&mut self, num_agents: AgentId) {
+        self.0.truncate(num_agents as usize);
+    }
+}
+
+/// The message signed for a span of versions from a single agent. Returns None if some of the
+/// versions in the span are unknown.
+#[cfg(feature = "version_hashes")]
+pub(crate) fn span_message(cg: &CausalGraph, hashes: &VersionHashes, agent: AgentId, seq_range: DTRange) -> Option<VersionHash> {
+    let lv_ranges = span_lv_ranges(cg, agent, seq_range)?;
+
+    let mut buf = Vec::new();
+    push_str(&mut buf, &cg.agent_assignment.client_data[agent as usize].name);
+    push_usize(&mut buf, seq_range.start);
+    push_usize(&mut buf, seq_range.end);
+    for v in lv_ranges.iter().flat_map(|r| r.iter()) {
+        buf.extend_from_slice(hashes.get(v));
+    }
+
+    Some(Sha256::digest(&buf).into())
+}
+
+#[cfg(feature = "version_hashes")]
+impl CausalGraph {
+    /// Get the stored signatures which cover any of the versions in the passed ranges.
+    pub(crate) fn signatures_in_ranges(&self, ranges: &[DTRange]) -> Vec<SignedSpan<'_>> {
+        let mut result: Vec<SignedSpan> = vec![];
+        if self.signatures.is_empty() { return result; }
+
+        let mut seen = vec![];
+        for range in ranges {
+            for KVPair(_, span) in self.agent_assignment.client_with_lv.iter_range(*range) {
+                for (idx, (seq_range, sig)) in self.signatures.for_agent(span.agent).iter().enumerate() {
+                    if seq_range.start < span.seq_range.end && span.seq_range.start < seq_range.end
+                        && !seen.contains(&(span.agent, idx))
+                    {
+                        seen.push((span.agent, idx));
+                        let name = &self.agent_assignment.client_data[span.agent as usize].name;
+                        result.push((name.as_str(), *seq_range, sig.as_slice()));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Sign any spans of versions which don't have a signature yet. The `sign` callback is passed
+    /// the agent's name and the message to sign, and returns None if we can't sign for that agent.
+    pub(crate) fn sign_spans<F>(&self, hashes: &VersionHashes, mut sign: F) -> Vec<(AgentId, DTRange, Vec<u8>)>
+        where F: FnMut(&str, &[u8]) -> Option<Vec<u8>>
+    {
+        let mut result = vec![];
+        for (agent, client) in self.agent_assignment.client_data.iter().enumerate() {
+            let agent = agent as AgentId;
+            let mut next_seq = 0;
+            let mut unsigned = vec![];
+            for (seq_range, _) in self.signatures.for_agent(agent) {
+                if seq_range.start > next_seq {
+                    unsigned.push((next_seq..seq_range.start).into());
+                }
+                next_seq = next_seq.max(seq_range.end);
+            }
+            if client.get_next_seq() > next_seq {
+                unsigned.push((next_seq..client.get_next_seq()).into());
+            }
+
+            for seq_range in unsigned {
+                let message = span_message(self, hashes, agent, seq_range).unwrap();
+                if let Some(signature) = sign(&client.name, &message) {
+                    result.push((agent, seq_range, signature));
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Public keys for agents, and a callback to check signatures with them.
+///
+/// When passed to [`ListOpLog::decode_and_add_opts`](crate::list::ListOpLog::decode_and_add_opts)
+/// or [`OpLog::merge_ops_opts`](crate::OpLog::merge_ops_opts), every new version must be covered
+/// by a valid signature from the agent which made it.
+///
+/// Checking signatures requires the `version_hashes` feature. Without it, any data decoded or
+/// merged with a verifier is rejected.
+#[cfg_attr(not(feature = "version_hashes"), allow(dead_code))]
+pub struct SignatureVerifier {
+    keys: BTreeMap<SmartString, Vec<u8>>,
+    verify: Box<VerifyFn>,
+}
+
+/// Called with (public key, message, signature).
+type VerifyFn = dyn Fn(&[u8], &[u8], &[u8]) -> bool;
+
+impl Debug for SignatureVerifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SignatureVerifier")
+            .field("keys", &self.keys)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SignatureVerifier {
+    /// Create a verifier. The callback is passed (public key, message, signature), and should
+    /// return true if the signature is valid.
+    pub fn new<F>(verify: F) -> Self where F: Fn(&[u8], &[u8], &[u8]) -> bool + 'static {
+        Self { keys: BTreeMap::new(), verify: Box::new(verify) }
+    }
+
+    /// Set the public key for the named agent. Operations from agents without a key are rejected.
+    pub fn add_key(&mut self, agent: &str, public_key: &[u8]) {
+        self.keys.insert(agent.into(), public_key.to_vec());
+    }
+}
+
+/// Find the signatures which cover any versions in `new_range`. If a verifier is passed, the
+/// signatures are checked, and every new version must be covered by a valid signature.
+#[cfg(feature = "version_hashes")]
+pub(crate) fn check_signatures<'a>(verifier: Option<(&SignatureVerifier, &VersionHashes)>, cg: &CausalGraph, new_range: DTRange, signatures: &[SignedSpan<'a>]) -> Result<Vec<SignedSpan<'a>>, ParseError> {
+    let mut covered = vec![];
+    let mut result = vec![];
+
+    for span in signatures {
+        let (name, seq_range, signature) = *span;
+        let agent = cg.agent_assignment.get_agent_id(name).ok_or(ParseError::SignatureInvalid)?;
+        let lv_ranges = span_lv_ranges(cg, agent, seq_range).ok_or(ParseError::SignatureInvalid)?;
+        if !lv_ranges.iter().any(|r| r.start < new_range.end && new_range.start < r.end) {
+            // We already have all of these versions. There's nothing to check.
+            continue;
+        }
+
+        if let Some((verifier, hashes)) = verifier {
+            let key = verifier.keys.get(name).ok_or(ParseError::SignatureInvalid)?;
+            let message = span_message(cg, hashes, agent, seq_range).ok_or(ParseError::SignatureInvalid)?;
+            if !(verifier.verify)(key, &message, signature) {
+                return Err(ParseError::SignatureInvalid);
+            }
+        }
+
+        covered.extend(lv_ranges);
+        result.push(*span);
+    }
+
+    if verifier.is_some() {
+        // Every new version needs to be signed.
+        covered.sort_unstable_by_key(|r| r.start);
+        let mut next = new_range.start;
+        for r in covered {
+            if r.start > next { break; }
+            next = next.max(r.end);
+        }
+        if next < new_range.end {
+            return Err(ParseError::SignatureMissing);
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(feature = "version_hashes")]
+impl CausalGraph {
+    /// Store signatures for new versions, returned from [`check_signatures`].
+    pub(crate) fn store_signatures(&mut self, signatures: &[SignedSpan]) {
+        for (name, seq_range, signature) in signatures {
+            let agent = self.agent_assignment.get_agent_id(name).unwrap();
+            self.signatures.insert(agent, *seq_range, signature.to_vec());
+        }
+    }
+}
+
+/// Drop any signatures which cover versions we don't have. This is used when a merge policy drops
+/// some of the new versions a signature covers.
+#[cfg(feature = "version_hashes")]
+pub(crate) fn retain_known_signatures<N: AsRef<str>, S>(cg: &CausalGraph, signatures: &mut Vec<(N, DTRange, S)>) {
+    signatures.retain(|(name, seq_range, _)| {
+        cg.agent_assignment.get_agent_id(name.as_ref())
+            .is_some_and(|agent| span_lv_ranges(cg, agent, *seq_range).is_some())
+    });
+}
+
+/// The local versions named by an agent's seq range, or None if some of them are unknown.
+#[cfg(feature = "version_hashes")]
+fn span_lv_ranges(cg: &CausalGraph, agent: AgentId, seq_range: DTRange) -> Option<Vec<DTRange>> {
+    let client = &cg.agent_assignment.client_data[agent as usize];
+    let mut result = vec![];
+    let mut seq = seq_range.start;
+    while seq < seq_range.end {
+        let lv_range = client.try_seq_to_lv_span((seq..seq_range.end).into())?;
+        seq += lv_range.len();
+        result.push(lv_range);
+    }
+    Some(result)
+}
+
+#[cfg(all(test, feature = "version_hashes"))]
+mod tests {
+    use sha2::{Digest, Sha256};
+    use crate::{CreateValue, MergeOptions, OpLog, Primitive, ROOT_CRDT_ID};
+    use crate::encoding::parseerror::ParseError;
+    use crate::list::encoding::{DecodeOptions, ENCODE_PATCH};
+    use crate::list::ListOpLog;
+    use super::SignatureVerifier;
+
+    // A toy signature scheme for testing. The "public key" is the same as the private key.
+    fn sign(key: &[u8], message: &[u8]) -> Vec<u8> {
+        Sha256::new().chain_update(key).chain_update(message).finalize().to_vec()
+    }
+
+    fn signer(agent: &str, message: &[u8]) -> Option<Vec<u8>> {
+        (agent != "mallory").then(|| sign(agent.as_bytes(), message))
+    }
+
+    fn verifier() -> SignatureVerifier {
+        let mut verifier = SignatureVerifier::new(|key, message, sig| sign(key, message) == sig);
+        verifier.add_key("seph", b"seph");
+        verifier.add_key("mike", b"mike");
+        verifier
+    }
+
+    fn decode_verified(oplog: &mut ListOpLog, data: &[u8]) -> Result<(), ParseError> {
+        let verifier = verifier();
+        oplog.decode_and_add_opts(data, DecodeOptions { verifier: Some(&verifier), ..Default::default() })
+            .map(|_| ())
+    }
+
+    #[test]
+    fn signed_list_ops_are_forwarded() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "hi there");
+        a.sign_pending(signer);
+
+        let mut b = ListOpLog::new();
+        decode_verified(&mut b, &a.encode(&ENCODE_PATCH)).unwrap();
+        let mike = b.get_or_create_agent_id("mike");
+        b.add_insert(mike, 0, "yo ");
+        b.sign_pending(signer);
+
+        // c gets seph's operations (and signature) from b.
+        let mut c = ListOpLog::new();
+        decode_verified(&mut c, &b.encode(&ENCODE_PATCH)).unwrap();
+        assert_eq!(c.checkout_tip().content(), "yo hi there");
+
+        // More operations are signed separately.
+        a.add_insert(seph, 0, "x");
+        a.sign_pending(signer);
+        decode_verified(&mut c, &a.encode_from(&ENCODE_PATCH, &[7])).unwrap();
+        assert_eq!(c.checkout_tip().content(), "yo xhi there");
+        c.dbg_check(true);
+    }
+
+    #[test]
+    fn unsigned_and_forged_ops_are_rejected() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "hi");
+        a.sign_pending(signer);
+        let mut b = ListOpLog::new();
+        decode_verified(&mut b, &a.encode(&ENCODE_PATCH)).unwrap();
+
+        // Unsigned operations.
+        a.add_insert(seph, 2, " there");
+        let data = a.encode_from(&ENCODE_PATCH, b.cg.version.as_ref());
+        assert_eq!(decode_verified(&mut b, &data), Err(ParseError::SignatureMissing));
+        assert_eq!(b.len(), 2);
+        b.dbg_check(true);
+
+        // Without a verifier, the data is accepted.
+        b.clone().decode_and_add(&data).unwrap();
+
+        // Operations from an agent we don't have a key for.
+        let mut m = a.clone();
+        let mallory = m.get_or_create_agent_id("mallory");
+        m.add_insert(mallory, 0, "boo");
+        m.cg.signatures.insert(mallory, (0..3).into(), vec![1, 2, 3]);
+        m.sign_pending(signer);
+        let data = m.encode_from(&ENCODE_PATCH, b.cg.version.as_ref());
+        assert_eq!(decode_verified(&mut b, &data), Err(ParseError::SignatureInvalid));
+
+        // Forged signatures.
+        let mut f = a.clone();
+        f.cg.signatures.insert(seph, (2..8).into(), vec![0; 32]);
+        let data = f.encode_from(&ENCODE_PATCH, b.cg.version.as_ref());
+        assert_eq!(decode_verified(&mut b, &data), Err(ParseError::SignatureInvalid));
+        assert_eq!(b.len(), 2);
+
+        a.sign_pending(signer);
+        let data = a.encode_from(&ENCODE_PATCH, b.cg.version.as_ref());
+        decode_verified(&mut b, &data).unwrap();
+        assert_eq!(b.checkout_tip().content(), "hi there");
+    }
+
+    #[test]
+    fn signed_oplog_ops() {
+        let verifier = verifier();
+        let opts = || MergeOptions { verifier: Some(&verifier), ..Default::default() };
+
+        let mut a = OpLog::new();
+        let seph = a.cg.get_or_create_agent_id("seph");
+        a.local_map_set(seph, ROOT_CRDT_ID, "x", CreateValue::Primitive(Primitive::I64(1)));
+        a.local_map_set(seph, ROOT_CRDT_ID, "y", CreateValue::Primitive(Primitive::I64(2)));
+
+        let mut b = OpLog::new();
+        assert_eq!(b.merge_ops_opts(a.ops_since(&[]), opts()), Err(ParseError::SignatureMissing));
+        assert_eq!(b.cg.len(), 0);
+
+        a.sign_pending(signer);
+        b.merge_ops_opts(a.ops_since(&[]), opts()).unwrap();
+        assert_eq!(a.checkout(), b.checkout());
+
+        let mut c = OpLog::new();
+        c.merge_ops_opts(b.ops_since(&[]), opts()).unwrap();
+        assert_eq!(a.checkout(), c.checkout());
+
+        // Tampering with a signature.
+        let mut ops = a.ops_since(&[]);
+        ops.signatures[0].2[0] ^= 1;
+        assert_eq!(OpLog::new().merge_ops_opts(ops, opts()), Err(ParseError::SignatureInvalid));
+    }
+}
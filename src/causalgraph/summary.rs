@@ -18,7 +18,7 @@ pub struct VSEntry {
 /// A full version summary names the ranges of known sequence numbers for each agent. This is useful
 /// when synchronizing changes.
 #[derive(Debug, Clone, Eq, PartialEq, Default)]
-pub struct VersionSummary(Vec<VSEntry>);
+pub struct VersionSummary(pub(crate) Vec<VSEntry>);
 
 /// A flat version summary just names the **next** sequence number from each user agent. This is
 /// useful when the agent IDs are guaranteed to be sequential - that is, for graphs with the
@@ -26,7 +26,7 @@ pub struct VersionSummary(Vec<VSEntry>);
 ///
 /// IF the same user agent can submit changes on multiple branches, this property does not hold.
 #[derive(Debug, Clone, Eq, PartialEq, Default)]
-pub struct VersionSummaryFlat(Vec<(SmartString, usize)>);
+pub struct VersionSummaryFlat(pub(crate) Vec<(SmartString, usize)>);
 
 // Serialize as {name1: [[start, end], [start, end], ..], name2: ...}.
 #[cfg(feature = "serde")]
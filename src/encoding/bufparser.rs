@@ -57,7 +57,8 @@ impl<'a> BufParser<'a> {
 
     pub(crate) fn next_u32_le(&mut self) -> Result<u32, ParseError> {
         // self.check_has_bytes(size_of::<u32>())?;
-        let val = u32::from_le_bytes(self.0[0..4].try_into().map_err(|_| ParseError::UnexpectedEOF)?);
+        let bytes = self.0.get(0..4).ok_or(ParseError::UnexpectedEOF)?;
+        let val = u32::from_le_bytes(bytes.try_into().unwrap());
         self.consume(size_of::<u32>());
         Ok(val)
     }
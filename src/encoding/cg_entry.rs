@@ -116,11 +116,13 @@ fn read_cg_aa(reader: &mut BufParser, persist: bool, aa: &mut AgentAssignment, a
         }
         (agent, 0, idx)
     } else {
-        let entry = agent_map[mapped_agent];
+        let entry = *agent_map.get(mapped_agent)
+            .ok_or(ParseError::GenericInvalidData)?;
         (entry.0, entry.1, mapped_agent)
     };
 
     let len = reader.next_usize()?;
+    if len == 0 { return Err(ParseError::InvalidLength); }
 
     let jump = if has_jump {
         reader.next_zigzag_isize()?
@@ -128,7 +130,8 @@ fn read_cg_aa(reader: &mut BufParser, persist: bool, aa: &mut AgentAssignment, a
 
     let start = isize_try_add(last_seq, jump)
         .ok_or(ParseError::GenericInvalidData)?;
-    let end = start + len;
+    let end = start.checked_add(len)
+        .ok_or(ParseError::GenericInvalidData)?;
 
     if persist {
         agent_map[idx].1 = end;
@@ -295,4 +298,17 @@ mod test {
         cg2.get_or_create_agent_id("a");
         check_merges_into_subset(cg2, &cg);
     }
+
+    #[test]
+    fn empty_entry_is_rejected() {
+        let mut cg = CausalGraph::new();
+        cg.get_or_create_agent_id("a");
+        cg.assign_local_op(0, 1);
+        let mut serialized = cg.serialize_changes_since(&[]);
+        // The entry is the agent header, the agent's name, then the span's length.
+        assert_eq!(&serialized[1..4], &[1, b'a', 1]);
+        serialized[3] = 0;
+
+        assert!(CausalGraph::new().merge_serialized_changes(&serialized).is_err());
+    }
 }
@@ -20,6 +20,7 @@ pub(crate) mod cg_entry;
 pub(crate) mod op;
 pub(crate) mod chunk_reader;
 pub(crate) mod map;
+pub(crate) mod summary;
 // mod agent_assignment;
 
 
@@ -56,7 +57,13 @@ pub(crate) enum ChunkType {
 
     // TransformedPositions = 27, // Currently unused
 
-    // Crc = 100,
+    /// Compact version summaries and frontiers, sent during sync handshakes.
+    VersionSummary = 30,
+    VersionSummaryFlat = 31,
+    RemoteFrontier = 32,
+    BloomSummary = 33,
+
+    Crc = 100,
 }
 
 #[derive(Clone)]
@@ -1,11 +1,14 @@
+use crate::encoding::tools::{ExtendFromSlice, push_str};
+use crate::encoding::varint::{num_encode_zigzag_i64, push_u32, push_u64, push_usize};
+use crate::{CreateValue, CRDTKind, Primitive};
+
 // use bumpalo::Bump;
 // use bumpalo::collections::vec::Vec as BumpVec;
 // use crate::{OpLog, Primitive, SnapshotValue};
-// use num_enum::TryFromPrimitive;
-// use crate::encoding::tools::{push_str, push_u32, push_u64};
+// // use crate::encoding::tools::{push_str, push_u32, push_u64};
 // use crate::encoding::varint::num_encode_zigzag_i64;
 //
-// #[derive(Debug, PartialEq, Eq, Copy, Clone, TryFromPrimitive)]
+// #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 // #[repr(u32)]
 // enum ValueType {
 //     // TODO: Assign numbers!
@@ -61,3 +64,158 @@
 //
 //     result
 // }
+
+
+/// The type tag written before each encoded primitive value.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[repr(u32)]
+enum PrimitiveType {
+    Nil = 0,
+    False = 1,
+    True = 2,
+    I64 = 3,
+    F64 = 4,
+    Str = 5,
+    Bytes = 6,
+}
+
+pub(crate) fn write_primitive<R: ExtendFromSlice>(result: &mut R, value: &Primitive) {
+    match value {
+        Primitive::Nil => {
+            push_u32(result, PrimitiveType::Nil as u32);
+        }
+        Primitive::Bool(b) => {
+            let kind = if *b { PrimitiveType::True } else { PrimitiveType::False };
+            push_u32(result, kind as u32);
+        }
+        Primitive::I64(num) => {
+            push_u32(result, PrimitiveType::I64 as u32);
+            push_u64(result, num_encode_zigzag_i64(*num));
+        }
+        Primitive::F64(num) => {
+            // Floats are stored as their raw bytes so every value (including NaNs) round-trips.
+            push_u32(result, PrimitiveType::F64 as u32);
+            result.extend_from_slice(&num.to_le_bytes());
+        }
+        Primitive::Str(str) => {
+            push_u32(result, PrimitiveType::Str as u32);
+            push_str(result, str);
+        }
+        Primitive::Bytes(bytes) => {
+            push_u32(result, PrimitiveType::Bytes as u32);
+            push_usize(result, bytes.len());
+            result.extend_from_slice(bytes);
+        }
+        Primitive::InvalidUninitialized => { panic!("Cannot encode uninitialized value") }
+    }
+}
+
+/// The type tag written before each encoded CreateValue.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[repr(u32)]
+enum CreateValueType {
+    Value = 0,
+    NewCRDT = 1,
+    Deleted = 2,
+}
+
+pub(crate) fn write_create_value<R: ExtendFromSlice>(result: &mut R, value: &CreateValue) {
+    match value {
+        CreateValue::Primitive(p) => {
+            push_u32(result, CreateValueType::Value as u32);
+            write_primitive(result, p);
+        }
+        CreateValue::NewCRDT(kind) => {
+            push_u32(result, CreateValueType::NewCRDT as u32);
+            push_u32(result, *kind as u16 as u32);
+        }
+        CreateValue::Deleted => {
+            push_u32(result, CreateValueType::Deleted as u32);
+        }
+    }
+}
+
+/// Counter operations are stored as the (zigzag encoded) amount added to the counter.
+pub(crate) fn write_counter_increment<R: ExtendFromSlice>(result: &mut R, amount: i64) {
+    push_u64(result, num_encode_zigzag_i64(amount));
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{CreateValue, CRDTKind, Primitive};
+    use super::*;
+
+    #[test]
+    fn primitive_encodings_are_distinct() {
+        // These encodings are hashed, so different values must never encode to the same bytes.
+        let values = [
+            Primitive::Nil,
+            Primitive::Bool(false),
+            Primitive::Bool(true),
+            Primitive::I64(-123),
+            Primitive::I64(i64::MAX),
+            Primitive::F64(1.5),
+            Primitive::F64(0.0),
+            Primitive::F64(-0.0),
+            Primitive::F64(f64::INFINITY),
+            Primitive::Str("hi there".into()),
+            Primitive::Str("".into()),
+            Primitive::Bytes(vec![]),
+            Primitive::Bytes(vec![0, 1, 2, 255]),
+        ];
+
+        let encoded: Vec<Vec<u8>> = values.iter().map(|v| {
+            let mut bytes = Vec::new();
+            write_primitive(&mut bytes, v);
+            bytes
+        }).collect();
+
+        for (i, a) in encoded.iter().enumerate() {
+            for b in encoded[i + 1..].iter() {
+                assert_ne!(a, b);
+            }
+        }
+
+        assert_eq!(encoded[0], [PrimitiveType::Nil as u8]);
+        let mut f64_bytes = vec![PrimitiveType::F64 as u8];
+        f64_bytes.extend_from_slice(&1.5f64.to_le_bytes());
+        assert_eq!(encoded[5], f64_bytes);
+    }
+
+    #[test]
+    fn create_value_encodings_are_distinct() {
+        let values = [
+            CreateValue::Primitive(Primitive::Nil),
+            CreateValue::Primitive(Primitive::F64(0.5)),
+            CreateValue::NewCRDT(CRDTKind::Map),
+            CreateValue::NewCRDT(CRDTKind::Counter),
+            CreateValue::Deleted,
+        ];
+
+        let encoded: Vec<Vec<u8>> = values.iter().map(|v| {
+            let mut bytes = Vec::new();
+            write_create_value(&mut bytes, v);
+            bytes
+        }).collect();
+
+        for (i, a) in encoded.iter().enumerate() {
+            for b in encoded[i + 1..].iter() {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn crdt_kind_encoding_is_stable() {
+        // Encoded CRDT kinds are hashed, so existing kinds must keep their numbers.
+        let expected = [
+            (CRDTKind::Map, 0), (CRDTKind::Register, 1), (CRDTKind::Collection, 2),
+            (CRDTKind::Text, 3), (CRDTKind::List, 4), (CRDTKind::Counter, 5),
+        ];
+        for (kind, n) in expected {
+            let mut bytes = Vec::new();
+            write_create_value(&mut bytes, &CreateValue::NewCRDT(kind));
+            assert_eq!(bytes, [CreateValueType::NewCRDT as u8, n]);
+        }
+    }
+}
@@ -117,8 +117,10 @@ pub(crate) fn read_parents_raw(reader: &mut BufParser, persist: bool, aa: &mut A
             let diff = n;
             // Local parents (parents inside this chunk of data) are stored using their local (file)
             // time offset.
-            let file_time = next_time - diff;
-            let (entry, offset) = read_map.txn_map.find_with_offset(file_time).unwrap();
+            let file_time = next_time.checked_sub(diff)
+                .ok_or(ParseError::GenericInvalidData)?;
+            let (entry, offset) = read_map.txn_map.find_with_offset(file_time)
+                .ok_or(ParseError::GenericInvalidData)?;
             entry.1.at_offset(offset)
         } else {
             let agent = match n {
@@ -139,7 +141,8 @@ pub(crate) fn read_parents_raw(reader: &mut BufParser, persist: bool, aa: &mut A
                 n => {
                     // n references a mapped agent.
                     let mapped_agent = n - 2;
-                    read_map.agent_map[mapped_agent].0
+                    read_map.agent_map.get(mapped_agent)
+                        .ok_or(ParseError::GenericInvalidData)?.0
                 }
             };
 
@@ -45,6 +45,18 @@ pub enum ParseError {
 
     ChecksumFailed,
 
+    /// The version hashes sent with the data don't match the hashes of our local versions. This
+    /// happens when a peer has different operations stored under the same IDs.
+    HashMismatch,
+
+    /// Some new operations aren't covered by a signature from their agent.
+    SignatureMissing,
+    /// A signature is invalid, or names an agent we don't have a public key for.
+    SignatureInvalid,
+
+    /// The merge policy rejected some of the incoming operations.
+    PolicyRejected,
+
     /// This error is interesting. We're loading a chunk but missing some of the data. In the future
     /// I'd like to explicitly support this case, and allow the oplog to contain a somewhat- sparse
     /// set of data, and load more as needed.
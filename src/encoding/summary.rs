@@ -0,0 +1,282 @@
+//! Compact binary encodings for version summaries and remote frontiers.
+//!
+//! These are small messages sent during sync handshakes. Each message is made up of a single data
+//! chunk followed by a CRC chunk:
+//!
+//! - `VersionSummary`: A list of agents. Each agent names the number of seq ranges, followed by
+//!   each range as a (delta from the end of the previous range, length) pair.
+//! - `VersionSummaryFlat`: A list of (agent, next seq) pairs.
+//! - `RemoteFrontier`: A list of (agent, seq) pairs.
+//!
+//! Agents are written the same way they are in the causal graph: The first time an agent is
+//! named, we write 0 followed by the agent's name. After that, the agent is referenced by its
+//! index in the message + 1. Unlike WriteMap / ReadMap, the table here is keyed by name, since
+//! summaries are usually decoded by peers which don't know about all of the named agents yet.
+
+use std::collections::HashMap;
+use rle::HasLength;
+use smallvec::SmallVec;
+use smartstring::alias::String as SmartString;
+use crate::causalgraph::agent_assignment::remote_ids::{RemoteFrontier, RemoteVersion};
+use crate::causalgraph::summary::{VersionSummary, VersionSummaryFlat, VSEntry};
+use crate::DTRange;
+use crate::encoding::bufparser::BufParser;
+use crate::encoding::chunk_reader::ChunkReader;
+use crate::encoding::ChunkType;
+use crate::encoding::map::isize_diff;
+use crate::encoding::parseerror::ParseError;
+use crate::encoding::tools::{calc_checksum, push_chunk, push_str};
+use crate::encoding::varint::{num_encode_zigzag_isize, push_usize};
+
+/// Maps each name already written to its index, so repeated names are written as a reference.
+#[derive(Debug, Default)]
+struct WriteNames<'a>(HashMap<&'a str, usize>);
+
+impl<'a> WriteNames<'a> {
+    fn write(&mut self, into: &mut Vec<u8>, name: &'a str) {
+        if let Some(idx) = self.0.get(name) {
+            push_usize(into, idx + 1);
+        } else {
+            push_usize(into, 0);
+            push_str(into, name);
+            let idx = self.0.len();
+            self.0.insert(name, idx);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ReadNames<'a>(Vec<&'a str>);
+
+impl<'a> ReadNames<'a> {
+    fn read(&mut self, reader: &mut BufParser<'a>) -> Result<&'a str, ParseError> {
+        match reader.next_usize()? {
+            0 => {
+                let name = reader.next_str()?;
+                self.0.push(name);
+                Ok(name)
+            }
+            n => self.0.get(n - 1).copied().ok_or(ParseError::GenericInvalidData),
+        }
+    }
+}
+
+/// Wrap the chunk data and append a CRC chunk.
+pub(crate) fn finish(chunk_type: ChunkType, data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len() + 10);
+    push_chunk(&mut result, chunk_type, data).unwrap();
+
+    let checksum = calc_checksum(&result);
+    push_chunk(&mut result, ChunkType::Crc, &checksum.to_le_bytes()).unwrap();
+    result
+}
+
+/// Read the data chunk out of a message, checking the CRC.
+pub(crate) fn read_chunk(bytes: &[u8], chunk_type: ChunkType) -> Result<BufParser<'_>, ParseError> {
+    let mut reader = ChunkReader(BufParser(bytes));
+    let data = reader.expect_chunk(chunk_type)?;
+
+    let checksummed_data = &bytes[..bytes.len() - reader.0.len()];
+    let mut crc_reader = reader.expect_chunk(ChunkType::Crc)?;
+    let expected_crc = crc_reader.next_u32_le()?;
+    crc_reader.expect_empty()?;
+    reader.expect_empty()?;
+
+    if calc_checksum(checksummed_data) != expected_crc {
+        return Err(ParseError::ChecksumFailed);
+    }
+
+    Ok(data)
+}
+
+impl VersionSummary {
+    /// Encode the summary in a compact binary format. Decode with
+    /// [`VersionSummary::decode`].
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![];
+        let mut names = WriteNames::default();
+
+        for VSEntry { name, seq_ranges } in self.0.iter() {
+            names.write(&mut data, name);
+            push_usize(&mut data, seq_ranges.len());
+
+            let mut last = 0;
+            for r in seq_ranges.iter() {
+                push_usize(&mut data, num_encode_zigzag_isize(isize_diff(r.start, last)));
+                push_usize(&mut data, r.len());
+                last = r.end;
+            }
+        }
+
+        finish(ChunkType::VersionSummary, &data)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = read_chunk(bytes, ChunkType::VersionSummary)?;
+        let mut names = ReadNames::default();
+        let mut entries = vec![];
+
+        while !reader.is_empty() {
+            let name = names.read(&mut reader)?;
+            let num_ranges = reader.next_usize()?;
+            // Each range takes at least 2 bytes.
+            if num_ranges > reader.len() { return Err(ParseError::InvalidLength); }
+
+            let mut seq_ranges = SmallVec::with_capacity(num_ranges);
+            let mut last: usize = 0;
+            for _ in 0..num_ranges {
+                let start = last.checked_add_signed(reader.next_zigzag_isize()?)
+                    .ok_or(ParseError::GenericInvalidData)?;
+                let len = reader.next_usize()?;
+                if len == 0 { return Err(ParseError::InvalidLength); }
+                let end = start.checked_add(len)
+                    .ok_or(ParseError::GenericInvalidData)?;
+                seq_ranges.push(DTRange { start, end });
+                last = end;
+            }
+
+            entries.push(VSEntry { name: name.into(), seq_ranges });
+        }
+
+        Ok(VersionSummary(entries))
+    }
+}
+
+impl VersionSummaryFlat {
+    /// Encode the summary in a compact binary format. Decode with
+    /// [`VersionSummaryFlat::decode`].
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![];
+        let mut names = WriteNames::default();
+
+        for (name, next_seq) in self.0.iter() {
+            names.write(&mut data, name);
+            push_usize(&mut data, *next_seq);
+        }
+
+        finish(ChunkType::VersionSummaryFlat, &data)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = read_chunk(bytes, ChunkType::VersionSummaryFlat)?;
+        let mut names = ReadNames::default();
+        let mut entries = vec![];
+
+        while !reader.is_empty() {
+            let name: SmartString = names.read(&mut reader)?.into();
+            entries.push((name, reader.next_usize()?));
+        }
+
+        Ok(VersionSummaryFlat(entries))
+    }
+}
+
+/// Encode a remote frontier in a compact binary format. Decode with [`decode_remote_frontier`].
+pub fn encode_remote_frontier(frontier: &[RemoteVersion]) -> Vec<u8> {
+    let mut data = vec![];
+    let mut names = WriteNames::default();
+
+    for RemoteVersion(name, seq) in frontier.iter() {
+        names.write(&mut data, name);
+        push_usize(&mut data, *seq);
+    }
+
+    finish(ChunkType::RemoteFrontier, &data)
+}
+
+/// Decode a remote frontier encoded with [`encode_remote_frontier`]. The agent names in the
+/// returned frontier borrow from the passed bytes.
+pub fn decode_remote_frontier(bytes: &[u8]) -> Result<RemoteFrontier<'_>, ParseError> {
+    let mut reader = read_chunk(bytes, ChunkType::RemoteFrontier)?;
+    let mut names = ReadNames::default();
+    let mut result = RemoteFrontier::new();
+
+    while !reader.is_empty() {
+        let name = names.read(&mut reader)?;
+        result.push(RemoteVersion(name, reader.next_usize()?));
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use smallvec::smallvec;
+    use crate::CausalGraph;
+    use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
+    use crate::causalgraph::agent_span::AgentSpan;
+    use crate::causalgraph::summary::{VersionSummary, VersionSummaryFlat, VSEntry};
+    use crate::encoding::parseerror::ParseError;
+    use super::*;
+
+    fn test_cg() -> CausalGraph {
+        let mut cg = CausalGraph::new();
+        cg.get_or_create_agent_id("seph");
+        cg.get_or_create_agent_id("mike");
+        cg.merge_and_assign(&[], AgentSpan { agent: 0, seq_range: (0..5).into() });
+        cg.merge_and_assign(&[], AgentSpan { agent: 1, seq_range: (0..5).into() });
+        cg.merge_and_assign(&[4], AgentSpan { agent: 0, seq_range: (5..10).into() });
+        cg
+    }
+
+    #[test]
+    fn summary_round_trips() {
+        let cg = test_cg();
+
+        let vs = cg.agent_assignment.summarize_versions();
+        let bytes = vs.encode();
+        assert_eq!(VersionSummary::decode(&bytes).unwrap(), vs);
+
+        let vs = VersionSummary(vec![
+            VSEntry { name: "a".into(), seq_ranges: smallvec![(10..20).into(), (30..31).into(), (0..5).into()] },
+            VSEntry { name: "b".into(), seq_ranges: smallvec![] },
+        ]);
+        assert_eq!(VersionSummary::decode(&vs.encode()).unwrap(), vs);
+
+        let flat = cg.agent_assignment.summarize_versions_flat();
+        assert_eq!(VersionSummaryFlat::decode(&flat.encode()).unwrap(), flat);
+
+        let empty = VersionSummary::default();
+        assert_eq!(VersionSummary::decode(&empty.encode()).unwrap(), empty);
+    }
+
+    #[test]
+    fn remote_frontier_round_trips() {
+        let cg = test_cg();
+        let rf = cg.remote_frontier();
+        assert_eq!(decode_remote_frontier(&encode_remote_frontier(&rf)).unwrap(), rf);
+
+        // Repeated agent names are written once.
+        let rf = [RemoteVersion("seph", 10), RemoteVersion("seph", 2000)];
+        let bytes = encode_remote_frontier(&rf);
+        assert_eq!(decode_remote_frontier(&bytes).unwrap().as_slice(), &rf);
+        assert_eq!(bytes.iter().filter(|b| **b == b's').count(), 1);
+
+        assert!(decode_remote_frontier(&encode_remote_frontier(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn decode_rejects_bad_data() {
+        let cg = test_cg();
+        let bytes = cg.agent_assignment.summarize_versions().encode();
+
+        // The wrong kind of message.
+        assert!(VersionSummaryFlat::decode(&bytes).is_err());
+        assert!(decode_remote_frontier(&bytes).is_err());
+
+        for i in 0..bytes.len() {
+            // Truncating or corrupting the message in any way should be an error, not a panic.
+            assert!(VersionSummary::decode(&bytes[..i]).is_err());
+
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 0x20;
+            assert!(VersionSummary::decode(&corrupted).is_err());
+        }
+
+        // Flipping a bit in the data should fail the checksum.
+        let mut corrupted = bytes.clone();
+        let name_pos = corrupted.iter().position(|b| *b == b's').unwrap();
+        corrupted[name_pos] = b'S';
+        assert_eq!(VersionSummary::decode(&corrupted), Err(ParseError::ChecksumFailed));
+    }
+}
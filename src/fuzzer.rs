@@ -1,23 +1,366 @@
-// use rand::prelude::*;
-// use crate::OpLog;
-//
-// fn merge_fuzz(seed: u64, verbose: bool) {
-//     // A parachute so if the fuzzer crashes we can recover the seed.
-//     let mut rng = SmallRng::seed_from_u64(seed);
-//
-//     let mut oplogs = [OpLog::new(), OpLog::new(), OpLog::new()];
-//     let agents = ["a", "b", "c"];
-//
-//     for _i in 0..300 {
-//         if verbose { println!("\n\ni {}", _i); }
-//
-//         // Generate some operations
-//         for _j in 0..2 {
-//             // for _j in 0..5 {
-//             let idx = rng.gen_range(0..oplogs.len());
-//             let oplog = &mut oplogs[idx];
-//
-//
-//         }
-//     }
-// }
\ No newline at end of file
+use rand::prelude::*;
+use crate::{CRDTKind, CreateValue, DTValue, MergeOptions, OpLog, PolicyDecision, PolicySpan, Primitive, ROOT_CRDT_ID, SerializedOps};
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
+use crate::list::operation::{ListOpKind, TextOperation};
+use crate::list_fuzzer_tools::{choose_2, fuzz_multithreaded};
+
+const AGENTS: [&str; 3] = ["a", "b", "c"];
+
+fn random_primitive(rng: &mut SmallRng) -> CreateValue {
+    CreateValue::Primitive(match rng.gen_range(0..3) {
+        0 => Primitive::I64(rng.gen_range(-10..10)),
+        1 => Primitive::Bool(rng.gen_bool(0.5)),
+        _ => Primitive::Str("hi".into()),
+    })
+}
+
+/// Make a random local change to the document. The document always starts with a text, list,
+/// collection, counter and register CRDT in the root map.
+fn random_change(oplog: &mut OpLog, agent: &str, rng: &mut SmallRng) {
+    let agent = oplog.cg.get_or_create_agent_id(agent);
+
+    match rng.gen_range(0..6) {
+        0 => {
+            let key = ["x", "y", "z"].choose(rng).unwrap();
+            let value = if rng.gen_bool(0.2) {
+                CreateValue::NewCRDT(CRDTKind::Counter)
+            } else { random_primitive(rng) };
+            oplog.local_map_set(agent, ROOT_CRDT_ID, key, value);
+        }
+        1 => {
+            let text = oplog.text_at_path(&["text".into()]).unwrap();
+            let len = oplog.checkout_text(text).unwrap().len_chars();
+            let op = if len == 0 || rng.gen_bool(0.6) {
+                let content = ["a", "bc", "😃d"].choose(rng).unwrap();
+                TextOperation::new_insert(rng.gen_range(0..=len), content)
+            } else {
+                let start = rng.gen_range(0..len);
+                TextOperation::new_delete(start..rng.gen_range(start + 1..=len))
+            };
+            oplog.local_text_op(agent, text, op);
+        }
+        2 => {
+            let (_, list) = oplog.crdt_at_path(&["list".into()]).unwrap();
+            let len = oplog.checkout_list(list).len();
+            if len == 0 || rng.gen_bool(0.6) {
+                let value = if rng.gen_bool(0.2) {
+                    CreateValue::NewCRDT(CRDTKind::Register)
+                } else { random_primitive(rng) };
+                oplog.local_list_insert(agent, list, rng.gen_range(0..=len), value);
+            } else {
+                let start = rng.gen_range(0..len);
+                oplog.local_list_delete(agent, list, start..start + 1);
+            }
+        }
+        3 => {
+            let (_, set) = oplog.crdt_at_path(&["set".into()]).unwrap();
+            let items: Vec<_> = oplog.checkout_collection(set).into_keys().collect();
+            if items.is_empty() || rng.gen_bool(0.6) {
+                oplog.local_collection_insert(agent, set, random_primitive(rng));
+            } else {
+                oplog.local_collection_remove(agent, set, *items.choose(rng).unwrap());
+            }
+        }
+        4 => {
+            let (_, counter) = oplog.crdt_at_path(&["count".into()]).unwrap();
+            oplog.local_counter_increment(agent, counter, rng.gen_range(-5..5));
+        }
+        _ => {
+            let (_, register) = oplog.crdt_at_path(&["reg".into()]).unwrap();
+            oplog.local_register_set(agent, register, random_primitive(rng));
+        }
+    }
+}
+
+fn random_rv<'a>(rng: &mut SmallRng, names: &[RemoteVersion<'a>]) -> RemoteVersion<'a> {
+    match rng.gen_range(0..4) {
+        0 => RemoteVersion("ROOT", 0),
+        1 => RemoteVersion("zzz", rng.gen_range(0..3)),
+        2 => RemoteVersion(AGENTS.choose(rng).unwrap(), rng.gen_range(0..1000)),
+        _ => names.choose(rng).copied().unwrap_or(RemoteVersion("a", 0)),
+    }
+}
+
+/// Make a single random change to the serialized operations. The result is often (but not always)
+/// invalid.
+fn corrupt(ops: &mut SerializedOps, rng: &mut SmallRng) {
+    let names: Vec<RemoteVersion> = ops.map_ops.iter().map(|op| op.1)
+        .chain(ops.text_ops.iter().map(|op| op.1))
+        .chain(ops.list_ops.iter().map(|op| op.1))
+        .chain(ops.collection_inserts.iter().map(|op| op.1))
+        .collect();
+
+    match rng.gen_range(0..13) {
+        0 => {
+            // Mess with the causal graph.
+            let len = ops.cg_changes.len();
+            if rng.gen_bool(0.5) {
+                ops.cg_changes.truncate(rng.gen_range(0..=len));
+            } else {
+                ops.cg_changes.extend((0..rng.gen_range(1..5)).map(|_| rng.gen::<u8>()));
+            }
+        }
+        1 => {
+            // Point an operation at a different CRDT.
+            let new_name = random_rv(rng, &names);
+            match rng.gen_range(0..6) {
+                0 => ops.map_ops.iter_mut().choose(rng).map(|op| op.0 = new_name),
+                1 => ops.text_ops.iter_mut().choose(rng).map(|op| op.0 = new_name),
+                2 => ops.list_ops.iter_mut().choose(rng).map(|op| op.0 = new_name),
+                3 => ops.collection_inserts.iter_mut().choose(rng).map(|op| op.0 = new_name),
+                4 => ops.collection_removes.iter_mut().choose(rng).map(|op| op.0 = new_name),
+                _ => ops.counter_ops.iter_mut().choose(rng).map(|op| op.0 = new_name),
+            };
+        }
+        2 => {
+            // Change the version of an operation.
+            let new_version = random_rv(rng, &names);
+            match rng.gen_range(0..4) {
+                0 => ops.map_ops.iter_mut().choose(rng).map(|op| op.1 = new_version),
+                1 => ops.text_ops.iter_mut().choose(rng).map(|op| op.1 = new_version),
+                2 => ops.register_ops.iter_mut().choose(rng).map(|op| op.1 = new_version),
+                _ => ops.counter_ops.iter_mut().choose(rng).map(|op| op.1 = new_version),
+            };
+        }
+        3 => {
+            // Remove the wrong item from a collection.
+            let target = random_rv(rng, &names);
+            if let Some(op) = ops.collection_removes.iter_mut().choose(rng) { op.2 = target; }
+        }
+        4 => {
+            // Change the kind of a new CRDT, or replace it with a primitive or deleted value.
+            let value = match rng.gen_range(0..3) {
+                0 => CreateValue::NewCRDT(CRDTKind::try_from(rng.gen_range(0..6)).unwrap()),
+                1 => CreateValue::Deleted,
+                _ => random_primitive(rng),
+            };
+            match rng.gen_range(0..3) {
+                0 => ops.map_ops.iter_mut().choose(rng).map(|op| op.3 = value),
+                1 => ops.collection_inserts.iter_mut().choose(rng).map(|op| op.2 = value),
+                _ => ops.list_ops.iter_mut()
+                    .flat_map(|op| op.3.iter_mut())
+                    .choose(rng)
+                    .map(|v| *v = value),
+            };
+        }
+        5 => {
+            // Add or remove list values.
+            if let Some(op) = ops.list_ops.iter_mut().choose(rng) {
+                if rng.gen_bool(0.5) { op.3.push(random_primitive(rng)); } else { op.3.pop(); }
+            }
+        }
+        6 => {
+            // Change the length of an operation.
+            let text_op = ops.text_ops.iter_mut().map(|op| &mut op.2);
+            let list_op = ops.list_ops.iter_mut().map(|op| &mut op.2);
+            if let Some(op) = text_op.chain(list_op).choose(rng) {
+                let span = &mut op.loc.span;
+                match rng.gen_range(0..3) {
+                    0 => span.end = span.start,
+                    1 => span.end += rng.gen_range(1..3),
+                    _ => std::mem::swap(&mut span.start, &mut span.end),
+                }
+            }
+        }
+        7 => {
+            // Move an operation. Small moves might still name a valid position, but operations
+            // moved past the end of the document at their parents must be rejected.
+            let text_op = ops.text_ops.iter_mut().map(|op| &mut op.2);
+            let list_op = ops.list_ops.iter_mut().map(|op| &mut op.2);
+            if let Some(op) = text_op.chain(list_op).choose(rng) {
+                let offset = match rng.gen_range(0..10) {
+                    0 => usize::MAX - op.loc.span.end,
+                    1..=5 => rng.gen_range(1..4),
+                    _ => 1000,
+                };
+                op.loc.span.start += offset;
+                op.loc.span.end += offset;
+            }
+        }
+        8 => {
+            // Corrupt the content of a text operation.
+            if let Some(op) = ops.text_ops.iter_mut().choose(rng) {
+                match (rng.gen_range(0..3), &mut op.2.content_pos) {
+                    (0, pos) => *pos = None,
+                    (1, Some(pos)) => pos.end += rng.gen_range(1..100),
+                    (_, Some(pos)) => pos.start += 1,
+                    _ => {}
+                }
+            }
+            if rng.gen_bool(0.3) {
+                ops.text_context.ins_content.push(0xff);
+            }
+        }
+        9 => {
+            // Add content to a list operation or flip an op between insert and delete.
+            if let Some(op) = ops.list_ops.iter_mut().choose(rng) {
+                if rng.gen_bool(0.5) {
+                    op.2.content_pos = Some((0..1).into());
+                } else {
+                    op.2.kind = match op.2.kind { ListOpKind::Ins => ListOpKind::Del, ListOpKind::Del => ListOpKind::Ins };
+                }
+            }
+        }
+        10 => {
+            // Duplicate an operation.
+            match rng.gen_range(0..4) {
+                0 => if let Some(op) = ops.map_ops.choose(rng).cloned() { ops.map_ops.push(op); },
+                1 => if let Some(op) = ops.text_ops.choose(rng).cloned() { ops.text_ops.push(op); },
+                2 => if let Some(op) = ops.list_ops.choose(rng).cloned() { ops.list_ops.push(op); },
+                _ => if let Some(op) = ops.counter_ops.choose(rng).cloned() { ops.counter_ops.push(op); },
+            }
+        }
+        11 => {
+            // Move a map or register operation onto a key or register which already has values, or
+            // swap the versions of two map operations. The moved operation has to be ordered
+            // against the values which are already there.
+            match rng.gen_range(0..3) {
+                0 => if let Some(op) = ops.map_ops.iter_mut().choose(rng) {
+                    op.2 = ["x", "y", "z", "text", "reg"].choose(rng).unwrap();
+                },
+                1 => if let Some(op) = ops.register_ops.iter_mut().choose(rng) {
+                    op.0 = random_rv(rng, &names);
+                },
+                _ => if !ops.map_ops.is_empty() {
+                    let a = rng.gen_range(0..ops.map_ops.len());
+                    let b = rng.gen_range(0..ops.map_ops.len());
+                    let version = ops.map_ops[a].1;
+                    ops.map_ops[a].1 = ops.map_ops[b].1;
+                    ops.map_ops[b].1 = version;
+                },
+            }
+        }
+        _ => {
+            // Shuffle the operations. This is valid.
+            ops.map_ops.shuffle(rng);
+            ops.text_ops.shuffle(rng);
+            ops.list_ops.shuffle(rng);
+            ops.collection_inserts.shuffle(rng);
+        }
+    }
+}
+
+/// Merge a corrupted set of changes into a document. If the merge fails, the document must be
+/// unchanged. Otherwise the document must still be valid.
+fn merge_corrupted(from: &OpLog, into: &OpLog, rng: &mut SmallRng) {
+    let mut ops = from.ops_since(&[]);
+    corrupt(&mut ops, rng);
+
+    let mut result = into.clone();
+    match result.merge_ops(ops) {
+        Ok(_) => {
+            result.dbg_check(true);
+        }
+        Err(_) => {
+            assert_eq!(format!("{:?}", result), format!("{:?}", into));
+            result.dbg_check(true);
+        }
+    }
+}
+
+/// Check two documents with the same operations have the same content.
+fn assert_docs_match(a: &OpLog, b: &OpLog) {
+    // Collection items are named by local versions, and register conflicts are in local
+    // version order. These are different in each document.
+    let (mut a_doc, mut b_doc) = (a.checkout(), b.checkout());
+    let (a_set, b_set) = (a_doc.remove("set").unwrap(), b_doc.remove("set").unwrap());
+    let (a_reg, b_reg) = (a_doc.remove("reg").unwrap(), b_doc.remove("reg").unwrap());
+    assert_eq!(a_doc, b_doc);
+    match (*a_set, *b_set) {
+        (DTValue::Collection(a_items), DTValue::Collection(b_items)) => {
+            assert_eq!(a_items.len(), b_items.len());
+        }
+        _ => panic!("Expected collections"),
+    }
+    match (*a_reg, *b_reg) {
+        (DTValue::Register { value: a_val, conflicts_with: a_conflicts }, DTValue::Register { value: b_val, conflicts_with: b_conflicts }) => {
+            assert_eq!(a_val, b_val);
+            assert_eq!(a_conflicts.len(), b_conflicts.len());
+        }
+        _ => panic!("Expected registers"),
+    }
+}
+
+/// Merge changes into a document with a policy which rejects one agent's changes. The changes
+/// which are kept must be valid, and the rejected changes can still be merged in afterwards.
+fn merge_with_policy(from: &OpLog, into: &OpLog, rng: &mut SmallRng) {
+    let rejected_agent = *AGENTS.choose(rng).unwrap();
+    let policy = |span: &PolicySpan| {
+        if span.agent == rejected_agent { PolicyDecision::Reject } else { PolicyDecision::Accept }
+    };
+
+    let mut result = into.clone();
+    result.merge_ops_opts(from.ops_since(&[]), MergeOptions { policy: Some(&policy), ..Default::default() }).unwrap();
+    result.dbg_check(true);
+
+    result.merge_ops(from.ops_since(&[])).unwrap();
+    result.dbg_check(true);
+    let mut expected = into.clone();
+    expected.merge_ops(from.ops_since(&[])).unwrap();
+    assert_docs_match(&result, &expected);
+}
+
+fn merge_corrupted_fuzz(seed: u64, verbose: bool) {
+    let mut rng = SmallRng::seed_from_u64(seed);
+
+    let mut oplogs = [OpLog::new(), OpLog::new(), OpLog::new()];
+
+    // All the documents share the same set of CRDTs in the root map.
+    let agent = oplogs[0].cg.get_or_create_agent_id("setup");
+    for (key, kind) in [("text", CRDTKind::Text), ("list", CRDTKind::List), ("set", CRDTKind::Collection), ("count", CRDTKind::Counter), ("reg", CRDTKind::Register)] {
+        oplogs[0].local_map_set(agent, ROOT_CRDT_ID, key, CreateValue::NewCRDT(kind));
+    }
+    let (first, rest) = oplogs.split_first_mut().unwrap();
+    for oplog in rest {
+        oplog.merge_ops(first.ops_since(&[])).unwrap();
+    }
+
+    for _i in 0..30 {
+        if verbose { println!("\n\ni {}", _i); }
+
+        // Generate some operations
+        for _j in 0..3 {
+            let idx = rng.gen_range(0..oplogs.len());
+            random_change(&mut oplogs[idx], AGENTS[idx], &mut rng);
+        }
+
+        let (_a_idx, a, _b_idx, b) = choose_2(&mut oplogs, &mut rng);
+
+        // Try merging some corrupted changes, then merge the real changes.
+        merge_corrupted(a, b, &mut rng);
+        merge_corrupted(b, a, &mut rng);
+        merge_with_policy(a, b, &mut rng);
+
+        b.merge_ops(a.ops_since(&[])).unwrap();
+        a.merge_ops(b.ops_since(&[])).unwrap();
+
+        assert_docs_match(a, b);
+    }
+
+    for oplog in oplogs {
+        oplog.dbg_check(true);
+    }
+}
+
+#[test]
+fn fuzz_merge_corrupted_once() {
+    merge_corrupted_fuzz(123, true);
+}
+
+#[test]
+fn fuzz_merge_corrupted() {
+    for k in 0..40 {
+        merge_corrupted_fuzz(k, false);
+    }
+}
+
+#[test]
+#[ignore]
+fn fuzz_merge_corrupted_forever() {
+    fuzz_multithreaded(u64::MAX, |seed| {
+        if seed % 1000 == 0 {
+            println!("Iteration {}", seed);
+        }
+        merge_corrupted_fuzz(seed, false);
+    })
+}
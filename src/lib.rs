@@ -188,6 +188,7 @@ use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Formatter};
 
 use jumprope::JumpRopeBuf;
+use num_enum::TryFromPrimitive;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
@@ -199,12 +200,18 @@ pub use frontier::Frontier;
 
 use crate::causalgraph::agent_assignment::remote_ids::{RemoteFrontierOwned, RemoteVersion, RemoteVersionOwned};
 use crate::causalgraph::agent_span::AgentVersion;
+use crate::causalgraph::agent_metadata::{AgentMetadata, Timestamp};
+use crate::causalgraph::signatures::SignatureVerifier;
 pub use crate::causalgraph::CausalGraph;
 pub use crate::dtrange::DTRange;
+pub use crate::path::{PathError, PathSegment};
+pub use crate::policy::{MergePolicy, PolicyDecision, PolicyOp, PolicySpan};
+pub use crate::wal::{WALError, WriteAheadLog};
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
 
 use crate::rle::{KVPair, RleVec};
 use crate::textinfo::TextInfo;
+use crate::listinfo::ListInfo;
 
 // use crate::list::internal_op::OperationInternal as TextOpInternal;
 
@@ -233,10 +240,18 @@ mod list_fuzzer_tools;
 mod fuzzer;
 mod branch;
 mod textinfo;
+mod listinfo;
+mod path;
+mod policy;
 mod oplog;
+#[cfg(feature = "version_hashes")]
+mod oplog_hashes;
 #[cfg(feature = "storage")]
 mod storage;
+#[cfg(feature = "storage")]
+pub use crate::storage::{DTFile, HeaderRecovery, ListOpLogReader, PersistentListOpLog, SEError};
 mod simple_checkout;
+pub mod sync;
 // mod listmerge2;
 mod stats;
 
@@ -250,20 +265,41 @@ pub type AgentId = u32;
 /// converted to RawVersions before being sent over the wire or saved to disk.
 pub type LV = usize;
 
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(untagged))]
 // #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Primitive {
     Nil,
     Bool(bool),
     I64(i64),
-    // F64(f64),
+    F64(f64),
     Str(SmartString),
+    Bytes(Vec<u8>),
 
     #[cfg_attr(feature = "serde", serde(skip))]
     InvalidUninitialized,
 }
 
+// Floats are compared by their bit patterns, so every value (including NaN) is equal to itself.
+// This keeps Primitive usable as Eq, and matches how floats are stored and sent over the wire.
+impl PartialEq for Primitive {
+    fn eq(&self, other: &Self) -> bool {
+        use Primitive::*;
+        match (self, other) {
+            (Nil, Nil) => true,
+            (Bool(a), Bool(b)) => a == b,
+            (I64(a), I64(b)) => a == b,
+            (F64(a), F64(b)) => a.to_bits() == b.to_bits(),
+            (Str(a), Str(b)) => a == b,
+            (Bytes(a), Bytes(b)) => a == b,
+            (InvalidUninitialized, InvalidUninitialized) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Primitive {}
+
 impl Debug for Primitive {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -271,21 +307,24 @@ impl Debug for Primitive {
             Primitive::Bool(val) => val.fmt(f),
             // Primitive::I64(val) => f.debug_tuple("I64").field(val).finish(),
             Primitive::I64(val) => val.fmt(f),
+            Primitive::F64(val) => val.fmt(f),
             Primitive::Str(val) => val.fmt(f),
+            Primitive::Bytes(val) => f.debug_tuple("Bytes").field(val).finish(),
             Primitive::InvalidUninitialized => f.debug_tuple("InvalidUninitialized").finish()
         }
     }
 }
 
-// #[derive(Debug, Eq, PartialEq, Copy, Clone, TryFromPrimitive)]
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, TryFromPrimitive)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-// #[repr(u16)]
+#[repr(u16)]
 pub enum CRDTKind {
     Map, // String => Register (like a JS object)
     Register,
     Collection, // SQL table / mongo collection
     Text,
+    List, // Ordered sequence of values (like a JS array)
+    Counter, // PN-counter. Concurrent increments are all kept.
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -293,7 +332,7 @@ pub enum CRDTKind {
 pub enum CreateValue {
     Primitive(Primitive),
     NewCRDT(CRDTKind),
-    // Deleted, // Marks that the key / contents should be deleted.
+    Deleted, // Marks that the key / contents should be deleted.
 }
 
 // #[derive(Debug, Clone, Eq, PartialEq)]
@@ -379,6 +418,25 @@ pub(crate) struct RegisterInfo {
     supremum: SmallVec<usize, 2>,
 }
 
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CollectionInfo {
+    /// Every item which has ever been inserted into the collection. Items are named by the LV of
+    /// the operation which inserted them.
+    inserts: BTreeMap<LV, CreateValue>,
+
+    /// All remove operations. This maps from the LV of the remove operation to the removed item.
+    removes: BTreeMap<LV, LV>,
+
+    /// Cached set of items which have been removed in the current version.
+    removed_items: BTreeSet<LV>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CounterInfo {
+    /// The amount added by each increment operation. Decrements are stored as negative amounts.
+    increments: BTreeMap<LV, i64>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RegisterValue {
     Primitive(Primitive),
@@ -394,21 +452,35 @@ pub struct OpLog {
     // cg_storage: Option<CGStorage>,
     // wal_storage: Option<WriteAheadLog>,
 
-    // Information about whether the map still exists!
-    // maps: BTreeMap<LVKey, MapInfo>,
+    /// The IDs of all the map CRDTs which have been created (excluding the root map).
+    maps: BTreeSet<LVKey>,
 
     /// (CRDT ID, key) -> MVRegister.
     map_keys: BTreeMap<(LVKey, SmartString), RegisterInfo>,
     /// CRDT ID -> Text CRDT.
     texts: BTreeMap<LVKey, TextInfo>,
+    /// CRDT ID -> Collection CRDT.
+    collections: BTreeMap<LVKey, CollectionInfo>,
+    /// CRDT ID -> List CRDT.
+    lists: BTreeMap<LVKey, ListInfo>,
 
     // These are always inserted at the end, but items in the middle are removed. There's probably
     // a better data structure to accomplish this.
     map_index: BTreeMap<LV, (LVKey, SmartString)>,
     text_index: BTreeMap<LV, LVKey>,
+    /// Unlike the other indexes, this contains every collection operation (insert and remove).
+    collection_index: BTreeMap<LV, LVKey>,
+    list_index: BTreeMap<LV, LVKey>,
+    /// CRDT ID -> Counter CRDT.
+    counters: BTreeMap<LVKey, CounterInfo>,
+    /// Like the collection index, this contains every counter operation.
+    counter_index: BTreeMap<LV, LVKey>,
 
     // TODO: Vec -> SmallVec.
-    // registers: BTreeMap<LVKey, RegisterInfo>,
+    /// CRDT ID -> Register CRDT.
+    registers: BTreeMap<LVKey, RegisterInfo>,
+    /// Register CRDT supremum versions -> CRDT ID.
+    register_index: BTreeMap<LV, LVKey>,
 
     // The set of CRDTs which have been deleted or superseded in the current version. This data is
     // pretty similar to the _index data, in that its mainly just useful for branches doing
@@ -427,14 +499,21 @@ pub struct Branch {
     // registers: BTreeMap<LVKey, SmallVec<LV, 2>>, // TODO.
     maps: BTreeMap<LVKey, BTreeMap<SmartString, RegisterState>>, // any objects.
     pub texts: BTreeMap<LVKey, JumpRopeBuf>,
+    /// Collection CRDT ID -> (item ID -> value).
+    collections: BTreeMap<LVKey, BTreeMap<LV, RegisterValue>>,
+    registers: BTreeMap<LVKey, RegisterState>,
+    /// List CRDT ID -> items (in order).
+    lists: BTreeMap<LVKey, Vec<RegisterValue>>,
+    /// Counter CRDT ID -> current value.
+    counters: BTreeMap<LVKey, i64>,
 }
 
 /// The register stores the specified value, but if conflicts_with is not empty, it has some
 /// conflicting concurrent values too. The `value` field will be consistent across all peers.
 #[derive(Debug, Clone, PartialEq, Eq)]
-struct RegisterState {
-    value: RegisterValue,
-    conflicts_with: Vec<RegisterValue>,
+pub struct RegisterState {
+    pub value: RegisterValue,
+    pub conflicts_with: Vec<RegisterValue>,
 }
 
 #[derive(Debug, Clone)]
@@ -447,6 +526,41 @@ pub struct SerializedOps<'a> {
     map_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, &'a str, CreateValue)>,
     text_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, ListOpMetrics)>,
     text_context: ListOperationCtx,
+
+    // (Register, version of the op, value).
+    #[cfg_attr(feature = "serde", serde(borrow, default))]
+    register_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, CreateValue)>,
+
+    // (Collection, version of the op, value).
+    #[cfg_attr(feature = "serde", serde(borrow, default))]
+    collection_inserts: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, CreateValue)>,
+    // (Collection, version of the op, removed item).
+    #[cfg_attr(feature = "serde", serde(borrow, default))]
+    collection_removes: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, RemoteVersion<'a>)>,
+
+    // (List, version of the op, op, inserted values). The op doesn't have any content.
+    #[cfg_attr(feature = "serde", serde(borrow, default))]
+    list_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, ListOpMetrics, Vec<CreateValue>)>,
+
+    // (Counter, version of the op, amount added).
+    #[cfg_attr(feature = "serde", serde(borrow, default))]
+    counter_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, i64)>,
+
+    // Content hashes of the sender's version, checked by the receiver. These are only filled in by
+    // OpLog::ops_since_with_hashes, and ignored unless the version_hashes feature is enabled.
+    #[cfg_attr(feature = "serde", serde(borrow, default))]
+    version_hashes: Vec<(RemoteVersion<'a>, [u8; 32])>,
+
+    // Signatures over spans of versions: (agent, seq range, signature).
+    #[cfg_attr(feature = "serde", serde(borrow, default))]
+    signatures: Vec<(&'a str, DTRange, Vec<u8>)>,
+
+    // Metadata for the agents which made the operations, and timestamps for spans of versions:
+    // (agent, seq range, timestamp).
+    #[cfg_attr(feature = "serde", serde(borrow, default))]
+    agent_metadata: Vec<(&'a str, AgentMetadata)>,
+    #[cfg_attr(feature = "serde", serde(borrow, default))]
+    timestamps: Vec<(&'a str, DTRange, Timestamp)>,
 }
 
 impl<'a> From<SerializedOps<'a>> for SerializedOpsOwned {
@@ -460,6 +574,75 @@ impl<'a> From<SerializedOps<'a>> for SerializedOpsOwned {
                 (crdt_name.to_owned(), rv.to_owned(), metrics)
             }).collect(),
             text_context: ops.text_context,
+            register_ops: ops.register_ops.into_iter().map(|(crdt_name, rv, val)| {
+                (crdt_name.to_owned(), rv.to_owned(), val)
+            }).collect(),
+            collection_inserts: ops.collection_inserts.into_iter().map(|(crdt_name, rv, val)| {
+                (crdt_name.to_owned(), rv.to_owned(), val)
+            }).collect(),
+            collection_removes: ops.collection_removes.into_iter().map(|(crdt_name, rv, target)| {
+                (crdt_name.to_owned(), rv.to_owned(), target.to_owned())
+            }).collect(),
+            list_ops: ops.list_ops.into_iter().map(|(crdt_name, rv, metrics, values)| {
+                (crdt_name.to_owned(), rv.to_owned(), metrics, values)
+            }).collect(),
+            counter_ops: ops.counter_ops.into_iter().map(|(crdt_name, rv, amount)| {
+                (crdt_name.to_owned(), rv.to_owned(), amount)
+            }).collect(),
+            version_hashes: ops.version_hashes.into_iter().map(|(rv, hash)| {
+                (rv.to_owned(), hash)
+            }).collect(),
+            signatures: ops.signatures.into_iter().map(|(name, seq_range, sig)| {
+                (SmartString::from(name), seq_range, sig)
+            }).collect(),
+            agent_metadata: ops.agent_metadata.into_iter().map(|(name, metadata)| {
+                (SmartString::from(name), metadata)
+            }).collect(),
+            timestamps: ops.timestamps.into_iter().map(|(name, seq_range, ts)| {
+                (SmartString::from(name), seq_range, ts)
+            }).collect(),
+        }
+    }
+}
+
+impl<'a> From<&'a SerializedOpsOwned> for SerializedOps<'a> {
+    fn from(ops: &'a SerializedOpsOwned) -> Self {
+        Self {
+            cg_changes: ops.cg_changes.clone(),
+            map_ops: ops.map_ops.iter().map(|(crdt_name, rv, key, val)| {
+                (crdt_name.into(), rv.into(), key.as_str(), val.clone())
+            }).collect(),
+            text_ops: ops.text_ops.iter().map(|(crdt_name, rv, metrics)| {
+                (crdt_name.into(), rv.into(), metrics.clone())
+            }).collect(),
+            text_context: ops.text_context.clone(),
+            register_ops: ops.register_ops.iter().map(|(crdt_name, rv, val)| {
+                (crdt_name.into(), rv.into(), val.clone())
+            }).collect(),
+            collection_inserts: ops.collection_inserts.iter().map(|(crdt_name, rv, val)| {
+                (crdt_name.into(), rv.into(), val.clone())
+            }).collect(),
+            collection_removes: ops.collection_removes.iter().map(|(crdt_name, rv, target)| {
+                (crdt_name.into(), rv.into(), target.into())
+            }).collect(),
+            list_ops: ops.list_ops.iter().map(|(crdt_name, rv, metrics, values)| {
+                (crdt_name.into(), rv.into(), metrics.clone(), values.clone())
+            }).collect(),
+            counter_ops: ops.counter_ops.iter().map(|(crdt_name, rv, amount)| {
+                (crdt_name.into(), rv.into(), *amount)
+            }).collect(),
+            version_hashes: ops.version_hashes.iter().map(|(rv, hash)| {
+                (rv.into(), *hash)
+            }).collect(),
+            signatures: ops.signatures.iter().map(|(name, seq_range, sig)| {
+                (name.as_str(), *seq_range, sig.clone())
+            }).collect(),
+            agent_metadata: ops.agent_metadata.iter().map(|(name, metadata)| {
+                (name.as_str(), metadata.clone())
+            }).collect(),
+            timestamps: ops.timestamps.iter().map(|(name, seq_range, ts)| {
+                (name.as_str(), *seq_range, *ts)
+            }).collect(),
         }
     }
 }
@@ -479,6 +662,35 @@ pub struct SerializedOpsOwned {
     map_ops: Vec<(RemoteVersionOwned, RemoteVersionOwned, SmartString, CreateValue)>,
     text_ops: Vec<(RemoteVersionOwned, RemoteVersionOwned, ListOpMetrics)>,
     text_context: ListOperationCtx,
+
+    #[cfg_attr(feature = "serde", serde(default))]
+    register_ops: Vec<(RemoteVersionOwned, RemoteVersionOwned, CreateValue)>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    collection_inserts: Vec<(RemoteVersionOwned, RemoteVersionOwned, CreateValue)>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    collection_removes: Vec<(RemoteVersionOwned, RemoteVersionOwned, RemoteVersionOwned)>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    list_ops: Vec<(RemoteVersionOwned, RemoteVersionOwned, ListOpMetrics, Vec<CreateValue>)>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    counter_ops: Vec<(RemoteVersionOwned, RemoteVersionOwned, i64)>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    version_hashes: Vec<(RemoteVersionOwned, [u8; 32])>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    signatures: Vec<(SmartString, DTRange, Vec<u8>)>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    agent_metadata: Vec<(SmartString, AgentMetadata)>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    timestamps: Vec<(SmartString, DTRange, Timestamp)>,
+}
+
+/// Options for [`OpLog::merge_ops_opts`].
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions<'a> {
+    /// If set, every new operation must be covered by a valid signature from its agent.
+    pub verifier: Option<&'a SignatureVerifier>,
+
+    /// If set, the policy decides which of the new operations are allowed to be merged.
+    pub policy: Option<&'a dyn MergePolicy>,
 }
 
 /// This is used for checkouts. This is a value tree.
@@ -487,8 +699,12 @@ pub struct SerializedOpsOwned {
 // #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum DTValue {
     Primitive(Primitive),
-    // Register(Box<DTValue>),
+    /// A register CRDT. The value is the (tie-broken) winner. If the register has been set
+    /// concurrently, the other values are listed in conflicts_with.
+    Register { value: Box<DTValue>, conflicts_with: Vec<DTValue> },
     Map(BTreeMap<SmartString, Box<DTValue>>),
-    // Collection(BTreeMap<LV, Box<DTValue>>),
+    Collection(BTreeMap<LV, Box<DTValue>>),
+    List(Vec<DTValue>),
+    Counter(i64),
     Text(String),
 }
@@ -1,11 +1,13 @@
+use std::collections::BTreeMap;
 use smallvec::{smallvec, SmallVec};
+use smartstring::alias::String as SmartString;
 use crate::list::encoding::*;
 use crate::list::{ListOpLog, switch};
 use crate::frontier::*;
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
 use crate::list::operation::ListOpKind::{Del, Ins};
 use crate::rev_range::RangeRev;
-use crate::{AgentId, Frontier, LV};
+use crate::{AgentId, Frontier, LV, ROOT_CRDT_ID};
 use crate::unicount::*;
 use rle::*;
 use crate::list::buffered_iter::Buffered;
@@ -19,6 +21,16 @@ use crate::rle::{KVPair, RleKeyedAndSplitable, RleSpanHelpers, RleVec};
 use crate::encoding::parseerror::ParseError;
 use crate::encoding::tools::calc_checksum;
 use crate::list::encoding::leb::num_decode_zigzag_isize_old;
+use crate::causalgraph::signatures::SignatureVerifier;
+use crate::causalgraph::agent_metadata::AgentMetadata;
+use crate::list::operation::TextOperation;
+use crate::policy::{check_policy, MergePolicy, PolicyOp, VersionRemap};
+#[cfg(feature = "version_hashes")]
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
+#[cfg(feature = "version_hashes")]
+use crate::causalgraph::hashes::VersionHash;
+#[cfg(feature = "version_hashes")]
+use crate::causalgraph::signatures::{check_signatures, retain_known_signatures};
 
 // If this is set to false, the compiler can optimize out the verbose printing code. This makes the
 // compiled output slightly smaller.
@@ -426,19 +438,27 @@ impl<'a> Iterator for ReadPatchContentIter<'a> {
 
 
 #[derive(Debug, Clone)]
-pub struct DecodeOptions {
+pub struct DecodeOptions<'a> {
     /// Ignore CRC check failures. This is mostly used for debugging.
     pub ignore_crc: bool,
 
     pub verbose: bool,
+
+    /// If set, every new operation must be covered by a valid signature from its agent.
+    pub verifier: Option<&'a SignatureVerifier>,
+
+    /// If set, the policy decides which of the new operations are allowed to be merged.
+    pub policy: Option<&'a dyn MergePolicy>,
 }
 
 #[allow(clippy::derivable_impls)]
-impl Default for DecodeOptions {
+impl<'a> Default for DecodeOptions<'a> {
     fn default() -> Self {
         Self {
             ignore_crc: false,
             verbose: false,
+            verifier: None,
+            policy: None,
         }
     }
 }
@@ -452,7 +472,10 @@ impl ListOpLog {
 
     pub fn load_from_opts(data: &[u8], opts: DecodeOptions) -> Result<Self, ParseError> {
         let mut oplog = Self::new();
-        oplog.decode_internal(data, opts)?;
+        if oplog.decode_internal(data, opts)?.is_none() {
+            // Rejected by the merge policy.
+            return Ok(Self::new());
+        }
         Ok(oplog)
     }
 
@@ -471,6 +494,10 @@ impl ListOpLog {
     /// If successful, returns the version of the loaded data (which could be different from the
     /// local version!)
     ///
+    /// If a merge policy is passed in the options and it silently rejects some of the data, the
+    /// rejected operations (and any operations which depend on them) are dropped, the rest are
+    /// added and the local version is returned.
+    ///
     /// This method takes an options object, which for now doesn't do much. Most users should just
     /// call [`OpLog::decode_and_add`](OpLog::decode_and_add)
     pub fn decode_and_add_opts(&mut self, data: &[u8], opts: DecodeOptions) -> Result<Frontier, ParseError> {
@@ -492,7 +519,7 @@ impl ListOpLog {
 
         let result = self.decode_internal(data, opts);
 
-        if result.is_err() {
+        if !matches!(result, Ok(Some(_))) {
             // Unwind changes back to len.
             // This would be nicer with an RleVec iterator, but the iter implementation doesn't
             // support iterating backwards.
@@ -579,7 +606,35 @@ impl ListOpLog {
             self.cg.version = old_frontier;
         }
 
-        result
+        result.map(|frontier| frontier.unwrap_or_else(|| self.cg.version.clone()))
+    }
+
+    /// Remove the operations rejected by a merge policy after they were decoded. The kept
+    /// operations are renumbered to follow on from `start`. The other arguments describe the oplog
+    /// before decoding.
+    fn remove_rejected(&mut self, start: LV, rejected: &[DTRange], version: Frontier, num_agents: AgentId, (ins_content_len, del_content_len): (usize, usize)) -> VersionRemap {
+        let remap = self.cg.remove_rejected(start, rejected, version, num_agents);
+
+        // The operations we keep are copied out, since their content is stored alongside the
+        // content of the rejected operations.
+        let kept_ops: Vec<TextOperation> = remap.kept_ranges()
+            .flat_map(|range| self.iter_range_simple(range))
+            .map(|(KVPair(_, metrics), content)| (metrics, content).into())
+            .collect();
+
+        let num_operations = self.operations.end();
+        self.operations.remove_ctx((start..num_operations).into(), &self.operation_ctx);
+        self.operation_ctx.ins_content.truncate(ins_content_len);
+        self.operation_ctx.del_content.truncate(del_content_len);
+
+        let mut v = start;
+        for op in kept_ops {
+            self.push_op_internal(v, op.loc, op.kind, op.content_as_str());
+            v += op.len();
+        }
+        debug_assert_eq!(v, self.cg.len());
+
+        remap
     }
 
     /// Merge data from the remote source into our local document state.
@@ -587,9 +642,16 @@ impl ListOpLog {
     /// NOTE: This code is quite new.
     /// TODO: Currently if this method returns an error, the local state is undefined & invalid.
     /// Until this is fixed, the signature of the method will stay kinda weird to prevent misuse.
-    fn decode_internal(&mut self, data: &[u8], opts: DecodeOptions) -> Result<Frontier, ParseError> {
+    /// Returns None if the merge policy silently rejected all of the new operations. The caller is
+    /// responsible for unwinding them.
+    fn decode_internal(&mut self, data: &[u8], opts: DecodeOptions) -> Result<Option<Frontier>, ParseError> {
         // Written to be symmetric with encode functions.
         let mut reader = BufReader(data);
+        let old_len = self.len();
+        // This is used to drop any operations rejected by the merge policy.
+        let old_version = self.cg.version.clone();
+        let old_num_agents = self.cg.num_agents();
+        let old_content_lens = (self.operation_ctx.ins_content.len(), self.operation_ctx.del_content.len());
 
         let verbose = ALLOW_VERBOSE && opts.verbose;
         if verbose {
@@ -936,6 +998,13 @@ impl ListOpLog {
             file_frontier
         }; // End of patches
 
+        // The version hashes chunk is optional. We read it even if we can't check the hashes, since
+        // it comes before the CRC.
+        let version_hashes = reader.read_chunk_if_eq(ListChunkType::VersionHashes)?;
+        let signatures = reader.read_chunk_if_eq(ListChunkType::Signatures)?;
+        let agent_metadata = reader.read_chunk_if_eq(ListChunkType::AgentMetadata)?;
+        let timestamps = reader.read_chunk_if_eq(ListChunkType::Timestamps)?;
+
         // TODO: Move checksum check to the start, so if it fails we don't modify the document.
         let reader_len = reader.0.len();
         if let Some(mut crc_reader) = reader.read_chunk_if_eq(ListChunkType::Crc)? {
@@ -954,9 +1023,130 @@ impl ListOpLog {
             }
         }
 
+        // The metadata chunks are parsed before any version hashes or signatures are stored, so a
+        // parse error here doesn't leave hashes or signatures behind for unwound versions.
+        let mut metadata = vec![];
+        if let Some(mut chunk) = agent_metadata {
+            while !chunk.is_empty() {
+                let name = chunk.next_str()?;
+                let mut next_opt_str = || -> Result<Option<SmartString>, ParseError> {
+                    Ok(match chunk.next_usize()? {
+                        0 => None,
+                        1 => Some(chunk.next_str()?.into()),
+                        _ => { return Err(ParseError::GenericInvalidData); }
+                    })
+                };
+                let display_name = next_opt_str()?;
+                let user_id = next_opt_str()?;
+                let mut extra = BTreeMap::new();
+                for _ in 0..chunk.next_usize()? {
+                    let key = chunk.next_str()?;
+                    let len = chunk.next_usize()?;
+                    extra.insert(key.into(), chunk.next_n_bytes(len)?.to_vec());
+                }
+                metadata.push((name, AgentMetadata { display_name, user_id, extra }));
+            }
+        }
+
+        let mut timestamp_spans = vec![];
+        if let Some(mut chunk) = timestamps {
+            while !chunk.is_empty() {
+                let name = chunk.next_str()?;
+                let start = chunk.next_usize()?;
+                let len = chunk.next_usize()?;
+                let end = start.checked_add(len).ok_or(ParseError::GenericInvalidData)?;
+                let timestamp = chunk.next_u64()?;
+                timestamp_spans.push((name, DTRange { start, end }, timestamp));
+            }
+        }
+
+        // Hashes and signatures are checked against all of the new versions, before the merge policy
+        // has a chance to drop any of them.
+        #[cfg(feature = "version_hashes")]
+        let (mut new_hashes, mut signed_spans) = {
+            let mut expected_hashes = vec![];
+            if let Some(mut chunk) = version_hashes {
+                while !chunk.is_empty() {
+                    let name = chunk.next_str()?;
+                    let seq = chunk.next_usize()?;
+                    let hash: VersionHash = chunk.next_n_bytes(32)?.try_into().unwrap();
+                    expected_hashes.push((RemoteVersion(name, seq), hash));
+                }
+            }
+
+            let mut signed_spans = vec![];
+            if let Some(mut chunk) = signatures {
+                while !chunk.is_empty() {
+                    let name = chunk.next_str()?;
+                    let start = chunk.next_usize()?;
+                    let len = chunk.next_usize()?;
+                    let end = start.checked_add(len).ok_or(ParseError::GenericInvalidData)?;
+                    let sig_len = chunk.next_usize()?;
+                    let signature = chunk.next_n_bytes(sig_len)?;
+                    signed_spans.push((name, DTRange { start, end }, signature));
+                }
+            }
+
+            // Hashes are only computed if they're needed, since it's slow the first time.
+            let new_range: DTRange = (old_len..self.len()).into();
+            if !expected_hashes.is_empty() || opts.verifier.is_some() {
+                let hashes = self.version_hashes();
+                hashes.verify(&self.cg, &expected_hashes)?;
+                let signed_spans = check_signatures(opts.verifier.map(|v| (v, &hashes)), &self.cg, new_range, &signed_spans)?;
+                (Some(hashes.into_tail()), signed_spans)
+            } else {
+                (None, check_signatures(None, &self.cg, new_range, &signed_spans)?)
+            }
+        };
+        #[cfg(not(feature = "version_hashes"))] {
+            let _ = (version_hashes, signatures);
+            if opts.verifier.is_some() { return Err(ParseError::SignatureInvalid); }
+        }
+
+        let mut result_version = file_frontier;
+        if let Some(policy) = opts.policy {
+            let new_range: DTRange = (old_len..self.len()).into();
+            // Operations are split at agent boundaries, so each one is passed with its own span.
+            let ops = self.cg.agent_assignment.client_with_lv.iter_range(new_range)
+                .flat_map(|KVPair(v, span)| self.iter_range_simple((v..v + span.len()).into()))
+                .map(|(KVPair(v, metrics), content)| {
+                    (v, PolicyOp::Text { crdt: ROOT_CRDT_ID, op: (metrics, content).into() })
+                })
+                .collect();
+            let rejected = check_policy(policy, &self.cg, new_range, ops)?;
+
+            if rejected.first() == Some(&new_range) { return Ok(None); }
+            if !rejected.is_empty() {
+                #[cfg(feature = "version_hashes")]
+                let cached_hashes = self.cg.hashes.len();
+                let remap = self.remove_rejected(old_len, &rejected, old_version, old_num_agents, old_content_lens);
+                #[cfg(feature = "version_hashes")] {
+                    if let Some(new_hashes) = new_hashes.as_mut() {
+                        remap.retain_kept(new_hashes, cached_hashes);
+                    }
+                    retain_known_signatures(&self.cg, &mut signed_spans);
+                }
+                #[cfg(not(feature = "version_hashes"))]
+                let _ = remap;
+
+                // Some of the loaded data was dropped, so there's no version naming what was loaded.
+                result_version = self.cg.version.clone();
+            }
+        }
+
+        #[cfg(feature = "version_hashes")] {
+            if let Some(new_hashes) = new_hashes {
+                self.cg.cache_version_hashes(new_hashes);
+            }
+            self.cg.store_signatures(&signed_spans);
+        }
+
+        // Nothing from here can fail, so the metadata doesn't need to be unwound.
+        self.cg.merge_agent_metadata(metadata, timestamp_spans);
+
         // self.frontier = end_frontier_chunk.read_full_frontier(&self)?;
 
-        Ok(file_frontier)
+        Ok(Some(result_version))
     }
 }
 
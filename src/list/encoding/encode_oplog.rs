@@ -10,8 +10,10 @@ use crate::frontier::local_frontier_is_root;
 use crate::list::op_metrics::ListOpMetrics;
 use crate::list::operation::ListOpKind;
 use crate::dtrange::DTRange;
+#[cfg(feature = "version_hashes")]
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
 use crate::encoding::tools::calc_checksum;
-use crate::list::encoding::encode_tools::{Merger, push_leb_chunk, push_leb_str, push_leb_u32, push_leb_usize, push_u32_le, write_leb_bit_run};
+use crate::list::encoding::encode_tools::{Merger, push_leb_chunk, push_leb_str, push_leb_u32, push_leb_u64, push_leb_usize, push_u32_le, write_leb_bit_run};
 use crate::list::encoding::leb::{encode_leb_u32, encode_leb_usize, num_encode_zigzag_isize_old};
 use crate::listmerge::merge::TransformedResultRaw;
 const ALLOW_VERBOSE: bool = true;
@@ -756,6 +758,68 @@ impl ListOpLog {
 
         write_chunk(ListChunkType::Patches, &mut patches_buf);
 
+        #[cfg(feature = "version_hashes")]
+        if opts.store_version_hashes {
+            let hashes = self.version_hashes();
+            for v in self.cg.version.iter() {
+                let RemoteVersion(name, seq) = self.cg.agent_assignment.local_to_remote_version(*v);
+                push_leb_str(&mut patches_buf, name);
+                push_leb_usize(&mut patches_buf, seq);
+                patches_buf.extend_from_slice(hashes.get(*v));
+            }
+            push_leb_chunk(&mut result, ListChunkType::VersionHashes, &patches_buf, verbose);
+            patches_buf.clear();
+        }
+
+        #[cfg(feature = "version_hashes")] {
+            for (name, seq_range, signature) in self.cg.signatures_in_ranges(&self.cg.diff_since(from_version)) {
+                push_leb_str(&mut patches_buf, name);
+                push_leb_usize(&mut patches_buf, seq_range.start);
+                push_leb_usize(&mut patches_buf, seq_range.len());
+                push_leb_usize(&mut patches_buf, signature.len());
+                patches_buf.extend_from_slice(signature);
+            }
+            if !patches_buf.is_empty() {
+                push_leb_chunk(&mut result, ListChunkType::Signatures, &patches_buf, verbose);
+                patches_buf.clear();
+            }
+        }
+
+        let ranges = self.cg.diff_since(from_version);
+        for (name, metadata) in self.cg.agent_metadata_in_ranges(&ranges) {
+            push_leb_str(&mut patches_buf, name);
+            for field in [&metadata.display_name, &metadata.user_id] {
+                match field {
+                    Some(s) => {
+                        push_leb_usize(&mut patches_buf, 1);
+                        push_leb_str(&mut patches_buf, s);
+                    }
+                    None => push_leb_usize(&mut patches_buf, 0),
+                }
+            }
+            push_leb_usize(&mut patches_buf, metadata.extra.len());
+            for (key, value) in metadata.extra.iter() {
+                push_leb_str(&mut patches_buf, key);
+                push_leb_usize(&mut patches_buf, value.len());
+                patches_buf.extend_from_slice(value);
+            }
+        }
+        if !patches_buf.is_empty() {
+            push_leb_chunk(&mut result, ListChunkType::AgentMetadata, &patches_buf, verbose);
+            patches_buf.clear();
+        }
+
+        for (name, seq_range, timestamp) in self.cg.timestamps_in_ranges(&ranges) {
+            push_leb_str(&mut patches_buf, name);
+            push_leb_usize(&mut patches_buf, seq_range.start);
+            push_leb_usize(&mut patches_buf, seq_range.len());
+            push_leb_u64(&mut patches_buf, timestamp);
+        }
+        if !patches_buf.is_empty() {
+            push_leb_chunk(&mut result, ListChunkType::Timestamps, &patches_buf, verbose);
+            patches_buf.clear();
+        }
+
         // TODO (later): Final branch content.
 
         // println!("checksum {checksum}");
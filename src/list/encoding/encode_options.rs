@@ -23,6 +23,8 @@ pub struct EncodeOptions<'a> {
 
     pub(crate) store_xf: bool,
     pub(crate) sort: bool,
+
+    pub(crate) store_version_hashes: bool,
 }
 
 
@@ -37,6 +39,7 @@ pub const ENCODE_PATCH: EncodeOptions = EncodeOptions {
     // sort_events:
     store_xf: false,
     sort: false,
+    store_version_hashes: false,
 };
 
 pub const ENCODE_FULL: EncodeOptions = EncodeOptions {
@@ -49,6 +52,7 @@ pub const ENCODE_FULL: EncodeOptions = EncodeOptions {
     verbose: false,
     store_xf: false,
     sort: false,
+    store_version_hashes: false,
 };
 
 impl<'a> Default for EncodeOptions<'a> {
@@ -118,6 +122,15 @@ impl<'a> EncodeOptions<'a> {
         self
     }
 
+    /// Store the content hashes of the oplog's frontier. The receiver checks these hashes when the
+    /// data is merged, and rejects the data if any operation conflicts with one it already has.
+    ///
+    /// This requires the `version_hashes` feature. Without it, this option is ignored.
+    pub fn store_version_hashes(mut self, store_version_hashes: bool) -> Self {
+        self.store_version_hashes = store_version_hashes;
+        self
+    }
+
     pub fn build(self) -> EncodeOptions<'a> {
         self
     }
@@ -20,6 +20,7 @@ use rle::MergableSpan;
 use crate::encoding::varint::*;
 use num_enum::TryFromPrimitive;
 pub use encode_options::{EncodeOptions, EncodeOptionsBuilder, ENCODE_FULL, ENCODE_PATCH};
+pub use decode_oplog::DecodeOptions;
 
 const MAGIC_BYTES: [u8; 8] = *b"DMNDTYPS";
 
@@ -61,6 +62,15 @@ enum ListChunkType {
     /// A chunk specifying the position deltas for operations when transformed in the stored order
     TransformedPositions = 28,
 
+    /// Content hashes for each version in the end frontier of the data, named by (agent, seq).
+    VersionHashes = 30,
+    /// Signatures over spans of versions, named by (agent, seq range).
+    Signatures = 31,
+    /// Metadata for the agents which made any of the operations in the data.
+    AgentMetadata = 32,
+    /// Wall-clock timestamps for spans of versions, named by (agent, seq range).
+    Timestamps = 33,
+
     Crc = 100,
 }
 
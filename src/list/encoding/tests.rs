@@ -198,6 +198,7 @@ fn check_unroll_works(dest: &ListOpLog, src: &ListOpLog) {
         let result = actual_output.decode_and_add_opts(&corrupted, DecodeOptions {
             ignore_crc: false,
             verbose: true,
+            ..Default::default()
         });
 
         if let Err(_err) = result {
@@ -0,0 +1,180 @@
+//! Version hashes for list oplogs. See [`crate::causalgraph::hashes`] for details.
+
+use rle::{HasLength, SplitableSpanCtx};
+use crate::causalgraph::hashes::{VersionHash, VersionHashes};
+use crate::encoding::tools::push_str;
+use crate::encoding::varint::push_usize;
+use crate::list::ListOpLog;
+use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
+use crate::list::operation::ListOpKind;
+use crate::LV;
+
+/// Write the content bytes of each version in a list operation, for hashing.
+///
+/// Each version is hashed with its kind, position and (for inserts) the inserted content. Deleted
+/// content is never hashed since it usually isn't sent to remote peers.
+pub(crate) fn write_list_op_content<F>(op: &ListOpMetrics, ctx: &ListOperationCtx, mut write: F)
+    where F: FnMut(&ListOpMetrics, &mut Vec<u8>)
+{
+    let mut buf = Vec::new();
+    let mut op = op.clone();
+    while !op.is_empty() {
+        let rest = if op.len() > 1 { Some(op.truncate_ctx(1, ctx)) } else { None };
+
+        buf.clear();
+        buf.push(match op.kind {
+            ListOpKind::Ins => 0,
+            ListOpKind::Del => 1,
+        });
+        push_usize(&mut buf, op.start());
+        if op.kind == ListOpKind::Ins {
+            if let Some(content) = op.get_content(ctx) {
+                push_str(&mut buf, content);
+            }
+        }
+        write(&op, &mut buf);
+
+        match rest {
+            Some(rest) => op = rest,
+            None => break,
+        }
+    }
+}
+
+impl ListOpLog {
+    pub(crate) fn version_hashes(&self) -> VersionHashes<'_> {
+        self.cg.version_hashes_with(|v, buf| {
+            let op = self.operations.find_packed_and_split_ctx((v..v + 1).into(), &self.operation_ctx).1;
+            write_list_op_content(&op, &self.operation_ctx, |_, bytes| buf.extend_from_slice(bytes));
+        })
+    }
+
+    /// Get the content hash of the named version. The hash covers the operation at the version and
+    /// (transitively) every version it depends on. This is consistent across peers.
+    pub fn version_hash(&self, v: LV) -> VersionHash {
+        *self.version_hashes().get(v)
+    }
+
+    /// Get the content hash of a frontier. Two peers with the same frontier hash have exactly the
+    /// same operations up to that frontier.
+    pub fn frontier_hash(&self, frontier: &[LV]) -> VersionHash {
+        self.version_hashes().frontier_hash(frontier)
+    }
+
+    /// Compute and cache the hashes of all versions in the oplog. This makes subsequent calls to
+    /// [`version_hash`](ListOpLog::version_hash) and [`frontier_hash`](ListOpLog::frontier_hash)
+    /// (and encoding with version hashes) much faster.
+    pub fn cache_version_hashes(&mut self) {
+        let tail = self.version_hashes().into_tail();
+        self.cg.cache_version_hashes(tail);
+    }
+
+    /// Sign all spans of versions which haven't been signed yet. The callback is passed the agent's
+    /// name and the message to sign, and should return None for agents we can't sign for.
+    ///
+    /// Signatures are stored in the oplog, and sent along with the operations they cover.
+    pub fn sign_pending<F>(&mut self, sign: F) where F: FnMut(&str, &[u8]) -> Option<Vec<u8>> {
+        let hashes = self.version_hashes();
+        let signatures = self.cg.sign_spans(&hashes, sign);
+        let tail = hashes.into_tail();
+        self.cg.cache_version_hashes(tail);
+        for (agent, seq_range, signature) in signatures {
+            self.cg.signatures.insert(agent, seq_range, signature);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::parseerror::ParseError;
+    use crate::list::encoding::{DecodeOptions, EncodeOptions, ENCODE_PATCH};
+    use crate::list::ListOpLog;
+
+    #[test]
+    fn hashes_match_across_peers() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        let mike = a.get_or_create_agent_id("mike");
+        a.add_insert_at(seph, &[], 0, "hi there");
+        a.add_insert_at(mike, &[], 0, "yo");
+        a.add_delete_at(seph, &[7, 9], 0..2);
+
+        let mut b = ListOpLog::new();
+        let mike = b.get_or_create_agent_id("mike");
+        b.add_insert_at(mike, &[], 0, "yo");
+        b.decode_and_add(&a.encode(&ENCODE_PATCH)).unwrap();
+
+        assert_eq!(a.frontier_hash(a.cg.version.as_ref()), b.frontier_hash(b.cg.version.as_ref()));
+        assert_eq!(a.version_hash(7), b.version_hash(9));
+        assert_ne!(a.version_hash(7), a.version_hash(6));
+
+        // Caching hashes doesn't change them.
+        let hash = a.frontier_hash(&[3, 9]);
+        a.cache_version_hashes();
+        assert_eq!(a.frontier_hash(&[3, 9]), hash);
+        a.add_insert(seph, 0, "x");
+        assert_eq!(a.frontier_hash(&[3, 9]), hash);
+        assert_ne!(a.frontier_hash(a.cg.version.as_ref()), b.frontier_hash(b.cg.version.as_ref()));
+
+        // The same edit with different content gets a different hash.
+        let mut c = ListOpLog::new();
+        let seph = c.get_or_create_agent_id("seph");
+        c.add_insert_at(seph, &[], 0, "hi therE");
+        assert_eq!(a.version_hash(6), c.version_hash(6));
+        assert_ne!(a.version_hash(7), c.version_hash(7));
+    }
+
+    #[test]
+    fn decode_checks_hashes() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "hi");
+        let mike = a.get_or_create_agent_id("mike");
+        a.add_insert(mike, 2, " there");
+
+        let opts = EncodeOptions::patch().store_version_hashes(true);
+        let mut b = ListOpLog::new();
+        b.decode_and_add(&a.encode(&opts)).unwrap();
+        assert_eq!(b.cg.hashes.len(), b.len());
+        b.decode_and_add(&a.encode(&opts)).unwrap();
+        assert_eq!(a.frontier_hash(a.cg.version.as_ref()), b.frontier_hash(b.cg.version.as_ref()));
+
+        // c reuses the ID of seph's first edit, with different content.
+        let mut c = ListOpLog::new();
+        let seph = c.get_or_create_agent_id("seph");
+        c.add_insert(seph, 0, "yo");
+        let data = c.encode(&opts);
+        assert_eq!(a.decode_and_add(&data), Err(ParseError::HashMismatch));
+        assert_eq!(c.decode_and_add(&a.encode(&opts)), Err(ParseError::HashMismatch));
+        assert_eq!(c.len(), 2);
+        c.dbg_check(true);
+
+        // Without hashes, the conflicting edit is silently ignored.
+        assert!(a.decode_and_add(&c.encode(&ENCODE_PATCH)).is_ok());
+        assert_eq!(ListOpLog::load_from(&a.encode(&opts)).unwrap(), a);
+    }
+
+    #[test]
+    fn failed_decode_leaves_no_hashes() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "hi there");
+        a.set_timestamp((0..8).into(), 1234);
+        let data = a.encode(&EncodeOptions::patch().store_version_hashes(true));
+
+        // Corrupt the end of the timestamps chunk, which comes after the version hashes.
+        let opts = DecodeOptions { ignore_crc: true, ..Default::default() };
+        let mut num_errors = 0;
+        for i in data.len() - 30..data.len() - 6 {
+            let mut corrupt = data.clone();
+            corrupt[i] = 0xff;
+            let mut b = ListOpLog::new();
+            if b.decode_and_add_opts(&corrupt, opts.clone()).is_err() {
+                num_errors += 1;
+                assert!(b.cg.hashes.is_empty());
+                b.dbg_check(true);
+            }
+        }
+        assert!(num_errors > 0);
+    }
+}
@@ -26,12 +26,14 @@ mod eq;
 mod oplog_merge;
 
 #[cfg(any(test, feature = "gen_test_data"))]
-mod old_fuzzer_tools;
+pub(crate) mod old_fuzzer_tools;
 #[cfg(test)]
 mod oplog_merge_fuzzer;
 
 pub(crate) mod buffered_iter;
 mod stochastic_summary;
+#[cfg(feature = "version_hashes")]
+pub(crate) mod hashes;
 mod merge;
 
 #[cfg(feature = "gen_test_data")]
@@ -39,6 +41,8 @@ mod gen_random;
 #[cfg(feature = "gen_test_data")]
 pub use gen_random::gen_oplog;
 
+pub use crate::listmerge::anchor::{Anchor, AnchorBias};
+
 // TODO!
 // trait InlineReplace<T> {
 //     fn insert(pos: usize, vals: &[T]);
@@ -206,7 +206,7 @@ impl RangeRev {
         self.span.end = self.span.start + at;
 
         RangeRev {
-            span: DTRange { start: start2, end: start2 + len - at },
+            span: DTRange { start: start2, end: start2 + (len - at) },
             fwd: self.fwd
         }
     }
@@ -365,6 +365,15 @@ mod test {
         dbg!(op, rem);
     }
 
+    #[test]
+    fn truncate_insert_at_end_of_range() {
+        // Regression. Remote operations can name positions right up to usize::MAX.
+        let mut loc = RangeRev { span: (usize::MAX - 5..usize::MAX).into(), fwd: true };
+        let rem = loc.truncate_tagged_span(ListOpKind::Ins, 2);
+        assert_eq!(loc.span, DTRange::from(usize::MAX - 5..usize::MAX - 3));
+        assert_eq!(rem.span, DTRange::from(usize::MAX - 3..usize::MAX));
+    }
+
     #[test]
     fn split_around_unicode() {
         // The ¥ symbol is a 2-byte encoding. And ↯ is 3 bytes.
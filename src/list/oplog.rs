@@ -2,6 +2,7 @@ use std::ops::Range;
 use rle::{HasLength, SplitableSpan};
 use crate::{AgentId, Frontier, LV};
 use crate::list::{ListBranch, ListOpLog};
+use crate::causalgraph::agent_metadata::{AgentMetadata, Timestamp};
 use crate::causalgraph::graph::GraphEntrySimple;
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
 use crate::list::operation::{TextOperation, ListOpKind};
@@ -61,6 +62,35 @@ impl ListOpLog {
         self.cg.num_agents()
     }
 
+    /// Get the metadata for an agent, if any has been set locally or received from a peer.
+    pub fn agent_metadata(&self, agent: AgentId) -> Option<&AgentMetadata> {
+        self.cg.agent_metadata(agent)
+    }
+
+    /// Set (or replace) the metadata for an agent. This is sent to remote peers along with the
+    /// agent's operations.
+    pub fn set_agent_metadata(&mut self, agent: AgentId, metadata: AgentMetadata) {
+        self.cg.set_agent_metadata(agent, metadata);
+    }
+
+    /// Set the wall-clock timestamp (in milliseconds since the unix epoch) of a range of local
+    /// versions. Versions which already have a timestamp aren't changed.
+    pub fn set_timestamp(&mut self, range: DTRange, timestamp: Timestamp) {
+        self.cg.set_timestamp(range, timestamp);
+    }
+
+    /// Get the wall-clock timestamp of a version, if it has one.
+    pub fn timestamp_at(&self, v: LV) -> Option<Timestamp> {
+        self.cg.timestamp_at(v)
+    }
+
+    /// List the timestamps of all the versions in the specified range which have one.
+    pub fn timestamps_in_range(&self, range: DTRange) -> Vec<(RemoteVersionSpan<'_>, Timestamp)> {
+        self.cg.timestamps_in_ranges(&[range]).into_iter()
+            .map(|(name, seq_range, ts)| (RemoteVersionSpan(name, seq_range), ts))
+            .collect()
+    }
+
     pub(crate) fn lv_to_agent_version(&self, lv: LV) -> AgentVersion {
         self.cg.agent_assignment.local_to_agent_version(lv)
     }
@@ -1,8 +1,7 @@
-// WIP.
-
-use crate::causalgraph::agent_span::AgentVersion;
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
+use crate::list::encoding::EncodeOptions;
 use crate::list::ListOpLog;
-use crate::LV;
+use crate::{Frontier, LV};
 
 impl ListOpLog {
 
@@ -22,75 +21,150 @@ impl ListOpLog {
     /// ancestor of the other peer's version. (Eg, I'm modifying a document and you're just
     /// observing it.)
     ///
-    /// Ny design here is a hybrid approach. I'm going to construct a fixed-sized chunk of known
+    /// My design here is a hybrid approach. I'm going to construct a fixed-sized chunk of known
     /// versions we can send to our remote peer. (And the remote peer can do the same with us). The
     /// chunk will contain exponentially less information the further back in time we scan; so the
     /// more time which has passed since we have a common ancestor, the more wasted bytes of changes
     /// we'll send to the remote peer. But this approach will always only need 1RTT to sync.
     ///
     /// Its not perfect, but it'll do donkey. It'll do.
-    #[allow(unused)]
-    fn get_stochastic_version(&self, target_count: usize) -> Vec<AgentVersion> {
-        // TODO: WIP.
-        let target_count = target_count.max(self.cg.version.len());
-        let mut result = Vec::with_capacity(target_count + 10);
+    ///
+    /// The returned set contains the current frontier, followed by (at most) `target_count`
+    /// versions in total, spaced exponentially further apart going back in time. The remote peer
+    /// passes it to [`intersect_with_stochastic_version`](ListOpLog::intersect_with_stochastic_version)
+    /// or [`encode_from_stochastic_version`](ListOpLog::encode_from_stochastic_version).
+    ///
+    /// Note the frontier is always included, so if the frontier contains more than `target_count`
+    /// versions, more versions are returned.
+    pub fn get_stochastic_version(&self, target_count: usize) -> Vec<RemoteVersion<'_>> {
+        let frontier = self.cg.version.as_ref();
+        let target_count = target_count.max(frontier.len());
+        let mut result = Vec::with_capacity(target_count);
 
         let time_len = self.len();
 
         // If we have no changes, just return the empty set. Descending from ROOT is implied anyway.
         if time_len == 0 { return result; }
 
-        let mut push_time = |t: LV| {
-            result.push(self.cg.agent_assignment.local_to_agent_version(t));
-        };
-
         // No matter what, we'll send the current frontier:
-        for t in self.cg.version.iter() {
-            push_time(*t);
-        }
+        result.extend(frontier.iter().map(|v| self.cg.agent_assignment.local_to_remote_version(*v)));
 
         // So we want about target_count items. I'm assuming there's an exponentially decaying
         // probability of syncing as we go further back in time. This is a big assumption - and
         // probably not true in practice. But it'll do. (TODO: Quadratic might be better?)
         //
-        // Given factor, the approx number of operations we'll return is log_f(|ops|).
-        // Solving for f gives f = |ops|^(1/target).
-        if target_count > self.cg.version.len() {
-            // Note I'm using n_ops here rather than time, since this easily scales time by the
-            // approximate size of the transmitted operations. TODO: This might be a faulty
-            // assumption given we're probably sending inserted content? Hm!
-            let remaining_count = target_count - self.cg.version.len();
-            let n_ops = self.operations.0.len();
-            let mut factor = f32::powf(n_ops as f32, 1f32 / (remaining_count) as f32);
-            factor = factor.max(1.1);
-
-            let mut t_inv = 1f32;
-            while t_inv < time_len as f32 {
-                dbg!(t_inv);
-                push_time(time_len - (t_inv as usize));
+        // Given factor, the approx number of versions we'll return is log_f(time_len).
+        // Solving for f gives f = time_len^(1/target).
+        let remaining_count = target_count - frontier.len();
+        if remaining_count > 0 {
+            let factor = f64::powf(time_len as f64, 1f64 / remaining_count as f64).max(1.1);
+
+            // The frontier is always time_len - 1 (or concurrent with it), so start one further back.
+            let mut t_inv = factor;
+            let mut last = time_len - 1;
+            while result.len() < target_count && (t_inv as usize) < time_len {
+                let v = time_len - 1 - (t_inv as usize);
+                // For small values of t_inv, the same version can come up multiple times.
+                if v < last {
+                    result.push(self.cg.agent_assignment.local_to_remote_version(v));
+                    last = v;
+                }
                 t_inv *= factor;
             }
         }
 
         result
     }
+
+    /// Find the latest version we have in common with a remote peer, given the remote peer's
+    /// stochastic version (from [`get_stochastic_version`](ListOpLog::get_stochastic_version)).
+    ///
+    /// Every version in the set which we know about is (along with all of its ancestors) known by
+    /// both peers. Any versions we don't know about are ignored.
+    pub fn intersect_with_stochastic_version(&self, remote_versions: &[RemoteVersion]) -> Frontier {
+        let known: Vec<LV> = remote_versions.iter()
+            .filter_map(|rv| self.cg.agent_assignment.try_remote_to_local_version(*rv).ok())
+            .collect();
+        self.cg.graph.find_dominators(&known)
+    }
+
+    /// Encode all the changes the remote peer might be missing, given its stochastic version. This
+    /// may include some operations the remote peer already has. They are ignored when merged.
+    pub fn encode_from_stochastic_version(&self, opts: &EncodeOptions, remote_versions: &[RemoteVersion]) -> Vec<u8> {
+        let common = self.intersect_with_stochastic_version(remote_versions);
+        self.encode_from(opts, common.as_ref())
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::list::encoding::ENCODE_PATCH;
     use crate::list::ListOpLog;
 
     #[test]
     fn test_versions_since() {
         let mut oplog = ListOpLog::new();
         // Should be an empty set
-        assert_eq!(oplog.get_stochastic_version(10), &[]);
+        assert!(oplog.get_stochastic_version(10).is_empty());
 
         oplog.get_or_create_agent_id("seph");
         oplog.add_insert(0, 0, "a");
         oplog.add_insert(0, 0, "a");
         oplog.add_insert(0, 0, "a");
         oplog.add_insert(0, 0, "a");
-        dbg!(oplog.get_stochastic_version(10));
+        let versions: Vec<usize> = oplog.get_stochastic_version(10).iter()
+            .map(|rv| rv.1)
+            .collect();
+        assert_eq!(versions, &[3, 2, 1, 0]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn stochastic_version_is_bounded() {
+        let mut oplog = ListOpLog::new();
+        oplog.get_or_create_agent_id("seph");
+        for _ in 0..10000 {
+            oplog.add_insert(0, 0, "a");
+        }
+
+        let versions = oplog.get_stochastic_version(20);
+        assert!(versions.len() <= 20);
+        assert!(versions.len() > 10);
+        assert_eq!(versions[0].1, 9999);
+        // The versions go back in time.
+        assert!(versions.windows(2).all(|w| w[0].1 > w[1].1));
+    }
+
+    #[test]
+    fn sync_with_stochastic_version() {
+        let mut a = ListOpLog::new();
+        a.get_or_create_agent_id("seph");
+        for i in 0..1000 {
+            a.add_insert(0, i, "a");
+        }
+        let mut b = a.clone();
+        b.get_or_create_agent_id("mike");
+
+        // Both peers make changes after forking.
+        for i in 0..100 {
+            a.add_insert(0, i, "x");
+        }
+        for i in 0..10 {
+            b.add_insert(1, i, "y");
+        }
+
+        let a_versions = a.get_stochastic_version(20);
+        let common = b.intersect_with_stochastic_version(&a_versions);
+        assert!(b.cg.graph.frontier_contains_frontier(&[999], common.as_ref()));
+        // The common version should be reasonably close to the fork point.
+        assert!(common.0[0] > 900);
+
+        let patch = b.encode_from_stochastic_version(&ENCODE_PATCH, &a_versions);
+        a.decode_and_add(&patch).unwrap();
+        let b_tip = a.cg.agent_assignment.remote_to_local_version(b.remote_frontier()[0]);
+        assert!(a.cg.graph.frontier_contains_frontier(a.local_frontier_ref(), &[b_tip]));
+
+        let patch = a.encode_from_stochastic_version(&ENCODE_PATCH, &b.get_stochastic_version(20));
+        b.decode_and_add(&patch).unwrap();
+        assert_eq!(a.checkout_tip().content(), b.checkout_tip().content());
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::{BTreeMap, BTreeSet};
+use rle::HasLength;
+use crate::causalgraph::CausalGraph;
+use crate::causalgraph::graph::Graph;
+use crate::dtrange::DTRange;
+use crate::frontier::Frontier;
+use crate::list::op_metrics::ListOpMetrics;
+use crate::list::operation::{ListOpKind, TextOperation};
+use crate::listmerge::merge::TransformedResultRaw;
+use crate::{CreateValue, LV};
+use crate::rle::KVPair;
+use crate::textinfo::TextInfo;
+
+/// A list CRDT contains an ordered sequence of values. Lists are merged using the same algorithm
+/// as text documents, but each inserted item stores a value instead of a character.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ListInfo {
+    /// The list's operations and frontier. Inserts don't store any content here. (So the ctx is
+    /// always empty.)
+    pub(crate) ops: TextInfo,
+
+    /// The value of every item which has ever been inserted into the list. Each item is named by
+    /// the LV of the operation which inserted it.
+    pub(crate) values: BTreeMap<LV, CreateValue>,
+
+    /// Cached set of items containing CRDTs which have been deleted in the current version.
+    pub(crate) removed_items: BTreeSet<LV>,
+}
+
+impl ListInfo {
+    fn push_values(&mut self, op: &TextOperation, v_range: DTRange, values: Vec<CreateValue>) {
+        debug_assert_eq!(v_range.len(), op.len());
+        if op.kind == ListOpKind::Ins {
+            assert_eq!(values.len(), v_range.len());
+            self.values.extend((v_range.start..v_range.end).zip(values));
+        } else {
+            assert!(values.is_empty());
+        }
+    }
+
+    pub(crate) fn remote_push_op_unknown_parents(&mut self, op: TextOperation, v_range: DTRange, values: Vec<CreateValue>, graph: &Graph) {
+        self.push_values(&op, v_range, values);
+        self.ops.remote_push_op_unknown_parents(op, v_range, graph);
+    }
+
+    pub(crate) fn local_push_op(&mut self, op: TextOperation, v_range: DTRange, values: Vec<CreateValue>) {
+        self.push_values(&op, v_range, values);
+        self.ops.local_push_op(op, v_range);
+    }
+
+    /// Iterate through all the items which haven't been deleted, which contain CRDTs.
+    pub(crate) fn iter_live_crdts(&self) -> impl Iterator<Item = (LV, &CreateValue)> + '_ {
+        self.values.iter()
+            .filter(|(v, val)| val.crdt_kind().is_some() && !self.removed_items.contains(v))
+            .map(|(v, val)| (*v, val))
+    }
+
+    pub(crate) fn contains_crdts(&self) -> bool {
+        self.values.values().any(|val| val.crdt_kind().is_some())
+    }
+
+    #[inline]
+    fn apply_op_to<T, F: FnMut(LV) -> T>(lv: LV, op: &ListOpMetrics, dest: &mut Vec<T>, make_item: &mut F) {
+        let pos = op.loc.span.start;
+        match op.kind {
+            ListOpKind::Ins => {
+                let items = lv..lv + op.len();
+                if op.loc.fwd {
+                    dest.splice(pos..pos, items.map(make_item));
+                } else {
+                    // The items were inserted in reverse order.
+                    dest.splice(pos..pos, items.rev().map(make_item));
+                }
+            }
+            ListOpKind::Del => {
+                dest.drain(pos..op.loc.span.end);
+            }
+        }
+    }
+
+    /// Add everything in merge_frontier into the list of items. Each inserted item is created by
+    /// calling make_item with the item's LV.
+    pub(crate) fn merge_into<T, F: FnMut(LV) -> T>(&self, into: &mut Vec<T>, cg: &CausalGraph, from: &[LV], merge_frontier: &[LV], mut make_item: F) -> Frontier {
+        self.ops.with_xf_iter(cg, from, merge_frontier, |iter, final_frontier| {
+            for xf in iter {
+                match xf {
+                    TransformedResultRaw::Apply { xf_pos, op: KVPair(lv, mut op) } => {
+                        op.transpose_to(xf_pos);
+                        Self::apply_op_to(lv, &op, into, &mut make_item);
+                    }
+
+                    TransformedResultRaw::FF(range) => {
+                        for KVPair(lv, op) in self.ops.ops.iter_range_ctx(range, &self.ops.ctx) {
+                            Self::apply_op_to(lv, &op, into, &mut make_item);
+                        }
+                    }
+
+                    TransformedResultRaw::DeleteAlreadyHappened(_) => {} // Discard.
+                }
+            }
+
+            final_frontier
+        })
+    }
+
+    /// Get the LVs of the items in the list at the specified version.
+    pub(crate) fn items_at(&self, cg: &CausalGraph, frontier: &[LV]) -> Vec<LV> {
+        let mut result = vec![];
+        self.merge_into(&mut result, cg, &[], frontier, |lv| lv);
+        result
+    }
+}
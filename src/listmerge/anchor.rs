@@ -0,0 +1,159 @@
+//! Stable anchors (eg, for cursors and comment ranges) which survive concurrent edits.
+//!
+//! A position in a document is only meaningful at one version. An [`Anchor`] instead names a
+//! character by its remote ID, and says which side of that character the anchor sits on. The anchor
+//! can then be resolved back into a position at any version - including versions where the
+//! character has been deleted, or hasn't been inserted yet.
+
+use rle::HasLength;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use crate::LV;
+use crate::causalgraph::agent_assignment::remote_ids::{RemoteVersion, RemoteVersionOwned};
+use crate::list::{ListBranch, ListOpLog};
+use crate::listmerge::M2Tracker;
+use crate::listmerge::yjsspan::INSERTED;
+
+/// Which side of its target character an anchor sits on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum AnchorBias {
+    /// The anchor sits immediately before its target. Content inserted at the anchor goes before
+    /// the anchor. Without a target, the anchor is at the end of the document.
+    Before,
+    /// The anchor sits immediately after its target. Content inserted at the anchor goes after
+    /// the anchor. Without a target, the anchor is at the start of the document.
+    After,
+}
+
+/// A position in a list document which stays attached to the same character as the document is
+/// edited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Anchor {
+    /// The ID of the character the anchor is attached to.
+    pub target: Option<RemoteVersionOwned>,
+    pub bias: AnchorBias,
+}
+
+impl M2Tracker {
+    /// The position of the named item, counting only the items which are currently visible. If
+    /// the item isn't visible, this is the position it would have if it were reinserted.
+    fn visible_pos_of(&self, lv: LV) -> Option<(usize, bool)> {
+        let mut pos = 0;
+        for item in self.range_tree.iter() {
+            let visible = item.current_state == INSERTED && !item.is_underwater();
+            if item.id.contains(lv) {
+                return Some(if visible { (pos + lv - item.id.start, true) } else { (pos, false) });
+            }
+            if visible { pos += item.len(); }
+        }
+        None
+    }
+}
+
+impl ListOpLog {
+    /// Make an anchor at a position in the document at the specified version.
+    ///
+    /// Panics if the position is past the end of the document.
+    pub fn anchor_at(&self, version: &[LV], pos: usize, bias: AnchorBias) -> Anchor {
+        let target_pos = match bias {
+            AnchorBias::Before => Some(pos),
+            AnchorBias::After => pos.checked_sub(1),
+        };
+
+        let mut target = None;
+        let mut len = 0;
+        for ids in self.visible_ids_at(version) {
+            if let Some(p) = target_pos {
+                if target.is_none() && p < len + ids.len() {
+                    target = Some(ids.start + p - len);
+                }
+            }
+            len += ids.len();
+        }
+        assert!(pos <= len, "Anchor position past the end of the document");
+
+        Anchor {
+            target: target.map(|lv| self.cg.agent_assignment.local_to_remote_version(lv).into()),
+            bias,
+        }
+    }
+
+    /// Find the position of an anchor in the document at the specified version. Returns None if
+    /// the anchor's target isn't an inserted character in this oplog.
+    ///
+    /// This replays the whole history of the oplog, so it's not fast.
+    pub fn resolve_anchor(&self, anchor: &Anchor, version: &[LV]) -> Option<usize> {
+        let Some(target) = anchor.target.as_ref() else {
+            return Some(match anchor.bias {
+                AnchorBias::Before => self.visible_ids_at(version).iter().map(|r| r.len()).sum(),
+                AnchorBias::After => 0,
+            });
+        };
+
+        let lv = self.cg.agent_assignment.try_remote_to_local_version(RemoteVersion::from(target)).ok()?;
+        let tracker = self.tracker_at(self.cg.version.as_ref(), version);
+        let (pos, visible) = tracker.visible_pos_of(lv)?;
+        Some(if visible && anchor.bias == AnchorBias::After { pos + 1 } else { pos })
+    }
+}
+
+impl ListBranch {
+    /// Make an anchor at a position in the branch. See [`ListOpLog::anchor_at`].
+    pub fn anchor_at(&self, oplog: &ListOpLog, pos: usize, bias: AnchorBias) -> Anchor {
+        oplog.anchor_at(self.local_frontier_ref(), pos, bias)
+    }
+
+    /// Find the position of an anchor in the branch. See [`ListOpLog::resolve_anchor`].
+    pub fn resolve_anchor(&self, oplog: &ListOpLog, anchor: &Anchor) -> Option<usize> {
+        oplog.resolve_anchor(anchor, self.local_frontier_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::list::ListOpLog;
+    use super::*;
+
+    #[test]
+    fn anchors_survive_concurrent_edits() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "hello world");
+        let v1 = oplog.local_frontier();
+
+        // Anchors on either side of the space.
+        let before = oplog.anchor_at(v1.as_ref(), 5, AnchorBias::Before);
+        let after = oplog.anchor_at(v1.as_ref(), 5, AnchorBias::After);
+        assert_eq!(before.target, Some(("seph", 5).into()));
+        assert_eq!(after.target, Some(("seph", 4).into()));
+        let start = oplog.anchor_at(v1.as_ref(), 0, AnchorBias::After);
+        let end = oplog.anchor_at(v1.as_ref(), 11, AnchorBias::Before);
+        assert_eq!(start.target, None);
+        assert_eq!(end.target, None);
+
+        // Concurrently, mike inserts at the anchor and seph deletes "o w".
+        oplog.add_insert_at(mike, v1.as_ref(), 5, "!!");
+        oplog.add_delete_at(seph, v1.as_ref(), 4..7);
+        let branch = oplog.checkout_tip();
+        assert_eq!(branch.content().to_string(), "hell!!orld");
+
+        assert_eq!(branch.resolve_anchor(&oplog, &before), Some(6));
+        assert_eq!(branch.resolve_anchor(&oplog, &after), Some(4));
+        assert_eq!(branch.resolve_anchor(&oplog, &start), Some(0));
+        assert_eq!(branch.resolve_anchor(&oplog, &end), Some(10));
+
+        // Anchors still resolve at the original version, and before their target was inserted.
+        assert_eq!(oplog.resolve_anchor(&before, v1.as_ref()), Some(5));
+        assert_eq!(oplog.resolve_anchor(&after, v1.as_ref()), Some(5));
+        let bang = branch.anchor_at(&oplog, 5, AnchorBias::Before);
+        assert_eq!(bang.target, Some(("mike", 1).into()));
+        assert_eq!(oplog.resolve_anchor(&bang, v1.as_ref()), Some(5));
+        assert_eq!(oplog.resolve_anchor(&bang, &[]), Some(0));
+
+        let unknown = Anchor { target: Some(("fred", 0).into()), bias: AnchorBias::Before };
+        assert_eq!(oplog.resolve_anchor(&unknown, v1.as_ref()), None);
+    }
+}
@@ -0,0 +1,124 @@
+//! Attribution ("blame") for list documents.
+//!
+//! The merge tracker already knows the identity (the LV of the insert) of every item in the
+//! document. To find out who inserted each character at some version, we replay the history up to
+//! that version into a fresh tracker and read off the items which are visible.
+
+use rle::{AppendRle, HasLength};
+use crate::{DTRange, Frontier, LV};
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersionSpan;
+use crate::list::{ListBranch, ListOpLog};
+use crate::listmerge::M2Tracker;
+use crate::listmerge::yjsspan::INSERTED;
+
+impl M2Tracker {
+    /// The IDs of all the items which are currently visible, in document order.
+    pub(super) fn visible_ids(&self) -> Vec<DTRange> {
+        let mut result: Vec<DTRange> = vec![];
+        for item in self.range_tree.iter() {
+            if item.current_state == INSERTED && !item.is_underwater() {
+                result.push_rle(item.id);
+            }
+        }
+        result
+    }
+}
+
+impl ListOpLog {
+    /// Make a tracker containing every item inserted in the history of `walk_to`, with each item's
+    /// state set as of `version`.
+    pub(super) fn tracker_at(&self, walk_to: &[LV], version: &[LV]) -> M2Tracker {
+        let mut tracker = M2Tracker::new();
+        let (_, rev_spans) = self.cg.graph.diff_rev(&[], walk_to);
+        let frontier = tracker.walk(&self.cg.graph, &self.cg.agent_assignment, &self.operation_ctx,
+                                    &self.operations, Frontier::root(), &rev_spans, None);
+
+        // The walk leaves the tracker at the last version it visited. Move it to the requested
+        // version.
+        let (retreat, advance_rev) = self.cg.graph.diff_rev(frontier.as_ref(), version);
+        for range in retreat {
+            tracker.retreat_by_range(range);
+        }
+        for range in advance_rev.into_iter().rev() {
+            tracker.advance_by_range(range);
+        }
+        tracker
+    }
+
+    /// Get the local versions of the inserts which created each item in the document at the
+    /// specified version, in document order.
+    pub(super) fn visible_ids_at(&self, version: &[LV]) -> Vec<DTRange> {
+        self.tracker_at(version, version).visible_ids()
+    }
+
+    /// Find out who inserted each character in the document at the specified version. Returns a
+    /// run-length encoded list of (document range, remote ID of the inserted characters), in
+    /// document order. Document ranges are measured in unicode characters.
+    pub fn blame_at(&self, version: &[LV]) -> Vec<(DTRange, RemoteVersionSpan<'_>)> {
+        let mut result: Vec<(DTRange, RemoteVersionSpan)> = vec![];
+        let mut pos = 0;
+        for ids in self.visible_ids_at(version) {
+            for span in self.cg.agent_assignment.iter_remote_mappings_range(ids) {
+                let len = span.len();
+                match result.last_mut() {
+                    // Items with consecutive local versions aren't always adjacent in the document,
+                    // and vice versa. Merge runs by remote ID instead.
+                    Some((range, last)) if last.0 == span.0 && last.1.end == span.1.start => {
+                        range.end += len;
+                        last.1.end = span.1.end;
+                    }
+                    _ => result.push(((pos..pos + len).into(), span)),
+                }
+                pos += len;
+            }
+        }
+        result
+    }
+
+    /// Find out who inserted each character in the document at the current version. See
+    /// [`blame_at`](ListOpLog::blame_at).
+    pub fn blame(&self) -> Vec<(DTRange, RemoteVersionSpan<'_>)> {
+        self.blame_at(self.cg.version.as_ref())
+    }
+}
+
+impl ListBranch {
+    /// Find out who inserted each character in the branch. The oplog must contain all the
+    /// operations the branch has merged.
+    pub fn blame<'a>(&self, oplog: &'a ListOpLog) -> Vec<(DTRange, RemoteVersionSpan<'a>)> {
+        oplog.blame_at(self.local_frontier_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::causalgraph::agent_assignment::remote_ids::RemoteVersionSpan;
+    use rle::HasLength;
+    use crate::list::ListOpLog;
+
+    #[test]
+    fn blame_concurrent_edits() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert_at(seph, &[], 0, "hello");
+        oplog.add_insert_at(mike, &[], 0, "world");
+        oplog.add_delete_at(seph, &[4], 1..3);
+        oplog.add_insert_at(mike, &[4, 9], 5, " ");
+
+        assert_eq!(oplog.checkout_tip().content().to_string(), "world hlo");
+        assert_eq!(oplog.blame(), vec![
+            ((0..6).into(), RemoteVersionSpan("mike", (0..6).into())),
+            ((6..7).into(), RemoteVersionSpan("seph", (0..1).into())),
+            ((7..9).into(), RemoteVersionSpan("seph", (3..5).into())),
+        ]);
+
+        // At an earlier version, deleted items are still visible.
+        let blame = oplog.blame_at(&[4]);
+        assert_eq!(blame, vec![((0..5).into(), RemoteVersionSpan("seph", (0..5).into()))]);
+        assert!(oplog.blame_at(&[]).is_empty());
+
+        let branch = oplog.checkout(&[4, 9]);
+        assert_eq!(branch.blame(&oplog).iter().map(|(r, _)| r.len()).sum::<usize>(), branch.len());
+    }
+}
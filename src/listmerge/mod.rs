@@ -19,6 +19,8 @@ pub(crate) mod yjsspan;
 pub(crate) mod merge;
 pub(crate) mod markers;
 mod advance_retreat;
+mod blame;
+pub(crate) mod anchor;
 // pub(crate) mod txn_trace;
 #[cfg(test)]
 pub mod fuzzer;
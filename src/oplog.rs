@@ -1,5 +1,5 @@
 use std::collections::{BTreeMap, BTreeSet};
-use smallvec::smallvec;
+use smallvec::{smallvec, SmallVec};
 use std::cmp::Ordering;
 use jumprope::JumpRopeBuf;
 use smartstring::alias::String as SmartString;
@@ -7,18 +7,25 @@ use smartstring::alias::String as SmartString;
 #[cfg(feature = "serde")]
 use serde::{Serialize, Serializer};
 
-use rle::{HasLength, SplitableSpanCtx};
-use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
-use crate::{AgentId, CRDTKind, CreateValue, DTRange, DTValue, OpLog, LV, LVKey, RegisterInfo, RegisterValue, ROOT_CRDT_ID, SerializedOps, ValPair};
+use rle::{HasLength, SplitableSpan, SplitableSpanCtx};
+use crate::causalgraph::agent_assignment::remote_ids::{RemoteVersion, VersionConversionError};
+use crate::{AgentId, CollectionInfo, CounterInfo, CRDTKind, CreateValue, DTRange, DTValue, Frontier, OpLog, LV, LVKey, MergeOptions, Primitive, RegisterInfo, RegisterValue, ROOT_CRDT_ID, SerializedOps, ValPair};
 use crate::encoding::bufparser::BufParser;
 use crate::encoding::cg_entry::{read_cg_entry_into_cg, write_cg_entry_iter};
 use crate::encoding::map::{ReadMap, WriteMap};
 use crate::encoding::parseerror::ParseError;
 use crate::branch::btree_range_for_crdt;
+use crate::causalgraph::graph::Graph;
 use crate::frontier::{is_sorted_iter_uniq, is_sorted_slice};
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
-use crate::list::operation::TextOperation;
+use crate::list::operation::{ListOpKind, TextOperation};
+use crate::path::{expect_kind, PathError, PathSegment};
+use crate::policy::{check_policy, PolicyOp};
+#[cfg(feature = "version_hashes")]
+use crate::causalgraph::signatures::retain_known_signatures;
 use crate::rle::{KVPair, RleSpanHelpers};
+use crate::textinfo::TextInfo;
+use crate::unicount::count_chars;
 
 #[cfg(feature = "serde")]
 impl Serialize for OpLog {
@@ -30,9 +37,143 @@ impl Serialize for OpLog {
 pub(super) fn create_to_snapshot(v: LV, create: &CreateValue) -> RegisterValue {
     match create {
         CreateValue::Primitive(p) => RegisterValue::Primitive(p.clone()),
-        CreateValue::NewCRDT(kind) => RegisterValue::OwnedCRDT(*kind, v)
+        CreateValue::NewCRDT(kind) => RegisterValue::OwnedCRDT(*kind, v),
+        CreateValue::Deleted => panic!("Deleted values have no snapshot"),
     }
 }
+/// Can CRDTs of this kind contain other (child) CRDTs?
+pub(crate) fn can_contain_crdts(kind: CRDTKind) -> bool {
+    matches!(kind, CRDTKind::Map | CRDTKind::Register | CRDTKind::Collection | CRDTKind::List)
+}
+
+/// Check a text or list operation received from a remote peer is well formed. Text inserts must
+/// have content, and list operations never have content.
+fn check_op_metrics(op: &ListOpMetrics, ctx: &ListOperationCtx, is_text: bool) -> Result<(), ParseError> {
+    if op.loc.span.start >= op.loc.span.end { return Err(ParseError::InvalidLength); }
+
+    match op.content_pos {
+        None => {
+            if is_text && op.kind == ListOpKind::Ins { return Err(ParseError::InvalidContent); }
+        }
+        Some(pos) => {
+            if !is_text { return Err(ParseError::InvalidContent); }
+            let bytes = ctx.switch(op.kind).get(pos.start..pos.end)
+                .ok_or(ParseError::InvalidLength)?;
+            let content = std::str::from_utf8(bytes).map_err(|_| ParseError::InvalidUTF8)?;
+            if count_chars(content) != op.len() { return Err(ParseError::InvalidLength); }
+        }
+    }
+    Ok(())
+}
+
+impl CreateValue {
+    pub(crate) fn crdt_kind(&self) -> Option<CRDTKind> {
+        match self {
+            CreateValue::Primitive(_) | CreateValue::Deleted => None,
+            CreateValue::NewCRDT(kind) => Some(*kind),
+        }
+    }
+
+    pub(crate) fn is_deleted(&self) -> bool {
+        matches!(self, CreateValue::Deleted)
+    }
+}
+
+/// The (version, CRDT kind) of the values replaced by a register operation.
+type SupersededValues = SmallVec<(LV, Option<CRDTKind>), 2>;
+
+impl RegisterInfo {
+    /// Add a new local operation to the register. The new operation supersedes all existing
+    /// values. Returns the (version, CRDT kind) of the superseded values.
+    fn push_local(&mut self, v: LV, value: CreateValue) -> SupersededValues {
+        let superseded = self.supremum.iter().map(|idx| {
+            let (lv, val) = &self.ops[*idx];
+            (*lv, val.crdt_kind())
+        }).collect();
+
+        self.supremum = smallvec![self.ops.len()];
+        self.ops.push((v, value));
+        superseded
+    }
+
+    /// Add a remote operation to the register. Any values which are concurrent with the new
+    /// operation are kept. Returns the (version, CRDT kind) of the superseded values, or None if
+    /// the operation is already known. If the operation is invalid, the register is unchanged.
+    fn push_remote(&mut self, graph: &Graph, v: LV, value: CreateValue) -> Result<Option<SupersededValues>, ParseError> {
+        if self.ops.binary_search_by_key(&v, |e| e.0).is_ok() {
+            return Ok(None);
+        }
+
+        // The added operation must have a higher local version than the last version.
+        if self.ops.last().is_some_and(|last_op| last_op.0 > v) {
+            return Err(ParseError::InvalidContent);
+        }
+
+        // The normal case is that the new operation replaces the old value. A faster implementation
+        // would special case that and fall back to the more complex version if need be.
+        let mut new_sup: SmallVec<usize, 2> = smallvec![];
+        let mut superseded = smallvec![];
+
+        for s_idx in &self.supremum {
+            let (old_lv, old_val) = &self.ops[*s_idx];
+            match graph.version_cmp(*old_lv, v) {
+                None => {
+                    // Versions are concurrent. Leave the old entry in index.
+                    new_sup.push(*s_idx);
+                }
+                Some(Ordering::Less) => {
+                    // The most common case. The new version dominates the old version. Remove the
+                    // old (version, value) pair.
+                    superseded.push((*old_lv, old_val.crdt_kind()));
+                }
+                Some(_) => {
+                    // Either the versions are equal, or the newly inserted version is earlier than
+                    // the existing version. Either way, this is an invalid operation.
+                    return Err(ParseError::InvalidContent);
+                }
+            }
+        }
+
+        // The new index is always the largest, so pushing it last keeps the supremum sorted.
+        new_sup.push(self.ops.len());
+        self.ops.push((v, value));
+        self.supremum = new_sup;
+        Ok(Some(superseded))
+    }
+
+    /// Iterate through all the operations on this register within the specified ranges.
+    pub(crate) fn iter_ops_in_ranges<'a>(&'a self, ranges: &'a [DTRange]) -> impl Iterator<Item = &'a ValPair> + 'a {
+        ranges.iter().flat_map(|r| {
+            // Find all the unknown ops.
+            // TODO: Add a flag to trim this to only the most recent ops.
+            let start_idx = self.ops
+                .binary_search_by_key(&r.start, |e| e.0)
+                .unwrap_or_else(|idx| idx);
+
+            self.ops[start_idx..].iter().take_while(|pair| pair.0 < r.end)
+        })
+    }
+}
+
+impl CollectionInfo {
+    /// Iterate through all the items in the collection which haven't been removed.
+    pub(crate) fn iter_live(&self) -> impl Iterator<Item = (LV, &CreateValue)> + '_ {
+        self.inserts.iter()
+            .filter(|(v, _)| !self.removed_items.contains(*v))
+            .map(|(v, val)| (*v, val))
+    }
+}
+
+impl CounterInfo {
+    /// Get the value of the counter, ignoring any operations in the excluded ranges. The counter
+    /// wraps around on overflow so increments can be summed in any order.
+    pub(crate) fn value_excluding(&self, excluded: &[DTRange]) -> i64 {
+        self.increments.iter()
+            .filter(|(v, _)| !excluded.iter().any(|r| r.contains(**v)))
+            .fold(0i64, |sum, (_, amount)| sum.wrapping_add(*amount))
+    }
+}
+
 // Hmmmm... If this is equivalent, could I just use ValPair() instead of RegisterValue?
 impl From<&ValPair> for RegisterValue {
     fn from((version, value): &ValPair) -> Self {
@@ -57,7 +198,7 @@ impl OpLog {
             // Record the type of all the items
             for op in &info.ops {
                 match op.1 {
-                    CreateValue::Primitive(_) => {}
+                    CreateValue::Primitive(_) | CreateValue::Deleted => {}
                     CreateValue::NewCRDT(crdt_type) => {
                         item_type.insert(op.0, crdt_type);
                     }
@@ -89,6 +230,150 @@ impl OpLog {
         }
         assert_eq!(self.map_index.len(), expected_idx_count);
 
+        // Register operations
+        let mut expected_idx_count = 0;
+        for (crdt, info) in self.registers.iter() {
+            assert_ne!(*crdt, ROOT_CRDT_ID);
+            assert!(is_sorted_slice::<true, _>(&info.supremum));
+            assert!(is_sorted_iter_uniq(info.ops.iter().map(|(v, _)| *v)));
+
+            for (v, val) in info.ops.iter() {
+                if let CreateValue::NewCRDT(crdt_type) = val {
+                    item_type.insert(*v, *crdt_type);
+                }
+                assert!(*v < cg_len);
+            }
+
+            for idx in info.supremum.iter() {
+                let v = info.ops[*idx].0;
+                assert_eq!(self.register_index.get(&v), Some(crdt));
+                expected_idx_count += 1;
+            }
+
+            if deep && !info.ops.is_empty() {
+                let all_versions = info.ops.iter().map(|(v, _)| *v).collect::<Vec<_>>();
+                let dominators = self.cg.graph.find_dominators(&all_versions);
+                let sup_versions = info.supremum.iter().map(|idx| info.ops[*idx].0).collect::<Vec<_>>();
+                assert_eq!(dominators.as_ref(), &sup_versions);
+            }
+        }
+        assert_eq!(self.register_index.len(), expected_idx_count);
+
+        // Collection operations
+        let mut expected_idx_count = 0;
+        for (crdt, info) in self.collections.iter() {
+            assert_ne!(*crdt, ROOT_CRDT_ID);
+
+            for (v, val) in info.inserts.iter() {
+                if let CreateValue::NewCRDT(crdt_type) = val {
+                    item_type.insert(*v, *crdt_type);
+                }
+                assert!(*v < cg_len);
+                assert_eq!(self.collection_index.get(v), Some(crdt));
+                expected_idx_count += 1;
+            }
+
+            for (v, target) in info.removes.iter() {
+                assert!(*v < cg_len);
+                // Items can only be removed after they've been inserted.
+                assert!(info.inserts.contains_key(target));
+                assert!(self.cg.graph.version_cmp(*target, *v) == Some(Ordering::Less));
+                assert_eq!(self.collection_index.get(v), Some(crdt));
+                expected_idx_count += 1;
+            }
+
+            let removed_items: BTreeSet<LV> = info.removes.values().copied().collect();
+            assert_eq!(removed_items, info.removed_items);
+        }
+        assert_eq!(self.collection_index.len(), expected_idx_count);
+
+
+        // List operations
+        let mut expected_idx_count = 0;
+        for (crdt, info) in self.lists.iter() {
+            assert_ne!(*crdt, ROOT_CRDT_ID);
+            assert!(info.ops.ctx.ins_content.is_empty());
+            assert!(info.ops.ctx.del_content.is_empty());
+            assert!(is_sorted_iter_uniq(info.ops.ops.iter().map(|KVPair(v, _)| *v)));
+
+            // Every inserted item has a value.
+            let mut num_inserted = 0;
+            for KVPair(v, op) in info.ops.ops.iter() {
+                if op.kind == ListOpKind::Ins {
+                    num_inserted += op.len();
+                    assert!(info.values.contains_key(v));
+                    assert!(info.values.contains_key(&(*v + op.len() - 1)));
+                }
+            }
+            assert_eq!(num_inserted, info.values.len());
+            assert_eq!(num_inserted, info.ops.num_inserted);
+
+            for (v, val) in info.values.iter() {
+                assert!(!val.is_deleted());
+                if let CreateValue::NewCRDT(crdt_type) = val {
+                    item_type.insert(*v, *crdt_type);
+                }
+            }
+
+            for v in info.ops.frontier.as_ref() {
+                assert!(*v < cg_len);
+                assert_eq!(self.list_index.get(v), Some(crdt));
+                expected_idx_count += 1;
+            }
+
+            if deep {
+                // Operations are merged in the RLE list even when they're concurrent, so we need
+                // to look at every version.
+                let all_versions = info.ops.ops.iter()
+                    .flat_map(|op| op.span().iter())
+                    .collect::<Vec<_>>();
+                let dominators = self.cg.graph.find_dominators(&all_versions);
+                assert_eq!(dominators, info.ops.frontier);
+
+                // Items are never un-deleted. So the removed items are all the CRDT items which
+                // aren't in the list now.
+                let live_items: BTreeSet<LV> = info.items_at(&self.cg, self.cg.version.as_ref())
+                    .into_iter().collect();
+                if let Some(len) = info.ops.len { assert_eq!(live_items.len(), len); }
+                let removed_items: BTreeSet<LV> = info.values.iter()
+                    .filter(|(v, val)| val.crdt_kind().is_some() && !live_items.contains(v))
+                    .map(|(v, _)| *v)
+                    .collect();
+                assert_eq!(removed_items, info.removed_items);
+            }
+        }
+        assert_eq!(self.list_index.len(), expected_idx_count);
+
+        // Counter operations
+        let mut expected_idx_count = 0;
+        for (crdt, info) in self.counters.iter() {
+            assert_ne!(*crdt, ROOT_CRDT_ID);
+
+            for v in info.increments.keys() {
+                assert!(*v < cg_len);
+                assert_eq!(self.counter_index.get(v), Some(crdt));
+                expected_idx_count += 1;
+            }
+        }
+        assert_eq!(self.counter_index.len(), expected_idx_count);
+
+        // Now all the item types are known, check each CRDT was created with the right kind.
+        for crdt in self.maps.iter() {
+            assert_eq!(*item_type.get(crdt).unwrap(), CRDTKind::Map);
+        }
+        for (crdt, _) in self.collections.iter() {
+            assert_eq!(*item_type.get(crdt).unwrap(), CRDTKind::Collection);
+        }
+        for (crdt, _) in self.registers.iter() {
+            assert_eq!(*item_type.get(crdt).unwrap(), CRDTKind::Register);
+        }
+        for (crdt, _) in self.lists.iter() {
+            assert_eq!(*item_type.get(crdt).unwrap(), CRDTKind::List);
+        }
+        for (crdt, _) in self.counters.iter() {
+            assert_eq!(*item_type.get(crdt).unwrap(), CRDTKind::Counter);
+        }
+
         // And now text operations
         let mut expected_idx_count = 0;
         for (crdt, info) in self.texts.iter() {
@@ -97,6 +382,11 @@ impl OpLog {
 
             // Check the operations are sorted
             assert!(is_sorted_iter_uniq(info.ops.iter().map(|KVPair(v, _)| *v)));
+            let num_inserted: usize = info.ops.iter()
+                .filter(|KVPair(_, op)| op.kind == ListOpKind::Ins)
+                .map(|KVPair(_, op)| op.len())
+                .sum();
+            assert_eq!(num_inserted, info.num_inserted);
 
             for v in info.frontier.as_ref() {
                 assert!(*v < cg_len);
@@ -108,9 +398,17 @@ impl OpLog {
 
             if deep {
                 // Also check the version is correct.
-                let all_versions = info.ops.iter().map(|op| op.last()).collect::<Vec<_>>();
+                // Operations are merged in the RLE list even when they're concurrent, so we need
+                // to look at every version.
+                let all_versions = info.ops.iter()
+                    .flat_map(|op| op.span().iter())
+                    .collect::<Vec<_>>();
                 let dominators = self.cg.graph.find_dominators(&all_versions);
                 assert_eq!(dominators, info.frontier);
+
+                if let Some(len) = info.len {
+                    assert_eq!(self.checkout_text_at(*crdt, info.frontier.as_ref()).len_chars(), len);
+                }
             }
         }
         assert_eq!(self.text_index.len(), expected_idx_count);
@@ -118,36 +416,73 @@ impl OpLog {
         if deep {
             // Find all the CRDTs which have been created then later overwritten or deleted.
             let mut deleted_crdts = BTreeSet::new();
-            let mut directly_overwritten_maps = vec![];
-            for reg_info in self.map_keys.values() {
+            let mut directly_overwritten_containers = vec![];
+            for reg_info in self.map_keys.values().chain(self.registers.values()) {
                 for (idx, (lv, val)) in reg_info.ops.iter().enumerate() {
                     if !reg_info.supremum.contains(&idx) {
                         if let CreateValue::NewCRDT(kind) = val {
                             deleted_crdts.insert(*lv);
 
-                            if *kind == CRDTKind::Map {
-                                directly_overwritten_maps.push(*lv);
+                            if can_contain_crdts(*kind) {
+                                directly_overwritten_containers.push(*lv);
                             }
                         }
                     }
                 }
             }
+            for info in self.collections.values() {
+                for item in info.removed_items.iter() {
+                    if let CreateValue::NewCRDT(kind) = &info.inserts[item] {
+                        deleted_crdts.insert(*item);
+
+                        if can_contain_crdts(*kind) {
+                            directly_overwritten_containers.push(*item);
+                        }
+                    }
+                }
+            }
+            for info in self.lists.values() {
+                for item in info.removed_items.iter() {
+                    deleted_crdts.insert(*item);
+
+                    if can_contain_crdts(info.values[item].crdt_kind().unwrap()) {
+                        directly_overwritten_containers.push(*item);
+                    }
+                }
+            }
 
             // Now find everything that has been removed indirectly
-            let mut queue = directly_overwritten_maps;
+            let mut queue = directly_overwritten_containers;
             while let Some(crdt_id) = queue.pop() {
-                for (_, info) in btree_range_for_crdt(&self.map_keys, crdt_id) {
-                    for s in info.supremum.iter() {
-                        let (lv, create_val) = &info.ops[*s];
-                        if let CreateValue::NewCRDT(kind) = create_val {
-                            assert!(deleted_crdts.insert(*lv));
+                let mut visit = |lv: LV, create_val: &CreateValue| {
+                    if let CreateValue::NewCRDT(kind) = create_val {
+                        assert!(deleted_crdts.insert(lv));
 
-                            if *kind == CRDTKind::Map {
-                                // Go through this CRDT's children.
-                                queue.push(*lv);
-                            }
+                        if can_contain_crdts(*kind) {
+                            // Go through this CRDT's children.
+                            queue.push(lv);
                         }
                     }
+                };
+
+                let registers = btree_range_for_crdt(&self.map_keys, crdt_id)
+                    .map(|(_, info)| info)
+                    .chain(self.registers.get(&crdt_id));
+                for info in registers {
+                    for s in info.supremum.iter() {
+                        let (lv, create_val) = &info.ops[*s];
+                        visit(*lv, create_val);
+                    }
+                }
+                if let Some(info) = self.collections.get(&crdt_id) {
+                    for (lv, create_val) in info.iter_live() {
+                        visit(lv, create_val);
+                    }
+                }
+                if let Some(info) = self.lists.get(&crdt_id) {
+                    for (lv, create_val) in info.iter_live_crdts() {
+                        visit(lv, create_val);
+                    }
                 }
             }
 
@@ -198,9 +533,21 @@ impl OpLog {
     // The way I'm using this below, it should be idempotent.
     fn create_child_crdt(&mut self, v: LV, kind: CRDTKind) {
         match kind {
-            CRDTKind::Map => {}
-            CRDTKind::Register => {}
-            CRDTKind::Collection => {}
+            CRDTKind::Map => {
+                self.maps.insert(v);
+            }
+            CRDTKind::Register => {
+                self.registers.entry(v).or_default();
+            }
+            CRDTKind::Collection => {
+                self.collections.entry(v).or_default();
+            }
+            CRDTKind::List => {
+                self.lists.entry(v).or_default();
+            }
+            CRDTKind::Counter => {
+                self.counters.entry(v).or_default();
+            }
             CRDTKind::Text => {
                 self.texts.entry(v).or_default();
             }
@@ -208,189 +555,464 @@ impl OpLog {
     }
 
     fn recursive_mark_deleted_inner(&mut self, mut to_delete: Vec<LV>) {
+        fn visit(deleted_crdts: &mut BTreeSet<LVKey>, to_delete: &mut Vec<LV>, lv: LV, create_val: &CreateValue) {
+            if let CreateValue::NewCRDT(kind) = create_val {
+                assert!(deleted_crdts.insert(lv));
+
+                if can_contain_crdts(*kind) {
+                    // Go through this CRDT's children.
+                    to_delete.push(lv);
+                }
+            }
+        }
+
         while let Some(crdt) = to_delete.pop() {
             for (_, info) in btree_range_for_crdt(&self.map_keys, crdt) {
                 for s in info.supremum.iter() {
                     let (lv, create_val) = &info.ops[*s];
-                    if let CreateValue::NewCRDT(kind) = create_val {
-                        assert!(self.deleted_crdts.insert(*lv));
+                    visit(&mut self.deleted_crdts, &mut to_delete, *lv, create_val);
+                }
+            }
+            if let Some(info) = self.registers.get(&crdt) {
+                for s in info.supremum.iter() {
+                    let (lv, create_val) = &info.ops[*s];
+                    visit(&mut self.deleted_crdts, &mut to_delete, *lv, create_val);
+                }
+            }
+            if let Some(info) = self.collections.get(&crdt) {
+                for (lv, create_val) in info.iter_live() {
+                    visit(&mut self.deleted_crdts, &mut to_delete, lv, create_val);
+                }
+            }
+            if let Some(info) = self.lists.get(&crdt) {
+                for (lv, create_val) in info.iter_live_crdts() {
+                    visit(&mut self.deleted_crdts, &mut to_delete, lv, create_val);
+                }
+            }
+        }
+    }
 
-                        if *kind == CRDTKind::Map {
-                            // Go through this CRDT's children.
-                            to_delete.push(*lv);
-                        }
+    /// When a value is added to a container which has already been deleted, any CRDT it creates is
+    /// deleted too.
+    fn mark_new_crdt_deleted(&mut self, v: LV, kind: CRDTKind) {
+        assert!(self.deleted_crdts.insert(v));
+        if can_contain_crdts(kind) {
+            self.recursive_mark_deleted_inner(vec![v]);
+        }
+    }
+
+    /// Update the set of deleted CRDTs after a register (owned by the named container) was set to a
+    /// new value, superseding some old values.
+    fn register_values_replaced(&mut self, container: LVKey, superseded: &[(LV, Option<CRDTKind>)], v: LV, new_kind: Option<CRDTKind>) {
+        if self.deleted_crdts.contains(&container) {
+            // If the container was deleted, the old values will already be marked as deleted. But
+            // the new value is deleted too.
+            if let Some(kind) = new_kind {
+                self.mark_new_crdt_deleted(v, kind);
+            }
+        } else {
+            let mut to_delete = vec![];
+            for (lv, kind) in superseded {
+                if let Some(kind) = kind {
+                    assert!(self.deleted_crdts.insert(*lv));
+                    if can_contain_crdts(*kind) {
+                        to_delete.push(*lv);
                     }
                 }
             }
+            self.recursive_mark_deleted_inner(to_delete);
         }
     }
 
     pub fn local_map_set(&mut self, agent: AgentId, crdt: LVKey, key: &str, value: CreateValue) -> LV {
         let v = self.cg.assign_local_op(agent, 1).start;
-        if let CreateValue::NewCRDT(kind) = value {
+        let new_kind = value.crdt_kind();
+        if let Some(kind) = new_kind {
             self.create_child_crdt(v, kind);
         }
 
         let entry = self.map_keys.entry((crdt, key.into()))
             .or_default();
+        let superseded = entry.push_local(v, value);
 
-        let new_idx = entry.ops.len();
-
-        let mut to_delete = vec![];
         // Remove the old supremum from the index
-        for idx in &entry.supremum {
-            let (lv, val) = &entry.ops[*idx];
-            if let CreateValue::NewCRDT(kind) = val {
-                assert!(self.deleted_crdts.insert(*lv));
-                if *kind == CRDTKind::Map {
-                    to_delete.push(*lv);
-                }
-            }
-
+        for (lv, _) in superseded.iter() {
             self.map_index.remove(lv);
         }
-
-        entry.supremum = smallvec![new_idx];
-        entry.ops.push((v, value));
-
         self.map_index.insert(v, (crdt, key.into()));
 
-        // dbg!((crdt, key, &to_delete));
-        self.recursive_mark_deleted_inner(to_delete);
+        self.register_values_replaced(crdt, &superseded, v, new_kind);
         v
     }
 
-    // This function requires that the lv has already been added to the causal graph.
-    pub fn remote_map_set(&mut self, crdt: LVKey, v: LV, key: &str, value: CreateValue) {
-        if let CreateValue::NewCRDT(kind) = value {
-            self.create_child_crdt(v, kind);
-        }
+    /// Delete a key from a map CRDT. Deletes are add-wins: if the key is concurrently set to some
+    /// value, the key will keep that value. Any CRDTs stored in the key are deleted.
+    pub fn local_map_delete(&mut self, agent: AgentId, crdt: LVKey, key: &str) -> LV {
+        self.local_map_set(agent, crdt, key, CreateValue::Deleted)
+    }
 
+    // This function requires that the lv has already been added to the causal graph.
+    /// Returns an error if the operation is invalid given the key's existing operations. (Eg, if
+    /// the key already has a newer operation.)
+    pub fn remote_map_set(&mut self, crdt: LVKey, v: LV, key: &str, value: CreateValue) -> Result<(), ParseError> {
+        let new_kind = value.crdt_kind();
         let entry = self.map_keys.entry((crdt, key.into()))
             .or_default();
 
         // If the entry already contains the new op, ignore it.
-        if entry.ops.binary_search_by_key(&v, |e| e.0).is_ok() {
-            return;
-        }
+        let Some(superseded) = entry.push_remote(&self.cg.graph, v, value)? else { return Ok(()); };
 
-        if let Some(last_op) = entry.ops.last() {
-            // The added operation must have a higher local version than the last version.
-            assert!(last_op.0 < v);
+        if let Some(kind) = new_kind {
+            self.create_child_crdt(v, kind);
         }
 
-        let new_idx = entry.ops.len();
-        entry.ops.push((v, value));
-
-        // The normal case is that the new operation replaces the old value. A faster implementation
-        // would special case that and fall back to the more complex version if need be.
-        let mut new_sup = smallvec![new_idx];
+        // Concurrent values are left in the index.
+        for (lv, _) in superseded.iter() {
+            self.map_index.remove(lv);
+        }
         self.map_index.insert(v, (crdt, key.into()));
-        let mut to_delete = vec![];
 
-        for s_idx in &entry.supremum {
-            let (old_lv, old_val) = &entry.ops[*s_idx];
-            match self.cg.graph.version_cmp(*old_lv, v) {
-                None => {
-                    // Versions are concurrent. Leave the old entry in index.
-                    new_sup.push(*s_idx);
-                }
-                Some(Ordering::Less) => {
-                    // The most common case. The new version dominates the old version. Remove the
-                    // old (version, value) pair.
-                    if let CreateValue::NewCRDT(kind) = old_val {
-                        assert!(self.deleted_crdts.insert(*old_lv));
-                        if *kind == CRDTKind::Map {
-                            to_delete.push(*old_lv);
-                        }
-                    }
-                    self.map_index.remove(old_lv);
-                }
-                Some(_) => {
-                    // Either the versions are equal, or the newly inserted version is earlier than
-                    // the existing version. Either way, this is an invalid operation.
-                    panic!("Invalid state");
-                }
-            }
-        }
-        entry.supremum = new_sup;
-        self.recursive_mark_deleted_inner(to_delete);
+        self.register_values_replaced(crdt, &superseded, v, new_kind);
+        Ok(())
     }
 
-    pub fn local_text_op(&mut self, agent: AgentId, crdt: LVKey, op: TextOperation) -> DTRange {
-        let v_range = self.cg.assign_local_op(agent, op.len());
-
-        let entry = self.texts.get_mut(&crdt).unwrap();
-
-        // Remove it from the index
-        for v in entry.frontier.as_ref() {
-            let old_index_item = self.text_index.remove(v);
-            assert!(old_index_item.is_some());
+    /// Set the value of a register CRDT.
+    pub fn local_register_set(&mut self, agent: AgentId, crdt: LVKey, value: CreateValue) -> LV {
+        let v = self.cg.assign_local_op(agent, 1).start;
+        let new_kind = value.crdt_kind();
+        if let Some(kind) = new_kind {
+            self.create_child_crdt(v, kind);
         }
 
-        entry.local_push_op(op, v_range);
+        let entry = self.registers.entry(crdt).or_default();
+        let superseded = entry.push_local(v, value);
 
-        // And add it back to the index.
-        self.text_index.insert(v_range.last(), crdt);
+        for (lv, _) in superseded.iter() {
+            self.register_index.remove(lv);
+        }
+        self.register_index.insert(v, crdt);
 
-        v_range
+        self.register_values_replaced(crdt, &superseded, v, new_kind);
+        v
     }
 
-    pub fn remote_text_op(&mut self, crdt: LVKey, v_range: DTRange, op: TextOperation) {
-        // TODO: This doesn't look like it handles discarding existing data...
-        debug_assert_eq!(v_range.len(), op.len());
+    // This function requires that the lv has already been added to the causal graph.
+    /// Returns an error if the operation is invalid given the register's existing operations.
+    pub fn remote_register_set(&mut self, crdt: LVKey, v: LV, value: CreateValue) -> Result<(), ParseError> {
+        let new_kind = value.crdt_kind();
+        let entry = self.registers.entry(crdt).or_default();
+        let Some(superseded) = entry.push_remote(&self.cg.graph, v, value)? else { return Ok(()); };
 
-        // What should we do here if the item is missing?
-        let entry = self.texts.get_mut(&crdt).unwrap();
+        if let Some(kind) = new_kind {
+            self.create_child_crdt(v, kind);
+        }
 
-        // Remove it from the index
-        for v in entry.frontier.as_ref() {
-            let old_index_item = self.text_index.remove(v);
-            assert!(old_index_item.is_some());
+        for (lv, _) in superseded.iter() {
+            self.register_index.remove(lv);
         }
+        self.register_index.insert(v, crdt);
 
-        entry.remote_push_op_unknown_parents(op, v_range, &self.cg.graph);
+        self.register_values_replaced(crdt, &superseded, v, new_kind);
+        Ok(())
+    }
 
-        // And add it back to the index.
-        for v in entry.frontier.as_ref() {
-            self.text_index.insert(*v, crdt);
-        }
+    /// Insert a new item into a collection CRDT. The item is named by the returned LV.
+    pub fn local_collection_insert(&mut self, agent: AgentId, crdt: LVKey, value: CreateValue) -> LV {
+        let v = self.cg.assign_local_op(agent, 1).start;
+        self.remote_collection_insert(crdt, v, value).expect("Invalid collection insert");
+        v
     }
 
-    // Its quite annoying, but RegisterInfo objects store the supremum as an array of indexes. This
-    // returns the active index and (if necessary) the set of indexes of conflicting values.
-    pub(crate) fn tie_break_mv<'a>(&self, reg: &'a RegisterInfo) -> (usize, Option<impl Iterator<Item = usize> + 'a>) {
-        match reg.supremum.len() {
-            0 => panic!("Internal consistency violation"),
-            1 => (reg.supremum[0], None),
-            _ => {
-                let active_idx = reg.supremum.iter()
-                    .map(|s| (*s, self.cg.agent_assignment.local_to_agent_version(reg.ops[*s].0)))
-                    .max_by(|(_, a), (_, b)| {
-                        self.cg.agent_assignment.tie_break_agent_versions(*a, *b)
-                    })
-                    .unwrap().0;
+    /// Remove the named item from a collection CRDT. The item must exist in the collection.
+    pub fn local_collection_remove(&mut self, agent: AgentId, crdt: LVKey, item: LV) -> LV {
+        let v = self.cg.assign_local_op(agent, 1).start;
+        self.remote_collection_remove(crdt, v, item).expect("Invalid collection remove");
+        v
+    }
 
-                (
-                    active_idx,
-                    Some(reg.supremum.iter().copied().filter(move |i| *i != active_idx))
-                )
+    // This function requires that the lv has already been added to the causal graph.
+    /// Returns an error if the named CRDT isn't a collection, or if the value is deleted.
+    pub fn remote_collection_insert(&mut self, crdt: LVKey, v: LV, value: CreateValue) -> Result<(), ParseError> {
+        // Collections cannot contain deleted values.
+        if value.is_deleted() { return Err(ParseError::InvalidContent); }
+        let info = self.collections.get_mut(&crdt).ok_or(ParseError::InvalidContent)?;
+        // If the collection already contains the new op, ignore it.
+        if info.inserts.contains_key(&v) { return Ok(()); }
+
+        let new_kind = value.crdt_kind();
+        info.inserts.insert(v, value);
+        self.collection_index.insert(v, crdt);
+
+        if let Some(kind) = new_kind {
+            self.create_child_crdt(v, kind);
+            if self.deleted_crdts.contains(&crdt) {
+                self.mark_new_crdt_deleted(v, kind);
             }
         }
+        Ok(())
     }
 
-    fn resolve_mv(&self, reg: &RegisterInfo) -> RegisterValue {
-        let (active_idx, _) = self.tie_break_mv(reg);
+    // This function requires that the lv has already been added to the causal graph.
+    /// Returns an error if the named CRDT isn't a collection, or if the removed item wasn't inserted
+    /// into the collection before the remove.
+    pub fn remote_collection_remove(&mut self, crdt: LVKey, v: LV, item: LV) -> Result<(), ParseError> {
+        let info = self.collections.get_mut(&crdt).ok_or(ParseError::InvalidContent)?;
+        if info.removes.contains_key(&v) { return Ok(()); }
+
+        let value = info.inserts.get(&item).ok_or(ParseError::InvalidContent)?;
+        // Removes must happen after the item was inserted.
+        if self.cg.graph.version_cmp(item, v) != Some(Ordering::Less) {
+            return Err(ParseError::InvalidContent);
+        }
+
+        info.removes.insert(v, item);
+        self.collection_index.insert(v, crdt);
+
+        // Items can be removed multiple times (concurrently). Only the first removal matters.
+        if info.removed_items.insert(item) && !self.deleted_crdts.contains(&crdt) {
+            if let CreateValue::NewCRDT(kind) = *value {
+                assert!(self.deleted_crdts.insert(item));
+                if can_contain_crdts(kind) {
+                    self.recursive_mark_deleted_inner(vec![item]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the specified amount to a counter CRDT. Concurrent increments are all kept.
+    pub fn local_counter_increment(&mut self, agent: AgentId, crdt: LVKey, amount: i64) -> LV {
+        let v = self.cg.assign_local_op(agent, 1).start;
+        self.remote_counter_increment(crdt, v, amount).expect("Invalid counter increment");
+        v
+    }
+
+    /// Subtract the specified amount from a counter CRDT.
+    pub fn local_counter_decrement(&mut self, agent: AgentId, crdt: LVKey, amount: i64) -> LV {
+        self.local_counter_increment(agent, crdt, amount.wrapping_neg())
+    }
+
+    // This function requires that the lv has already been added to the causal graph.
+    /// Returns an error if the named CRDT isn't a counter.
+    pub fn remote_counter_increment(&mut self, crdt: LVKey, v: LV, amount: i64) -> Result<(), ParseError> {
+        let info = self.counters.get_mut(&crdt).ok_or(ParseError::InvalidContent)?;
+        if info.increments.contains_key(&v) { return Ok(()); }
+
+        info.increments.insert(v, amount);
+        self.counter_index.insert(v, crdt);
+        Ok(())
+    }
+
+    /// Insert a new value into a list CRDT at the specified position.
+    pub fn local_list_insert(&mut self, agent: AgentId, crdt: LVKey, pos: usize, value: CreateValue) -> LV {
+        let v = self.cg.assign_local_op(agent, 1).start;
+        let op = TextOperation { loc: (pos..pos + 1).into(), kind: ListOpKind::Ins, content: None };
+        self.list_op_inner(crdt, (v..v + 1).into(), op, vec![value], true);
+        v
+    }
+
+    /// Delete the items in the specified range from a list CRDT. Any CRDTs in the deleted items
+    /// are deleted too.
+    pub fn local_list_delete(&mut self, agent: AgentId, crdt: LVKey, range: std::ops::Range<usize>) -> DTRange {
+        let v_range = self.cg.assign_local_op(agent, range.len());
+        let op = TextOperation { loc: range.into(), kind: ListOpKind::Del, content: None };
+        self.list_op_inner(crdt, v_range, op, vec![], true);
+        v_range
+    }
+
+    /// Apply a remote operation to a list CRDT. Inserts must have one value for each inserted item
+    /// (in version order). The content of the operation is ignored.
+    ///
+    /// This function requires that the operation has already been added to the causal graph.
+    /// Returns an error if the named CRDT isn't a list, or if the values don't match the operation.
+    pub fn remote_list_op(&mut self, crdt: LVKey, v_range: DTRange, op: TextOperation, values: Vec<CreateValue>) -> Result<(), ParseError> {
+        // Lists cannot contain deleted values.
+        let expected_values = if op.kind == ListOpKind::Ins { op.len() } else { 0 };
+        if !self.lists.contains_key(&crdt) || v_range.len() != op.len()
+            || values.len() != expected_values || values.iter().any(|val| val.is_deleted()) {
+            return Err(ParseError::InvalidContent);
+        }
+
+        self.list_op_inner(crdt, v_range, op, values, false);
+        Ok(())
+    }
+
+    fn list_op_inner(&mut self, crdt: LVKey, v_range: DTRange, mut op: TextOperation, values: Vec<CreateValue>, local: bool) {
+        debug_assert_eq!(v_range.len(), op.len());
+        assert!(values.iter().all(|val| !val.is_deleted()), "Lists cannot contain deleted values");
+        op.content = None;
+
+        for (v, value) in (v_range.start..v_range.end).zip(values.iter()) {
+            if let CreateValue::NewCRDT(kind) = value {
+                self.create_child_crdt(v, *kind);
+                if self.deleted_crdts.contains(&crdt) {
+                    self.mark_new_crdt_deleted(v, *kind);
+                }
+            }
+        }
+
+        let info = self.lists.get_mut(&crdt).unwrap();
+        let del_op = if op.kind == ListOpKind::Del { Some(op.clone()) } else { None };
+
+        // Remove it from the index
+        for v in info.ops.frontier.as_ref() {
+            let old_index_item = self.list_index.remove(v);
+            assert!(old_index_item.is_some());
+        }
+
+        if local {
+            info.local_push_op(op, v_range, values);
+        } else {
+            info.remote_push_op_unknown_parents(op, v_range, values, &self.cg.graph);
+        }
+
+        // And add it back to the index.
+        for v in info.ops.frontier.as_ref() {
+            self.list_index.insert(*v, crdt);
+        }
+
+        // Figure out which items this operation removes. Deletes name positions in the list at the
+        // operation's parents, so we need to replay the list up to that version. This is slow, so
+        // we only bother if the list contains CRDTs which might need to be marked as deleted.
+        //
+        // A remote operation can span several entries in the causal graph, and each piece's
+        // positions are relative to that piece's parents (which can include the earlier pieces).
+        let info = &self.lists[&crdt];
+        let mut removed: Vec<LV> = vec![];
+        if let Some(mut piece) = del_op.filter(|_| info.contains_crdts()) {
+            let mut start = v_range.start;
+            loop {
+                let entry_end = self.cg.graph.entries.find_packed(start).span.end;
+                let rest = if entry_end < v_range.end {
+                    Some(piece.truncate(entry_end - start))
+                } else { None };
+
+                let parents = self.cg.graph.parents_at_version(start);
+                let items = info.items_at(&self.cg, parents.as_ref());
+                // Remote operations are only checked against the number of items ever inserted,
+                // so the range might be past the end of the list here.
+                removed.extend(items.get(piece.loc.span.start..piece.loc.span.end).unwrap_or_default().iter()
+                    .copied()
+                    .filter(|item| info.values[item].crdt_kind().is_some()));
+
+                match rest {
+                    Some(rest) => {
+                        start = entry_end;
+                        piece = rest;
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        // Items can be deleted multiple times (concurrently). Only the first deletion matters.
+        let info = self.lists.get_mut(&crdt).unwrap();
+        let mut to_delete = vec![];
+        for item in removed {
+            if info.removed_items.insert(item) && !self.deleted_crdts.contains(&crdt) {
+                assert!(self.deleted_crdts.insert(item));
+                if can_contain_crdts(info.values[&item].crdt_kind().unwrap()) {
+                    to_delete.push(item);
+                }
+            }
+        }
+        self.recursive_mark_deleted_inner(to_delete);
+    }
+
+    pub fn local_text_op(&mut self, agent: AgentId, crdt: LVKey, op: TextOperation) -> DTRange {
+        let v_range = self.cg.assign_local_op(agent, op.len());
+
+        let entry = self.texts.get_mut(&crdt).unwrap();
+
+        // Remove it from the index
+        for v in entry.frontier.as_ref() {
+            let old_index_item = self.text_index.remove(v);
+            assert!(old_index_item.is_some());
+        }
+
+        entry.local_push_op(op, v_range);
+
+        // And add it back to the index.
+        self.text_index.insert(v_range.last(), crdt);
+
+        v_range
+    }
+
+    pub fn remote_text_op(&mut self, crdt: LVKey, v_range: DTRange, op: TextOperation) {
+        // TODO: This doesn't look like it handles discarding existing data...
+        debug_assert_eq!(v_range.len(), op.len());
+
+        // What should we do here if the item is missing?
+        let entry = self.texts.get_mut(&crdt).unwrap();
+
+        // Remove it from the index
+        for v in entry.frontier.as_ref() {
+            let old_index_item = self.text_index.remove(v);
+            assert!(old_index_item.is_some());
+        }
+
+        entry.remote_push_op_unknown_parents(op, v_range, &self.cg.graph);
+
+        // And add it back to the index.
+        for v in entry.frontier.as_ref() {
+            self.text_index.insert(*v, crdt);
+        }
+    }
+
+    // Its quite annoying, but RegisterInfo objects store the supremum as an array of indexes. This
+    // returns the active index and (if necessary) the set of indexes of conflicting values.
+    //
+    // Deletes are add-wins: they're ignored unless every value in the supremum is a delete, in
+    // which case the register has no value and this returns None.
+    pub(crate) fn tie_break_mv<'a>(&self, reg: &'a RegisterInfo) -> Option<(usize, Option<impl Iterator<Item = usize> + 'a>)> {
+        self.tie_break_idxes(&reg.ops, &reg.supremum)
+    }
+
+    /// Same as tie_break_mv, but this works with an arbitrary supremum (set of indexes into ops).
+    pub(crate) fn tie_break_idxes<'a>(&self, ops: &'a [ValPair], supremum: &'a [usize]) -> Option<(usize, Option<impl Iterator<Item = usize> + 'a>)> {
+        let is_live = move |i: &usize| !ops[*i].1.is_deleted();
+
+        match supremum.iter().filter(|i| is_live(i)).count() {
+            0 => None,
+            1 => Some((supremum.iter().copied().find(is_live).unwrap(), None)),
+            _ => {
+                let active_idx = supremum.iter()
+                    .filter(|i| is_live(i))
+                    .map(|s| (*s, self.cg.agent_assignment.local_to_agent_version(ops[*s].0)))
+                    .max_by(|(_, a), (_, b)| {
+                        self.cg.agent_assignment.tie_break_agent_versions(*a, *b)
+                    })
+                    .unwrap().0;
+
+                Some((
+                    active_idx,
+                    Some(supremum.iter().copied().filter(move |i| *i != active_idx && is_live(i)))
+                ))
+            }
+        }
+    }
+
+    /// Returns the current value of the register, or None if it has been deleted.
+    fn resolve_mv(&self, reg: &RegisterInfo) -> Option<RegisterValue> {
+        let (active_idx, _) = self.tie_break_mv(reg)?;
 
         let (v, value) = &reg.ops[active_idx];
-        create_to_snapshot(*v, value)
+        Some(create_to_snapshot(*v, value))
+    }
+
+    /// Checkout the current contents of the named text CRDT.
+    pub fn checkout_text(&self, crdt: LVKey) -> Result<JumpRopeBuf, PathError> {
+        if self.deleted_crdts.contains(&crdt) { return Err(PathError::Deleted); }
+        if !self.texts.contains_key(&crdt) { return Err(PathError::MissingKey); }
+        Ok(self.checkout_text_at(crdt, self.cg.version.as_ref()))
     }
 
-    pub fn checkout_text(&self, crdt: LVKey) -> JumpRopeBuf {
+    /// Checkout the contents of the named text CRDT at some (possibly historical) version.
+    pub fn checkout_text_at(&self, crdt: LVKey, frontier: &[LV]) -> JumpRopeBuf {
         let info = self.texts.get(&crdt).unwrap();
 
         let mut result = JumpRopeBuf::new();
-        info.merge_into(&mut result, &self.cg, &[], self.cg.version.as_ref());
+        info.merge_into(&mut result, &self.cg, &[], frontier);
         result
     }
 
@@ -404,61 +1026,123 @@ impl OpLog {
             self.map_keys.range((crdt, empty_str.clone())..(crdt + 1, empty_str))
         };
 
-        iter.map(|((_, key), info)| {
-            let inner = match self.resolve_mv(info) {
-                RegisterValue::Primitive(p) => DTValue::Primitive(p),
-                RegisterValue::OwnedCRDT(kind, child_crdt) => {
-                    match kind {
-                        CRDTKind::Map => DTValue::Map(self.checkout_map(child_crdt)),
-                        CRDTKind::Text => DTValue::Text(self.checkout_text(child_crdt).to_string()),
-                        _ => unimplemented!(),
-                        // CRDTKind::Register => {}
-                        // CRDTKind::Collection => {}
-                        // CRDTKind::Text => {}
+        // Deleted keys are omitted.
+        iter.filter_map(|((_, key), info)| {
+            let inner = self.checkout_value(self.resolve_mv(info)?);
+            Some((key.clone(), Box::new(inner)))
+        }).collect()
+    }
+
+    pub fn checkout_list(&self, crdt: LVKey) -> Vec<DTValue> {
+        self.checkout_list_at(crdt, self.cg.version.as_ref()).into_iter()
+            .map(|value| self.checkout_value(value))
+            .collect()
+    }
+
+    /// Get the items in the named list CRDT at some (possibly historical) version.
+    pub(crate) fn checkout_list_at(&self, crdt: LVKey, frontier: &[LV]) -> Vec<RegisterValue> {
+        let info = self.lists.get(&crdt).unwrap();
+        let mut result = vec![];
+        info.merge_into(&mut result, &self.cg, &[], frontier, |lv| create_to_snapshot(lv, &info.values[&lv]));
+        result
+    }
+
+    pub fn checkout_collection(&self, crdt: LVKey) -> BTreeMap<LV, Box<DTValue>> {
+        let info = self.collections.get(&crdt).unwrap();
+        info.iter_live().map(|(v, value)| {
+            (v, Box::new(self.checkout_value(create_to_snapshot(v, value))))
+        }).collect()
+    }
+
+    /// Checkout the current value of a register CRDT, and any other values it has been concurrently
+    /// set to. A register which has never been set (or which has been deleted) contains nil.
+    pub fn checkout_register(&self, crdt: LVKey) -> (DTValue, Vec<DTValue>) {
+        let info = self.registers.get(&crdt).unwrap();
+        let Some((active_idx, other_idxes)) = self.tie_break_mv(info) else {
+            return (DTValue::Primitive(Primitive::Nil), vec![]);
+        };
+        let value = self.checkout_value((&info.ops[active_idx]).into());
+        let conflicts_with = other_idxes.map(|iter| {
+            iter.map(|idx| self.checkout_value((&info.ops[idx]).into())).collect()
+        }).unwrap_or_default();
+        (value, conflicts_with)
+    }
+
+    /// Get the current value of a counter CRDT.
+    pub fn checkout_counter(&self, crdt: LVKey) -> i64 {
+        self.counters.get(&crdt).unwrap().value_excluding(&[])
+    }
+
+    fn checkout_value(&self, value: RegisterValue) -> DTValue {
+        match value {
+            RegisterValue::Primitive(p) => DTValue::Primitive(p),
+            RegisterValue::OwnedCRDT(kind, child_crdt) => {
+                match kind {
+                    CRDTKind::Map => DTValue::Map(self.checkout_map(child_crdt)),
+                    CRDTKind::Register => {
+                        let (value, conflicts_with) = self.checkout_register(child_crdt);
+                        DTValue::Register { value: Box::new(value), conflicts_with }
                     }
+                    CRDTKind::Collection => DTValue::Collection(self.checkout_collection(child_crdt)),
+                    CRDTKind::List => DTValue::List(self.checkout_list(child_crdt)),
+                    CRDTKind::Counter => DTValue::Counter(self.checkout_counter(child_crdt)),
+                    CRDTKind::Text => DTValue::Text(self.checkout_text_at(child_crdt, self.cg.version.as_ref()).to_string()),
                 }
-            };
-            (key.clone(), Box::new(inner))
-        }).collect()
+            }
+        }
     }
 
     pub fn checkout(&self) -> BTreeMap<SmartString, Box<DTValue>> {
         self.checkout_map(ROOT_CRDT_ID)
     }
 
-    pub fn crdt_at_path(&self, path: &[&str]) -> (CRDTKind, LVKey) {
+    /// Find the CRDT at the specified path in the document. Map keys are looked up by name, list
+    /// items by position and collection items by ID.
+    ///
+    /// Returns [`PathError::Deleted`] if the path names a deleted map key, a removed collection
+    /// item or a deleted CRDT.
+    pub fn crdt_at_path(&self, path: &[PathSegment]) -> Result<(CRDTKind, LVKey), PathError> {
         let mut kind = CRDTKind::Map;
         let mut key = ROOT_CRDT_ID;
 
         for p in path {
-            match kind {
-                CRDTKind::Map => {
-                    let container = self.map_keys.get(&(key, (*p).into()))
-                        .unwrap();
-                    match self.resolve_mv(container) {
-                        RegisterValue::Primitive(_) => {
-                            panic!("Found primitive, not CRDT");
-                        }
-                        RegisterValue::OwnedCRDT(new_kind, new_key) => {
-                            kind = new_kind;
-                            key = new_key;
-                        }
-                    }
+            let value = match (kind, *p) {
+                (CRDTKind::Map, PathSegment::Key(k)) => {
+                    let container = self.map_keys.get(&(key, k.into()))
+                        .ok_or(PathError::MissingKey)?;
+                    // If the key has been deleted, resolve_mv returns None.
+                    self.resolve_mv(container).ok_or(PathError::Deleted)?
+                }
+                (CRDTKind::List, PathSegment::Index(idx)) => {
+                    let info = &self.lists[&key];
+                    let items = info.items_at(&self.cg, self.cg.version.as_ref());
+                    let item = *items.get(idx).ok_or(PathError::MissingKey)?;
+                    create_to_snapshot(item, &info.values[&item])
                 }
-                _ => {
-                    panic!("Invalid path in document");
+                (CRDTKind::Collection, PathSegment::Index(item)) => {
+                    let info = &self.collections[&key];
+                    let value = info.inserts.get(&item).ok_or(PathError::MissingKey)?;
+                    if info.removed_items.contains(&item) { return Err(PathError::Deleted); }
+                    create_to_snapshot(item, value)
+                }
+                _ => { return Err(PathError::InvalidSegment); }
+            };
+
+            match value {
+                RegisterValue::Primitive(_) => { return Err(PathError::NotCRDT); }
+                RegisterValue::OwnedCRDT(new_kind, new_key) => {
+                    if self.deleted_crdts.contains(&new_key) { return Err(PathError::Deleted); }
+                    kind = new_kind;
+                    key = new_key;
                 }
             }
         }
 
-        (kind, key)
+        Ok((kind, key))
     }
 
-    pub fn text_at_path(&self, path: &[&str]) -> LVKey {
-        let (kind, key) = self.crdt_at_path(path);
-        if kind != CRDTKind::Text {
-            panic!("Unexpected CRDT kind {:?}", kind);
-        } else { key }
+    pub fn text_at_path(&self, path: &[PathSegment]) -> Result<LVKey, PathError> {
+        expect_kind(self.crdt_at_path(path)?, CRDTKind::Text)
     }
 
     pub fn text_changes_since(&self, text: LVKey, since_frontier: &[LV]) -> Vec<(DTRange, Option<TextOperation>)> {
@@ -496,7 +1180,14 @@ impl OpLog {
         let mut cg_changes = Vec::new();
         let mut text_crdts_to_send = BTreeSet::new();
         let mut map_crdts_to_send = BTreeSet::new();
-        for range_rev in diff_rev.iter() {
+        let mut register_crdts_to_send = BTreeSet::new();
+        let mut list_crdts_to_send = BTreeSet::new();
+        let mut collection_inserts = Vec::new();
+        let mut collection_removes = Vec::new();
+        let mut counter_ops = Vec::new();
+        // The causal graph entries must be written in order, so parents are sent before their
+        // children.
+        for range_rev in diff_rev.iter().rev() {
             let iter = self.cg.iter_range(*range_rev);
             write_cg_entry_iter(&mut cg_changes, iter, &mut write_map, &self.cg);
 
@@ -508,6 +1199,34 @@ impl OpLog {
                 // dbg!(map_crdt, key);
                 map_crdts_to_send.insert((*map_crdt, key));
             }
+
+            for (_, register_crdt) in self.register_index.range(*range_rev) {
+                register_crdts_to_send.insert(*register_crdt);
+            }
+
+            for (_, list_crdt) in self.list_index.range(*range_rev) {
+                list_crdts_to_send.insert(*list_crdt);
+            }
+
+            // The collection index names every collection operation, so we can just send them.
+            for (v, collection_crdt) in self.collection_index.range(*range_rev) {
+                let crdt_name = self.crdt_name_to_remote(*collection_crdt);
+                let rv = self.cg.agent_assignment.local_to_remote_version(*v);
+                let info = &self.collections[collection_crdt];
+                if let Some(value) = info.inserts.get(v) {
+                    collection_inserts.push((crdt_name, rv, value.clone()));
+                } else {
+                    let target = self.cg.agent_assignment.local_to_remote_version(info.removes[v]);
+                    collection_removes.push((crdt_name, rv, target));
+                }
+            }
+
+            // Same for counters.
+            for (v, counter_crdt) in self.counter_index.range(*range_rev) {
+                let crdt_name = self.crdt_name_to_remote(*counter_crdt);
+                let rv = self.cg.agent_assignment.local_to_remote_version(*v);
+                counter_ops.push((crdt_name, rv, self.counters[counter_crdt].increments[v]));
+            }
         }
 
         // Serialize map operations
@@ -516,20 +1235,20 @@ impl OpLog {
             let crdt_name = self.crdt_name_to_remote(crdt);
             let entry = self.map_keys.get(&(crdt, key.clone()))
                 .unwrap();
-            for r in diff_rev.iter() {
-                // Find all the unknown ops.
-                // TODO: Add a flag to trim this to only the most recent ops.
-                let start_idx = entry.ops
-                    .binary_search_by_key(&r.start, |e| e.0)
-                    .unwrap_or_else(|idx| idx);
-
-                for pair in &entry.ops[start_idx..] {
-                    if pair.0 >= r.end { break; }
+            for pair in entry.iter_ops_in_ranges(&diff_rev) {
+                // dbg!(pair);
+                let rv = self.cg.agent_assignment.local_to_remote_version(pair.0);
+                map_ops.push((crdt_name, rv, key.as_str(), pair.1.clone()));
+            }
+        }
 
-                    // dbg!(pair);
-                    let rv = self.cg.agent_assignment.local_to_remote_version(pair.0);
-                    map_ops.push((crdt_name, rv, key.as_str(), pair.1.clone()));
-                }
+        // Serialize register operations
+        let mut register_ops = Vec::new();
+        for crdt in register_crdts_to_send {
+            let crdt_name = self.crdt_name_to_remote(crdt);
+            for pair in self.registers[&crdt].iter_ops_in_ranges(&diff_rev) {
+                let rv = self.cg.agent_assignment.local_to_remote_version(pair.0);
+                register_ops.push((crdt_name, rv, pair.1.clone()));
             }
         }
 
@@ -540,7 +1259,9 @@ impl OpLog {
             let crdt_name = self.crdt_name_to_remote(crdt);
             let info = &self.texts[&crdt];
             for r in diff_rev.iter() {
-                for KVPair(lv, op) in info.ops.iter_range_ctx(*r, &info.ctx) {
+                let ops = info.ops.iter_range_ctx(*r, &info.ctx)
+                    .flat_map(|KVPair(lv, op)| self.split_op_by_agent(lv, op, &info.ctx));
+                for (rv, op) in ops {
                     // dbg!(&op);
 
                     let op_out = ListOpMetrics {
@@ -551,26 +1272,213 @@ impl OpLog {
                             text_context.push_str(op.kind, content)
                         }),
                     };
-                    let rv = self.cg.agent_assignment.local_to_remote_version(lv);
                     text_ops.push((crdt_name, rv, op_out));
                 }
             }
         }
 
+        // Serialize list operations. The values are sent alongside each insert.
+        let mut list_ops = Vec::new();
+        for crdt in list_crdts_to_send {
+            let crdt_name = self.crdt_name_to_remote(crdt);
+            let info = &self.lists[&crdt];
+            for r in diff_rev.iter() {
+                for KVPair(lv, op) in info.ops.ops.iter_range_ctx(*r, &info.ops.ctx) {
+                    let mut lv = lv;
+                    for (rv, op) in self.split_op_by_agent(lv, op, &info.ops.ctx) {
+                        let values = if op.kind == ListOpKind::Ins {
+                            info.values.range(lv..lv + op.len()).map(|(_, val)| val.clone()).collect()
+                        } else { vec![] };
+                        lv += op.len();
+                        list_ops.push((crdt_name, rv, op, values));
+                    }
+                }
+            }
+        }
+
         SerializedOps {
             cg_changes,
             map_ops,
             text_ops,
             text_context,
+            register_ops,
+            collection_inserts,
+            collection_removes,
+            list_ops,
+            counter_ops,
+            version_hashes: vec![],
+            #[cfg(feature = "version_hashes")]
+            signatures: self.cg.signatures_in_ranges(&diff_rev).into_iter()
+                .map(|(name, seq_range, sig)| (name, seq_range, sig.to_vec()))
+                .collect(),
+            #[cfg(not(feature = "version_hashes"))]
+            signatures: vec![],
+            agent_metadata: self.cg.agent_metadata_in_ranges(&diff_rev).into_iter()
+                .map(|(name, metadata)| (name, metadata.clone()))
+                .collect(),
+            timestamps: self.cg.timestamps_in_ranges(&diff_rev),
+        }
+    }
+
+
+    /// Split a text or list operation at the boundaries between agent spans, so each piece can be
+    /// named by the remote version of its first item.
+    fn split_op_by_agent(&self, mut lv: LV, mut op: ListOpMetrics, ctx: &ListOperationCtx) -> SmallVec<(RemoteVersion<'_>, ListOpMetrics), 1> {
+        let mut result = smallvec![];
+        loop {
+            let span = self.cg.agent_assignment.local_to_remote_version_span((lv..lv + op.len()).into());
+            let rv = RemoteVersion(span.0, span.1.start);
+            if span.1.len() < op.len() {
+                let rest = op.truncate_ctx(span.1.len(), ctx);
+                result.push((rv, op));
+                lv += span.1.len();
+                op = rest;
+            } else {
+                result.push((rv, op));
+                return result;
+            }
+        }
+    }
+
+    /// Find the local versions of a text or list operation received from a remote peer. The
+    /// operation's items might not have contiguous local versions, so the operation is split into
+    /// pieces. Returns each piece which isn't already known, along with the piece's offset in the
+    /// original operation.
+    fn remote_op_pieces(&self, rv: RemoteVersion, mut op: ListOpMetrics, ctx: &ListOperationCtx, new_range: DTRange) -> Result<Vec<(usize, DTRange, ListOpMetrics)>, ParseError> {
+        let agent = self.cg.agent_assignment.get_agent_id(rv.0)
+            .ok_or(ParseError::InvalidRemoteID(VersionConversionError::UnknownAgent))?;
+        let client = &self.cg.agent_assignment.client_data[agent as usize];
+
+        let mut result = vec![];
+        let mut offset = 0;
+        loop {
+            let seq = rv.1.checked_add(offset).ok_or(ParseError::InvalidLength)?;
+            let seq_end = seq.checked_add(op.len()).ok_or(ParseError::InvalidLength)?;
+            let mut v_range = client.try_seq_to_lv_span((seq..seq_end).into())
+                .ok_or(ParseError::InvalidRemoteID(VersionConversionError::SeqInFuture))?;
+            let piece_len = v_range.len();
+            let rest = if piece_len < op.len() {
+                Some(op.truncate_ctx(piece_len, ctx))
+            } else { None };
+
+            if v_range.end > new_range.start {
+                let mut piece_offset = offset;
+                if v_range.start < new_range.start {
+                    // Trim the new operation.
+                    let trim = new_range.start - v_range.start;
+                    op.truncate_keeping_right_ctx(trim, ctx);
+                    v_range.start = new_range.start;
+                    piece_offset += trim;
+                }
+                result.push((piece_offset, v_range, op));
+            }
+
+            match rest {
+                Some(rest) => {
+                    op = rest;
+                    offset += piece_len;
+                }
+                None => return Ok(result),
+            }
         }
     }
 
+    /// Get the kind of the named CRDT, if it exists.
+    fn crdt_kind(&self, crdt: LVKey) -> Option<CRDTKind> {
+        if crdt == ROOT_CRDT_ID || self.maps.contains(&crdt) { Some(CRDTKind::Map) }
+        else if self.registers.contains_key(&crdt) { Some(CRDTKind::Register) }
+        else if self.collections.contains_key(&crdt) { Some(CRDTKind::Collection) }
+        else if self.lists.contains_key(&crdt) { Some(CRDTKind::List) }
+        else if self.counters.contains_key(&crdt) { Some(CRDTKind::Counter) }
+        else if self.texts.contains_key(&crdt) { Some(CRDTKind::Text) }
+        else { None }
+    }
+
+    /// Check that an operation at version v modifies a CRDT of the expected kind, and that the CRDT
+    /// was created before the operation happened. new_crdts contains the CRDTs created by
+    /// operations which are being merged but haven't been applied yet.
+    fn check_op_target(&self, crdt: LVKey, v: LV, expected: CRDTKind, new_crdts: &BTreeMap<LV, CRDTKind>) -> Result<(), ParseError> {
+        let kind = self.crdt_kind(crdt).or_else(|| new_crdts.get(&crdt).copied());
+        if kind != Some(expected) { return Err(ParseError::InvalidContent); }
+
+        if crdt != ROOT_CRDT_ID && self.cg.graph.version_cmp(crdt, v) != Some(Ordering::Less) {
+            return Err(ParseError::InvalidContent);
+        }
+        Ok(())
+    }
+
+    /// Check the positions named by new text and list operations against the document at each
+    /// operation's parents. Returns the resulting length of each modified CRDT.
+    fn check_seq_ops(&self, text_ops: &[(LVKey, DTRange, TextOperation)], list_ops: &[(LVKey, DTRange, TextOperation, Vec<CreateValue>)], new_crdts: &BTreeMap<LV, CRDTKind>) -> Result<Vec<(LVKey, usize)>, ParseError> {
+        let mut new_seq_ops: BTreeMap<LVKey, Vec<(DTRange, &TextOperation)>> = BTreeMap::new();
+        for (crdt, v_range, op) in text_ops.iter() {
+            self.check_op_target(*crdt, v_range.start, CRDTKind::Text, new_crdts)?;
+            new_seq_ops.entry(*crdt).or_default().push((*v_range, op));
+        }
+        for (crdt, v_range, op, _) in list_ops.iter() {
+            self.check_op_target(*crdt, v_range.start, CRDTKind::List, new_crdts)?;
+            new_seq_ops.entry(*crdt).or_default().push((*v_range, op));
+        }
+
+        let mut new_lens = vec![];
+        let empty_info = TextInfo::default();
+        for (crdt, mut ops) in new_seq_ops {
+            ops.sort_unstable_by_key(|(v_range, _)| v_range.start);
+            let info = self.texts.get(&crdt)
+                .or_else(|| self.lists.get(&crdt).map(|info| &info.ops))
+                .unwrap_or(&empty_info);
+            let len = info.check_remote_ops(&self.cg, &ops).ok_or(ParseError::InvalidContent)?;
+            new_lens.push((crdt, len));
+        }
+        Ok(new_lens)
+    }
 
+    fn remote_to_lv(&self, rv: RemoteVersion) -> Result<LV, ParseError> {
+        self.cg.agent_assignment.try_remote_to_local_version(rv)
+            .map_err(ParseError::InvalidRemoteID)
+    }
+
+    fn try_remote_to_crdt_name(&self, crdt_rv: RemoteVersion) -> Result<LVKey, ParseError> {
+        if crdt_rv.0 == "ROOT" { Ok(ROOT_CRDT_ID) }
+        else { self.remote_to_lv(crdt_rv) }
+    }
+
+    /// Merge operations from a remote peer into the oplog. Returns the range of new local versions.
+    ///
+    /// The incoming operations are fully checked before any of them are applied. If the data is
+    /// invalid, an error is returned and the oplog is left unchanged.
     pub fn merge_ops(&mut self, changes: SerializedOps) -> Result<DTRange, ParseError> {
+        self.merge_ops_opts(changes, MergeOptions::default())
+    }
+
+    /// Merge operations from a remote peer, with options. See [`merge_ops`](OpLog::merge_ops).
+    pub fn merge_ops_opts(&mut self, changes: SerializedOps, opts: MergeOptions) -> Result<DTRange, ParseError> {
+        let old_end = self.cg.len();
+        let old_version = self.cg.version.clone();
+        let old_num_agents = self.cg.num_agents();
+
+        let result = self.merge_ops_inner(changes, opts, &old_version, old_num_agents);
+        if !matches!(result, Ok(Some(_))) {
+            // Roll back any entries which were added to the causal graph.
+            self.cg.truncate(old_end, old_version, old_num_agents);
+        }
+        result.map(|range| range.unwrap_or((old_end..old_end).into()))
+    }
+
+    /// Returns None if the merge policy silently rejected all of the new operations.
+    fn merge_ops_inner(&mut self, changes: SerializedOps, opts: MergeOptions, old_version: &Frontier, old_num_agents: AgentId) -> Result<Option<DTRange>, ParseError> {
         let mut read_map = ReadMap::new();
 
         let old_end = self.cg.len();
 
+        // If the changes name version hashes (or need their signatures checked), we need the
+        // content of our own versions to compute hashes. This has to be read before any new entries
+        // are added to the causal graph.
+        #[cfg(feature = "version_hashes")]
+        let local_content = self.prepare_hash_check(&changes, opts.verifier.is_some());
+        #[cfg(not(feature = "version_hashes"))]
+        if opts.verifier.is_some() { return Err(ParseError::SignatureInvalid); }
+
         let mut buf = BufParser(&changes.cg_changes);
         while !buf.is_empty() {
             read_cg_entry_into_cg(&mut buf, true, &mut self.cg, &mut read_map)?;
@@ -579,38 +1487,289 @@ impl OpLog {
         let new_end = self.cg.len();
         let new_range: DTRange = (old_end..new_end).into();
 
+        // Check hashes and signatures before anything else. This rejects operations which reuse
+        // the IDs of operations we already have - even if none of the operations are new.
+        #[cfg(feature = "version_hashes")]
+        let (mut new_hashes, mut new_signatures) = self.check_hashes_and_signatures(&changes, new_range, local_content.as_ref(), opts.verifier)?;
+
         // The code above will discard any operations we already know about. The new range could be empty, could
         // contain all of the new changes, or have some subset of them. We need to respect that in the code below
         // and only append new changes.
-        if new_range.is_empty() { return Ok(new_range); }
+        if new_range.is_empty() {
+            self.cg.merge_agent_metadata(changes.agent_metadata, changes.timestamps);
+            return Ok(Some(new_range));
+        }
 
+        // First convert all the new operations to local versions. Nothing is modified until all
+        // the operations have been checked.
+        let mut used_versions: Vec<DTRange> = vec![];
+        let mut new_crdts: BTreeMap<LV, CRDTKind> = BTreeMap::new();
+        let mut note_value = |v: LV, val: &CreateValue| {
+            if let CreateValue::NewCRDT(kind) = val {
+                new_crdts.insert(v, *kind);
+            }
+        };
+
+        let mut map_ops = vec![];
         for (crdt_r_name, rv, key, val) in changes.map_ops {
-            let lv = self.cg.agent_assignment.remote_to_local_version(rv);
+            let lv = self.remote_to_lv(rv)?;
             if new_range.contains(lv) {
-                let crdt_id = self.remote_to_crdt_name(crdt_r_name);
-                // dbg!(crdt_id, lv, key, val);
-                self.remote_map_set(crdt_id, lv, key, val);
+                let crdt = self.try_remote_to_crdt_name(crdt_r_name)?;
+                note_value(lv, &val);
+                used_versions.push((lv..lv + 1).into());
+                map_ops.push((crdt, lv, key, val));
             }
         }
 
-        for (crdt_r_name, rv, mut op_metrics) in changes.text_ops {
-            let lv = self.cg.agent_assignment.remote_to_local_version(rv);
-            let mut v_range: DTRange = (lv..lv + op_metrics.len()).into();
+        let mut register_ops = vec![];
+        for (crdt_r_name, rv, val) in changes.register_ops {
+            let lv = self.remote_to_lv(rv)?;
+            if new_range.contains(lv) {
+                let crdt = self.try_remote_to_crdt_name(crdt_r_name)?;
+                note_value(lv, &val);
+                used_versions.push((lv..lv + 1).into());
+                register_ops.push((crdt, lv, val));
+            }
+        }
 
-            if v_range.end <= new_range.start { continue; }
-            else if v_range.start < new_range.start {
-                // Trim the new operation.
-                op_metrics.truncate_keeping_right_ctx(new_range.start - v_range.start, &changes.text_context);
-                v_range.start = new_range.start;
+        let mut collection_inserts = vec![];
+        for (crdt_r_name, rv, val) in changes.collection_inserts {
+            let lv = self.remote_to_lv(rv)?;
+            if new_range.contains(lv) {
+                if val.is_deleted() { return Err(ParseError::InvalidContent); }
+                let crdt = self.try_remote_to_crdt_name(crdt_r_name)?;
+                note_value(lv, &val);
+                used_versions.push((lv..lv + 1).into());
+                collection_inserts.push((crdt, lv, val));
             }
+        }
 
-            let crdt_id = self.remote_to_crdt_name(crdt_r_name);
+        let mut collection_removes = vec![];
+        for (crdt_r_name, rv, target) in changes.collection_removes {
+            let lv = self.remote_to_lv(rv)?;
+            if new_range.contains(lv) {
+                let crdt = self.try_remote_to_crdt_name(crdt_r_name)?;
+                let target = self.remote_to_lv(target)?;
+                used_versions.push((lv..lv + 1).into());
+                collection_removes.push((crdt, lv, target));
+            }
+        }
 
-            let op = op_metrics.to_operation(&changes.text_context);
-            self.remote_text_op(crdt_id, v_range, op);
+        let mut counter_ops = vec![];
+        for (crdt_r_name, rv, amount) in changes.counter_ops {
+            let lv = self.remote_to_lv(rv)?;
+            if new_range.contains(lv) {
+                let crdt = self.try_remote_to_crdt_name(crdt_r_name)?;
+                used_versions.push((lv..lv + 1).into());
+                counter_ops.push((crdt, lv, amount));
+            }
         }
 
-        Ok(new_range)
+        let mut text_ops = vec![];
+        for (crdt_r_name, rv, op_metrics) in changes.text_ops {
+            check_op_metrics(&op_metrics, &changes.text_context, true)?;
+            let pieces = self.remote_op_pieces(rv, op_metrics, &changes.text_context, new_range)?;
+            if pieces.is_empty() { continue; }
+
+            let crdt = self.try_remote_to_crdt_name(crdt_r_name)?;
+            for (_, v_range, op) in pieces {
+                used_versions.push(v_range);
+                text_ops.push((crdt, v_range, op.to_operation(&changes.text_context)));
+            }
+        }
+
+        let mut list_ops = vec![];
+        let empty_ctx = ListOperationCtx::new();
+        for (crdt_r_name, rv, op_metrics, values) in changes.list_ops {
+            check_op_metrics(&op_metrics, &empty_ctx, false)?;
+            let is_insert = op_metrics.kind == ListOpKind::Ins;
+            let expected_values = if is_insert { op_metrics.len() } else { 0 };
+            if values.len() != expected_values || values.iter().any(|val| val.is_deleted()) {
+                return Err(ParseError::InvalidContent);
+            }
+
+            let pieces = self.remote_op_pieces(rv, op_metrics, &empty_ctx, new_range)?;
+            if pieces.is_empty() { continue; }
+
+            let crdt = self.try_remote_to_crdt_name(crdt_r_name)?;
+            for (offset, v_range, op) in pieces {
+                // Values are stored in version order.
+                let values = if is_insert {
+                    values[offset..offset + v_range.len()].to_vec()
+                } else { vec![] };
+                for (v, val) in (v_range.start..v_range.end).zip(values.iter()) {
+                    note_value(v, val);
+                }
+                used_versions.push(v_range);
+                list_ops.push((crdt, v_range, op.to_operation(&empty_ctx), values));
+            }
+        }
+
+        // Each new version can only be used by one operation.
+        used_versions.sort_unstable_by_key(|r| r.start);
+        if used_versions.windows(2).any(|w| w[0].end > w[1].start) {
+            return Err(ParseError::InvalidContent);
+        }
+
+        // Now check every operation modifies a CRDT which exists.
+        for (crdt, lv, _, _) in map_ops.iter() {
+            self.check_op_target(*crdt, *lv, CRDTKind::Map, &new_crdts)?;
+        }
+        for (crdt, lv, _) in register_ops.iter() {
+            self.check_op_target(*crdt, *lv, CRDTKind::Register, &new_crdts)?;
+        }
+        for (crdt, lv, _) in collection_inserts.iter() {
+            self.check_op_target(*crdt, *lv, CRDTKind::Collection, &new_crdts)?;
+        }
+        for (crdt, lv, target) in collection_removes.iter() {
+            self.check_op_target(*crdt, *lv, CRDTKind::Collection, &new_crdts)?;
+
+            // Removed items must have been inserted into the same collection before the remove.
+            let inserted = self.collections.get(crdt).is_some_and(|info| info.inserts.contains_key(target))
+                || collection_inserts.iter().any(|(c, v, _)| c == crdt && v == target);
+            if !inserted || self.cg.graph.version_cmp(*target, *lv) != Some(Ordering::Less) {
+                return Err(ParseError::InvalidContent);
+            }
+        }
+        for (crdt, lv, _) in counter_ops.iter() {
+            self.check_op_target(*crdt, *lv, CRDTKind::Counter, &new_crdts)?;
+        }
+
+        // Operations in text and list CRDTs can only name positions which exist in the document
+        // at the operation's parents.
+        let mut new_lens = self.check_seq_ops(&text_ops, &list_ops, &new_crdts)?;
+
+        let mut new_range = new_range;
+        if let Some(policy) = opts.policy {
+            let mut ops = vec![];
+            ops.extend(map_ops.iter().map(|(crdt, lv, key, value)| (*lv, PolicyOp::MapSet { crdt: *crdt, key, value })));
+            ops.extend(register_ops.iter().map(|(crdt, lv, value)| (*lv, PolicyOp::RegisterSet { crdt: *crdt, value })));
+            ops.extend(collection_inserts.iter().map(|(crdt, lv, value)| (*lv, PolicyOp::CollectionInsert { crdt: *crdt, value })));
+            ops.extend(collection_removes.iter().map(|(crdt, lv, target)| (*lv, PolicyOp::CollectionRemove { crdt: *crdt, target: *target })));
+            ops.extend(counter_ops.iter().map(|(crdt, lv, amount)| (*lv, PolicyOp::CounterIncrement { crdt: *crdt, amount: *amount })));
+            ops.extend(text_ops.iter().map(|(crdt, v_range, op)| (v_range.start, PolicyOp::Text { crdt: *crdt, op: op.clone() })));
+            ops.extend(list_ops.iter().map(|(crdt, v_range, op, values)| {
+                (v_range.start, PolicyOp::List { crdt: *crdt, op: op.clone(), values: &values[..] })
+            }));
+            let rejected = check_policy(policy, &self.cg, new_range, ops)?;
+
+            if rejected.first() == Some(&new_range) { return Ok(None); }
+            if !rejected.is_empty() {
+                // Drop the rejected versions, and renumber everything else. The operations we keep
+                // only depend on versions we keep, so they're all still valid.
+                #[cfg(feature = "version_hashes")]
+                let cached_hashes = self.cg.hashes.len();
+                let remap = self.cg.remove_rejected(new_range.start, &rejected, old_version.clone(), old_num_agents);
+                new_range = (new_range.start..self.cg.len()).into();
+                let crdt = |crdt: LVKey| if crdt == ROOT_CRDT_ID { Some(crdt) } else { remap.get(crdt) };
+
+                new_crdts = new_crdts.into_iter()
+                    .filter_map(|(v, kind)| Some((remap.get(v)?, kind)))
+                    .collect();
+                map_ops = map_ops.into_iter()
+                    .filter_map(|(c, lv, key, val)| Some((crdt(c)?, remap.get(lv)?, key, val)))
+                    .collect();
+                register_ops = register_ops.into_iter()
+                    .filter_map(|(c, lv, val)| Some((crdt(c)?, remap.get(lv)?, val)))
+                    .collect();
+                collection_inserts = collection_inserts.into_iter()
+                    .filter_map(|(c, lv, val)| Some((crdt(c)?, remap.get(lv)?, val)))
+                    .collect();
+                collection_removes = collection_removes.into_iter()
+                    .filter_map(|(c, lv, target)| Some((crdt(c)?, remap.get(lv)?, remap.get(target)?)))
+                    .collect();
+                counter_ops = counter_ops.into_iter()
+                    .filter_map(|(c, lv, amount)| Some((crdt(c)?, remap.get(lv)?, amount)))
+                    .collect();
+                text_ops = text_ops.into_iter().flat_map(|(c, v_range, op)| {
+                    let c = crdt(c);
+                    remap.split_op(v_range, op).into_iter()
+                        .filter_map(move |(_, v_range, op)| Some((c?, v_range, op)))
+                }).collect();
+                list_ops = list_ops.into_iter().flat_map(|(c, v_range, op, values)| {
+                    let c = crdt(c);
+                    remap.split_op(v_range, op).into_iter().filter_map(move |(offset, v_range, op)| {
+                        let values = if values.is_empty() { vec![] } else { values[offset..offset + v_range.len()].to_vec() };
+                        Some((c?, v_range, op, values))
+                    })
+                }).collect();
+
+                #[cfg(feature = "version_hashes")] {
+                    if let Some(new_hashes) = new_hashes.as_mut() {
+                        remap.retain_kept(new_hashes, cached_hashes);
+                    }
+                    retain_known_signatures(&self.cg, &mut new_signatures);
+                }
+
+                new_lens = self.check_seq_ops(&text_ops, &list_ops, &new_crdts)?;
+            }
+        }
+
+        // Everything is valid. Apply the changes. Operations are applied in version order, and any
+        // new CRDTs are created first since operations can modify CRDTs created by other kinds of
+        // operations.
+        for (v, kind) in new_crdts {
+            self.create_child_crdt(v, kind);
+        }
+
+        // Setting a map key or register can't fail here. Every new version is larger than the
+        // versions already in the oplog, and the operations are applied in version order, so each
+        // operation is newer than the values it's ordered against.
+        map_ops.sort_unstable_by_key(|op| op.1);
+        for (crdt, lv, key, val) in map_ops {
+            self.remote_map_set(crdt, lv, key, val).expect("Map operations are validated above");
+        }
+
+        text_ops.sort_unstable_by_key(|op| op.1.start);
+        for (crdt, v_range, op) in text_ops {
+            self.remote_text_op(crdt, v_range, op);
+        }
+
+        register_ops.sort_unstable_by_key(|op| op.1);
+        for (crdt, lv, val) in register_ops {
+            self.remote_register_set(crdt, lv, val).expect("Register operations are validated above");
+        }
+
+        // Inserts are applied before removes, since removes name the inserted items.
+        for (crdt, lv, val) in collection_inserts {
+            self.remote_collection_insert(crdt, lv, val).expect("Collection inserts are validated above");
+        }
+
+        for (crdt, lv, target) in collection_removes {
+            self.remote_collection_remove(crdt, lv, target).expect("Collection removes are validated above");
+        }
+
+        list_ops.sort_unstable_by_key(|op| op.1.start);
+        for (crdt, v_range, op, values) in list_ops {
+            self.remote_list_op(crdt, v_range, op, values).expect("List operations are validated above");
+        }
+
+        // We worked out the length of each modified text and list CRDT while checking the
+        // operations above.
+        for (crdt, len) in new_lens {
+            let info = self.texts.get_mut(&crdt)
+                .or_else(|| self.lists.get_mut(&crdt).map(|info| &mut info.ops))
+                .unwrap();
+            info.len = Some(len);
+        }
+
+        for (crdt, lv, amount) in counter_ops {
+            self.remote_counter_increment(crdt, lv, amount).expect("Counter operations are validated above");
+        }
+
+        #[cfg(feature = "version_hashes")] {
+            if let Some(new_hashes) = new_hashes {
+                self.cg.cache_version_hashes(new_hashes);
+            }
+            for (name, seq_range, signature) in new_signatures {
+                let agent = self.cg.agent_assignment.get_agent_id(&name).unwrap();
+                self.cg.signatures.insert(agent, seq_range, signature);
+            }
+        }
+
+        self.cg.merge_agent_metadata(changes.agent_metadata, changes.timestamps);
+
+        Ok(Some(new_range))
     }
 
     pub fn xf_text_changes_since(&self, text_crdt: LVKey, since: &[LV]) -> Vec<(DTRange, Option<TextOperation>)> {
@@ -622,12 +1781,14 @@ impl OpLog {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
     #[cfg(feature = "serde")]
     use serde::{Deserialize, Serialize};
-    use crate::{CRDTKind, CreateValue, OpLog, Primitive, ROOT_CRDT_ID, SerializedOps};
+    use crate::{CRDTKind, CreateValue, DTValue, OpLog, PathError, PathSegment, Primitive, ROOT_CRDT_ID, SerializedOps};
+    use crate::encoding::parseerror::ParseError;
     use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
     use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
-    use crate::list::operation::TextOperation;
+    use crate::list::operation::{ListOpKind, TextOperation};
 
     #[test]
     fn smoke() {
@@ -655,7 +1816,7 @@ mod tests {
 
         // dbg!(&oplog);
 
-        assert_eq!(oplog.checkout_text(text).to_string(), "hai!");
+        assert_eq!(oplog.checkout_text(text).unwrap().to_string(), "hai!");
         oplog.dbg_check(true);
 
         // dbg!(oplog.checkout());
@@ -675,6 +1836,28 @@ mod tests {
         assert_eq!(oplog.checkout(), oplog_2.checkout());
     }
 
+    #[test]
+    fn float_and_bytes_values() {
+        let mut oplog1 = OpLog::new();
+        let seph = oplog1.cg.get_or_create_agent_id("seph");
+
+        oplog1.local_map_set(seph, ROOT_CRDT_ID, "x", CreateValue::Primitive(Primitive::F64(12.5)));
+        oplog1.local_map_set(seph, ROOT_CRDT_ID, "hash", CreateValue::Primitive(Primitive::Bytes(vec![0xde, 0xad, 0xbe, 0xef])));
+        let list = oplog1.local_map_set(seph, ROOT_CRDT_ID, "coords", CreateValue::NewCRDT(CRDTKind::List));
+        oplog1.local_list_insert(seph, list, 0, CreateValue::Primitive(Primitive::F64(-0.25)));
+        oplog1.dbg_check(true);
+
+        let mut oplog2 = OpLog::new();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog2.dbg_check(true);
+
+        let checkout = oplog2.checkout();
+        assert_eq!(checkout, oplog1.checkout());
+        assert_eq!(*checkout["x"], DTValue::Primitive(Primitive::F64(12.5)));
+        assert_eq!(*checkout["hash"], DTValue::Primitive(Primitive::Bytes(vec![0xde, 0xad, 0xbe, 0xef])));
+        assert_eq!(*checkout["coords"], DTValue::List(vec![DTValue::Primitive(Primitive::F64(-0.25))]));
+    }
+
     #[test]
     fn concurrent_changes() {
         let mut oplog1 = OpLog::new();
@@ -706,7 +1889,9 @@ mod tests {
         // dbg!(oplog2.checkout());
         assert_eq!(oplog1.checkout(), oplog2.checkout());
 
-        dbg!(oplog1.crdt_at_path(&["title"]));
+        let (kind, title) = oplog1.crdt_at_path(&["title".into()]).unwrap();
+        assert_eq!(kind, CRDTKind::Text);
+        assert_eq!(oplog1.checkout_text(title).unwrap().to_string(), "Better keep it clean");
     }
 
     #[test]
@@ -751,8 +1936,53 @@ mod tests {
 
         // Now overwrite the parent item with a remote operation.
         let lv = oplog.cg.assign_local_op(seph, 1).start;
-        oplog.remote_map_set(ROOT_CRDT_ID, lv, "overwritten", CreateValue::Primitive(Primitive::I64(123)));
+        oplog.remote_map_set(ROOT_CRDT_ID, lv, "overwritten", CreateValue::Primitive(Primitive::I64(123))).unwrap();
+
+        oplog.dbg_check(true);
+    }
+
+    #[test]
+    fn merge_checks_positions_at_parents() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "text", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "abc"));
+
+        // Concurrently delete everything and append an x.
+        let parents = oplog.cg.version.clone();
+        let a = oplog.cg.assign_local_op_with_parents(parents.as_ref(), seph, 3);
+        oplog.remote_text_op(text, a, TextOperation::new_delete(0..3));
+        let b = oplog.cg.assign_local_op_with_parents(parents.as_ref(), kaarina, 1);
+        oplog.remote_text_op(text, b, TextOperation::new_insert(3, "x"));
+
+        let mut dest = OpLog::new();
+        dest.merge_ops(oplog.ops_since(&[])).unwrap();
+        assert_eq!(dest.checkout_text(text).unwrap().to_string(), "x");
+
+        // 4 items have been inserted, but the document only has 1 item.
+        let mut bad = oplog.clone();
+        let v = bad.cg.assign_local_op(seph, 1);
+        bad.remote_text_op(text, v, TextOperation::new_insert(2, "y"));
+        assert_eq!(dest.merge_ops(bad.ops_since(&[])), Err(ParseError::InvalidContent));
+
+        let v = oplog.cg.assign_local_op(seph, 1);
+        oplog.remote_text_op(text, v, TextOperation::new_insert(1, "y"));
+        dest.merge_ops(oplog.ops_since(&[])).unwrap();
+        assert_eq!(dest.checkout_text(text).unwrap().to_string(), "xy");
+        dest.dbg_check(true);
+    }
+
+    #[test]
+    fn remote_set_out_of_order_errors() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let v = oplog.cg.assign_local_op(seph, 2).start;
+        oplog.remote_map_set(ROOT_CRDT_ID, v + 1, "x", CreateValue::NewCRDT(CRDTKind::Register)).unwrap();
 
+        // Operations must be added to a key in version order.
+        assert_eq!(oplog.remote_map_set(ROOT_CRDT_ID, v, "x", CreateValue::NewCRDT(CRDTKind::Text)), Err(ParseError::InvalidContent));
+        assert!(oplog.texts.is_empty());
         oplog.dbg_check(true);
     }
 
@@ -775,6 +2005,414 @@ mod tests {
         oplog2.merge_ops(full_update).unwrap();
     }
 
+    #[test]
+    fn map_delete_key() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "name", CreateValue::Primitive(Primitive::Str("seph".into())));
+        let inner = oplog.local_map_set(seph, ROOT_CRDT_ID, "details", CreateValue::NewCRDT(CRDTKind::Map));
+        let text = oplog.local_map_set(seph, inner, "bio", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+
+        oplog.local_map_delete(seph, ROOT_CRDT_ID, "name");
+        oplog.local_map_delete(seph, ROOT_CRDT_ID, "details");
+        oplog.dbg_check(true);
+
+        // Deleted keys don't show up in the checkout, and everything inside them is deleted.
+        assert!(oplog.checkout().is_empty());
+        assert!(oplog.deleted_crdts.contains(&inner));
+        assert!(oplog.deleted_crdts.contains(&text));
+
+        // Deleted keys can be set again.
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "name", CreateValue::Primitive(Primitive::I64(1)));
+        oplog.dbg_check(true);
+        assert_eq!(*oplog.checkout()["name"], DTValue::Primitive(Primitive::I64(1)));
+    }
+
+    #[test]
+    fn map_delete_concurrent_with_set() {
+        let mut oplog1 = OpLog::new();
+        let mut oplog2 = OpLog::new();
+        let seph = oplog1.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog2.cg.get_or_create_agent_id("kaarina");
+
+        oplog1.local_map_set(seph, ROOT_CRDT_ID, "a", CreateValue::Primitive(Primitive::I64(1)));
+        oplog1.local_map_set(seph, ROOT_CRDT_ID, "b", CreateValue::Primitive(Primitive::I64(2)));
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+
+        // "a" is concurrently set and deleted. "b" is deleted by both peers.
+        oplog1.local_map_delete(seph, ROOT_CRDT_ID, "a");
+        oplog1.local_map_delete(seph, ROOT_CRDT_ID, "b");
+        let set = oplog2.local_map_set(kaarina, ROOT_CRDT_ID, "a", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog2.local_text_op(kaarina, set, TextOperation::new_insert(0, "yo"));
+        oplog2.local_map_delete(kaarina, ROOT_CRDT_ID, "b");
+
+        oplog1.merge_ops(oplog2.ops_since(&[])).unwrap();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog1.dbg_check(true);
+        oplog2.dbg_check(true);
+
+        // The set wins over the concurrent delete.
+        let checkout = oplog1.checkout();
+        assert_eq!(checkout, oplog2.checkout());
+        assert_eq!(checkout.len(), 1);
+        assert_eq!(*checkout["a"], DTValue::Text("yo".into()));
+        assert!(oplog2.deleted_crdts.is_empty());
+    }
+
+    #[test]
+    fn counter_concurrent_increments() {
+        let mut oplog1 = OpLog::new();
+        let seph = oplog1.cg.get_or_create_agent_id("seph");
+        let views = oplog1.local_map_set(seph, ROOT_CRDT_ID, "views", CreateValue::NewCRDT(CRDTKind::Counter));
+        oplog1.local_counter_increment(seph, views, 5);
+        assert_eq!(oplog1.checkout_counter(views), 5);
+
+        let mut oplog2 = OpLog::new();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        let kaarina = oplog2.cg.get_or_create_agent_id("kaarina");
+
+        // Concurrent changes are all kept.
+        let (kind, views_2) = oplog2.crdt_at_path(&["views".into()]).unwrap();
+        assert_eq!(kind, CRDTKind::Counter);
+        oplog1.local_counter_increment(seph, views, 1);
+        oplog2.local_counter_increment(kaarina, views_2, 10);
+        oplog2.local_counter_decrement(kaarina, views_2, 3);
+
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog1.merge_ops(oplog2.ops_since(&[])).unwrap();
+        oplog1.dbg_check(true);
+        oplog2.dbg_check(true);
+
+        let checkout = oplog1.checkout();
+        assert_eq!(checkout, oplog2.checkout());
+        assert_eq!(*checkout["views"], DTValue::Counter(13));
+    }
+
+    #[test]
+    fn remote_counter_increment_errors() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let views = oplog.local_map_set(seph, ROOT_CRDT_ID, "views", CreateValue::NewCRDT(CRDTKind::Counter));
+        let v = oplog.cg.assign_local_op(seph, 1).start;
+
+        // Increments must name a counter which exists.
+        assert_eq!(oplog.remote_counter_increment(ROOT_CRDT_ID, v, 1), Err(ParseError::InvalidContent));
+        assert_eq!(oplog.remote_counter_increment(1000, v, 1), Err(ParseError::InvalidContent));
+
+        oplog.remote_counter_increment(views, v, 1).unwrap();
+        assert_eq!(oplog.checkout_counter(views), 1);
+        oplog.dbg_check(true);
+    }
+
+    #[test]
+    fn path_lookups() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+
+        let list = oplog.local_map_set(seph, ROOT_CRDT_ID, "list", CreateValue::NewCRDT(CRDTKind::List));
+        oplog.local_list_insert(seph, list, 0, CreateValue::Primitive(Primitive::I64(1)));
+        let inner = oplog.local_list_insert(seph, list, 1, CreateValue::NewCRDT(CRDTKind::Collection));
+        let text = oplog.local_collection_insert(seph, inner, CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "n", CreateValue::Primitive(Primitive::I64(5)));
+        oplog.dbg_check(true);
+
+        let path = ["list".into(), PathSegment::Index(1), PathSegment::Index(text)];
+        assert_eq!(oplog.crdt_at_path(&path), Ok((CRDTKind::Text, text)));
+        assert_eq!(oplog.text_at_path(&path), Ok(text));
+        assert_eq!(oplog.crdt_at_path(&path[..2]), Ok((CRDTKind::Collection, inner)));
+        assert_eq!(oplog.text_at_path(&path[..2]), Err(PathError::WrongKind {
+            expected: CRDTKind::Text,
+            actual: CRDTKind::Collection,
+        }));
+
+        assert_eq!(oplog.crdt_at_path(&["missing".into()]), Err(PathError::MissingKey));
+        assert_eq!(oplog.crdt_at_path(&["list".into(), 2.into()]), Err(PathError::MissingKey));
+        assert_eq!(oplog.crdt_at_path(&["list".into(), 1.into(), 12345.into()]), Err(PathError::MissingKey));
+        assert_eq!(oplog.crdt_at_path(&["list".into(), 0.into()]), Err(PathError::NotCRDT));
+        assert_eq!(oplog.crdt_at_path(&["n".into()]), Err(PathError::NotCRDT));
+        assert_eq!(oplog.crdt_at_path(&[0.into()]), Err(PathError::InvalidSegment));
+        assert_eq!(oplog.crdt_at_path(&["list".into(), "x".into()]), Err(PathError::InvalidSegment));
+        assert_eq!(oplog.crdt_at_path(&[path[0], path[1], path[2], 0.into()]), Err(PathError::InvalidSegment));
+
+        assert_eq!(oplog.checkout_text(list).unwrap_err(), PathError::MissingKey);
+        oplog.local_map_delete(seph, ROOT_CRDT_ID, "list");
+        assert_eq!(oplog.crdt_at_path(&path), Err(PathError::Deleted));
+        assert_eq!(oplog.checkout_text(text).unwrap_err(), PathError::Deleted);
+    }
+
+    #[test]
+    fn path_lookups_deleted() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+
+        // A map key which has been deleted.
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "x", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_map_delete(seph, ROOT_CRDT_ID, "x");
+        assert_eq!(oplog.crdt_at_path(&["x".into()]), Err(PathError::Deleted));
+
+        // A collection item which has been removed.
+        let set = oplog.local_map_set(seph, ROOT_CRDT_ID, "set", CreateValue::NewCRDT(CRDTKind::Collection));
+        let item = oplog.local_collection_insert(seph, set, CreateValue::NewCRDT(CRDTKind::Text));
+        assert_eq!(oplog.crdt_at_path(&["set".into(), item.into()]), Ok((CRDTKind::Text, item)));
+        oplog.local_collection_remove(seph, set, item);
+        assert_eq!(oplog.crdt_at_path(&["set".into(), item.into()]), Err(PathError::Deleted));
+        oplog.dbg_check(true);
+
+        // A CRDT which has been deleted, even though the path still names it.
+        let map = oplog.local_map_set(seph, ROOT_CRDT_ID, "map", CreateValue::NewCRDT(CRDTKind::Map));
+        assert_eq!(oplog.crdt_at_path(&["map".into()]), Ok((CRDTKind::Map, map)));
+        oplog.deleted_crdts.insert(map);
+        assert_eq!(oplog.crdt_at_path(&["map".into()]), Err(PathError::Deleted));
+    }
+
+    #[test]
+    fn register_set() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+
+        let reg = oplog.local_map_set(seph, ROOT_CRDT_ID, "owner", CreateValue::NewCRDT(CRDTKind::Register));
+        oplog.dbg_check(true);
+        assert_eq!(oplog.checkout_register(reg), (DTValue::Primitive(Primitive::Nil), vec![]));
+
+        oplog.local_register_set(seph, reg, CreateValue::Primitive(Primitive::Str("seph".into())));
+        let text = oplog.local_register_set(seph, reg, CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+        oplog.dbg_check(true);
+        assert_eq!(oplog.checkout_register(reg), (DTValue::Text("hi".into()), vec![]));
+
+        // Overwriting the register's value deletes the text CRDT.
+        oplog.local_register_set(seph, reg, CreateValue::Primitive(Primitive::I64(5)));
+        oplog.dbg_check(true);
+        assert!(oplog.deleted_crdts.contains(&text));
+
+        // And deleting the register deletes everything inside it.
+        let inner = oplog.local_register_set(seph, reg, CreateValue::NewCRDT(CRDTKind::Map));
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "owner", CreateValue::Primitive(Primitive::Nil));
+        oplog.dbg_check(true);
+        assert!(oplog.deleted_crdts.contains(&reg));
+        assert!(oplog.deleted_crdts.contains(&inner));
+    }
+
+    #[test]
+    fn register_concurrent_sets() {
+        let mut oplog1 = OpLog::new();
+        let mut oplog2 = OpLog::new();
+        let seph = oplog1.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog2.cg.get_or_create_agent_id("kaarina");
+
+        let reg = oplog1.local_map_set(seph, ROOT_CRDT_ID, "status", CreateValue::NewCRDT(CRDTKind::Register));
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        let reg_2 = oplog2.crdt_at_path(&["status".into()]).unwrap().1;
+
+        oplog1.local_register_set(seph, reg, CreateValue::Primitive(Primitive::Str("open".into())));
+        oplog2.local_register_set(kaarina, reg_2, CreateValue::Primitive(Primitive::Str("closed".into())));
+
+        oplog1.merge_ops(oplog2.ops_since(&[])).unwrap();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog1.dbg_check(true);
+        oplog2.dbg_check(true);
+
+        // Both peers agree on the winner, and report the other value as a conflict.
+        let (value, conflicts) = oplog1.checkout_register(reg);
+        assert_eq!((value, conflicts.clone()), oplog2.checkout_register(reg_2));
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(oplog1.checkout(), oplog2.checkout());
+
+        // Setting the register again resolves the conflict.
+        oplog2.local_register_set(kaarina, reg_2, CreateValue::Primitive(Primitive::Str("merged".into())));
+        oplog1.merge_ops(oplog2.ops_since(oplog1.cg.version.as_ref())).unwrap();
+        oplog1.dbg_check(true);
+        assert_eq!(oplog1.checkout_register(reg), (DTValue::Primitive(Primitive::Str("merged".into())), vec![]));
+    }
+
+    #[test]
+    fn list_insert_delete() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+
+        let blocks = oplog.local_map_set(seph, ROOT_CRDT_ID, "blocks", CreateValue::NewCRDT(CRDTKind::List));
+        oplog.local_list_insert(seph, blocks, 0, CreateValue::Primitive(Primitive::I64(1)));
+        let text = oplog.local_list_insert(seph, blocks, 1, CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+        let map = oplog.local_list_insert(seph, blocks, 0, CreateValue::NewCRDT(CRDTKind::Map));
+        oplog.local_map_set(seph, map, "x", CreateValue::Primitive(Primitive::Bool(true)));
+        oplog.dbg_check(true);
+
+        assert_eq!(oplog.checkout_list(blocks), vec![
+            DTValue::Map(BTreeMap::from([("x".into(), Box::new(DTValue::Primitive(Primitive::Bool(true))))])),
+            DTValue::Primitive(Primitive::I64(1)),
+            DTValue::Text("hi".into()),
+        ]);
+
+        // Deleting items deletes the CRDTs they contain.
+        oplog.local_list_delete(seph, blocks, 0..2);
+        oplog.dbg_check(true);
+        assert!(oplog.deleted_crdts.contains(&map));
+        assert!(!oplog.deleted_crdts.contains(&text));
+        assert_eq!(oplog.checkout_list(blocks), vec![DTValue::Text("hi".into())]);
+
+        // And deleting the list deletes everything inside it.
+        oplog.local_map_delete(seph, ROOT_CRDT_ID, "blocks");
+        oplog.dbg_check(true);
+        assert!(oplog.deleted_crdts.contains(&text));
+    }
+
+    #[test]
+    fn remote_list_op_errors() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let blocks = oplog.local_map_set(seph, ROOT_CRDT_ID, "blocks", CreateValue::NewCRDT(CRDTKind::List));
+        let v = oplog.cg.assign_local_op(seph, 1).start;
+        let v_range = (v..v + 1).into();
+        let ins = TextOperation { loc: (0..1).into(), kind: ListOpKind::Ins, content: None };
+        let value = CreateValue::Primitive(Primitive::I64(1));
+
+        // Operations must name a list which exists.
+        assert_eq!(oplog.remote_list_op(ROOT_CRDT_ID, v_range, ins.clone(), vec![value.clone()]), Err(ParseError::InvalidContent));
+        assert_eq!(oplog.remote_list_op(1000, v_range, ins.clone(), vec![value.clone()]), Err(ParseError::InvalidContent));
+
+        // Inserts need one (non-deleted) value for each item, and deletes have no values.
+        assert_eq!(oplog.remote_list_op(blocks, v_range, ins.clone(), vec![CreateValue::Deleted]), Err(ParseError::InvalidContent));
+        assert_eq!(oplog.remote_list_op(blocks, v_range, ins.clone(), vec![]), Err(ParseError::InvalidContent));
+        assert_eq!(oplog.remote_list_op(blocks, v_range, TextOperation::new_delete(0..1), vec![value.clone()]), Err(ParseError::InvalidContent));
+        assert!(oplog.checkout_list(blocks).is_empty());
+
+        oplog.remote_list_op(blocks, v_range, ins, vec![value]).unwrap();
+        assert_eq!(oplog.checkout_list(blocks), vec![DTValue::Primitive(Primitive::I64(1))]);
+        oplog.dbg_check(true);
+    }
+
+    #[test]
+    fn list_concurrent_changes() {
+        let mut oplog1 = OpLog::new();
+        let mut oplog2 = OpLog::new();
+        let seph = oplog1.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog2.cg.get_or_create_agent_id("kaarina");
+
+        let list = oplog1.local_map_set(seph, ROOT_CRDT_ID, "list", CreateValue::NewCRDT(CRDTKind::List));
+        oplog1.local_list_insert(seph, list, 0, CreateValue::Primitive(Primitive::I64(1)));
+        oplog1.local_list_insert(seph, list, 1, CreateValue::NewCRDT(CRDTKind::Map));
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog2.dbg_check(true);
+        let list_2 = oplog2.crdt_at_path(&["list".into()]).unwrap().1;
+
+        // Both peers concurrently delete the map, and insert new items.
+        oplog1.local_list_delete(seph, list, 1..2);
+        oplog1.local_list_insert(seph, list, 0, CreateValue::Primitive(Primitive::I64(2)));
+        oplog2.local_list_delete(kaarina, list_2, 1..2);
+        let inner = oplog2.local_list_insert(kaarina, list_2, 1, CreateValue::NewCRDT(CRDTKind::List));
+        oplog2.local_list_insert(kaarina, inner, 0, CreateValue::Primitive(Primitive::I64(3)));
+
+        oplog1.merge_ops(oplog2.ops_since(&[])).unwrap();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog1.dbg_check(true);
+        oplog2.dbg_check(true);
+
+        let checkout = oplog1.checkout();
+        assert_eq!(checkout, oplog2.checkout());
+        assert_eq!(*checkout["list"], DTValue::List(vec![
+            DTValue::Primitive(Primitive::I64(2)),
+            DTValue::Primitive(Primitive::I64(1)),
+            DTValue::List(vec![DTValue::Primitive(Primitive::I64(3))]),
+        ]));
+    }
+
+    #[test]
+    fn collection_insert_remove() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+
+        let todos = oplog.local_map_set(seph, ROOT_CRDT_ID, "todos", CreateValue::NewCRDT(CRDTKind::Collection));
+        let a = oplog.local_collection_insert(seph, todos, CreateValue::Primitive(Primitive::Str("milk".into())));
+        let b = oplog.local_collection_insert(seph, todos, CreateValue::NewCRDT(CRDTKind::Map));
+        oplog.local_map_set(seph, b, "done", CreateValue::Primitive(Primitive::Bool(false)));
+        let c = oplog.local_collection_insert(seph, todos, CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, c, TextOperation::new_insert(0, "eggs"));
+        oplog.dbg_check(true);
+
+        let items = oplog.checkout_collection(todos);
+        assert_eq!(items.keys().copied().collect::<Vec<_>>(), vec![a, b, c]);
+        assert_eq!(*items[&c], DTValue::Text("eggs".into()));
+
+        oplog.local_collection_remove(seph, todos, a);
+        oplog.local_collection_remove(seph, todos, b);
+        oplog.dbg_check(true);
+        assert!(oplog.deleted_crdts.contains(&b));
+
+        let items = oplog.checkout_collection(todos);
+        assert_eq!(items.keys().copied().collect::<Vec<_>>(), vec![c]);
+
+        // Removing the whole collection deletes the text CRDT inside it.
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "todos", CreateValue::Primitive(Primitive::Nil));
+        oplog.dbg_check(true);
+        assert!(oplog.deleted_crdts.contains(&c));
+    }
+
+    #[test]
+    fn remote_collection_op_errors() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let todos = oplog.local_map_set(seph, ROOT_CRDT_ID, "todos", CreateValue::NewCRDT(CRDTKind::Collection));
+        let item = oplog.local_collection_insert(seph, todos, CreateValue::Primitive(Primitive::I64(1)));
+        let v = oplog.cg.assign_local_op(seph, 1).start;
+
+        // Operations must name a collection which exists.
+        let value = CreateValue::Primitive(Primitive::I64(2));
+        assert_eq!(oplog.remote_collection_insert(ROOT_CRDT_ID, v, value.clone()), Err(ParseError::InvalidContent));
+        assert_eq!(oplog.remote_collection_insert(1000, v, value.clone()), Err(ParseError::InvalidContent));
+        assert_eq!(oplog.remote_collection_remove(ROOT_CRDT_ID, v, item), Err(ParseError::InvalidContent));
+
+        // Collections cannot contain deleted values, and only inserted items can be removed.
+        assert_eq!(oplog.remote_collection_insert(todos, v, CreateValue::Deleted), Err(ParseError::InvalidContent));
+        assert_eq!(oplog.remote_collection_remove(todos, v, todos), Err(ParseError::InvalidContent));
+        assert_eq!(oplog.remote_collection_remove(todos, item, item), Err(ParseError::InvalidContent));
+        assert_eq!(oplog.checkout_collection(todos).len(), 1);
+
+        oplog.remote_collection_remove(todos, v, item).unwrap();
+        assert!(oplog.checkout_collection(todos).is_empty());
+        oplog.dbg_check(true);
+    }
+
+    #[test]
+    fn collection_concurrent_changes() {
+        let mut oplog1 = OpLog::new();
+        let mut oplog2 = OpLog::new();
+        let seph = oplog1.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog2.cg.get_or_create_agent_id("kaarina");
+
+        let list = oplog1.local_map_set(seph, ROOT_CRDT_ID, "comments", CreateValue::NewCRDT(CRDTKind::Collection));
+        let item = oplog1.local_collection_insert(seph, list, CreateValue::Primitive(Primitive::I64(1)));
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog2.dbg_check(true);
+
+        // Both peers concurrently remove the same item, and insert new items.
+        let list_2 = oplog2.crdt_at_path(&["comments".into()]).unwrap().1;
+        let item_2 = oplog2.cg.agent_assignment.remote_to_local_version(oplog1.cg.agent_assignment.local_to_remote_version(item));
+        oplog1.local_collection_remove(seph, list, item);
+        oplog1.local_collection_insert(seph, list, CreateValue::Primitive(Primitive::I64(2)));
+        oplog2.local_collection_remove(kaarina, list_2, item_2);
+        let nested = oplog2.local_collection_insert(kaarina, list_2, CreateValue::NewCRDT(CRDTKind::Collection));
+        oplog2.local_collection_insert(kaarina, nested, CreateValue::Primitive(Primitive::I64(3)));
+
+        oplog1.merge_ops(oplog2.ops_since(&[])).unwrap();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog1.dbg_check(true);
+        oplog2.dbg_check(true);
+
+        // Items are keyed by local versions, which differ between peers.
+        let DTValue::Collection(items_1) = &*oplog1.checkout()["comments"] else { panic!() };
+        let DTValue::Collection(items_2) = &*oplog2.checkout()["comments"] else { panic!() };
+        for items in [items_1, items_2] {
+            assert_eq!(items.len(), 2);
+            assert!(items.values().any(|i| **i == DTValue::Primitive(Primitive::I64(2))));
+            assert!(items.values().any(|i| {
+                matches!(&**i, DTValue::Collection(inner) if inner.len() == 1)
+            }));
+        }
+    }
+
 
 
 
@@ -0,0 +1,314 @@
+//! Version hashes for [`OpLog`]. See [`crate::causalgraph::hashes`] for details.
+//!
+//! The content of each operation is hashed in the form it's sent to remote peers (with CRDTs and
+//! versions named by their remote IDs). This way the local and remote sides of a merge produce
+//! the same bytes for the same operation.
+
+use rle::HasLength;
+use smartstring::alias::String as SmartString;
+use crate::{CreateValue, DTRange, Frontier, LV, OpLog, SerializedOps};
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
+use crate::causalgraph::hashes::{VersionHash, VersionHashes};
+use crate::causalgraph::signatures::{check_signatures, SignatureVerifier, SignedSpan};
+use crate::encoding::op_contents::{write_counter_increment, write_create_value};
+use crate::encoding::parseerror::ParseError;
+use crate::encoding::tools::push_str;
+use crate::encoding::varint::push_usize;
+use crate::list::hashes::write_list_op_content;
+use crate::list::op_metrics::ListOperationCtx;
+use crate::list::operation::ListOpKind;
+
+fn push_rv(buf: &mut Vec<u8>, RemoteVersion(name, seq): RemoteVersion) {
+    push_str(buf, name);
+    push_usize(buf, seq);
+}
+
+/// The content bytes of each version in a range. Each version is written by the operation at that
+/// version.
+pub(crate) struct VersionContent {
+    range: DTRange,
+    content: Vec<Vec<u8>>,
+}
+
+impl VersionContent {
+    fn new(range: DTRange) -> Self {
+        Self { range, content: vec![vec![]; range.len()] }
+    }
+
+    fn get(&self, v: LV) -> &[u8] {
+        &self.content[v - self.range.start]
+    }
+
+    fn slot(&mut self, v: LV) -> Option<&mut Vec<u8>> {
+        if self.range.contains(v) { Some(&mut self.content[v - self.range.start]) }
+        else { None }
+    }
+}
+
+impl OpLog {
+    /// Gather the content bytes of every version in `range` from a set of serialized operations.
+    /// Operations outside the range are ignored.
+    fn serialized_ops_content(&self, ops: &SerializedOps, range: DTRange) -> Result<VersionContent, ParseError> {
+        let mut result = VersionContent::new(range);
+        if range.is_empty() { return Ok(result); }
+
+        let to_lv = |rv: RemoteVersion| {
+            self.cg.agent_assignment.try_remote_to_local_version(rv)
+                .map_err(ParseError::InvalidRemoteID)
+        };
+
+        for (crdt, rv, key, val) in ops.map_ops.iter() {
+            if let Some(buf) = result.slot(to_lv(*rv)?) {
+                buf.push(1);
+                push_rv(buf, *crdt);
+                push_str(buf, key);
+                write_create_value(buf, val);
+            }
+        }
+
+        for (crdt, rv, val) in ops.register_ops.iter() {
+            if let Some(buf) = result.slot(to_lv(*rv)?) {
+                buf.push(2);
+                push_rv(buf, *crdt);
+                write_create_value(buf, val);
+            }
+        }
+
+        for (crdt, rv, val) in ops.collection_inserts.iter() {
+            if let Some(buf) = result.slot(to_lv(*rv)?) {
+                buf.push(3);
+                push_rv(buf, *crdt);
+                write_create_value(buf, val);
+            }
+        }
+
+        for (crdt, rv, target) in ops.collection_removes.iter() {
+            if let Some(buf) = result.slot(to_lv(*rv)?) {
+                buf.push(4);
+                push_rv(buf, *crdt);
+                push_rv(buf, *target);
+            }
+        }
+
+        for (crdt, rv, amount) in ops.counter_ops.iter() {
+            if let Some(buf) = result.slot(to_lv(*rv)?) {
+                buf.push(5);
+                push_rv(buf, *crdt);
+                write_counter_increment(buf, *amount);
+            }
+        }
+
+        // Text and list operations cover a run of versions from the same agent.
+        for (crdt, RemoteVersion(name, seq), op) in ops.text_ops.iter() {
+            let mut seq = *seq;
+            let mut err = None;
+            write_list_op_content(op, &ops.text_context, |_, bytes| {
+                match to_lv(RemoteVersion(name, seq)) {
+                    Ok(lv) => if let Some(buf) = result.slot(lv) {
+                        buf.push(6);
+                        push_rv(buf, *crdt);
+                        buf.extend_from_slice(bytes);
+                    },
+                    Err(e) => { err.get_or_insert(e); }
+                }
+                seq += 1;
+            });
+            if let Some(e) = err { return Err(e); }
+        }
+
+        let empty_ctx = ListOperationCtx::new();
+        for (crdt, RemoteVersion(name, seq), op, values) in ops.list_ops.iter() {
+            let mut offset = 0;
+            let mut err = None;
+            write_list_op_content(op, &empty_ctx, |op, bytes| {
+                match to_lv(RemoteVersion(name, seq + offset)) {
+                    Ok(lv) => if let Some(buf) = result.slot(lv) {
+                        buf.push(7);
+                        push_rv(buf, *crdt);
+                        buf.extend_from_slice(bytes);
+                        if op.kind == ListOpKind::Ins {
+                            write_create_value(buf, values.get(offset).unwrap_or(&CreateValue::Deleted));
+                        }
+                    },
+                    Err(e) => { err.get_or_insert(e); }
+                }
+                offset += 1;
+            });
+            if let Some(e) = err { return Err(e); }
+        }
+
+        Ok(result)
+    }
+
+    /// The content of all the versions in the oplog which aren't in the hash cache.
+    fn uncached_content(&self) -> VersionContent {
+        let cached_len = self.cg.hashes.len();
+
+        // Versions are numbered in causal order, so the cached versions are a causally closed set.
+        let mut cached_version = Frontier::root();
+        for entry in self.cg.graph.iter_range((0..cached_len).into()) {
+            cached_version.advance_by_known_run(entry.parents.as_ref(), entry.span);
+        }
+
+        let ops = self.ops_since(cached_version.as_ref());
+        self.serialized_ops_content(&ops, (cached_len..self.cg.len()).into())
+            .expect("Local operations are valid")
+    }
+
+    pub(crate) fn version_hashes(&self) -> VersionHashes<'_> {
+        let content = self.uncached_content();
+        self.cg.version_hashes_with(|v, buf| buf.extend_from_slice(content.get(v)))
+    }
+
+    /// Get the content hash of the named version. The hash covers the operation at the version and
+    /// (transitively) every version it depends on. This is consistent across peers.
+    pub fn version_hash(&self, v: LV) -> VersionHash {
+        *self.version_hashes().get(v)
+    }
+
+    /// Get the content hash of a frontier. Two peers with the same frontier hash have exactly the
+    /// same operations up to that frontier.
+    pub fn frontier_hash(&self, frontier: &[LV]) -> VersionHash {
+        self.version_hashes().frontier_hash(frontier)
+    }
+
+    /// Compute and cache the hashes of all versions in the oplog.
+    pub fn cache_version_hashes(&mut self) {
+        let tail = self.version_hashes().into_tail();
+        self.cg.cache_version_hashes(tail);
+    }
+
+    /// This is the same as [`ops_since`](OpLog::ops_since), but the result also contains the hashes
+    /// of our current version. The receiver checks the hashes when merging, and rejects the
+    /// operations if any of them conflict with operations it already has.
+    pub fn ops_since_with_hashes(&self, since_frontier: &[LV]) -> SerializedOps<'_> {
+        let mut ops = self.ops_since(since_frontier);
+        let hashes = self.version_hashes();
+        ops.version_hashes = self.cg.version.iter().map(|v| {
+            (self.cg.agent_assignment.local_to_remote_version(*v), *hashes.get(*v))
+        }).collect();
+        ops
+    }
+
+    /// Get the content of our versions which aren't in the hash cache, if the changes need to be
+    /// verified. This is called before the changes are merged into the causal graph.
+    pub(crate) fn prepare_hash_check(&self, changes: &SerializedOps, check_signatures: bool) -> Option<VersionContent> {
+        (check_signatures || !changes.version_hashes.is_empty()).then(|| self.uncached_content())
+    }
+
+    /// Check the version hashes and signatures in a set of changes. The changes must already be
+    /// merged into the causal graph (but not applied), with `new_range` naming the new versions.
+    ///
+    /// Returns the new hashes and signatures, to be stored once the changes have been applied.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn check_hashes_and_signatures(&self, changes: &SerializedOps, new_range: DTRange, local_content: Option<&VersionContent>, verifier: Option<&SignatureVerifier>)
+        -> Result<(Option<Vec<VersionHash>>, Vec<(SmartString, DTRange, Vec<u8>)>), ParseError>
+    {
+        let signed_spans: Vec<SignedSpan> = changes.signatures.iter()
+            .map(|(name, seq_range, sig)| (*name, *seq_range, sig.as_slice()))
+            .collect();
+
+        let (new_hashes, signed_spans) = if let Some(local_content) = local_content {
+            let new_content = self.serialized_ops_content(changes, new_range)?;
+            let hashes = self.cg.version_hashes_with(|v, buf| {
+                let content = if new_range.contains(v) { &new_content } else { local_content };
+                buf.extend_from_slice(content.get(v));
+            });
+            hashes.verify(&self.cg, &changes.version_hashes)?;
+            let signed_spans = check_signatures(verifier.map(|v| (v, &hashes)), &self.cg, new_range, &signed_spans)?;
+            (Some(hashes.into_tail()), signed_spans)
+        } else {
+            (None, check_signatures(None, &self.cg, new_range, &signed_spans)?)
+        };
+
+        let signed_spans = signed_spans.into_iter().map(|(name, seq_range, sig)| {
+            (name.into(), seq_range, sig.to_vec())
+        }).collect();
+        Ok((new_hashes, signed_spans))
+    }
+
+    /// Sign all spans of versions which haven't been signed yet. See
+    /// [`ListOpLog::sign_pending`](crate::list::ListOpLog::sign_pending).
+    pub fn sign_pending<F>(&mut self, sign: F) where F: FnMut(&str, &[u8]) -> Option<Vec<u8>> {
+        let hashes = self.version_hashes();
+        let signatures = self.cg.sign_spans(&hashes, sign);
+        let tail = hashes.into_tail();
+        self.cg.cache_version_hashes(tail);
+        for (agent, seq_range, signature) in signatures {
+            self.cg.signatures.insert(agent, seq_range, signature);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CRDTKind, CreateValue, OpLog, Primitive, ROOT_CRDT_ID};
+    use crate::encoding::parseerror::ParseError;
+    use crate::list::operation::TextOperation;
+
+    fn make_oplog(agent: &str) -> OpLog {
+        let mut oplog = OpLog::new();
+        let agent = oplog.cg.get_or_create_agent_id(agent);
+        oplog.local_map_set(agent, ROOT_CRDT_ID, "x", CreateValue::Primitive(Primitive::I64(10)));
+        let text = oplog.local_map_set(agent, ROOT_CRDT_ID, "text", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(agent, text, TextOperation::new_insert(0, "hi there"));
+        oplog.local_text_op(agent, text, TextOperation::new_delete(0..3));
+        let reg = oplog.local_map_set(agent, ROOT_CRDT_ID, "reg", CreateValue::NewCRDT(CRDTKind::Register));
+        oplog.local_register_set(agent, reg, CreateValue::Primitive(Primitive::Bool(true)));
+        let set = oplog.local_map_set(agent, ROOT_CRDT_ID, "set", CreateValue::NewCRDT(CRDTKind::Collection));
+        let item = oplog.local_collection_insert(agent, set, CreateValue::Primitive(Primitive::Nil));
+        oplog.local_collection_remove(agent, set, item);
+        let counter = oplog.local_map_set(agent, ROOT_CRDT_ID, "counter", CreateValue::NewCRDT(CRDTKind::Counter));
+        oplog.local_counter_increment(agent, counter, 5);
+        let list = oplog.local_map_set(agent, ROOT_CRDT_ID, "list", CreateValue::NewCRDT(CRDTKind::List));
+        oplog.local_list_insert(agent, list, 0, CreateValue::Primitive(Primitive::I64(1)));
+        oplog.local_list_insert(agent, list, 1, CreateValue::Primitive(Primitive::I64(2)));
+        oplog.local_list_delete(agent, list, 0..1);
+        oplog
+    }
+
+    #[test]
+    fn hashes_match_after_merge() {
+        let mut a = make_oplog("seph");
+        let mut b = make_oplog("mike");
+
+        b.merge_ops(a.ops_since_with_hashes(&[])).unwrap();
+        a.merge_ops(b.ops_since_with_hashes(&[])).unwrap();
+        a.dbg_check(true);
+        assert_eq!(a.frontier_hash(a.cg.version.as_ref()), b.frontier_hash(b.cg.version.as_ref()));
+
+        // Merging the same changes again is fine.
+        let hash = a.frontier_hash(a.cg.version.as_ref());
+        a.merge_ops(b.ops_since_with_hashes(&[])).unwrap();
+        a.cache_version_hashes();
+        assert_eq!(a.frontier_hash(a.cg.version.as_ref()), hash);
+
+        let agent = a.cg.get_or_create_agent_id("seph");
+        a.local_map_set(agent, ROOT_CRDT_ID, "y", CreateValue::Primitive(Primitive::Nil));
+        let b_version = b.cg.version.clone();
+        b.merge_ops(a.ops_since_with_hashes(b_version.as_ref())).unwrap();
+        assert_eq!(a.frontier_hash(a.cg.version.as_ref()), b.frontier_hash(b.cg.version.as_ref()));
+    }
+
+    #[test]
+    fn conflicting_ids_are_rejected() {
+        let mut a = make_oplog("seph");
+        let mut b = OpLog::new();
+        let seph = b.cg.get_or_create_agent_id("seph");
+        b.local_map_set(seph, ROOT_CRDT_ID, "x", CreateValue::Primitive(Primitive::I64(11)));
+
+        // b reuses the ID of a's first operation. The first operation is already known, so without
+        // hashes it would be silently ignored.
+        let before = a.checkout();
+        assert_eq!(a.merge_ops(b.ops_since_with_hashes(&[])), Err(ParseError::HashMismatch));
+        assert_eq!(a.checkout(), before);
+        a.dbg_check(true);
+
+        assert_eq!(b.merge_ops(a.ops_since_with_hashes(&[])), Err(ParseError::HashMismatch));
+        assert_eq!(b.cg.len(), 1);
+        b.dbg_check(true);
+
+        // Without hashes, the conflict isn't noticed.
+        assert!(a.merge_ops(b.ops_since(&[])).is_ok());
+    }
+}
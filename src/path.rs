@@ -0,0 +1,58 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::{CRDTKind, LVKey};
+
+/// A single step in a path through a document, starting from the root map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathSegment<'a> {
+    /// A key in a map CRDT.
+    Key(&'a str),
+    /// An item in a list CRDT (by position) or an item in a collection CRDT (by the item's ID -
+    /// which is the version of the operation which inserted it).
+    Index(usize),
+}
+
+impl<'a> From<&'a str> for PathSegment<'a> {
+    fn from(key: &'a str) -> Self {
+        PathSegment::Key(key)
+    }
+}
+
+impl From<usize> for PathSegment<'_> {
+    fn from(index: usize) -> Self {
+        PathSegment::Index(index)
+    }
+}
+
+/// Errors returned when looking up a value by path (or by CRDT ID) in a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PathError {
+    /// The map key doesn't exist, the index is past the end of the list or the collection doesn't
+    /// contain the named item. Branches don't keep deleted values, so path lookups in a branch
+    /// return this for deleted values too.
+    MissingKey,
+    /// The path names a primitive value, but a CRDT was expected.
+    NotCRDT,
+    /// The path names a CRDT of a different kind than the one expected.
+    WrongKind { expected: CRDTKind, actual: CRDTKind },
+    /// The named CRDT has been deleted.
+    Deleted,
+    /// The path segment can't be used to look inside this kind of CRDT. (Eg, a key in a list, or
+    /// anything inside a text document).
+    InvalidSegment,
+}
+
+impl Display for PathError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PathError {:?}", self)
+    }
+}
+
+impl Error for PathError {}
+
+/// Check the CRDT found at some path has the expected kind.
+pub(crate) fn expect_kind((kind, crdt): (CRDTKind, LVKey), expected: CRDTKind) -> Result<LVKey, PathError> {
+    if kind == expected { Ok(crdt) }
+    else { Err(PathError::WrongKind { expected, actual: kind }) }
+}
@@ -0,0 +1,367 @@
+//! Access control for operations merged from remote peers.
+//!
+//! A [`MergePolicy`] is consulted when remote changes are merged into a
+//! [`ListOpLog`](crate::list::ListOpLog) (via `decode_and_add_opts`) or an [`OpLog`](crate::OpLog)
+//! (via `merge_ops_opts`). The policy is called once for each span of new versions from a single
+//! agent, after the changes have been parsed and checked but before anything is committed.
+//!
+//! Rejecting a span drops it along with every new version which depends on it. The rest of the
+//! changes are still merged, and are renumbered to follow on from the existing versions.
+
+use std::fmt::{Debug, Formatter};
+use rle::{HasLength, SplitableSpan};
+use crate::{AgentId, CausalGraph, CreateValue, DTRange, Frontier, LV, LVKey};
+use crate::causalgraph::agent_span::AgentSpan;
+use crate::encoding::parseerror::ParseError;
+use crate::list::operation::TextOperation;
+use crate::rle::{KVPair, RleVec};
+
+/// What to do with a span of incoming operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyDecision {
+    /// Allow the operations to be merged.
+    Accept,
+    /// Silently drop the operations, along with any incoming operations which depend on them. The
+    /// merge succeeds, and the rest of the changes are added.
+    Reject,
+    /// Drop all of the incoming changes and fail the merge with [`ParseError::PolicyRejected`].
+    RejectWithError,
+}
+
+/// A single operation in a span passed to a [`MergePolicy`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum PolicyOp<'a> {
+    /// Set a key in a map CRDT.
+    MapSet { crdt: LVKey, key: &'a str, value: &'a CreateValue },
+    /// Set the value of a register CRDT.
+    RegisterSet { crdt: LVKey, value: &'a CreateValue },
+    /// Insert an item into a collection CRDT.
+    CollectionInsert { crdt: LVKey, value: &'a CreateValue },
+    /// Remove the item inserted at version `target` from a collection CRDT.
+    CollectionRemove { crdt: LVKey, target: LV },
+    /// Increment a counter CRDT.
+    CounterIncrement { crdt: LVKey, amount: i64 },
+    /// Edit a text CRDT. Operations merged into a `ListOpLog` use [`ROOT_CRDT_ID`](crate::ROOT_CRDT_ID)
+    /// for the CRDT, since there's only one document.
+    Text { crdt: LVKey, op: TextOperation },
+    /// Insert or delete items in a list CRDT. For inserts, `values` contains the inserted values.
+    List { crdt: LVKey, op: TextOperation, values: &'a [CreateValue] },
+}
+
+/// A run of new versions from a single agent, along with the operations at those versions (in
+/// version order).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicySpan<'a> {
+    pub agent: &'a str,
+    pub seq_range: DTRange,
+    pub ops: Vec<PolicyOp<'a>>,
+}
+
+/// Decides which remote operations are allowed to be merged.
+///
+/// This is implemented for any `Fn(&PolicySpan) -> PolicyDecision`.
+pub trait MergePolicy {
+    fn check_span(&self, span: &PolicySpan) -> PolicyDecision;
+}
+
+impl<F: Fn(&PolicySpan) -> PolicyDecision> MergePolicy for F {
+    fn check_span(&self, span: &PolicySpan) -> PolicyDecision {
+        self(span)
+    }
+}
+
+impl Debug for dyn MergePolicy + '_ {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MergePolicy")
+    }
+}
+
+/// Run the policy over the new versions in the causal graph. `ops` names the local version of
+/// each operation. Returns the new versions which should be dropped (in order) - the spans which
+/// were rejected, along with all of their descendants.
+pub(crate) fn check_policy(policy: &dyn MergePolicy, cg: &CausalGraph, new_range: DTRange, mut ops: Vec<(LV, PolicyOp)>) -> Result<Vec<DTRange>, ParseError> {
+    ops.sort_by_key(|(v, _)| *v);
+    let mut ops = ops.into_iter().peekable();
+    let mut rejected_spans = vec![];
+
+    for entry in cg.agent_assignment.client_with_lv.iter_range(new_range) {
+        let end = entry.0 + entry.1.seq_range.len();
+        let mut span_ops = vec![];
+        while let Some((_, op)) = ops.next_if(|(v, _)| *v < end) {
+            span_ops.push(op);
+        }
+
+        let span = PolicySpan {
+            agent: cg.agent_assignment.get_agent_name(entry.1.agent),
+            seq_range: entry.1.seq_range,
+            ops: span_ops,
+        };
+        match policy.check_span(&span) {
+            PolicyDecision::Accept => {}
+            PolicyDecision::Reject => { rejected_spans.push(DTRange::from(entry.0..end)); }
+            PolicyDecision::RejectWithError => { return Err(ParseError::PolicyRejected); }
+        }
+    }
+
+    // Anything which depends on a rejected version is rejected too. Within a graph entry each
+    // version depends on the one before it, so once a version is rejected the rest of the entry is
+    // rejected along with it.
+    let mut rejected: Vec<DTRange> = vec![];
+    let mut spans = rejected_spans.into_iter().peekable();
+    for entry in cg.graph.iter_range(new_range) {
+        let span = entry.span;
+        while spans.next_if(|r| r.end <= span.start).is_some() {}
+
+        let start = if entry.parents.iter().any(|p| ranges_contain(&rejected, *p)) {
+            Some(span.start)
+        } else {
+            spans.peek().filter(|r| r.start < span.end).map(|r| r.start.max(span.start))
+        };
+
+        if let Some(start) = start {
+            match rejected.last_mut() {
+                Some(last) if last.end == start => { last.end = span.end; }
+                _ => { rejected.push((start..span.end).into()); }
+            }
+        }
+    }
+
+    Ok(rejected)
+}
+
+fn ranges_contain(ranges: &[DTRange], v: LV) -> bool {
+    let idx = ranges.partition_point(|r| r.end <= v);
+    ranges.get(idx).is_some_and(|r| r.start <= v)
+}
+
+/// Maps the new versions which were kept after a merge policy rejected some of them to their new
+/// local versions.
+#[derive(Debug, Clone)]
+pub(crate) struct VersionRemap {
+    /// Versions before start are unchanged.
+    start: LV,
+    /// Map from (old) kept versions to their new local versions.
+    map: RleVec<KVPair<DTRange>>,
+}
+
+impl VersionRemap {
+    /// Get the new local version of v, or None if v was dropped.
+    pub(crate) fn get(&self, v: LV) -> Option<LV> {
+        if v < self.start { return Some(v); }
+        self.map.find_with_offset(v).map(|(KVPair(_, range), offset)| range.start + offset)
+    }
+
+    /// Iterate over the ranges of old versions which were kept, in order.
+    pub(crate) fn kept_ranges(&self) -> impl Iterator<Item = DTRange> + '_ {
+        self.map.iter().map(|KVPair(start, new_range)| (*start..*start + new_range.len()).into())
+    }
+
+    /// Split an operation which spans the named range of old versions into the pieces which were
+    /// kept. Each piece is returned along with its offset in the operation and its new versions.
+    pub(crate) fn split_op<T: SplitableSpan + HasLength>(&self, range: DTRange, op: T) -> Vec<(usize, DTRange, T)> {
+        let mut result = vec![];
+        let mut rest = Some(op);
+        let mut pos = range.start;
+        for KVPair(old_start, new_range) in self.map.iter_range(range) {
+            let mut piece = rest.take().unwrap();
+            if old_start > pos {
+                piece.truncate_keeping_right(old_start - pos);
+            }
+            if piece.len() > new_range.len() {
+                rest = Some(piece.truncate(new_range.len()));
+            }
+            pos = old_start + new_range.len();
+            result.push((old_start - range.start, new_range, piece));
+        }
+        result
+    }
+
+    /// Drop the items for removed versions from a list with an item for each version from `first`.
+    #[cfg(feature = "version_hashes")]
+    pub(crate) fn retain_kept<T>(&self, items: &mut Vec<T>, first: LV) {
+        let mut v = first;
+        items.retain(|_| {
+            v += 1;
+            self.get(v - 1).is_some()
+        });
+    }
+}
+
+impl CausalGraph {
+    /// Remove the rejected versions returned by [`check_policy`] from the causal graph. The kept
+    /// versions from `start` onwards are renumbered so they follow on from each other. `version`
+    /// and `num_agents` are the causal graph's version and number of agents before `start`.
+    pub(crate) fn remove_rejected(&mut self, start: LV, rejected: &[DTRange], version: Frontier, num_agents: AgentId) -> VersionRemap {
+        let end = self.len();
+        let mut remap = VersionRemap { start, map: RleVec::new() };
+        let mut next = start;
+        let mut old_start = start;
+        for r in rejected.iter().copied().chain(std::iter::once((end..end).into())) {
+            if r.start > old_start {
+                let len = r.start - old_start;
+                remap.map.push(KVPair(old_start, (next..next + len).into()));
+                next += len;
+            }
+            old_start = r.end;
+        }
+
+        // The kept entries are read out before truncating, since agents might be renumbered.
+        let mut entries = vec![];
+        for kept in remap.kept_ranges() {
+            for entry in self.graph.iter_range(kept) {
+                let mut parents = Frontier::from_unsorted_iter(entry.parents.iter().map(|p| remap.get(*p).unwrap()));
+                for KVPair(v, span) in self.agent_assignment.client_with_lv.iter_range(entry.span) {
+                    let name = self.agent_assignment.get_agent_name(span.agent).to_string();
+                    let new_v = remap.get(v).unwrap();
+                    entries.push((parents, name, span.seq_range));
+                    parents = Frontier::new_1(new_v + span.len() - 1);
+                }
+            }
+        }
+
+        self.truncate(start, version, num_agents);
+        for (parents, name, seq_range) in entries {
+            let agent = self.get_or_create_agent_id(&name);
+            self.merge_and_assign_nonoverlapping(parents.as_ref(), AgentSpan { agent, seq_range });
+        }
+        debug_assert_eq!(self.len(), next);
+
+        remap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use crate::{CRDTKind, CreateValue, DTRange, MergeOptions, OpLog, Primitive, ROOT_CRDT_ID};
+    use crate::encoding::parseerror::ParseError;
+    use crate::list::encoding::{DecodeOptions, ENCODE_PATCH};
+    use crate::list::ListOpLog;
+    use crate::list::operation::TextOperation;
+    use super::*;
+
+    fn reject_viewer(decision: PolicyDecision) -> impl Fn(&PolicySpan) -> PolicyDecision {
+        move |span: &PolicySpan| {
+            if span.agent == "viewer" { decision } else { PolicyDecision::Accept }
+        }
+    }
+
+    #[test]
+    fn list_policy_rejects_viewer() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        let viewer = a.get_or_create_agent_id("viewer");
+        a.add_insert(seph, 0, "hi");
+        let editor_data = a.encode(&ENCODE_PATCH);
+        a.add_insert(viewer, 2, " there");
+        // seph's next edit is concurrent with the viewer's, but the edit after that depends on it.
+        a.add_insert_at(seph, &[1], 0, "x");
+        a.add_insert(seph, 9, "!");
+        let data = a.encode(&ENCODE_PATCH);
+
+        let spans = RefCell::new(vec![]);
+        let record = |span: &PolicySpan| {
+            let ops: Vec<TextOperation> = span.ops.iter().map(|op| match op {
+                PolicyOp::Text { crdt: ROOT_CRDT_ID, op } => op.clone(),
+                _ => panic!("Unexpected op"),
+            }).collect();
+            spans.borrow_mut().push((span.agent.to_string(), span.seq_range, ops));
+            PolicyDecision::Accept
+        };
+        let mut b = ListOpLog::new();
+        b.decode_and_add_opts(&data, DecodeOptions { policy: Some(&record), ..Default::default() }).unwrap();
+        assert_eq!(b, a);
+        assert_eq!(spans.into_inner(), vec![
+            ("seph".into(), DTRange::from(0..2), vec![TextOperation::new_insert(0, "hi")]),
+            ("viewer".into(), (0..6).into(), vec![TextOperation::new_insert(2, " there")]),
+            ("seph".into(), (2..4).into(), vec![TextOperation::new_insert(0, "x"), TextOperation::new_insert(9, "!")]),
+        ]);
+
+        let mut b = ListOpLog::new();
+        let policy = reject_viewer(PolicyDecision::Reject);
+        let opts = DecodeOptions { policy: Some(&policy), ..Default::default() };
+        b.decode_and_add_opts(&editor_data, opts.clone()).unwrap();
+        let expected = b.clone();
+
+        // The viewer's edit is dropped along with seph's edit which depends on it. seph's other edit
+        // is kept.
+        let mut expected_partial = expected.clone();
+        let seph = expected_partial.get_or_create_agent_id("seph");
+        expected_partial.add_insert(seph, 0, "x");
+        assert_eq!(b.clone().decode_and_add_opts(&data, opts.clone()).unwrap(), expected_partial.cg.version);
+        let mut partial = b.clone();
+        partial.decode_and_add_opts(&data, opts.clone()).unwrap();
+        assert_eq!(partial, expected_partial);
+        assert_eq!(partial.checkout_tip().content().to_string(), "xhi");
+        partial.dbg_check(true);
+        assert_eq!(ListOpLog::load_from_opts(&data, opts.clone()).unwrap(), expected_partial);
+
+        // The rest of the changes can still be merged in later.
+        partial.decode_and_add(&data).unwrap();
+        assert_eq!(partial.checkout_tip().content(), a.checkout_tip().content());
+        partial.dbg_check(true);
+
+        // If everything new is rejected, nothing is added.
+        let viewer_data = a.encode_from(&ENCODE_PATCH, &[1]);
+        let reject_all = |_: &PolicySpan| PolicyDecision::Reject;
+        let opts_all = DecodeOptions { policy: Some(&reject_all), ..Default::default() };
+        assert_eq!(b.decode_and_add_opts(&viewer_data, opts_all).unwrap(), b.cg.version);
+        assert_eq!(b, expected);
+
+        let policy = reject_viewer(PolicyDecision::RejectWithError);
+        let opts = DecodeOptions { policy: Some(&policy), ..Default::default() };
+        assert_eq!(b.decode_and_add_opts(&data, opts.clone()), Err(ParseError::PolicyRejected));
+        assert_eq!(b, expected);
+        b.dbg_check(true);
+
+        // Versions we already have aren't checked again.
+        a.decode_and_add_opts(&data, opts).unwrap();
+    }
+
+    #[test]
+    fn oplog_policy_protects_map_key() {
+        let mut a = OpLog::new();
+        let seph = a.cg.get_or_create_agent_id("seph");
+        let text = a.local_map_set(seph, ROOT_CRDT_ID, "content", CreateValue::NewCRDT(CRDTKind::Text));
+        a.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+        let seph_only = a.clone();
+
+        let mike = a.cg.get_or_create_agent_id("mike");
+        a.local_text_op(mike, text, TextOperation::new_insert(2, " there"));
+        a.local_map_set(mike, ROOT_CRDT_ID, "owner", CreateValue::Primitive(Primitive::Str("mike".into())));
+
+        let protect_owner = |span: &PolicySpan| {
+            let protected = span.ops.iter().any(|op| matches!(op, PolicyOp::MapSet { crdt: ROOT_CRDT_ID, key: "owner", .. }));
+            if protected && span.agent != "seph" { PolicyDecision::RejectWithError } else { PolicyDecision::Accept }
+        };
+        let opts = MergeOptions { policy: Some(&protect_owner), ..Default::default() };
+
+        let mut b = OpLog::new();
+        assert_eq!(b.merge_ops_opts(a.ops_since(&[]), opts.clone()), Err(ParseError::PolicyRejected));
+        assert_eq!(b.cg.len(), 0);
+        b.dbg_check(true);
+
+        assert_eq!(b.merge_ops_opts(seph_only.ops_since(&[]), opts.clone()).unwrap(), (0..3).into());
+
+        // Rejecting mike's changes keeps seph's changes which don't depend on them, including a
+        // new CRDT. They're renumbered to follow on from the versions b already has.
+        let mut seph_more = seph_only.clone();
+        seph_more.local_text_op(seph, text, TextOperation::new_insert(0, "oh "));
+        let list = seph_more.local_map_set(seph, ROOT_CRDT_ID, "list", CreateValue::NewCRDT(CRDTKind::List));
+        seph_more.local_list_insert(seph, list, 0, CreateValue::Primitive(Primitive::I64(1)));
+        a.merge_ops(seph_more.ops_since(&[])).unwrap();
+        a.local_text_op(seph, text, TextOperation::new_insert(0, "!"));
+
+        let reject = |span: &PolicySpan| {
+            if span.agent == "mike" { PolicyDecision::Reject } else { PolicyDecision::Accept }
+        };
+        let r = b.merge_ops_opts(a.ops_since(&[]), MergeOptions { policy: Some(&reject), ..Default::default() }).unwrap();
+        assert_eq!(r, (3..seph_more.cg.len()).into());
+        assert_eq!(b.checkout(), seph_more.checkout());
+        b.dbg_check(true);
+
+        b.merge_ops(a.ops_since(&[])).unwrap();
+        assert_eq!(b.checkout(), a.checkout());
+    }
+}
@@ -140,7 +140,8 @@ pub fn try_trim<V>(mut x: V, target_span: DTRange) -> Option<V>
 
     if x_span.end > target_span.end {
         if x_span.start >= target_span.end { return None; }
-        x.truncate(target_span.end - x_span.start);
+        // The start of x might have been trimmed above.
+        x.truncate(target_span.end - x.rle_key());
     }
 
     Some(x)
@@ -203,4 +204,20 @@ pub fn trim<V>(val: V, span: DTRange) -> V
 //             len: 5
 //         }));
 //     }
-// }
\ No newline at end of file
+// }
+#[cfg(test)]
+mod trim_test {
+    use crate::dtrange::DTRange;
+    use crate::rle::{KVPair, try_trim};
+
+    #[test]
+    fn trim_both_ends() {
+        let x = KVPair(10, DTRange::from(100..110));
+        assert_eq!(try_trim(x, (12..15).into()), Some(KVPair(12, (102..105).into())));
+        assert_eq!(try_trim(x, (5..12).into()), Some(KVPair(10, (100..102).into())));
+        assert_eq!(try_trim(x, (18..30).into()), Some(KVPair(18, (108..110).into())));
+        assert_eq!(try_trim(x, (0..30).into()), Some(x));
+        assert_eq!(try_trim(x, (20..30).into()), None);
+        assert_eq!(try_trim(x, (0..10).into()), None);
+    }
+}
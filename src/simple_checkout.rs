@@ -6,6 +6,8 @@ use smartstring::alias::String as SmartString;
 pub enum SimpleVal {
     Text(String),
     Map(BTreeMap<SmartString, Box<SimpleVal>>),
+    Collection(BTreeMap<LV, Box<SimpleVal>>),
+    List(Vec<SimpleVal>),
     Primitive(Primitive),
 }
 
@@ -16,24 +18,26 @@ impl Branch {
                 let mut map = BTreeMap::new();
                 for (key, state) in self.maps.get(&key).unwrap() {
                     // TODO: Rewrite this as an iterator map then collect().
-                    map.insert(key.clone(), Box::new(match &state.value {
-                        RegisterValue::Primitive(primitive) => {
-                            SimpleVal::Primitive(primitive.clone())
-                        }
-                        RegisterValue::OwnedCRDT(inner_kind, inner_key) => {
-                            self.simple_val_at(*inner_key, *inner_kind)
-                        }
-                    }));
+                    map.insert(key.clone(), Box::new(self.simple_val_for(&state.value)));
                 }
                 SimpleVal::Map(map)
             }
             CRDTKind::Register => {
-                // TODO
-                SimpleVal::Primitive(Primitive::Nil)
+                // Conflicting values are discarded in the simple view.
+                self.simple_val_for(&self.registers.get(&key).unwrap().value)
             }
             CRDTKind::Collection => {
-                // todo!();
-                SimpleVal::Primitive(Primitive::Nil)
+                SimpleVal::Collection(self.collections.get(&key).unwrap().iter().map(|(item, val)| {
+                    (*item, Box::new(self.simple_val_for(val)))
+                }).collect())
+            }
+            CRDTKind::List => {
+                SimpleVal::List(self.lists.get(&key).unwrap().iter().map(|val| {
+                    self.simple_val_for(val)
+                }).collect())
+            }
+            CRDTKind::Counter => {
+                SimpleVal::Primitive(Primitive::I64(*self.counters.get(&key).unwrap()))
             }
             CRDTKind::Text => {
                 SimpleVal::Text(self.texts.get(&key).unwrap().to_string())
@@ -41,6 +45,17 @@ impl Branch {
         }
     }
 
+    fn simple_val_for(&self, val: &RegisterValue) -> SimpleVal {
+        match val {
+            RegisterValue::Primitive(primitive) => {
+                SimpleVal::Primitive(primitive.clone())
+            }
+            RegisterValue::OwnedCRDT(inner_kind, inner_key) => {
+                self.simple_val_at(*inner_key, *inner_kind)
+            }
+        }
+    }
+
     pub fn simple_val(&self) -> SimpleVal {
         self.simple_val_at(ROOT_CRDT_ID, CRDTKind::Map)
     }
@@ -102,6 +102,26 @@ pub mod test {
         Write(usize, Vec<u8>),
     }
 
+    /// How a simulated power failure affects the write which was in flight when it happened.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub enum CrashMode {
+        /// The write never made it to disk.
+        Lost,
+        /// Only the first half of the write made it to disk.
+        Torn,
+        /// The write made it to disk, but the other writes since the last barrier didn't. (The
+        /// drive is allowed to reorder writes between barriers.)
+        Reordered,
+    }
+
+    fn apply_write(committed: &mut Vec<u8>, offset: usize, data: &[u8]) {
+        let end = offset + data.len();
+        if committed.len() < end {
+            committed.resize(end, 0);
+        }
+        committed[offset..end].copy_from_slice(data);
+    }
+
     /// Testing files here have 2 uses:
     ///
     /// 1. Its used to test saving and loading without needing to actually create and destroy files
@@ -122,6 +142,13 @@ pub mod test {
 
         // rng, per_write_crash_chance.
         failure_rng: Option<(SmallRng, f64)>,
+
+        /// Every write and barrier since the file was created. Syncs are recorded as barriers. This
+        /// is used to reconstruct what the file could contain after a crash.
+        history: Vec<UncommittedEntry>,
+
+        /// The number of reads made from the file.
+        reads: usize,
     }
 
     impl TestFile {
@@ -134,6 +161,8 @@ pub mod test {
                 committed: vec![],
                 uncommitted: vec![],
                 failure_rng: Some((SmallRng::seed_from_u64(seed), failure_rate)),
+                history: vec![],
+                reads: 0,
             }
         }
 
@@ -149,12 +178,64 @@ pub mod test {
             let writes = replace(&mut self.uncommitted, vec![]);
             for e in writes {
                 let UncommittedEntry::Write(offset, write_data) = e else { continue };
+                apply_write(&mut self.committed, offset, &write_data);
+            }
+        }
+
+        /// The number of writes made to the file since it was created.
+        pub fn num_writes(&self) -> usize {
+            self.history.iter()
+                .filter(|e| matches!(e, UncommittedEntry::Write(..)))
+                .count()
+        }
 
-                let end = offset + write_data.len();
-                if self.committed.len() < end {
-                    self.committed.resize(end, 0);
+        /// The number of reads made from the file (or the file it was cloned from).
+        pub fn num_reads(&self) -> usize {
+            self.reads
+        }
+
+        /// Simulate a power failure while the write at index `write_idx` was happening, and return
+        /// what the file would contain when the computer restarts. Writes before the last barrier
+        /// are all on disk, and nothing after the crashed write is. Passing
+        /// `write_idx == num_writes()` returns the file with every write applied.
+        pub fn crash_at(&self, write_idx: usize, mode: CrashMode) -> TestFile {
+            let mut committed = vec![];
+            // Writes since the last barrier.
+            let mut pending: Vec<(usize, &[u8])> = vec![];
+            let mut n = 0;
+
+            for e in self.history.iter() {
+                match e {
+                    UncommittedEntry::Barrier => {
+                        for (offset, data) in pending.drain(..) {
+                            apply_write(&mut committed, offset, data);
+                        }
+                    }
+                    UncommittedEntry::Write(offset, data) => {
+                        if n == write_idx {
+                            if mode == CrashMode::Reordered { pending.clear(); }
+                            for (offset, data) in pending.drain(..) {
+                                apply_write(&mut committed, offset, data);
+                            }
+                            match mode {
+                                CrashMode::Lost => {}
+                                CrashMode::Torn => apply_write(&mut committed, *offset, &data[..data.len() / 2]),
+                                CrashMode::Reordered => apply_write(&mut committed, *offset, data),
+                            }
+                            break;
+                        }
+                        pending.push((*offset, data));
+                        n += 1;
+                    }
                 }
-                self.committed[offset..end].copy_from_slice(&write_data);
+            }
+            for (offset, data) in pending {
+                apply_write(&mut committed, offset, data);
+            }
+
+            TestFile {
+                committed,
+                ..TestFile::default()
             }
         }
 
@@ -201,11 +282,7 @@ pub mod test {
                         }
                     }
 
-                    let end = offset + data.len();
-                    if self.committed.len() < end {
-                        self.committed.resize(end, 0);
-                    }
-                    self.committed[offset..end].copy_from_slice(data);
+                    apply_write(&mut self.committed, offset, data);
                 }
 
                 if crash_here {
@@ -224,8 +301,9 @@ pub mod test {
 
         fn write_all_at(&mut self, write_data: &[u8], offset: u64) -> io::Result<()> {
             // Just add the uncommitted data to the queue.
-            self.uncommitted
-                .push(UncommittedEntry::Write(offset as usize, write_data.into()));
+            let entry = UncommittedEntry::Write(offset as usize, write_data.into());
+            self.history.push(entry.clone());
+            self.uncommitted.push(entry);
 
             Ok(())
         }
@@ -233,6 +311,7 @@ pub mod test {
         fn read_all_at(&mut self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
             // Linux guarantees that if you write then immediately read, you'll see your written
             // data. So read_all_at() here will return data from uncommitted blocks too.
+            self.reads += 1;
             buffer.fill(0);
             let mut last_read_pos = 0;
 
@@ -271,11 +350,13 @@ pub mod test {
         }
 
         fn write_barrier(&mut self) -> io::Result<()> {
+            self.history.push(UncommittedEntry::Barrier);
             self.uncommitted.push(UncommittedEntry::Barrier);
             Ok(())
         }
 
         fn sync_data(&mut self) -> io::Result<()> {
+            self.history.push(UncommittedEntry::Barrier);
             self.sync_and_maybe_crash()
         }
     }
@@ -323,4 +404,25 @@ pub mod test {
             }
         }
     }
+
+    #[test]
+    fn crash_at_write() {
+        let mut file = TestFile::new();
+        file.write_all_at(&[1, 1], 0).unwrap();
+        file.sync_data().unwrap();
+        file.write_all_at(&[2, 2], 2).unwrap();
+        file.write_all_at(&[3, 3], 4).unwrap();
+        file.write_barrier().unwrap();
+        file.write_all_at(&[4, 4], 0).unwrap();
+        assert_eq!(file.num_writes(), 4);
+
+        let contents = |idx, mode| file.crash_at(idx, mode).contents().to_vec();
+        assert_eq!(contents(0, CrashMode::Lost), Vec::<u8>::new());
+        assert_eq!(contents(0, CrashMode::Torn), vec![1]);
+        assert_eq!(contents(2, CrashMode::Lost), vec![1, 1, 2, 2]);
+        assert_eq!(contents(2, CrashMode::Torn), vec![1, 1, 2, 2, 3]);
+        assert_eq!(contents(2, CrashMode::Reordered), vec![1, 1, 0, 0, 3, 3]);
+        assert_eq!(contents(3, CrashMode::Reordered), vec![4, 4, 2, 2, 3, 3]);
+        assert_eq!(contents(4, CrashMode::Lost), vec![4, 4, 2, 2, 3, 3]);
+    }
 }
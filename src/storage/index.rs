@@ -0,0 +1,226 @@
+//! The page index for each type of data is stored in the data pages themselves.
+//!
+//! Each data page is indexed by the key of its first record. When a page starts with a key, any
+//! records with that key or larger on earlier pages are replaced - so at any point, the index is a
+//! list of pages sorted by key, where every page holds the current records from its key up to the
+//! next page's key.
+//!
+//! When the first record is written to a page, the page stores a link to the entry before it in the
+//! index (its parent) and a "jump" link further back. The jump links use Myers' skew-binary scheme
+//! ("An applicative random-access stack", 1983), so we can find the entry for any key by reading
+//! `O(log n)` pages, starting at the newest entry. Entries never change once they're written, and
+//! replacing entries only ever removes entries from the end of the list. So the links stored in a
+//! page are still valid for as long as the page is in the index.
+
+use crate::storage::*;
+
+/// A link to an entry in the page index for a data type.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(super) struct IndexPtr {
+    pub(super) key: usize,
+    pub(super) page: PageNum,
+    /// The number of entries before this one in the index.
+    pub(super) depth: usize,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub(super) struct IndexLinks {
+    /// The previous entry in the index. None if this is the first entry.
+    pub(super) parent: Option<IndexPtr>,
+    pub(super) jump: Option<IndexPtr>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(super) struct IndexEntry {
+    pub(super) key: usize,
+    pub(super) page: PageNum,
+    pub(super) links: IndexLinks,
+}
+
+impl IndexEntry {
+    pub(super) fn depth(&self) -> usize {
+        self.links.parent.map_or(0, |p| p.depth + 1)
+    }
+
+    fn ptr(&self) -> IndexPtr {
+        IndexPtr { key: self.key, page: self.page, depth: self.depth() }
+    }
+
+    /// The next entry to visit while looking for the last entry with a key less than `bound`.
+    fn step_towards(&self, bound: usize) -> Option<IndexPtr> {
+        match (self.links.jump, self.links.parent) {
+            // Every entry between here and the jump target has a larger key than it does.
+            (Some(jump), _) if jump.key >= bound => Some(jump),
+            (_, parent) => parent,
+        }
+    }
+}
+
+impl<F: DTFile> StorageEngine<F> {
+    /// Read a data page of the named type. The current page might not have been written to disk
+    /// yet, so it's read from memory.
+    fn read_data_page(&mut self, kind: DataPageType, page_no: PageNum) -> Result<DataPage, SEError> {
+        match self.data_chunks[kind as usize].as_deref() {
+            Some(state) if state.current_page_no == page_no => {
+                let mut page = state.page.clone();
+                page.reset_read_pos();
+                Ok(page)
+            }
+            // Other pages are always written to their own page (not the blit page) when they're
+            // finished.
+            _ => DataPage::try_read_raw(&mut self.file, page_no)?
+                .ok_or(SEError::GenericInvalidData),
+        }
+    }
+
+    fn read_index_entry(&mut self, kind: DataPageType, ptr: IndexPtr) -> Result<(IndexEntry, DataPage), SEError> {
+        let page = self.read_data_page(kind, ptr.page)?;
+        let entry = page.index_entry(kind, ptr.page)?;
+        if entry.key != ptr.key || entry.depth() != ptr.depth {
+            return Err(SEError::GenericInvalidData);
+        }
+        Ok((entry, page))
+    }
+
+    /// Find the last entry in the index with a key less than `bound`, along with its page (unless
+    /// it's the newest entry, which we don't need to read).
+    fn find_index_entry_before(&mut self, kind: DataPageType, bound: usize) -> Result<Option<(IndexEntry, Option<DataPage>)>, SEError> {
+        let Some(mut entry) = self.index_heads[kind as usize] else { return Ok(None); };
+        let mut page = None;
+
+        while entry.key >= bound {
+            let Some(next) = entry.step_towards(bound) else { return Ok(None); };
+            let (next_entry, next_page) = self.read_index_entry(kind, next)?;
+            entry = next_entry;
+            page = Some(next_page);
+        }
+
+        Ok(Some((entry, page)))
+    }
+
+    /// Work out the links for a new index entry with the named key. Any entries with the same or
+    /// larger keys are replaced by the new entry.
+    pub(super) fn new_index_links(&mut self, kind: DataPageType, key: usize) -> Result<IndexLinks, SEError> {
+        let Some((parent, _)) = self.find_index_entry_before(kind, key)? else {
+            return Ok(IndexLinks::default());
+        };
+
+        // If the parent's jump and the jump after that cover the same number of entries, the new
+        // entry jumps over both of them. Otherwise it jumps to its parent.
+        let mut jump = parent.ptr();
+        if let Some(parent_jump) = parent.links.jump {
+            let (jump_entry, _) = self.read_index_entry(kind, parent_jump)?;
+            if let Some(next_jump) = jump_entry.links.jump {
+                if parent.depth() - parent_jump.depth == parent_jump.depth - next_jump.depth {
+                    jump = next_jump;
+                }
+            }
+        }
+
+        Ok(IndexLinks { parent: Some(parent.ptr()), jump: Some(jump) })
+    }
+
+    /// Read the pages which might contain records of this type with keys in the range. This reads
+    /// `O(log n)` pages to find the end of the range, then every page in the range.
+    ///
+    /// Each page is returned along with the range of keys it holds current records for, clamped to
+    /// the end of the range. Records on the page with keys outside that range have been replaced by
+    /// later pages.
+    pub(super) fn read_pages_in_range(&mut self, kind: DataPageType, range: DTRange) -> Result<Vec<(DTRange, DataPage)>, SEError> {
+        let Some((mut entry, mut page)) = self.find_index_entry_before(kind, range.end)? else {
+            return Ok(vec![]);
+        };
+
+        let mut pages = vec![];
+        let mut end = range.end;
+        loop {
+            let p = match page.take() {
+                Some(p) => p,
+                None => self.read_data_page(kind, entry.page)?,
+            };
+            pages.push(((entry.key..end).into(), p));
+            end = entry.key;
+
+            match entry.links.parent {
+                Some(parent) if entry.key > range.start => {
+                    let (parent_entry, parent_page) = self.read_index_entry(kind, parent)?;
+                    entry = parent_entry;
+                    page = Some(parent_page);
+                }
+                _ => break,
+            }
+        }
+
+        pages.reverse();
+        Ok(pages)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use rand::prelude::*;
+    use crate::storage::{DataPageType, PageNum, StorageEngine};
+    use crate::storage::file::test::TestFile;
+
+    #[test]
+    fn index_matches_model() {
+        let mut rng = SmallRng::seed_from_u64(321);
+        let mut se = StorageEngine::from_file(TestFile::new()).unwrap();
+        let kind = DataPageType::Operations;
+
+        // Maps the key at the start of each page to its page number.
+        let mut model = BTreeMap::<usize, PageNum>::new();
+        let data = "some data here ".repeat(10);
+        let mut key = 0;
+        for i in 0..10000 {
+            if rng.gen_bool(0.02) {
+                // Replace some records, as we do when uncommitted data is discarded.
+                key -= rng.gen_range(0..=key.min(200));
+                se.start_new_page(kind).unwrap();
+            }
+            se.append_chunk(kind, &key, data.as_str()).unwrap();
+
+            let state = se.data_chunks[kind as usize].as_deref().unwrap();
+            if model.last_key_value().is_none_or(|(_, page)| *page != state.current_page_no) {
+                model.split_off(&key);
+                model.insert(key, state.current_page_no);
+            }
+            key += rng.gen_range(1..10);
+
+            if i % 300 == 0 { se.fsync().unwrap(); }
+        }
+        se.fsync().unwrap();
+
+        let file = se.file.clone();
+        drop(se);
+        let mut se = StorageEngine::from_file(file.clone()).unwrap();
+        for _ in 0..100 {
+            let start = rng.gen_range(0..key);
+            let end = rng.gen_range(start + 1..=key + 10);
+
+            let expect: Vec<_> = model.range(..end).rev()
+                .take_while(|(k, _)| **k >= start).map(|(_, p)| *p)
+                .chain(model.range(..=start).next_back().filter(|(k, _)| **k < start).map(|(_, p)| *p))
+                .collect::<Vec<_>>().into_iter().rev().collect();
+            let actual: Vec<_> = se.read_pages_in_range(kind, (start..end).into()).unwrap()
+                .into_iter()
+                .map(|(keys, page)| {
+                    let entry = page.index_entry(kind, 0).unwrap();
+                    assert_eq!(keys.start, entry.key);
+                    model[&entry.key]
+                })
+                .collect();
+            assert_eq!(actual, expect);
+        }
+
+        // Opening the file and reading a single record only reads a handful of pages.
+        assert!(model.len() > 200);
+        let reads = file.num_reads();
+        let mut se = StorageEngine::from_file(file).unwrap();
+        let pages = se.read_pages_in_range(kind, (key / 2..key / 2 + 1).into()).unwrap();
+        assert_eq!(pages.len(), 1);
+        let reads = se.file.num_reads() - reads;
+        assert!(reads < 40, "Opening and reading took {reads} page reads");
+    }
+}
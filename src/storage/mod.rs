@@ -4,6 +4,8 @@
 
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io;
 use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
@@ -12,16 +14,26 @@ use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::FileExt;
 
 use std::path::Path;
+use std::mem::replace;
 use num_enum::{TryFromPrimitive, TryFromPrimitiveError};
 use smallvec::{smallvec, SmallVec};
+use crate::DTRange;
+use crate::encoding::bufparser::BufParser;
 use crate::encoding::parseerror::ParseError;
 use crate::encoding::tools::{DTSerializable, ExtendFromSlice, StackWriteBuf, try_push_str, TryExtendFromSlice};
 use crate::encoding::varint::{try_push_u32, try_push_u64, try_push_usize};
-use crate::storage::file::DTFile;
+use crate::storage::index::{IndexEntry, IndexLinks, IndexPtr};
 use crate::storage::page::{BlitStatus, DataPage, DataPageImmutableFields, HeaderPage, Page};
 
 mod page;
 mod file;
+mod index;
+mod persistent;
+mod recovery;
+
+pub use file::DTFile;
+pub use persistent::{ListOpLogReader, PersistentListOpLog};
+pub use recovery::HeaderRecovery;
 
 const SE_MAGIC_BYTES: [u8; 8] = *b"DT_STOR1";
 const SE_VERSION: u32 = 1; // 2 bytes would probably be fine for this but eh.
@@ -39,6 +51,8 @@ pub enum CorruptPageError {
     InvalidHeaderMagicBytes,
     InvalidChecksum,
     VersionTooNew(u16),
+    /// The file was written by an older version of the storage format, which can't be read.
+    UnsupportedVersion(u16),
     InvalidHeaderPageSize(usize),
     PageLengthInvalid(u16),
 }
@@ -66,6 +80,13 @@ pub enum SEError {
     IO(io::Error),
 }
 
+impl Display for SEError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SEError {:?}", self)
+    }
+}
+
+impl Error for SEError {}
 
 impl From<io::Error> for SEError {
     fn from(io_err: io::Error) -> Self {
@@ -96,7 +117,7 @@ impl<E: TryFromPrimitive> From<TryFromPrimitiveError<E>> for SEError {
     }
 }
 
-const NUM_DATA_CHUNK_TYPES: usize = 3;
+const NUM_DATA_CHUNK_TYPES: usize = 5;
 type PageNum = u32;
 
 #[derive(Debug)]
@@ -107,6 +128,13 @@ struct StorageEngine<F: DTFile = File> {
     header_fields: StorageHeaderFields,
     next_free_page: PageNum,
 
+    // Set if the header page was corrupt and had to be rebuilt when the file was opened.
+    recovery: Option<HeaderRecovery>,
+
+    // The newest entry in the page index for each data type. The rest of the index is stored in
+    // the data pages (see the index module). The slot is the chunk type.
+    index_heads: [Option<IndexEntry>; NUM_DATA_CHUNK_TYPES],
+
     // Using a Box<> here because the inlined data pages are 4kb each. Could just box the entire
     // array or something instead? Eh.
     data_chunks: [Option<Box<DataPageState>>; NUM_DATA_CHUNK_TYPES] // The slot is the chunk type.
@@ -133,7 +161,9 @@ struct DataChunkHeaderInfo {
     blit_page: PageNum,
     first_page: PageNum,
 
-    // cur_block: BlockNum,
+    // The last page in the chain which is known to be durably written (or the first page). Pages
+    // before it don't need to be read when the file is opened.
+    last_page: PageNum,
 }
 
 #[derive(Debug)]
@@ -143,6 +173,9 @@ struct DataPageState {
     blit_page: PageNum, // Copied from header info.
     page: DataPage,
     dirty: bool,
+    // Set if nothing has been written to the page yet. The page's cursor is reset when the first
+    // record is written.
+    empty: bool,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -161,6 +194,9 @@ enum PageType {
 enum DataPageType {
     AgentNames = 0,
     CGInfo = 1,
+    Operations = 2,
+    Content = 3,
+    Commit = 4,
     // etc.
 }
 
@@ -197,15 +233,21 @@ impl StorageHeaderFields {
 
 const NEXT_PAGE_BYTE_OFFSET: usize = 4 + 2; // checksum then length.
 
+/// Cursors are indexed by the number they start with.
+fn parse_cursor_key(cursor: &[u8]) -> Option<usize> {
+    BufParser(cursor).next_usize().ok()
+}
+
 // This function does a lot. I could refactor it to pass a visitor function or something, but I'm
 // only using it in this one context so I think its ok.
-fn scan_blocks<F: DTFile>(file: &mut F, header_fields: &StorageHeaderFields) -> Result<(PageNum, [Option<Box<DataPageState>>; NUM_DATA_CHUNK_TYPES]), SEError> {
+#[allow(clippy::type_complexity)]
+fn scan_blocks<F: DTFile>(file: &mut F, header_fields: &StorageHeaderFields) -> Result<(PageNum, [Option<Box<DataPageState>>; NUM_DATA_CHUNK_TYPES], [Option<IndexEntry>; NUM_DATA_CHUNK_TYPES]), SEError> {
     // Ok, now we need to find the next free page.
-    // For now, the file should always be "packed" - that is, there can't be any holes in
-    // the file. I might be able to get away with scanning from the back, but this is
-    // cleaner.
+    // Normally the file is "packed" - that is, there aren't any holes in the file. But if the
+    // header was rebuilt, pages we couldn't recover are left as holes.
     //
-    // For now I'll just scan the whole file, making sure it doesn't have any holes.
+    // Each chain is scanned from the last page the header says was durably written, making sure no
+    // page is used twice. Pages before that are only read on demand.
     // Any allocated / assigned (but unused) blocks are skipped. Blits are skipped. And
     // items which are valid and have a page number assigned are considered to have
     // allocated that block, even if nothing is written at that block number.
@@ -229,7 +271,15 @@ fn scan_blocks<F: DTFile>(file: &mut F, header_fields: &StorageHeaderFields) ->
 
     let mut queue = BinaryHeap::<Item>::new();
     for (kind, info) in header_fields.data_chunk_info_iter() {
-        queue.push(Item(info.first_page, 0, kind, false));
+        // If the last written page is unreadable (which shouldn't happen), scan the whole chain.
+        let start = match info.last_page {
+            page_no if page_no == info.first_page => None,
+            page_no => DataPage::try_read_raw(file, page_no)?
+                .map(|page| page.prev_page().map(|prev| (page_no, prev)))
+                .transpose()?,
+        };
+        let (start, prev) = start.unwrap_or((info.first_page, 0));
+        queue.push(Item(start, prev, kind, false));
         // Only adding the blit pages so we can move past them while checking that the file is
         // packed.
         queue.push(Item(info.blit_page, 0, kind, true));
@@ -238,6 +288,7 @@ fn scan_blocks<F: DTFile>(file: &mut F, header_fields: &StorageHeaderFields) ->
     // dbg!(header_fields);
     const HACK_NONE: Option<Box<DataPageState>> = None;
     let mut data_chunks = [HACK_NONE; NUM_DATA_CHUNK_TYPES];
+    let mut index_heads: [Option<IndexEntry>; NUM_DATA_CHUNK_TYPES] = Default::default();
     // let mut blit_flags = [BlitStatus(u8::MAX); NUM_DATA_CHUNK_TYPES];
     // let mut blit_associated_page = [PageNum; NUM_DATA_CHUNK_TYPES];
 
@@ -261,11 +312,11 @@ fn scan_blocks<F: DTFile>(file: &mut F, header_fields: &StorageHeaderFields) ->
     }
 
     let mut next_page = 1;
-    while let Some(Item(page_no, _prev_page, kind, is_blit)) = queue.pop() {
+    while let Some(Item(page_no, prev_page, kind, is_blit)) = queue.pop() {
         // dbg!((page_no, kind, is_blit));
-        if page_no != next_page {
-            panic!("Ermagherd bad {page_no} {next_page}");
-            // return Err(SEError::GenericInvalidData);
+        if page_no < next_page {
+            // The same page is used more than once.
+            return Err(SEError::GenericInvalidData);
         }
 
         next_page = page_no + 1;
@@ -280,10 +331,11 @@ fn scan_blocks<F: DTFile>(file: &mut F, header_fields: &StorageHeaderFields) ->
 
         if let Some(page) = page.as_ref() {
             let next_page = page.get_next_or_associated_page();
-            println!("Next page {next_page}");
             if next_page != 0 {
                 // The page is valid and it has an assigned next page. Onwards!
-                queue.push(Item(next_page, page_no, kind, false));
+                let kind = DataPageType::try_from(kind as u16)?;
+                index_heads[kind as usize] = Some(page.index_entry(kind, page_no)?);
+                queue.push(Item(next_page, page_no, kind as u32, false));
                 continue;
             }
         }
@@ -310,57 +362,78 @@ fn scan_blocks<F: DTFile>(file: &mut F, header_fields: &StorageHeaderFields) ->
         //     }
         // }
 
-        dbg!((page.is_some(), blit_page.is_some()));
-        let (write_to_blit_next, page_used) = match (page, blit_page) {
+        let (write_to_blit_next, page_used, empty) = match (page, blit_page) {
             (Some(page), Some(blit_page)) => {
                 // Keep the page which is "furthest along".
-                dbg!(page.get_blit_status());
-                dbg!(blit_page.get_blit_status());
                 match page.get_blit_status().partial_cmp(&blit_page.get_blit_status()) {
                     // Use the page version.
                     None => { return Err(SEError::GenericInvalidData); }
                     Some(Ordering::Greater) | Some(Ordering::Equal) => {
-                        println!("page");
                         // Use the page version. If the blits are equal it doesn't matter.
-                        (true, page)
+                        (true, page, false)
                     }
                     Some(Ordering::Less) => {
-                        println!("blit");
                         // Use the blit version.
-                        (false, blit_page)
+                        (false, blit_page, false)
                     }
                 }
             }
             (None, Some(blit_page)) => {
-                (false, blit_page)
+                (false, blit_page, false)
             }
             (Some(page), None) => {
-                (true, page)
+                (true, page, false)
             }
             (None, None) => {
-                // This is a tricky one. In this case, the next page was allocated but is either
-                // corrupt or was never written to. We'll just leave the DataPageState in its
-                // current configuration - I think thats correct?
-                continue;
-
-                // (true, DataPage::new(DataPageImmutableFields {
-                //                         kind: (kind as u16).try_into().unwrap(),
-                //                         prev_page,
-                //                     },
-                // ))
+                // This is a tricky one. In this case, the page was allocated but is either
+                // corrupt or was never written to. Any data it had was never synced, so we'll
+                // start a new, empty page in its place. (If we didn't, the next write would
+                // allocate a whole new chain of pages for this data type and orphan this one.)
+                //
+                // The cursor data used to create the page has been lost. It'll be replaced when
+                // the first record is written to the page.
+                (false, DataPage::new(DataPageImmutableFields {
+                    kind: DataPageType::try_from(kind as u16)?,
+                    prev_page,
+                    links: IndexLinks::default(),
+                }, &[]), true)
             }
         };
 
+        if !empty {
+            let kind = DataPageType::try_from(kind as u16)?;
+            index_heads[kind as usize] = Some(page_used.index_entry(kind, page_no)?);
+        }
+
         data_chunks[kind as usize] = Some(Box::new(DataPageState {
             current_page_no: page_no,
             write_to_blit_next,
             blit_page: blit_page_no,
             page: page_used,
             dirty: false,
+            empty,
         }));
     }
 
-    Ok((next_page, data_chunks))
+    Ok((next_page, data_chunks, index_heads))
+}
+
+/// We only rebuild a corrupt header page if the file looks like one of ours. Otherwise we'd
+/// clobber some other file. The magic bytes must be intact, or zeroed (never written).
+fn header_recoverable<F: DTFile>(file: &mut F, err: &SEError) -> Result<bool, SEError> {
+    let corrupt = match err {
+        SEError::PageIsCorrupt(CorruptPageError::InvalidHeaderMagicBytes
+            | CorruptPageError::InvalidChecksum
+            | CorruptPageError::PageLengthInvalid(_)) => true,
+        SEError::IO(e) => e.kind() == ErrorKind::UnexpectedEof,
+        _ => false,
+    };
+    if !corrupt { return Ok(false); }
+
+    let len = file.stream_len()?.min(SE_MAGIC_BYTES.len() as u64) as usize;
+    let mut magic = [0u8; SE_MAGIC_BYTES.len()];
+    file.read_all_at(&mut magic[..len], 0)?;
+    Ok(magic[..len] == SE_MAGIC_BYTES[..len] || magic[..len].iter().all(|b| *b == 0))
 }
 
 impl StorageEngine<File> {
@@ -383,7 +456,6 @@ impl<F: DTFile> StorageEngine<F> {
         // let (header_fields, next_free_page, data_chunks) = Self::read_or_initialize_header(&mut file, total_len)?;
         
         if total_len == 0 {
-            println!("Initializing headers");
             // Presumably a new file. Initialize it using the default options.
             let header_fields = StorageHeaderFields::default();
 
@@ -398,31 +470,47 @@ impl<F: DTFile> StorageEngine<F> {
                 header_dirty: false,
                 header_fields,
                 next_free_page: 1,
+                recovery: None,
+                index_heads: Default::default(),
                 data_chunks: [HACK_NONE; NUM_DATA_CHUNK_TYPES],
             })
         } else {
-            println!("Parsing fields");
-            // Parse the header page.
-            let header_fields = HeaderPage::read(&mut file, 0)?;
-            // TODO: If the header page has an invalid checksum, we should now search the file for
-            // the backup header page and load that instead.
+            // Parse the header page. If its corrupt (eg, we crashed while writing it), rebuild it
+            // from the data pages instead.
+            let (header_fields, recovery) = match HeaderPage::read(&mut file, 0) {
+                Ok(header_fields) => (header_fields, None),
+                Err(e) if header_recoverable(&mut file, &e)? => {
+                    // The recovery is reported to the caller via header_recovery().
+                    let (header_fields, recovery) = recovery::rebuild_header(&mut file)?;
+                    (header_fields, Some(recovery))
+                }
+                Err(e) => { return Err(e); }
+            };
 
             // TODO: It would be better if I didn't have to do this, but eh.
             // let last_page_for_type
             // let data_chunks = [HACK_NONE; NUM_DATA_CHUNK_TYPES];
 
-            let (next_free_page, data_chunks) = scan_blocks(&mut file, &header_fields)?;
+            let (next_free_page, data_chunks, index_heads) = scan_blocks(&mut file, &header_fields)?;
 
             Ok(Self {
                 file,
-                header_dirty: false,
+                // Write out the rebuilt header on the next sync.
+                header_dirty: recovery.is_some(),
                 header_fields,
                 next_free_page,
+                recovery,
+                index_heads,
                 data_chunks,
             })
         }
     }
 
+    /// If the header page was corrupt when the file was opened, this describes how it was rebuilt.
+    pub fn header_recovery(&self) -> Option<&HeaderRecovery> {
+        self.recovery.as_ref()
+    }
+
     fn assign_next_page(&mut self) -> PageNum {
         let page = self.next_free_page;
         self.next_free_page += 1;
@@ -439,12 +527,10 @@ impl<F: DTFile> StorageEngine<F> {
         assert!(kind_usize < self.data_chunks.len());
         let state = self.data_chunks[kind_usize].get_or_insert_with(|| {
             // Assign new pages for it.
-            println!("Assigning new pages {}", self.next_free_page);
             // not using assign_next_page because of borrowck.
             let blit_page = self.next_free_page;
             let first_page = self.next_free_page + 1;
             self.next_free_page += 2;
-            dbg!((blit_page, first_page));
 
             let chunks = &mut self.header_fields.data_page_info;
             if chunks.len() <= kind_usize {
@@ -454,6 +540,7 @@ impl<F: DTFile> StorageEngine<F> {
             chunks[kind_usize] = Some(DataChunkHeaderInfo {
                 blit_page,
                 first_page,
+                last_page: first_page,
             });
 
             self.header_dirty = true;
@@ -476,8 +563,10 @@ impl<F: DTFile> StorageEngine<F> {
                 page: DataPage::new(DataPageImmutableFields {
                     kind,
                     prev_page: 0,
+                    links: IndexLinks::default(),
                 }, cursor_buf.data_slice()),
                 dirty: false,
+                empty: true,
             })
         });
 
@@ -507,19 +596,6 @@ impl<F: DTFile> StorageEngine<F> {
     pub fn fsync(&mut self) -> Result<(), SEError> {
         let mut sync_needed = false;
 
-        if self.header_dirty {
-            let new_head = HeaderPage::encode_and_bake(&self.header_fields);
-
-            println!("Writing new header {:?} to page {}", &self.header_fields, self.next_free_page);
-            new_head.write(&mut self.file, self.next_free_page)?;
-            // We need a barrier here in case the writes are reordered, and the write to page 0 is
-            // only partially completed and the write to next_free_page doesn't happen at all.
-            self.file.write_barrier()?;
-            new_head.write(&mut self.file, 0)?;
-
-            sync_needed = true;
-        }
-
         // for (kind_usize, state) in self.data_chunks.iter_mut()
         //     .enumerate()
         //     .filter_map(|(kind, chunk)| {
@@ -536,6 +612,32 @@ impl<F: DTFile> StorageEngine<F> {
             sync_needed = true;
         }
 
+        // Pages before the current page in each chain have been finished. Once they're on disk, the
+        // header can point to them so they don't need to be scanned when the file is opened.
+        for (kind, state) in self.data_chunks.iter().enumerate() {
+            let Some(state) = state.as_deref() else { continue; };
+            let finished = state.page.prev_page()?;
+            let info = self.header_fields.data_page_info[kind].as_mut().unwrap();
+            if finished != 0 && info.last_page != finished {
+                info.last_page = finished;
+                self.header_dirty = true;
+            }
+        }
+
+        if self.header_dirty {
+            let new_head = HeaderPage::encode_and_bake(&self.header_fields);
+
+            new_head.write(&mut self.file, self.next_free_page)?;
+            // We need a barrier here in case the writes are reordered, and the write to page 0 is
+            // only partially completed and the write to next_free_page doesn't happen at all. The
+            // barrier also makes sure the pages named by the header are on disk before it is.
+            self.file.write_barrier()?;
+            new_head.write(&mut self.file, 0)?;
+
+            self.header_dirty = false;
+            sync_needed = true;
+        }
+
         if sync_needed {
             self.file.sync_data()?;
         }
@@ -581,11 +683,11 @@ impl<F: DTFile> StorageEngine<F> {
         // This logic is a bit special. Its possible that the page already has a next page assigned,
         // but the new page was never written to due to an unexpected shutdown or something.
         //
-        // In this case, we'll keep the next_page assignment.
+        // In this case, we'll keep the next_page assignment. (If the page was last written to the
+        // blit page, the field instead names the page itself.)
         let mut new_page = state.page.get_next_or_associated_page();
-        if new_page == 0 { // Almost always true.
+        if new_page == 0 || new_page == state.current_page_no { // Almost always true.
             new_page = *next_free_page;
-            println!("Page full! Assigning new page {}", new_page);
             *next_free_page += 1;
             state.dirty = true;
         }
@@ -600,19 +702,20 @@ impl<F: DTFile> StorageEngine<F> {
                 //
                 // So, if we just wrote to the blit page, we'll call write_page again to actually write
                 // to the real page.
-                println!("Writing back to the page");
                 file.write_barrier()?;
                 Self::write_page(file, state, new_page)?;
             }
         }
 
         // Might be an easier way to wipe this.
-        state.current_page_no = new_page;
+        let prev_page = replace(&mut state.current_page_no, new_page);
         state.write_to_blit_next = false;
         state.page = DataPage::new(DataPageImmutableFields {
             kind,
-            prev_page: state.current_page_no,
+            prev_page,
+            links: IndexLinks::default(),
         }, cursor_data);
+        state.empty = true;
         // Not reassigning the dirty bit here or the assigned blit page. Should we mark the new page
         // as dirty?
 
@@ -635,29 +738,46 @@ impl<F: DTFile> StorageEngine<F> {
 
         // dbg!(bytes);
 
-        let (file, next_free_page, state) = self.prepare_data_page_type(kind, before_cursor);
-
-        match state.page.try_extend_from_slice(bytes) {
-            Ok(()) => {},
-            Err(()) => {
-                // The page is full. Finish out the page and assign a new one.
+        // Each data page starts with cursor information before the data itself.
+        // If this errors, we'll be in an unrecoverable error state internally.
+        // I could return a page-too-large error, but panicking is better here.
+        let cursor_buf = before_cursor.to_stack_buf()
+            .expect("Cursor object is too large. File a bug - this should never happen.");
+        let cursor = cursor_buf.data_slice();
 
-                // Each data page starts with cursor information before the data itself.
-                // If this errors, we'll be in an unrecoverable error state internally.
-                // I could return a page-too-large error, but panicking is better here.
-                let cursor_buf = before_cursor.to_stack_buf()
-                    .expect("Cursor object is too large. File a bug - this should never happen.");
+        let (file, next_free_page, state) = self.prepare_data_page_type(kind, before_cursor);
 
-                Self::finalize_and_assign_next_page(kind, file, next_free_page, state, cursor_buf.data_slice())?;
+        if !state.empty && !state.page.has_room(bytes.len()) {
+            // The page is full. Finish out the page and assign a new one.
+            Self::finalize_and_assign_next_page(kind, file, next_free_page, state, cursor)?;
+        }
 
-                // state.page.try_extend_or_se_error(cursor_buf.data_slice())?;
-                state.page.try_extend_or_se_error(bytes)?;
-            }
+        if state.empty {
+            // This is the first record on the page, so the page is added to the index. The page
+            // may have been started before we knew what would be written to it first.
+            let key = parse_cursor_key(cursor)
+                .expect("Cursors must start with a key");
+            let links = self.new_index_links(kind, key)?;
+            let state = self.data_chunks[kind as usize].as_deref_mut().unwrap();
+            state.page.reset_cursor(cursor, links)?;
+            self.index_heads[kind as usize] = Some(IndexEntry { key, page: state.current_page_no, links });
         }
+
+        let state = self.data_chunks[kind as usize].as_deref_mut().unwrap();
+        state.page.try_extend_or_se_error(bytes)?;
         state.dirty = true;
+        state.empty = false;
 
         Ok(())
     }
+
+    /// Finish the current page for this data type (if it has anything in it). The next record will
+    /// be written to a new page.
+    fn start_new_page(&mut self, kind: DataPageType) -> Result<(), SEError> {
+        let Some(state) = self.data_chunks[kind as usize].as_deref_mut() else { return Ok(()); };
+        if state.empty { return Ok(()); }
+        Self::finalize_and_assign_next_page(kind, &mut self.file, &mut self.next_free_page, state, &[])
+    }
 }
 
 
@@ -693,7 +813,6 @@ impl<'a, F: DTFile> Iterator for DataChunkIterator<'a, F> {
                 //
                 // Also note when the returned page is read, we'll update the start cursor position.
                 // ... so this makes it quite practical to read the page like this.
-                println!("Returning current");
                 let mut page = current_page.page.clone();
                 // The page should already have its read position set to the correct place...
                 page.reset_read_pos();
@@ -54,6 +54,8 @@ use crate::storage::*;
 ///
 /// - Page type
 /// - Pointer to the previous page (or 0 if none)
+/// - Index links (see [`index`](super::index)). These are set when the first record is written
+///   to the page, along with the cursor.
 /// - Cursor data
 #[derive(Clone)]
 pub(super) struct Page<const T: usize> {
@@ -98,6 +100,7 @@ const PO_DATA_IMMUTABLE_FIELD_START: usize = 12;
 pub(super) struct DataPageImmutableFields {
     pub(super) kind: DataPageType,
     pub(super) prev_page: PageNum,
+    pub(super) links: IndexLinks,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -116,7 +119,7 @@ impl PartialOrd for BlitStatus {
 // *** Header pages ***
 
 const MAGIC_BYTES: [u8; 8] = *b"DT_STOR1";
-const FORMAT_VERSION: u16 = 0; // 2 bytes would probably be fine for this but eh.
+const FORMAT_VERSION: u16 = 1; // 2 bytes would probably be fine for this but eh.
 
 // Mutable page fields are at fixed offsets.
 const PO_HEADER_MAGIC: Range<usize> = 0..8;
@@ -228,6 +231,11 @@ impl<const T: usize> Page<T> {
         // self.data
     }
 
+    /// Returns true if `len` more bytes can be written to the page.
+    pub(super) fn has_room(&self, len: usize) -> bool {
+        self.write_pos + len <= self.data.len()
+    }
+
     pub(crate) fn try_extend_or_se_error(&mut self, slice: &[u8]) -> Result<(), SEError> {
         self.try_extend_from_slice(slice)
             .map_err(|_| SEError::PageFull)
@@ -319,10 +327,7 @@ impl<const T: usize> Page<T> {
         // dbg!((page_no, &p, p.as_ref().ok().map(|p| p.get_next_or_associated_page())));
         match p {
             Ok(page) => Ok(Some(page)),
-            Err(SEError::PageIsCorrupt(e)) => {
-                eprintln!("Page is corrupt. This is probably fine? {:?}", e);
-                Ok(None)
-            }, // Ignore this.
+            Err(SEError::PageIsCorrupt(_)) => Ok(None), // Ignore this.
             Err(SEError::IO(io_err)) => {
                 // We'll get an UnexpectedEof error if we hit the end of the file. Its
                 // possible the next block is assigned by the previous block, but not
@@ -394,6 +399,7 @@ impl HeaderPage {
             page.push_u32(kind + 1);
             page.push_u32(c.first_page);
             page.push_u32(c.blit_page);
+            page.push_u32(c.last_page);
         }
         page.push_u32(0);
         page.bake_len_and_checksum();
@@ -412,8 +418,10 @@ impl HeaderPage {
         // At this point the magic bytes have already been checked by read_raw.
 
         let file_version = page.get_version();
-        if file_version != FORMAT_VERSION {
+        if file_version > FORMAT_VERSION {
             return Err(CorruptPageError::VersionTooNew(file_version).into());
+        } else if file_version < FORMAT_VERSION {
+            return Err(CorruptPageError::UnsupportedVersion(file_version).into());
         }
 
         let mut parser = page.make_parser();
@@ -429,9 +437,10 @@ impl HeaderPage {
             let chunk_type = chunk_type_or_end - 1;
             let first_page = parser.next_u32()?;
             let blit_page = parser.next_u32()?;
+            let last_page = parser.next_u32()?;
 
             // TODO: Is it worth checking that the pages are valid?
-            if first_page == blit_page { return Err(SEError::GenericInvalidData); }
+            if first_page == blit_page || last_page < first_page { return Err(SEError::GenericInvalidData); }
 
             if data_page_info.len() < chunk_type {
                 data_page_info.resize(chunk_type, None);
@@ -440,6 +449,7 @@ impl HeaderPage {
             data_page_info[chunk_type] = Some(DataChunkHeaderInfo {
                 blit_page,
                 first_page,
+                last_page,
             });
         }
 
@@ -463,6 +473,17 @@ impl DataPage {
         // Write the immutable bytes. This will write at self.content_start_pos.
         page.push_u32(fields.kind as u32);
         page.push_u32(fields.prev_page);
+        for ptr in [fields.links.parent, fields.links.jump] {
+            // Data pages are never page 0, so that marks a missing link.
+            match ptr {
+                Some(ptr) => {
+                    page.push_u32(ptr.page);
+                    page.push_usize(ptr.key);
+                    page.push_usize(ptr.depth);
+                }
+                None => page.push_u32(0),
+            }
+        }
         // page.cursor_start_pos = page.content_end_pos;
 
         // If the cursor is too large, this will panic. But that is a logic error, and I accept it.
@@ -476,12 +497,61 @@ impl DataPage {
     pub(super) fn read_fields(&mut self) -> Result<DataPageImmutableFields, SEError> {
         let kind = self.next_u32()?;
         let prev_page = self.next_u32()?;
+        let parent = self.next_index_ptr()?;
+        let jump = self.next_index_ptr()?;
         Ok(DataPageImmutableFields {
             kind: DataPageType::try_from(kind as u16)?,
             prev_page,
+            links: IndexLinks { parent, jump },
         })
     }
 
+    fn next_index_ptr(&mut self) -> Result<Option<IndexPtr>, SEError> {
+        let page = self.next_u32()?;
+        if page == 0 { return Ok(None); }
+        let key = self.next_usize()?;
+        let depth = self.next_usize()?;
+        Ok(Some(IndexPtr { key, page, depth }))
+    }
+
+    /// Read the cursor data stored at the start of the page. This must be called after
+    /// [`read_fields`](Self::read_fields), and leaves the read position at the start of the content.
+    pub(super) fn read_cursor(&mut self) -> Result<&[u8], SEError> {
+        let len = self.next_usize()?;
+        let start = self.read_pos;
+        if len > self.write_pos - start {
+            return Err(ParseError::UnexpectedEOF.into());
+        }
+        self.consume(len);
+        Ok(&self.data[start..start + len])
+    }
+
+    /// Replace the cursor and index links of a page which doesn't have any content yet.
+    pub(super) fn reset_cursor(&mut self, cursor_data: &[u8], links: IndexLinks) -> Result<(), SEError> {
+        self.reset_read_pos();
+        let fields = self.read_fields()?;
+        *self = Self::new(DataPageImmutableFields { links, ..fields }, cursor_data);
+        Ok(())
+    }
+
+    /// The page's entry in the page index. Pages are indexed by the number at the start of their
+    /// cursor.
+    pub(super) fn index_entry(&self, kind: DataPageType, page_no: PageNum) -> Result<IndexEntry, SEError> {
+        let mut page = self.clone();
+        page.reset_read_pos();
+        let fields = page.read_fields()?;
+        if fields.kind != kind { return Err(SEError::UnexpectedPageType); }
+        let key = parse_cursor_key(page.read_cursor()?).ok_or(SEError::GenericInvalidData)?;
+        Ok(IndexEntry { key, page: page_no, links: fields.links })
+    }
+
+    /// The page before this one in its chain, or 0 if this is the first page.
+    pub(super) fn prev_page(&self) -> Result<PageNum, SEError> {
+        let mut page = self.clone();
+        page.reset_read_pos();
+        Ok(page.read_fields()?.prev_page)
+    }
+
     // pub fn get_cursor_data(&self) -> &[u8] {
     //     &self.data[self.cursor_start_pos..self.content_start_pos]
     // }
@@ -532,14 +602,16 @@ pub fn page_first_byte_offset(is_header: bool) -> usize {
 #[cfg(test)]
 mod test {
     use crate::encoding::tools::{ExtendFromSlice, TryExtendFromSlice};
-    use crate::storage::page::{BlitStatus, Page, DataPageImmutableFields, DataPage};
-    use crate::storage::{DataPageType, PageType};
+    use crate::storage::page::{BlitStatus, Page, DataPageImmutableFields, DataPage, HeaderPage, FORMAT_VERSION, PO_HEADER_FORMAT_VERSION};
+    use crate::storage::{CorruptPageError, DataPageType, PageType, SEError, StorageHeaderFields};
+    use crate::storage::file::test::TestFile;
 
     #[test]
     fn blah() {
         let mut page = DataPage::new(DataPageImmutableFields {
                     kind: DataPageType::AgentNames,
                     prev_page: 0,
+                    links: Default::default(),
                 },
         &[1,2,3]
         );
@@ -567,4 +639,23 @@ mod test {
         assert!(BlitStatus(2) > BlitStatus(1));
         assert!(BlitStatus(0) > BlitStatus(2));
     }
+
+    fn read_header_with_version(version: u16) -> Result<StorageHeaderFields, SEError> {
+        let mut page = HeaderPage::encode_and_bake(&StorageHeaderFields::default());
+        page.data[PO_HEADER_FORMAT_VERSION].copy_from_slice(&version.to_le_bytes());
+        page.bake_len_and_checksum();
+
+        let mut file = TestFile::new();
+        page.write(&mut file, 0).unwrap();
+        HeaderPage::read(&mut file, 0)
+    }
+
+    #[test]
+    fn header_version() {
+        assert!(read_header_with_version(FORMAT_VERSION).is_ok());
+        assert!(matches!(read_header_with_version(FORMAT_VERSION + 1),
+            Err(SEError::PageIsCorrupt(CorruptPageError::VersionTooNew(v))) if v == FORMAT_VERSION + 1));
+        assert!(matches!(read_header_with_version(0),
+            Err(SEError::PageIsCorrupt(CorruptPageError::UnsupportedVersion(0)))));
+    }
 }
@@ -0,0 +1,753 @@
+//! A [`ListOpLog`] which is saved incrementally to disk using the storage engine.
+//!
+//! Each kind of data is stored as a stream of records in its own chain of data pages:
+//!
+//! - `AgentNames`: (agent, name)
+//! - `CGInfo`: (start LV, agent, seq, len, parents)
+//! - `Operations`: (start LV, len, flags, position)
+//! - `Content`: (LV of the first character, content). Long content is split across records.
+//! - `Commit`: (number of agents, number of versions)
+//!
+//! Every record (apart from commits) starts with a key naming the first agent or LV it contains.
+//! Pages for different kinds of data are written independently, so if we crash while saving, some
+//! of the streams may have data which the others don't. To deal with that, we sync all the data
+//! before appending a commit record, and when the file is loaded, anything past the last commit is
+//! ignored. The next save overwrites the ignored records by writing records with the same keys
+//! again. When reading, a record replaces any earlier records in its stream with the same or
+//! larger keys.
+//!
+//! [`ListOpLogReader`] reads parts of a saved document without loading the rest of it. The storage
+//! engine indexes each stream's pages by the key of their first record, and stores the index in the
+//! pages themselves. So opening a file and finding any record only reads `O(log n)` pages.
+
+use std::fs::File;
+use std::iter::Peekable;
+use std::path::Path;
+use rle::{AppendRle, HasLength};
+use smartstring::alias::String as SmartString;
+use crate::{AgentId, CausalGraph, DTRange, Frontier, LV};
+use crate::causalgraph::agent_span::AgentSpan;
+use crate::causalgraph::entry::CGEntry;
+use crate::encoding::bufparser::BufParser;
+use crate::encoding::tools::{DTSerializable, ExtendFromSlice, TryExtendFromSlice, try_push_str};
+use crate::encoding::varint::{try_push_u32, try_push_usize};
+use crate::list::ListOpLog;
+use crate::list::op_metrics::ListOpMetrics;
+use crate::list::operation::{ListOpKind, TextOperation};
+use crate::rev_range::RangeRev;
+use crate::rle::{KVPair, try_trim};
+use crate::storage::{DataPageType, DTFile, HeaderRecovery, SEError, StorageEngine};
+use crate::unicount::count_chars;
+
+// Records need to fit in a StackWriteBuf (1kb). Content is split into chunks of at most this many
+// bytes.
+const MAX_CONTENT_CHUNK_BYTES: usize = 512;
+
+const OP_FLAG_DEL: usize = 1;
+const OP_FLAG_FWD: usize = 2;
+const OP_FLAG_CONTENT: usize = 4;
+
+struct AgentRecord<'a>(AgentId, &'a str);
+struct CGRecord<'a>(&'a CGEntry);
+struct OpRecord<'a>(LV, &'a ListOpMetrics, bool);
+struct ContentRecord<'a>(LV, &'a str);
+struct CommitRecord(AgentId, LV);
+
+// Records are only ever written into pages (which can fill up), so the infallible serialize
+// methods just call try_serialize.
+impl DTSerializable for AgentRecord<'_> {
+    fn serialize<S: ExtendFromSlice>(&self, into: &mut S) { self.try_serialize(into).unwrap() }
+
+    fn try_serialize<S: TryExtendFromSlice>(&self, into: &mut S) -> Result<(), ()> {
+        try_push_u32(into, self.0)?;
+        try_push_str(into, self.1)
+    }
+}
+
+impl DTSerializable for CGRecord<'_> {
+    fn serialize<S: ExtendFromSlice>(&self, into: &mut S) { self.try_serialize(into).unwrap() }
+
+    fn try_serialize<S: TryExtendFromSlice>(&self, into: &mut S) -> Result<(), ()> {
+        let entry = self.0;
+        try_push_usize(into, entry.start)?;
+        try_push_u32(into, entry.span.agent)?;
+        try_push_usize(into, entry.span.seq_range.start)?;
+        try_push_usize(into, entry.span.len())?;
+        try_push_usize(into, entry.parents.len())?;
+        for p in entry.parents.iter() {
+            try_push_usize(into, *p)?;
+        }
+        Ok(())
+    }
+}
+
+impl DTSerializable for OpRecord<'_> {
+    fn serialize<S: ExtendFromSlice>(&self, into: &mut S) { self.try_serialize(into).unwrap() }
+
+    fn try_serialize<S: TryExtendFromSlice>(&self, into: &mut S) -> Result<(), ()> {
+        let OpRecord(start, op, has_content) = *self;
+        let mut flags = 0;
+        if op.kind == ListOpKind::Del { flags |= OP_FLAG_DEL; }
+        if op.loc.fwd { flags |= OP_FLAG_FWD; }
+        if has_content { flags |= OP_FLAG_CONTENT; }
+
+        try_push_usize(into, start)?;
+        try_push_usize(into, op.len())?;
+        try_push_usize(into, flags)?;
+        try_push_usize(into, op.loc.span.start)
+    }
+}
+
+impl DTSerializable for ContentRecord<'_> {
+    fn serialize<S: ExtendFromSlice>(&self, into: &mut S) { self.try_serialize(into).unwrap() }
+
+    fn try_serialize<S: TryExtendFromSlice>(&self, into: &mut S) -> Result<(), ()> {
+        try_push_usize(into, self.0)?;
+        try_push_str(into, self.1)
+    }
+}
+
+impl DTSerializable for CommitRecord {
+    fn serialize<S: ExtendFromSlice>(&self, into: &mut S) { self.try_serialize(into).unwrap() }
+
+    fn try_serialize<S: TryExtendFromSlice>(&self, into: &mut S) -> Result<(), ()> {
+        try_push_u32(into, self.0)?;
+        try_push_usize(into, self.1)
+    }
+}
+
+/// A [`ListOpLog`] backed by a file on disk.
+///
+/// Changes made to the oplog are appended to the file by [`write_pending`](Self::write_pending),
+/// and made durable by [`fsync`](Self::fsync). Saving only writes the changes made since the last
+/// save, so saving is cheap even for documents with very long histories. If the process crashes or
+/// the oplog is dropped before `fsync` is called, the file will be loaded at the version it had
+/// the last time `fsync` succeeded.
+///
+/// Only agent names, the causal graph and the operations themselves are stored. Agent metadata,
+/// timestamps and the document ID are not saved.
+#[derive(Debug)]
+pub struct PersistentListOpLog<F: DTFile = File> {
+    oplog: ListOpLog,
+    engine: StorageEngine<F>,
+
+    /// All agents and versions before these have been passed to the storage engine.
+    written: (AgentId, LV),
+    /// ... And all agents and versions before these are durably stored on disk.
+    committed: (AgentId, LV),
+}
+
+impl PersistentListOpLog<File> {
+    /// Open (or create) a document stored at the named path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SEError> {
+        Self::from_engine(StorageEngine::open(path)?)
+    }
+}
+
+impl<F: DTFile> PersistentListOpLog<F> {
+    /// Load a document from a file. If the file is empty, it's initialized with an empty document.
+    pub fn from_file(file: F) -> Result<Self, SEError> {
+        Self::from_engine(StorageEngine::from_file(file)?)
+    }
+
+    fn from_engine(mut engine: StorageEngine<F>) -> Result<Self, SEError> {
+        let oplog = load_oplog(&mut engine)?;
+        let version = (oplog.num_agents(), oplog.len());
+
+        Ok(Self {
+            oplog,
+            engine,
+            written: version,
+            committed: version,
+        })
+    }
+
+    pub fn oplog(&self) -> &ListOpLog {
+        &self.oplog
+    }
+
+    /// Get mutable access to the oplog to add local or remote changes. Any changes made will be
+    /// saved by the next call to [`write_pending`](Self::write_pending) or [`fsync`](Self::fsync).
+    pub fn oplog_mut(&mut self) -> &mut ListOpLog {
+        &mut self.oplog
+    }
+
+    /// If the file's header page was corrupt when it was opened, this describes how it was rebuilt.
+    /// Any changes stored in lost pages are missing from the loaded oplog.
+    pub fn header_recovery(&self) -> Option<&HeaderRecovery> {
+        self.engine.header_recovery()
+    }
+
+    /// Returns true if there are changes in the oplog which haven't been durably saved.
+    pub fn has_unsaved_changes(&self) -> bool {
+        self.committed != (self.oplog.num_agents(), self.oplog.len())
+    }
+
+    /// Pass any new changes in the oplog to the storage engine. Full pages are written to disk, but
+    /// the changes won't be visible when the file is loaded until [`fsync`](Self::fsync) is called.
+    pub fn write_pending(&mut self) -> Result<(), SEError> {
+        let oplog = &self.oplog;
+        let engine = &mut self.engine;
+        let (written_agents, written_len) = self.written;
+
+        for agent in written_agents..oplog.num_agents() {
+            let name = oplog.get_agent_name(agent);
+            engine.append_chunk(DataPageType::AgentNames, &(agent as usize), &AgentRecord(agent, name))?;
+        }
+
+        let range = (written_len..oplog.len()).into();
+        for entry in oplog.cg.iter_range(range) {
+            engine.append_chunk(DataPageType::CGInfo, &entry.start, &CGRecord(&entry))?;
+        }
+
+        for (KVPair(start, op), content) in oplog.iter_range_simple(range) {
+            engine.append_chunk(DataPageType::Operations, &start, &OpRecord(start, &op, content.is_some()))?;
+
+            let Some(mut content) = content else { continue; };
+            let mut lv = start;
+            while !content.is_empty() {
+                // Split the content on a character boundary.
+                let mut len = content.len().min(MAX_CONTENT_CHUNK_BYTES);
+                while !content.is_char_boundary(len) { len -= 1; }
+                let (chunk, rest) = content.split_at(len);
+
+                engine.append_chunk(DataPageType::Content, &lv, &ContentRecord(lv, chunk))?;
+                lv += count_chars(chunk);
+                content = rest;
+            }
+        }
+
+        self.written = (oplog.num_agents(), oplog.len());
+        Ok(())
+    }
+
+    /// Write any pending changes and durably commit them to disk.
+    pub fn fsync(&mut self) -> Result<(), SEError> {
+        self.write_pending()?;
+        if self.committed == self.written { return Ok(()); }
+
+        // The data must be on disk before the commit record which references it.
+        self.engine.fsync()?;
+        let (num_agents, len) = self.written;
+        self.engine.append_chunk(DataPageType::Commit, &len, &CommitRecord(num_agents, len))?;
+        self.engine.fsync()?;
+
+        self.committed = self.written;
+        Ok(())
+    }
+}
+
+/// Read access to a document saved by [`PersistentListOpLog`], without loading the whole document
+/// into memory. Lookups use the storage engine's page index, so they only read the pages they need.
+///
+/// Only data which has been committed (by [`PersistentListOpLog::fsync`]) can be read.
+#[derive(Debug)]
+pub struct ListOpLogReader<F: DTFile = File> {
+    engine: StorageEngine<F>,
+    num_agents: AgentId,
+    len: LV,
+}
+
+impl ListOpLogReader<File> {
+    /// Open a document stored at the named path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SEError> {
+        Self::from_engine(StorageEngine::open(path)?)
+    }
+}
+
+impl<F: DTFile> ListOpLogReader<F> {
+    pub fn from_file(file: F) -> Result<Self, SEError> {
+        Self::from_engine(StorageEngine::from_file(file)?)
+    }
+
+    fn from_engine(mut engine: StorageEngine<F>) -> Result<Self, SEError> {
+        // The last commit is the last record in its stream.
+        let commits = read_records_in_range(&mut engine, DataPageType::Commit, (usize::MAX - 1..usize::MAX).into(), parse_commit_record)?;
+        let (len, num_agents) = commits.last().copied().unwrap_or((0, 0));
+        Ok(Self { engine, num_agents, len })
+    }
+
+    /// The number of agents in the saved document.
+    pub fn num_agents(&self) -> AgentId {
+        self.num_agents
+    }
+
+    /// The number of versions (operations) in the saved document.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Read the name of an agent. Returns None if the agent doesn't exist.
+    pub fn agent_name(&mut self, agent: AgentId) -> Result<Option<SmartString>, SEError> {
+        if agent >= self.num_agents { return Ok(None); }
+
+        let key = agent as usize;
+        let agents = read_records_in_range(&mut self.engine, DataPageType::AgentNames, (key..key + 1).into(), parse_agent_record)?;
+        match agents.into_iter().find(|(k, _)| *k == key) {
+            Some((_, name)) => Ok(Some(name)),
+            None => Err(SEError::GenericInvalidData),
+        }
+    }
+
+    /// Read the causal graph entries for the versions in the range. Entries are trimmed to the
+    /// range, which is clamped to the length of the document.
+    pub fn cg_entries(&mut self, range: DTRange) -> Result<Vec<CGEntry>, SEError> {
+        let range: DTRange = (range.start..range.end.min(self.len)).into();
+        if range.is_empty() { return Ok(vec![]); }
+
+        let records = read_records_in_range(&mut self.engine, DataPageType::CGInfo, range, parse_cg_record)?;
+        let mut result: Vec<CGEntry> = vec![];
+        let mut total_len = 0;
+        for (start, (span, parents)) in records {
+            if let Some(entry) = try_trim(CGEntry { start, parents, span }, range) {
+                total_len += entry.len();
+                result.push_rle(entry);
+            }
+        }
+        if total_len != range.len() { return Err(SEError::GenericInvalidData); }
+
+        Ok(result)
+    }
+
+    /// Read the operations for the versions in the range. Operations are trimmed to the range,
+    /// which is clamped to the length of the document.
+    pub fn ops(&mut self, range: DTRange) -> Result<Vec<TextOperation>, SEError> {
+        let range: DTRange = (range.start..range.end.min(self.len)).into();
+        if range.is_empty() { return Ok(vec![]); }
+
+        let ops = read_records_in_range(&mut self.engine, DataPageType::Operations, range, parse_op_record)?;
+
+        // The first and last ops might extend outside the range. We need all of their content.
+        let content_range: DTRange = match (ops.first(), ops.last()) {
+            (Some((start, _)), Some((last_start, (len, _, _)))) => (*start..last_start + len).into(),
+            _ => return Err(SEError::GenericInvalidData),
+        };
+        let content_chunks = read_records_in_range(&mut self.engine, DataPageType::Content, content_range, parse_content_record)?;
+        let mut content_chunks = content_chunks.into_iter()
+            .filter(|(lv, _)| *lv >= content_range.start)
+            .peekable();
+
+        let mut result: Vec<TextOperation> = vec![];
+        let mut total_len = 0;
+        let mut content = String::new();
+        for (start, (op_len, flags, pos)) in ops {
+            let (kind, loc) = decode_op(op_len, flags, pos);
+            let op_content = if flags & OP_FLAG_CONTENT != 0 {
+                read_content(&mut content_chunks, (start..start + op_len).into(), &mut content)?;
+                Some(SmartString::from(content.as_str()))
+            } else { None };
+
+            let op = TextOperation { loc, kind, content: op_content };
+            if let Some(KVPair(_, op)) = try_trim(KVPair(start, op), range) {
+                total_len += op.len();
+                result.push_rle(op);
+            }
+        }
+        if total_len != range.len() { return Err(SEError::GenericInvalidData); }
+
+        Ok(result)
+    }
+}
+
+/// Read all the records of the named type. Records with the same or larger key than a later record
+/// are discarded.
+fn read_records<F: DTFile, R, P>(engine: &mut StorageEngine<F>, kind: DataPageType, mut parse: P) -> Result<Vec<(usize, R)>, SEError>
+    where P: FnMut(&mut BufParser) -> Result<(usize, R), SEError>
+{
+    let mut records: Vec<(usize, R)> = vec![];
+
+    for page in engine.iter_data_pages(kind) {
+        let mut page = page?;
+        if page.read_fields()?.kind != kind {
+            return Err(SEError::UnexpectedPageType);
+        }
+        page.read_cursor()?;
+
+        let mut parser = BufParser(page.get_content());
+        while !parser.is_empty() {
+            let (key, record) = parse(&mut parser)?;
+            while records.last().is_some_and(|(k, _)| *k >= key) {
+                records.pop();
+            }
+            records.push((key, record));
+        }
+    }
+
+    Ok(records)
+}
+
+/// Read the records of the named type with keys in the range, using the page index. The record
+/// containing the start of the range might start before it, so that record is returned too.
+fn read_records_in_range<F: DTFile, R, P>(engine: &mut StorageEngine<F>, kind: DataPageType, range: DTRange, mut parse: P) -> Result<Vec<(usize, R)>, SEError>
+    where P: FnMut(&mut BufParser) -> Result<(usize, R), SEError>
+{
+    let mut records: Vec<(usize, R)> = vec![];
+
+    for (keys, mut page) in engine.read_pages_in_range(kind, range)? {
+        if page.read_fields()?.kind != kind {
+            return Err(SEError::UnexpectedPageType);
+        }
+        page.read_cursor()?;
+
+        let mut parser = BufParser(page.get_content());
+        while !parser.is_empty() {
+            let (key, record) = parse(&mut parser)?;
+            // Records outside this range have been replaced by records on later pages.
+            if !keys.contains(key) || key >= range.end { continue; }
+            while records.last().is_some_and(|(k, _)| *k >= key) {
+                records.pop();
+            }
+            records.push((key, record));
+        }
+    }
+
+    Ok(records)
+}
+
+fn parse_agent_record(p: &mut BufParser) -> Result<(usize, SmartString), SEError> {
+    let agent = p.next_u32()?;
+    Ok((agent as usize, SmartString::from(p.next_str()?)))
+}
+
+fn parse_cg_record(p: &mut BufParser) -> Result<(usize, (AgentSpan, Frontier)), SEError> {
+    let start = p.next_usize()?;
+    let agent = p.next_u32()?;
+    let seq = p.next_usize()?;
+    let len = p.next_usize()?;
+    let num_parents = p.next_usize()?;
+    let mut parents = Frontier::default();
+    for _ in 0..num_parents {
+        parents.0.push(p.next_usize()?);
+    }
+    Ok((start, (AgentSpan { agent, seq_range: (seq..seq + len).into() }, parents)))
+}
+
+/// Ops are read as (start, (len, flags, position)).
+fn parse_op_record(p: &mut BufParser) -> Result<(usize, (usize, usize, usize)), SEError> {
+    let start = p.next_usize()?;
+    let len = p.next_usize()?;
+    let flags = p.next_usize()?;
+    let pos = p.next_usize()?;
+    Ok((start, (len, flags, pos)))
+}
+
+fn parse_content_record(p: &mut BufParser) -> Result<(usize, SmartString), SEError> {
+    let lv = p.next_usize()?;
+    Ok((lv, SmartString::from(p.next_str()?)))
+}
+
+/// Commits are read as (len, num_agents).
+fn parse_commit_record(p: &mut BufParser) -> Result<(usize, AgentId), SEError> {
+    let num_agents = p.next_u32()?;
+    let len = p.next_usize()?;
+    Ok((len, num_agents))
+}
+
+fn decode_op(len: usize, flags: usize, pos: usize) -> (ListOpKind, RangeRev) {
+    let kind = if flags & OP_FLAG_DEL != 0 { ListOpKind::Del } else { ListOpKind::Ins };
+    let loc = RangeRev {
+        span: (pos..pos + len).into(),
+        fwd: flags & OP_FLAG_FWD != 0,
+    };
+    (kind, loc)
+}
+
+/// Read the content of the op spanning `range` into `content` from the (sorted) content chunks.
+fn read_content<I>(content_chunks: &mut Peekable<I>, range: DTRange, content: &mut String) -> Result<(), SEError>
+    where I: Iterator<Item = (usize, SmartString)>
+{
+    content.clear();
+    let mut lv = range.start;
+    while let Some((chunk_lv, chunk)) = content_chunks.next_if(|(chunk_lv, _)| *chunk_lv < range.end) {
+        if chunk_lv != lv { return Err(SEError::GenericInvalidData); }
+        content.push_str(&chunk);
+        lv += count_chars(&chunk);
+    }
+    if lv != range.end { return Err(SEError::GenericInvalidData); }
+    Ok(())
+}
+
+fn load_oplog<F: DTFile>(engine: &mut StorageEngine<F>) -> Result<ListOpLog, SEError> {
+    let commits = read_records(engine, DataPageType::Commit, parse_commit_record)?;
+    let (len, num_agents) = commits.last().copied().unwrap_or((0, 0));
+
+    let mut oplog = ListOpLog::new();
+    // Streams which have records past the last commit.
+    let mut uncommitted = vec![];
+
+    let agents = read_records(engine, DataPageType::AgentNames, parse_agent_record)?;
+    for (agent, name) in agents.iter().take_while(|(agent, _)| *agent < num_agents as usize) {
+        if oplog.get_or_create_agent_id(name) as usize != *agent {
+            return Err(SEError::GenericInvalidData);
+        }
+    }
+    if oplog.num_agents() != num_agents { return Err(SEError::GenericInvalidData); }
+    if agents.last().is_some_and(|(agent, _)| *agent >= num_agents as usize) {
+        uncommitted.push(DataPageType::AgentNames);
+    }
+
+    let entries = read_records(engine, DataPageType::CGInfo, parse_cg_record)?;
+    for (start, (span, parents)) in entries.iter().take_while(|(start, _)| *start < len) {
+        check_cg_entry(&oplog.cg, *start, span, parents)?;
+        let range = oplog.cg.merge_and_assign(parents.as_ref(), *span);
+        if range.start != *start || range.len() != span.len() {
+            return Err(SEError::GenericInvalidData);
+        }
+    }
+    if oplog.cg.len() != len { return Err(SEError::GenericInvalidData); }
+    if entries.last().is_some_and(|(start, _)| *start >= len) {
+        uncommitted.push(DataPageType::CGInfo);
+    }
+
+    let content_chunks = read_records(engine, DataPageType::Content, parse_content_record)?;
+    if content_chunks.last().is_some_and(|(lv, _)| *lv >= len) {
+        uncommitted.push(DataPageType::Content);
+    }
+    let mut content_chunks = content_chunks.into_iter().peekable();
+
+    let ops = read_records(engine, DataPageType::Operations, parse_op_record)?;
+    if ops.last().is_some_and(|(start, _)| *start >= len) {
+        uncommitted.push(DataPageType::Operations);
+    }
+    let mut next = 0;
+    let mut content = String::new();
+    for (start, (op_len, flags, pos)) in ops.into_iter().take_while(|(start, _)| *start < len) {
+        if start != next || op_len == 0 || start + op_len > len {
+            return Err(SEError::GenericInvalidData);
+        }
+        next = start + op_len;
+
+        let (kind, loc) = decode_op(op_len, flags, pos);
+        let has_content = flags & OP_FLAG_CONTENT != 0;
+        if has_content {
+            read_content(&mut content_chunks, (start..next).into(), &mut content)?;
+        } else if content_chunks.peek().is_some_and(|(chunk_lv, _)| *chunk_lv < next) {
+            return Err(SEError::GenericInvalidData);
+        }
+
+        oplog.push_op_internal(start, loc, kind, if has_content { Some(&content) } else { None });
+    }
+    if next != len { return Err(SEError::GenericInvalidData); }
+
+    // The uncommitted records will be replaced when the document is next saved. The replacements
+    // go on new pages, so the page index can tell which records are current.
+    for kind in uncommitted {
+        engine.start_new_page(kind)?;
+    }
+
+    Ok(oplog)
+}
+
+fn check_cg_entry(cg: &CausalGraph, start: LV, span: &AgentSpan, parents: &Frontier) -> Result<(), SEError> {
+    if start != cg.len()
+        || span.seq_range.is_empty()
+        || span.agent >= cg.agent_assignment.client_data.len() as AgentId
+        || parents.iter().any(|p| *p >= start)
+    {
+        Err(SEError::GenericInvalidData)
+    } else { Ok(()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+    use crate::list::old_fuzzer_tools::old_make_random_change_raw;
+    use crate::storage::file::test::{CrashMode, TestFile};
+    use super::*;
+
+    fn reopen(doc: &PersistentListOpLog<TestFile>) -> PersistentListOpLog<TestFile> {
+        PersistentListOpLog::from_file(doc.engine.file.clone()).unwrap()
+    }
+
+    /// Check a reader on the document's file reads the same data as the in-memory oplog.
+    fn check_reader(doc: &PersistentListOpLog<TestFile>, ranges: &[DTRange]) {
+        let oplog = doc.oplog();
+        let mut reader = ListOpLogReader::from_file(doc.engine.file.clone()).unwrap();
+        assert_eq!(reader.len(), oplog.len());
+        assert_eq!(reader.num_agents(), oplog.num_agents());
+        for agent in 0..oplog.num_agents() {
+            assert_eq!(reader.agent_name(agent).unwrap().as_deref(), Some(oplog.get_agent_name(agent)));
+        }
+        assert_eq!(reader.agent_name(oplog.num_agents()).unwrap(), None);
+
+        for range in ranges {
+            let mut expect_cg: Vec<CGEntry> = vec![];
+            for e in oplog.cg.iter_range(*range) { expect_cg.push_rle(e); }
+            assert_eq!(reader.cg_entries(*range).unwrap(), expect_cg);
+
+            let mut expect_ops: Vec<TextOperation> = vec![];
+            for op in oplog.iter_ops_range(*range) { expect_ops.push_rle(op); }
+            assert_eq!(reader.ops(*range).unwrap(), expect_ops);
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut doc = PersistentListOpLog::from_file(TestFile::new()).unwrap();
+        assert_eq!(doc.oplog().len(), 0);
+
+        let oplog = doc.oplog_mut();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "hello world");
+        oplog.add_insert_at(mike, &[4], 5, "🐸 yo");
+        oplog.add_delete_at(seph, &[10], 0..3);
+        assert!(doc.has_unsaved_changes());
+        doc.fsync().unwrap();
+        assert!(!doc.has_unsaved_changes());
+
+        let mut loaded = reopen(&doc);
+        assert_eq!(loaded.oplog(), doc.oplog());
+
+        // Append to the loaded document. The content spans several pages.
+        let long_content = "abcdéfg ".repeat(1000);
+        let oplog = loaded.oplog_mut();
+        let fred = oplog.get_or_create_agent_id("fred");
+        oplog.add_insert(fred, 2, &long_content);
+        oplog.add_delete_without_content(fred, 100..5000);
+        for i in 0..500 {
+            oplog.add_insert(fred, i, "x");
+        }
+        loaded.fsync().unwrap();
+
+        let reloaded = reopen(&loaded);
+        assert_eq!(reloaded.oplog(), loaded.oplog());
+        reloaded.oplog().dbg_check(true);
+        assert_eq!(reloaded.oplog().checkout_tip().content(), loaded.oplog().checkout_tip().content());
+    }
+
+    #[test]
+    fn uncommitted_changes_are_discarded() {
+        let mut doc = PersistentListOpLog::from_file(TestFile::new()).unwrap();
+        let seph = doc.oplog_mut().get_or_create_agent_id("seph");
+        doc.oplog_mut().add_insert(seph, 0, "hi");
+        doc.fsync().unwrap();
+        let expected = doc.oplog().clone();
+
+        // Write new data to disk, but crash before the commit record is written.
+        let mike = doc.oplog_mut().get_or_create_agent_id("mike");
+        doc.oplog_mut().add_insert(mike, 0, "mike was here ".repeat(200).as_str());
+        doc.write_pending().unwrap();
+        doc.engine.fsync().unwrap();
+
+        let mut loaded = reopen(&doc);
+        assert_eq!(loaded.oplog(), &expected);
+
+        // The discarded records are replaced by the next save.
+        let oplog = loaded.oplog_mut();
+        let fred = oplog.get_or_create_agent_id("fred");
+        oplog.add_insert(fred, 1, "ey");
+        oplog.add_delete_without_content(seph, 0..1);
+        loaded.fsync().unwrap();
+
+        let reloaded = reopen(&loaded);
+        assert_eq!(reloaded.oplog(), loaded.oplog());
+        assert_eq!(reloaded.oplog().checkout_tip().content().to_string(), "eyi");
+    }
+
+    #[test]
+    fn reader_reads_ranges() {
+        let mut rng = SmallRng::seed_from_u64(10);
+        let mut doc = PersistentListOpLog::from_file(TestFile::new()).unwrap();
+        let mut branch = doc.oplog().checkout_tip();
+        for i in 0..4000 {
+            let oplog = doc.oplog_mut();
+            // Alternating agents stops the edits being merged together.
+            let agent = oplog.get_or_create_agent_id(["seph", "mike", "fred"][i % 3]);
+            let v = old_make_random_change_raw(oplog, &branch, None, agent, &mut rng, true);
+            branch.merge(oplog, &[v]);
+            if i % 500 == 0 {
+                oplog.add_insert(agent, 0, &"xyz🐸".repeat(300));
+                branch.merge(oplog, oplog.local_frontier_ref());
+                doc.fsync().unwrap();
+            }
+        }
+        doc.fsync().unwrap();
+
+        let len = doc.oplog().len();
+        let mut ranges: Vec<DTRange> = vec![(0..len).into(), (0..1).into(), (len - 1..len).into()];
+        for _ in 0..100 {
+            let start = rng.gen_range(0..len);
+            ranges.push((start..rng.gen_range(start + 1..=len)).into());
+        }
+        check_reader(&doc, &ranges);
+
+        // Reading a small range only needs to read a page or two, and opening the file doesn't read
+        // every page.
+        let file = doc.engine.file.clone();
+        let reads = file.num_reads();
+        let mut reader = ListOpLogReader::from_file(file).unwrap();
+        for kind in [DataPageType::CGInfo, DataPageType::Operations, DataPageType::Content] {
+            assert!(reader.engine.index_heads[kind as usize].unwrap().depth() > 3);
+            let pages = reader.engine.read_pages_in_range(kind, (len / 2..len / 2 + 10).into()).unwrap();
+            assert!(pages.len() <= 2);
+        }
+        assert!(reader.engine.file.num_reads() - reads < 60);
+        assert!(reader.cg_entries((len..len + 10).into()).unwrap().is_empty());
+        assert_eq!(reader.ops((len - 10..len + 10).into()).unwrap(), reader.ops((len - 10..len).into()).unwrap());
+    }
+
+    #[test]
+    fn crash_consistency() {
+        // Make a series of edits, committing after each round. Then simulate a power failure at
+        // every write, and check that the file always loads as one of the committed states.
+        let mut rng = SmallRng::seed_from_u64(123);
+        let mut doc = PersistentListOpLog::from_file(TestFile::new()).unwrap();
+        let mut branches = [doc.oplog().checkout_tip(), doc.oplog().checkout_tip()];
+
+        // (number of writes when the commit finished, committed oplog)
+        let mut commits = vec![(0, doc.oplog().clone())];
+        for round in 0..15 {
+            let oplog = doc.oplog_mut();
+            let agent = oplog.get_or_create_agent_id(["seph", "mike", "fred"][round % 3]);
+            for _ in 0..5 {
+                let branch = &mut branches[rng.gen_range(0..2)];
+                let v = old_make_random_change_raw(oplog, branch, None, agent, &mut rng, true);
+                branch.merge(oplog, &[v]);
+            }
+            if round % 5 == 4 {
+                // Sometimes add lots of content, to fill up pages.
+                oplog.add_insert(agent, 0, &"xyz🐸".repeat(300));
+            }
+
+            doc.fsync().unwrap();
+            commits.push((doc.engine.file.num_writes(), doc.oplog().clone()));
+        }
+
+        let file = doc.engine.file.clone();
+        for idx in 0..=file.num_writes() {
+            // The commit which was last completed before the crash.
+            let last = commits.iter().rposition(|(n, _)| *n <= idx).unwrap();
+
+            for mode in [CrashMode::Lost, CrashMode::Torn, CrashMode::Reordered] {
+                let image = file.crash_at(idx, mode);
+                let mut loaded = PersistentListOpLog::from_file(image).unwrap();
+
+                // The loaded data must be the last commit, or the commit in progress.
+                let state = commits[last..].iter().position(|(_, oplog)| oplog == loaded.oplog());
+                assert!(matches!(state, Some(0 | 1)), "Crash at write {idx} ({mode:?}) loaded an invalid state");
+
+                // And we should be able to keep working with the recovered file.
+                let oplog = loaded.oplog_mut();
+                let agent = oplog.get_or_create_agent_id("recovery");
+                oplog.add_insert(agent, 0, "hi");
+                loaded.fsync().unwrap();
+                assert_eq!(reopen(&loaded).oplog(), loaded.oplog());
+                // Records replaced after the crash mustn't confuse the page index.
+                check_reader(&loaded, &[(0..loaded.oplog().len()).into()]);
+            }
+        }
+
+        // A corrupt header page on its own shouldn't lose anything.
+        let mut image = file.crash_at(file.num_writes(), CrashMode::Lost);
+        image.write_all_at(&[0xff; 10], 20).unwrap();
+        image.sync_data().unwrap();
+        let loaded = PersistentListOpLog::from_file(image).unwrap();
+        assert_eq!(loaded.header_recovery().map(|r| r.lost_pages.is_empty()), Some(true));
+        assert_eq!(loaded.oplog(), doc.oplog());
+    }
+}
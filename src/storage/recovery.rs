@@ -0,0 +1,214 @@
+//! Recovery for files with a corrupt header page.
+//!
+//! The header page names the first page and blit page of each chain of data pages. Its rewritten
+//! whenever a new chain is started, so a crash (or a bad disk sector) during that write leaves the
+//! rest of the file intact but unreachable. Every data page stores its type and the previous page
+//! in its chain, so we can rebuild the header by reading every page in the file.
+
+use std::collections::{BTreeMap, BTreeSet};
+use smallvec::smallvec;
+use crate::storage::{DataChunkHeaderInfo, DEFAULT_PAGE_SIZE, DTFile, NUM_DATA_CHUNK_TYPES, PageNum, SEError, StorageHeaderFields};
+use crate::storage::page::{DataPage, Page};
+
+/// Describes how the header page of a file was rebuilt when it was opened, because the header page
+/// was corrupt.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderRecovery {
+    /// The number of chains of data pages (one per data type) found in the file.
+    pub chains_found: usize,
+    /// Valid data pages which couldn't be linked into any chain. Their content has been discarded,
+    /// and the pages will be overwritten as the file grows.
+    pub lost_pages: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PageLinks {
+    prev: PageNum,
+    // The next page in the chain, or for blit pages, the page the blit is a copy of.
+    next_or_associated: PageNum,
+}
+
+type Chain = BTreeMap<PageNum, PageLinks>;
+
+/// Blit pages are indistinguishable from regular pages by their content. But the blit page is
+/// allocated before the first page in its chain, and it always points forwards to the page it
+/// shadows - which has the same prev page as the blit, unless it was never written.
+fn is_blit(pages: &Chain, page_no: PageNum, links: PageLinks) -> bool {
+    let assoc = links.next_or_associated;
+    if assoc <= page_no { return false; }
+
+    match pages.get(&assoc) {
+        Some(other) => other.prev == links.prev,
+        None => match links.prev {
+            // A regular first page has its blit page right before it, pointing at it.
+            0 => assoc == page_no + 1
+                && pages.get(&(page_no - 1)).is_none_or(|p| p.next_or_associated != page_no),
+            prev => pages.get(&prev).is_some_and(|p| p.next_or_associated == assoc),
+        }
+    }
+}
+
+/// Rebuild the header fields by scanning every page in the file.
+pub(super) fn rebuild_header<F: DTFile>(file: &mut F) -> Result<(StorageHeaderFields, HeaderRecovery), SEError> {
+    let num_pages = file.stream_len()?.div_ceil(DEFAULT_PAGE_SIZE as u64) as PageNum;
+
+    let mut chains: [Chain; NUM_DATA_CHUNK_TYPES] = Default::default();
+    for page_no in 1..num_pages {
+        let Some(mut page) = DataPage::try_read_raw(file, page_no)? else { continue; };
+        // Pages with a valid checksum but data we don't understand aren't ours to recover.
+        let Ok(fields) = page.read_fields() else { continue; };
+        chains[fields.kind as usize].insert(page_no, PageLinks {
+            prev: fields.prev_page,
+            next_or_associated: page.get_next_or_associated_page(),
+        });
+    }
+
+    let mut header_fields = StorageHeaderFields {
+        data_page_info: smallvec![None; NUM_DATA_CHUNK_TYPES],
+        ..Default::default()
+    };
+    let mut recovery = HeaderRecovery::default();
+
+    for (kind, pages) in chains.iter().enumerate() {
+        if pages.is_empty() { continue; }
+
+        // Find the first page in the chain. If the first page was never written, its blit page
+        // might still have a copy of it.
+        let mut first_page = None;
+        for (&page_no, &links) in pages.iter().filter(|(_, links)| links.prev == 0) {
+            if is_blit(pages, page_no, links) {
+                first_page.get_or_insert(links.next_or_associated);
+            } else {
+                first_page = Some(page_no);
+                break;
+            }
+        }
+
+        let mut reachable = BTreeSet::new();
+        if let Some(first_page) = first_page.filter(|&p| p >= 2) {
+            let blit_page = first_page - 1;
+            // We don't know which pages were durably written, so the chain is scanned from the start
+            // when the file is opened.
+            header_fields.data_page_info[kind] = Some(DataChunkHeaderInfo { blit_page, first_page, last_page: first_page });
+            recovery.chains_found += 1;
+
+            reachable.insert(blit_page);
+            let mut page_no = first_page;
+            while reachable.insert(page_no) {
+                match pages.get(&page_no) {
+                    Some(links) if links.next_or_associated != 0 => page_no = links.next_or_associated,
+                    _ => break,
+                }
+            }
+        }
+
+        recovery.lost_pages.extend(pages.keys().filter(|p| !reachable.contains(p)));
+    }
+
+    recovery.lost_pages.sort_unstable();
+    Ok((header_fields, recovery))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::{DataPageType, StorageEngine};
+    use crate::storage::file::DTFile;
+    use crate::storage::file::test::TestFile;
+
+    fn contents<F: DTFile>(se: &mut StorageEngine<F>, kind: DataPageType) -> Vec<Vec<u8>> {
+        se.iter_data_pages(kind).map(|page| {
+            let mut page = page.unwrap();
+            page.read_fields().unwrap();
+            page.read_cursor().unwrap();
+            page.get_content().to_vec()
+        }).collect()
+    }
+
+    fn fill_file() -> StorageEngine<TestFile> {
+        let mut se = StorageEngine::from_file(TestFile::new()).unwrap();
+        for i in 0..1000usize {
+            se.append_chunk(DataPageType::AgentNames, &i, "some agent name").unwrap();
+            if i % 2 == 0 {
+                se.append_chunk(DataPageType::Operations, &i, "some longer data here").unwrap();
+            }
+            if i % 100 == 0 { se.fsync().unwrap(); }
+        }
+        se.fsync().unwrap();
+        se
+    }
+
+    #[test]
+    fn recover_corrupt_header() {
+        let mut se = fill_file();
+        let expect_names = contents(&mut se, DataPageType::AgentNames);
+        let expect_ops = contents(&mut se, DataPageType::Operations);
+        let expect_info: Vec<_> = se.header_fields.data_chunk_info_iter()
+            .map(|(kind, info)| (kind, info.first_page, info.blit_page))
+            .collect();
+        let expect_next = se.next_free_page;
+        assert!(expect_names.len() > 2 && expect_ops.len() > 2);
+
+        let mut file = se.file.clone();
+        drop(se);
+        // Scribble over the header page content.
+        file.write_all_at(&[0xff; 10], 20).unwrap();
+        file.sync_data().unwrap();
+
+        let mut se = StorageEngine::from_file(file).unwrap();
+        let recovery = se.header_recovery().unwrap();
+        assert_eq!(recovery.chains_found, 2);
+        assert!(recovery.lost_pages.is_empty());
+        let info: Vec<_> = se.header_fields.data_chunk_info_iter()
+            .map(|(kind, info)| (kind, info.first_page, info.blit_page))
+            .collect();
+        assert_eq!(info, expect_info);
+        assert_eq!(se.next_free_page, expect_next);
+        assert_eq!(contents(&mut se, DataPageType::AgentNames), expect_names);
+        assert_eq!(contents(&mut se, DataPageType::Operations), expect_ops);
+
+        // The rebuilt header is written out, so the file opens normally next time.
+        se.append_chunk(DataPageType::Commit, &0usize, &0usize).unwrap();
+        se.fsync().unwrap();
+        let file = se.file.clone();
+        drop(se);
+        let mut se = StorageEngine::from_file(file).unwrap();
+        assert!(se.header_recovery().is_none());
+        assert_eq!(contents(&mut se, DataPageType::AgentNames), expect_names);
+        assert_eq!(contents(&mut se, DataPageType::Commit).len(), 1);
+    }
+
+    #[test]
+    fn recovery_reports_lost_pages() {
+        let mut se = fill_file();
+        let expect_first = contents(&mut se, DataPageType::AgentNames)[0].clone();
+        let first_page = se.header_fields.data_page_info[DataPageType::AgentNames as usize].unwrap().first_page;
+        let mut file = se.file.clone();
+        drop(se);
+
+        // Zero the header and break the agent names chain after its first page.
+        file.write_all_at(&[0; 4096], 0).unwrap();
+        let mut second_page = [0u8; 4];
+        file.read_all_at(&mut second_page, first_page as u64 * 4096 + 6).unwrap();
+        let second_page = u32::from_le_bytes(second_page);
+        file.write_all_at(&[0xff; 10], second_page as u64 * 4096 + 20).unwrap();
+        file.sync_data().unwrap();
+
+        let mut se = StorageEngine::from_file(file).unwrap();
+        let recovery = se.header_recovery().unwrap();
+        assert_eq!(recovery.chains_found, 2);
+        assert!(!recovery.lost_pages.is_empty());
+        assert!(recovery.lost_pages.iter().all(|&p| p > second_page));
+        let names = contents(&mut se, DataPageType::AgentNames);
+        assert_eq!(names[0], expect_first);
+        // The broken page is replaced by a new, empty page.
+        assert!(names[1..].iter().all(|c| c.is_empty()));
+    }
+
+    #[test]
+    fn foreign_files_are_not_recovered() {
+        let mut file = TestFile::new();
+        file.write_all_at(b"hello, this is not a diamond types file", 0).unwrap();
+        file.sync_data().unwrap();
+        assert!(StorageEngine::from_file(file).is_err());
+    }
+}
@@ -0,0 +1,517 @@
+//! A transport-agnostic protocol for keeping two peers in sync.
+//!
+//! Each peer keeps a [`SyncSession`] for every other peer it talks to. Sessions don't own the
+//! document or do any IO. Instead they produce [`SyncMessage`]s which the application sends to the
+//! remote peer however it likes, and they consume messages received from the remote peer.
+//!
+//! The protocol works like this:
+//!
+//! - When a connection is opened, each side calls [`SyncSession::start`] and sends the resulting
+//!   summary of every version it knows about.
+//! - When a peer receives a summary, it figures out which versions both peers have in common and
+//!   replies with a patch containing everything the other peer is missing.
+//! - Patches are acknowledged with another summary, so the sender knows what the remote peer has.
+//! - After local changes, [`SyncSession::flush`] produces a patch with the new changes.
+//!
+//! Messages can be delivered in any order, and delivered more than once. Merging a patch which
+//! has already been merged does nothing. If a message is lost, the application should call
+//! [`SyncSession::retry`] (eg after a timeout) to resend anything which hasn't been acknowledged.
+//! If a patch arrives before the changes it depends on, the receiving peer replies with its
+//! summary and the sender resends the missing changes.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use crate::{CausalGraph, DTRange, Frontier, LV, OpLog, SerializedOpsOwned};
+use crate::causalgraph::summary::VersionSummary;
+use crate::encoding::parseerror::ParseError;
+use crate::list::encoding::ENCODE_PATCH;
+use crate::list::ListOpLog;
+
+/// A document which can be synchronized using a [`SyncSession`].
+pub trait SyncDoc {
+    /// The format used to send changes to a remote peer.
+    type Patch;
+
+    fn causal_graph(&self) -> &CausalGraph;
+
+    /// Make a patch containing all the changes since the specified version.
+    fn patch_since(&self, from: &[LV]) -> Self::Patch;
+
+    /// Merge a patch from a remote peer. If the patch can't be merged, the document must be left
+    /// unchanged.
+    fn merge_patch(&mut self, patch: &Self::Patch) -> Result<(), ParseError>;
+}
+
+impl SyncDoc for ListOpLog {
+    type Patch = Vec<u8>;
+
+    fn causal_graph(&self) -> &CausalGraph { &self.cg }
+
+    fn patch_since(&self, from: &[LV]) -> Vec<u8> {
+        self.encode_from(&ENCODE_PATCH, from)
+    }
+
+    fn merge_patch(&mut self, patch: &Vec<u8>) -> Result<(), ParseError> {
+        self.decode_and_add(patch).map(|_| ())
+    }
+}
+
+impl SyncDoc for OpLog {
+    type Patch = SerializedOpsOwned;
+
+    fn causal_graph(&self) -> &CausalGraph { &self.cg }
+
+    fn patch_since(&self, from: &[LV]) -> SerializedOpsOwned {
+        self.ops_since(from).into()
+    }
+
+    fn merge_patch(&mut self, patch: &SerializedOpsOwned) -> Result<(), ParseError> {
+        self.merge_ops(patch.into()).map(|_| ())
+    }
+}
+
+/// A message sent between two peers' sync sessions.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SyncMessage<P> {
+    /// Names every version the sender knows about. The receiver replies with anything the sender
+    /// is missing, and with its own summary if it hasn't sent one yet.
+    Summary(VersionSummary),
+    /// Changes which the receiver is (probably) missing.
+    Patch(P),
+    /// Sent after merging a patch. Names every version the sender knows about.
+    Ack(VersionSummary),
+}
+
+/// The state of the sync protocol with a single remote peer. See the [module
+/// documentation](crate::sync) for details.
+#[derive(Debug, Clone, Default)]
+pub struct SyncSession {
+    /// The versions we know the remote peer has, or None if we haven't heard from the peer yet.
+    remote_version: Option<Frontier>,
+
+    /// Versions the remote peer has told us about which we don't have yet.
+    remote_only: Vec<VersionSummary>,
+
+    /// The remote version plus everything we've sent in patches which haven't been acknowledged.
+    sent_version: Frontier,
+
+    sent_summary: bool,
+}
+
+/// These errors happen when a patch arrives before the changes it depends on. They're fixed by
+/// asking the peer to resend.
+fn is_missing_data(err: ParseError) -> bool {
+    matches!(err, ParseError::BaseVersionUnknown | ParseError::DataMissing)
+}
+
+impl SyncSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// (Re)start the session. This should be called whenever the connection to the remote peer is
+    /// opened. The returned message should be sent to the peer.
+    pub fn start<D: SyncDoc>(&mut self, doc: &D) -> SyncMessage<D::Patch> {
+        *self = Self::new();
+        self.sent_summary = true;
+        SyncMessage::Summary(doc.causal_graph().agent_assignment.summarize_versions())
+    }
+
+    /// Returns true if we know the remote peer has exactly the same set of versions as doc.
+    pub fn is_synced<D: SyncDoc>(&self, doc: &D) -> bool {
+        let cg = doc.causal_graph();
+        self.remote_version.as_ref() == Some(&cg.version)
+            && self.remote_only.iter().all(|s| cg.intersect_with_summary(s, &[]).1.is_none())
+    }
+
+    /// Forget about any remote-only versions which we've since received (from any peer).
+    fn prune_remote_only(&mut self, cg: &CausalGraph) {
+        self.remote_only = std::mem::take(&mut self.remote_only).into_iter()
+            .filter_map(|s| cg.intersect_with_summary(&s, &[]).1)
+            .collect();
+    }
+
+    /// Update our knowledge of what the remote peer has from its summary.
+    ///
+    /// Patches we've sent might still be in flight when an ack arrives, so acks only ever move
+    /// sent_version forward. But a summary is a request to resend anything the remote peer is
+    /// missing.
+    fn learn_summary(&mut self, cg: &CausalGraph, summary: &VersionSummary, resend: bool) {
+        let (common, remainder) = cg.intersect_with_summary(summary, &[]);
+        // Messages can arrive out of order, so the summary might be older than what we already
+        // know about the remote peer.
+        let remote_version = match &self.remote_version {
+            Some(v) => cg.graph.find_dominators_2(v.as_ref(), common.as_ref()),
+            None => common,
+        };
+        self.sent_version = if resend { remote_version.clone() } else {
+            cg.graph.find_dominators_2(self.sent_version.as_ref(), remote_version.as_ref())
+        };
+        self.remote_version = Some(remote_version);
+        self.prune_remote_only(cg);
+        self.remote_only.extend(remainder);
+    }
+
+    /// The remote peer has sent us the versions in range (and everything they depend on).
+    fn learn_merged(&mut self, cg: &CausalGraph, range: DTRange) {
+        let mut versions: Vec<LV> = self.remote_version.as_ref()
+            .map(|v| v.as_ref().to_vec())
+            .unwrap_or_default();
+        versions.extend(cg.graph.iter_range(range).map(|e| e.span.last()));
+        let remote_version = cg.graph.find_dominators(&versions);
+
+        self.sent_version = cg.graph.find_dominators_2(self.sent_version.as_ref(), remote_version.as_ref());
+        self.remote_version = Some(remote_version);
+        self.prune_remote_only(cg);
+    }
+
+    /// Process a message from the remote peer. Returns the messages to send back.
+    ///
+    /// An error is returned if the message contains invalid data. Patches which arrive before the
+    /// changes they depend on are not errors - the session asks the peer to resend instead.
+    pub fn receive<D: SyncDoc>(&mut self, doc: &mut D, msg: SyncMessage<D::Patch>) -> Result<Vec<SyncMessage<D::Patch>>, ParseError> {
+        let mut result = vec![];
+
+        match msg {
+            SyncMessage::Summary(summary) => {
+                if !self.sent_summary {
+                    self.sent_summary = true;
+                    result.push(SyncMessage::Summary(doc.causal_graph().agent_assignment.summarize_versions()));
+                }
+                self.learn_summary(doc.causal_graph(), &summary, true);
+            }
+            SyncMessage::Ack(summary) => {
+                self.learn_summary(doc.causal_graph(), &summary, false);
+            }
+            SyncMessage::Patch(patch) => {
+                let old_len = doc.causal_graph().len();
+                match doc.merge_patch(&patch) {
+                    Ok(()) => {
+                        let cg = doc.causal_graph();
+                        self.learn_merged(cg, (old_len..cg.len()).into());
+                        result.push(SyncMessage::Ack(cg.agent_assignment.summarize_versions()));
+                    }
+                    Err(err) if is_missing_data(err) => {
+                        // Tell the peer what we actually have, so it can resend.
+                        self.sent_summary = true;
+                        result.push(SyncMessage::Summary(doc.causal_graph().agent_assignment.summarize_versions()));
+                        return Ok(result);
+                    }
+                    Err(err) => { return Err(err); }
+                }
+            }
+        }
+
+        result.extend(self.flush(doc));
+        Ok(result)
+    }
+
+    /// Make a patch containing any local changes which haven't been sent to the remote peer yet.
+    /// Returns None if there's nothing to send, or if we don't know what the remote peer has.
+    pub fn flush<D: SyncDoc>(&mut self, doc: &D) -> Option<SyncMessage<D::Patch>> {
+        self.remote_version.as_ref()?;
+
+        let cg = doc.causal_graph();
+        if cg.graph.frontier_contains_frontier(self.sent_version.as_ref(), cg.version.as_ref()) {
+            return None;
+        }
+
+        let patch = doc.patch_since(self.sent_version.as_ref());
+        self.sent_version = cg.version.clone();
+        Some(SyncMessage::Patch(patch))
+    }
+
+    /// Resend anything which the remote peer hasn't acknowledged. This should be called when
+    /// messages might have been lost (eg, after a timeout).
+    pub fn retry<D: SyncDoc>(&mut self, doc: &D) -> Vec<SyncMessage<D::Patch>> {
+        match &self.remote_version {
+            None => vec![self.start(doc)],
+            Some(remote_version) => {
+                self.sent_version = remote_version.clone();
+                // The remote peer's summary might be out of date, so we also send ours. This lets
+                // the remote peer resend anything we've missed.
+                let mut result = vec![SyncMessage::Summary(doc.causal_graph().agent_assignment.summarize_versions())];
+                result.extend(self.flush(doc));
+                result
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+    use crate::list::ListOpLog;
+    use crate::list_fuzzer_tools::fuzz_multithreaded;
+    use crate::{CreateValue, CRDTKind, OpLog, Primitive, ROOT_CRDT_ID};
+    use crate::list::operation::TextOperation;
+    use super::*;
+
+    /// An in-memory network of peers. Every peer has a session with every other peer.
+    struct Network<D: SyncDoc> {
+        docs: Vec<D>,
+        /// sessions[i][j] is peer i's session with peer j.
+        sessions: Vec<Vec<SyncSession>>,
+        /// Messages in flight as (from, to, message).
+        in_flight: Vec<(usize, usize, SyncMessage<D::Patch>)>,
+    }
+
+    impl<D: SyncDoc> Network<D> where D::Patch: Clone {
+        fn new(docs: Vec<D>) -> Self {
+            let n = docs.len();
+            let mut network = Self {
+                docs,
+                sessions: (0..n).map(|_| (0..n).map(|_| SyncSession::new()).collect()).collect(),
+                in_flight: vec![],
+            };
+
+            for i in 0..n {
+                for j in 0..n {
+                    if i != j {
+                        let msg = network.sessions[i][j].start(&network.docs[i]);
+                        network.in_flight.push((i, j, msg));
+                    }
+                }
+            }
+            network
+        }
+
+        fn flush(&mut self, i: usize) {
+            for j in 0..self.docs.len() {
+                if i != j {
+                    if let Some(msg) = self.sessions[i][j].flush(&self.docs[i]) {
+                        self.in_flight.push((i, j, msg));
+                    }
+                }
+            }
+        }
+
+        fn deliver(&mut self, idx: usize) {
+            let (from, to, msg) = self.in_flight.swap_remove(idx);
+            let replies = self.sessions[to][from].receive(&mut self.docs[to], msg)
+                .unwrap_or_else(|e| panic!("Error receiving message: {:?}", e));
+            self.in_flight.extend(replies.into_iter().map(|msg| (to, from, msg)));
+
+            // Anything we just received should be passed on to the other peers.
+            self.flush(to);
+        }
+
+        /// Randomly deliver, duplicate and drop messages.
+        fn step(&mut self, rng: &mut SmallRng) {
+            if self.in_flight.is_empty() { return; }
+            let idx = rng.gen_range(0..self.in_flight.len());
+
+            match rng.gen_range(0..10) {
+                0 => { self.in_flight.swap_remove(idx); }
+                1 => {
+                    let (from, to, msg) = &self.in_flight[idx];
+                    let dup = (*from, *to, msg.clone());
+                    self.in_flight.push(dup);
+                }
+                _ => self.deliver(idx),
+            }
+        }
+
+        /// Retry everything then deliver all messages in order until the network is quiet.
+        fn settle(&mut self) {
+            let n = self.docs.len();
+            for i in 0..n {
+                for j in 0..n {
+                    if i != j {
+                        let msgs = self.sessions[i][j].retry(&self.docs[i]);
+                        self.in_flight.extend(msgs.into_iter().map(|msg| (i, j, msg)));
+                    }
+                }
+            }
+
+            let mut steps = 0;
+            while !self.in_flight.is_empty() {
+                self.deliver(0);
+                steps += 1;
+                assert!(steps < 100000, "Sync protocol did not terminate");
+            }
+
+            for i in 0..n {
+                for j in 0..n {
+                    if i != j { assert!(self.sessions[i][j].is_synced(&self.docs[i])); }
+                }
+            }
+        }
+    }
+
+    fn list_network_fuzz(seed: u64, num_peers: usize, verbose: bool) {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut network = Network::new((0..num_peers).map(|_| ListOpLog::new()).collect());
+
+        for _i in 0..100 {
+            if verbose { println!("i {}", _i); }
+
+            if rng.gen_bool(0.3) {
+                let idx = rng.gen_range(0..num_peers);
+                let doc = &mut network.docs[idx];
+                let agent = doc.get_or_create_agent_id(["a", "b", "c", "d", "e"][idx]);
+                let len = doc.checkout_tip().len();
+                if len == 0 || rng.gen_bool(0.7) {
+                    doc.add_insert(agent, rng.gen_range(0..=len), "hi");
+                } else {
+                    let start = rng.gen_range(0..len);
+                    doc.add_delete_without_content(agent, start..start + 1);
+                }
+                network.flush(idx);
+            }
+
+            network.step(&mut rng);
+        }
+
+        network.settle();
+        for doc in &network.docs[1..] {
+            assert_eq!(doc, &network.docs[0]);
+        }
+    }
+
+    fn oplog_network_fuzz(seed: u64, num_peers: usize) {
+        let mut rng = SmallRng::seed_from_u64(seed);
+
+        let mut first = OpLog::new();
+        let agent = first.cg.get_or_create_agent_id("setup");
+        first.local_map_set(agent, ROOT_CRDT_ID, "text", CreateValue::NewCRDT(CRDTKind::Text));
+        let docs = (0..num_peers).map(|_| {
+            let mut doc = OpLog::new();
+            doc.merge_ops(first.ops_since(&[])).unwrap();
+            doc
+        }).collect();
+        let mut network = Network::new(docs);
+
+        for _i in 0..100 {
+            if rng.gen_bool(0.3) {
+                let idx = rng.gen_range(0..num_peers);
+                let doc = &mut network.docs[idx];
+                let agent = doc.cg.get_or_create_agent_id(["a", "b", "c", "d", "e"][idx]);
+                if rng.gen_bool(0.5) {
+                    let text = doc.text_at_path(&["text".into()]).unwrap();
+                    let len = doc.checkout_text(text).unwrap().len_chars();
+                    doc.local_text_op(agent, text, TextOperation::new_insert(rng.gen_range(0..=len), "yo"));
+                } else {
+                    let key = ["x", "y"].choose(&mut rng).unwrap();
+                    doc.local_map_set(agent, ROOT_CRDT_ID, key, CreateValue::Primitive(Primitive::I64(rng.gen_range(0..10))));
+                }
+                network.flush(idx);
+            }
+
+            network.step(&mut rng);
+        }
+
+        network.settle();
+        for doc in &network.docs {
+            doc.dbg_check(true);
+            assert_eq!(doc.checkout(), network.docs[0].checkout());
+        }
+    }
+
+    #[test]
+    fn two_peer_handshake() {
+        let mut a = ListOpLog::new();
+        let mut b = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "hi there");
+        let mike = b.get_or_create_agent_id("mike");
+        b.add_insert(mike, 0, "yo");
+
+        let mut sa = SyncSession::new();
+        let mut sb = SyncSession::new();
+
+        // Only a starts the session. b replies with its summary and the patch a is missing.
+        let hello = sa.start(&a);
+        let replies = sb.receive(&mut b, hello).unwrap();
+        assert!(matches!(replies.as_slice(), [SyncMessage::Summary(_), SyncMessage::Patch(_)]));
+
+        let mut to_a = replies;
+        let mut to_b = vec![];
+        while !to_a.is_empty() || !to_b.is_empty() {
+            for msg in std::mem::take(&mut to_a) {
+                to_b.extend(sa.receive(&mut a, msg).unwrap());
+            }
+            for msg in std::mem::take(&mut to_b) {
+                to_a.extend(sb.receive(&mut b, msg).unwrap());
+            }
+        }
+
+        assert_eq!(a, b);
+        assert!(sa.is_synced(&a));
+        assert!(sb.is_synced(&b));
+
+        // Nothing more to send.
+        assert!(sa.flush(&a).is_none());
+        a.add_insert(seph, 0, "x");
+        assert!(!sa.is_synced(&a));
+        let patch = sa.flush(&a).unwrap();
+        assert!(sa.flush(&a).is_none());
+
+        // Redelivering the patch is harmless.
+        sb.receive(&mut b, patch.clone()).unwrap();
+        sb.receive(&mut b, patch).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn patch_out_of_order_requests_resend() {
+        let mut a = ListOpLog::new();
+        let mut b = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+
+        let mut sa = SyncSession::new();
+        let mut sb = SyncSession::new();
+        let hello = sa.start(&a);
+        for msg in sb.receive(&mut b, hello).unwrap() {
+            sa.receive(&mut a, msg).unwrap();
+        }
+
+        a.add_insert(seph, 0, "aaa");
+        let _lost = sa.flush(&a).unwrap();
+        a.add_insert(seph, 3, "bbb");
+        let patch = sa.flush(&a).unwrap();
+
+        // The second patch can't be merged without the first. b asks for a resend.
+        let replies = sb.receive(&mut b, patch).unwrap();
+        assert!(matches!(replies.as_slice(), [SyncMessage::Summary(_)]));
+        assert_eq!(b.len(), 0);
+
+        let mut to_a = replies;
+        while !to_a.is_empty() {
+            let mut to_b = vec![];
+            for msg in std::mem::take(&mut to_a) {
+                to_b.extend(sa.receive(&mut a, msg).unwrap());
+            }
+            for msg in to_b {
+                to_a.extend(sb.receive(&mut b, msg).unwrap());
+            }
+        }
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn list_network_fuzz_once() {
+        list_network_fuzz(10, 3, false);
+    }
+
+    #[test]
+    fn network_fuzz() {
+        for seed in 0..30 {
+            list_network_fuzz(seed, 2 + (seed as usize % 4), false);
+            oplog_network_fuzz(seed, 2 + (seed as usize % 4));
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn network_fuzz_forever() {
+        fuzz_multithreaded(u64::MAX, |seed| {
+            if seed % 1000 == 0 {
+                println!("Iteration {}", seed);
+            }
+            list_network_fuzz(seed, 2 + (seed as usize % 4), false);
+            oplog_network_fuzz(seed, 2 + (seed as usize % 4));
+        })
+    }
+}
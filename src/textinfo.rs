@@ -1,5 +1,6 @@
 use jumprope::JumpRopeBuf;
 use rle::HasLength;
+use crate::causalgraph::CausalGraph;
 use crate::causalgraph::graph::Graph;
 use crate::dtrange::DTRange;
 use crate::frontier::Frontier;
@@ -7,7 +8,7 @@ use crate::list::ListOpLog;
 use crate::list::op_iter::{OpMetricsWithContent, OpMetricsIter};
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
 use crate::list::operation::{ListOpKind, TextOperation};
-use crate::listmerge::merge::reverse_str;
+use crate::listmerge::merge::{reverse_str, TransformedResultRaw};
 use crate::LV;
 use crate::rle::KVPair;
 use crate::rle::rle_vec::RleVec;
@@ -17,6 +18,11 @@ pub(crate) struct TextInfo {
     pub(crate) ctx: ListOperationCtx,
     pub(crate) ops: RleVec<KVPair<ListOpMetrics>>,
     pub(crate) frontier: Frontier,
+
+    /// The number of items which have ever been inserted.
+    pub(crate) num_inserted: usize,
+    /// Cached length of the document at frontier, or None if it needs to be recalculated.
+    pub(crate) len: Option<usize>,
 }
 
 impl TextInfo {
@@ -41,6 +47,7 @@ impl TextInfo {
         let content_pos = op.content.as_ref().map(|content| {
             self.ctx.push_str(op.kind, content)
         });
+        if op.kind == ListOpKind::Ins { self.num_inserted += op.len(); }
 
         self.ops.push(KVPair(v_range.start, ListOpMetrics {
             loc: op.loc,
@@ -51,6 +58,7 @@ impl TextInfo {
 
     pub fn remote_push_op(&mut self, op: TextOperation, v_range: DTRange, parents: &[LV], graph: &Graph) {
         self.push_op_internal(op, v_range);
+        self.len = None;
         // // TODO: Its probably simpler to just call advance_sparse() here.
         // let local_parents = graph.project_onto_subgraph_raw(
         //     subgraph_rev_iter(&self.ops),
@@ -62,14 +70,98 @@ impl TextInfo {
 
     pub fn remote_push_op_unknown_parents(&mut self, op: TextOperation, v_range: DTRange, graph: &Graph) {
         self.push_op_internal(op, v_range);
+        self.len = None;
         self.frontier.advance_sparse(graph, v_range);
     }
 
     pub fn local_push_op(&mut self, op: TextOperation, v_range: DTRange) {
+        if let Some(len) = self.len.as_mut() {
+            match op.kind {
+                ListOpKind::Ins => *len += op.len(),
+                ListOpKind::Del => *len -= op.len(),
+            }
+        }
         self.push_op_internal(op, v_range);
         self.frontier.replace_with_1(v_range.last());
     }
 
+    /// Find the length of the document after merging merge_frontier into the document at `from`,
+    /// which has length from_len. Returns None if any of the merged operations names a position
+    /// past the end of the document.
+    fn merged_len(&self, cg: &CausalGraph, from: &[LV], from_len: usize, merge_frontier: &[LV]) -> Option<usize> {
+        fn apply(len: &mut usize, op: &ListOpMetrics) -> Option<()> {
+            match op.kind {
+                ListOpKind::Ins => {
+                    if op.loc.span.start > *len { return None; }
+                    *len += op.len();
+                }
+                ListOpKind::Del => {
+                    if op.loc.span.end > *len { return None; }
+                    *len -= op.len();
+                }
+            }
+            Some(())
+        }
+
+        self.with_xf_iter(cg, from, merge_frontier, |iter, _| {
+            let mut len = from_len;
+            for xf in iter {
+                match xf {
+                    TransformedResultRaw::Apply { xf_pos, op: KVPair(_, mut op) } => {
+                        op.transpose_to(xf_pos);
+                        apply(&mut len, &op)?;
+                    }
+                    TransformedResultRaw::FF(range) => {
+                        for KVPair(_, op) in self.ops.iter_range_ctx(range, &self.ctx) {
+                            apply(&mut len, &op)?;
+                        }
+                    }
+                    TransformedResultRaw::DeleteAlreadyHappened(_) => {}
+                }
+            }
+            Some(len)
+        })
+    }
+
+    /// Check that operations received from a remote peer only name positions which exist in the
+    /// document at the operation's parents. The operations must be sorted, and they must be newer
+    /// than all of the operations already in this CRDT. Returns the length of the document once
+    /// the operations have been merged, or None if any of the operations are invalid.
+    pub(crate) fn check_remote_ops(&self, cg: &CausalGraph, new_ops: &[(DTRange, &TextOperation)]) -> Option<usize> {
+        // Positions past the end of every item ever inserted are definitely invalid. This check is
+        // cheap, and it keeps absurd positions away from the merge code below.
+        let mut num_inserted = self.num_inserted;
+        for (_, op) in new_ops.iter() {
+            let pos = if op.kind == ListOpKind::Ins { op.loc.span.start } else { op.loc.span.end };
+            if pos > num_inserted { return None; }
+            if op.kind == ListOpKind::Ins { num_inserted += op.len(); }
+        }
+
+        let len = match self.len {
+            Some(len) => len,
+            None if self.ops.is_empty() => 0,
+            None => self.merged_len(cg, &[], 0, self.frontier.as_ref())?,
+        };
+
+        // Transforming the new operations only needs our operations which are concurrent with them.
+        // Copy those (minus their content) and the new operations into a scratch CRDT.
+        let last_versions: Vec<LV> = new_ops.iter().map(|(v_range, _)| v_range.last()).collect();
+        let merge_frontier = cg.graph.find_dominators(&last_versions);
+        let conflict = cg.graph.find_conflicting_simple(self.frontier.as_ref(), merge_frontier.as_ref());
+
+        let mut scratch = TextInfo::default();
+        for span in conflict.rev_spans.iter().rev() {
+            for KVPair(v, op) in self.ops.iter_range_ctx(*span, &self.ctx) {
+                scratch.ops.push(KVPair(v, ListOpMetrics { content_pos: None, ..op }));
+            }
+        }
+        for (v_range, op) in new_ops.iter() {
+            scratch.ops.push(KVPair(v_range.start, ListOpMetrics { loc: op.loc, kind: op.kind, content_pos: None }));
+        }
+
+        scratch.merged_len(cg, self.frontier.as_ref(), len, merge_frontier.as_ref())
+    }
+
     #[inline(always)]
     pub(crate) fn apply_op_to(&self, op: ListOpMetrics, dest: &mut JumpRopeBuf) {
         // let xf_pos = op.loc.span.start;